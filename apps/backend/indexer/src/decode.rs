@@ -0,0 +1,179 @@
+use anchor_lang::{
+    __private::base64::{engine::general_purpose::STANDARD, Engine},
+    AnchorDeserialize, Discriminator, Event as AnchorEvent,
+};
+use backend::{
+    MarketCategory, MarketClosed, MarketCreated, MarketInvalidated, MarketMetadataUpdated,
+    MarketResolved, PositionTransferred, RefundClaimed, ResidualWithdrawn, SeriesMarketSpawned,
+    SharesBought, SharesSold, WinningsClaimed,
+};
+use serde::{Deserialize, Serialize};
+
+/// A confirmed transaction that touched the program, in the shape both the
+/// fixture files and the RPC source produce.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Transaction {
+    pub slot: u64,
+    pub signature: String,
+    #[serde(default)]
+    pub block_time: Option<i64>,
+    /// Failed transactions are still recorded so the checkpoint moves past them.
+    #[serde(default)]
+    pub failed: bool,
+    #[serde(default)]
+    pub instructions: Vec<RawInstruction>,
+    #[serde(default)]
+    pub logs: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RawInstruction {
+    pub program_id: String,
+    pub accounts: Vec<String>,
+    /// Base64 encoded instruction data.
+    pub data: String,
+}
+
+/// The text of a market, which travels in `create_market` and
+/// `update_metadata` instruction data. Spawned series markets carry theirs
+/// in `SeriesMarketSpawned` instead.
+pub struct MarketMetadata {
+    /// Address of the market account the instruction writes.
+    pub market: String,
+    pub question: String,
+    pub description: String,
+    pub category: MarketCategory,
+    pub oracle_source: String,
+    pub metadata_uri: String,
+    pub metadata_hash: [u8; 32],
+}
+
+pub enum Event {
+    MarketCreated(MarketCreated),
+    SharesBought(SharesBought),
+    SharesSold(SharesSold),
+    PositionTransferred(PositionTransferred),
+    MarketResolved(MarketResolved),
+    MarketInvalidated(MarketInvalidated),
+    WinningsClaimed(WinningsClaimed),
+    RefundClaimed(RefundClaimed),
+    ResidualWithdrawn(ResidualWithdrawn),
+    MarketClosed(MarketClosed),
+    MarketMetadataUpdated(MarketMetadataUpdated),
+    SeriesMarketSpawned(SeriesMarketSpawned),
+}
+
+fn try_event<T: AnchorEvent>(data: &[u8]) -> Option<T> {
+    let payload = data.strip_prefix(T::DISCRIMINATOR)?;
+    T::deserialize(&mut &payload[..]).ok()
+}
+
+impl Event {
+    /// Decodes the payload of a `Program data:` log line.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        None.or_else(|| try_event(data).map(Event::MarketCreated))
+            .or_else(|| try_event(data).map(Event::SharesBought))
+            .or_else(|| try_event(data).map(Event::SharesSold))
+            .or_else(|| try_event(data).map(Event::PositionTransferred))
+            .or_else(|| try_event(data).map(Event::MarketResolved))
+            .or_else(|| try_event(data).map(Event::MarketInvalidated))
+            .or_else(|| try_event(data).map(Event::WinningsClaimed))
+            .or_else(|| try_event(data).map(Event::RefundClaimed))
+            .or_else(|| try_event(data).map(Event::ResidualWithdrawn))
+            .or_else(|| try_event(data).map(Event::MarketClosed))
+            .or_else(|| try_event(data).map(Event::MarketMetadataUpdated))
+            .or_else(|| try_event(data).map(Event::SeriesMarketSpawned))
+    }
+}
+
+/// The program's events in emission order. When the logs carry the runtime's
+/// `invoke`/`success` framing, only data logged while the program itself is
+/// executing is considered, so other programs cannot forge events.
+pub fn events(transaction: &Transaction) -> Vec<Event> {
+    let program_id = backend::ID.to_string();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut events = Vec::new();
+
+    for log in &transaction.logs {
+        let mut words = log.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("Program"), Some(id), Some("invoke")) => stack.push(id),
+            (Some("Program"), Some(_), Some("success" | "failed:")) => {
+                stack.pop();
+            }
+            (Some("Program"), Some("data:"), Some(data)) => {
+                if stack.last().is_some_and(|id| *id != program_id) {
+                    continue;
+                }
+                if let Some(event) = STANDARD.decode(data).ok().and_then(|d| Event::decode(&d)) {
+                    events.push(event);
+                }
+            }
+            _ => {}
+        }
+    }
+    events
+}
+
+fn try_instruction<T: AnchorDeserialize + Discriminator>(data: &[u8]) -> Option<T> {
+    let args = data.strip_prefix(T::DISCRIMINATOR)?;
+    T::deserialize(&mut &args[..]).ok()
+}
+
+/// Metadata of every market the transaction creates or updates.
+pub fn market_metadata(transaction: &Transaction) -> Vec<MarketMetadata> {
+    use backend::instruction::{CreateMarket, UpdateMetadata};
+
+    let program_id = backend::ID.to_string();
+    transaction
+        .instructions
+        .iter()
+        .filter(|ix| ix.program_id == program_id)
+        .filter_map(|ix| {
+            // Both instructions take the market as their first account.
+            let market = ix.accounts.first()?.clone();
+            let data = STANDARD.decode(&ix.data).ok()?;
+            if let Some(ix) = try_instruction::<CreateMarket>(&data) {
+                return Some(MarketMetadata {
+                    market,
+                    question: ix.question,
+                    description: ix.description,
+                    category: ix.category,
+                    oracle_source: ix.oracle_source,
+                    metadata_uri: ix.metadata_uri,
+                    metadata_hash: ix.metadata_hash,
+                });
+            }
+            let ix = try_instruction::<UpdateMetadata>(&data)?;
+            Some(MarketMetadata {
+                market,
+                question: ix.question,
+                description: ix.description,
+                category: ix.category,
+                oracle_source: ix.oracle_source,
+                metadata_uri: ix.metadata_uri,
+                metadata_hash: ix.metadata_hash,
+            })
+        })
+        .collect()
+}
+
+/// Lowercase hex of a metadata hash, or `None` for a market with on-chain text.
+pub fn hash_hex(hash: &[u8; 32]) -> Option<String> {
+    if *hash == [0; 32] {
+        return None;
+    }
+    Some(hash.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+pub fn category_name(category: MarketCategory) -> &'static str {
+    match category {
+        MarketCategory::Sports => "sports",
+        MarketCategory::Politics => "politics",
+        MarketCategory::Crypto => "crypto",
+        MarketCategory::Economics => "economics",
+        MarketCategory::Entertainment => "entertainment",
+        MarketCategory::Science => "science",
+        MarketCategory::Other => "other",
+    }
+}
@@ -0,0 +1,27 @@
+//! Off-chain indexer for the `kalshi` program.
+//!
+//! Transactions are pulled from a [`Source`](source::Source), their events
+//! and `create_market` instructions decoded, and the result written to the
+//! normalized `markets`, `trades`, `positions` and `price_history` tables.
+//! Each transaction is applied atomically together with the source's
+//! checkpoint, so an interrupted run resumes where it stopped.
+
+pub mod decode;
+pub mod source;
+pub mod store;
+
+use anyhow::Result;
+
+use source::Source;
+use store::Store;
+
+/// Applies one batch from `source`, returning how many transactions it held.
+pub fn index_batch(store: &mut Store, source: &mut dyn Source) -> Result<usize> {
+    let name = source.name();
+    let checkpoint = store.checkpoint(&name)?;
+    let batch = source.next_batch(checkpoint.as_ref())?;
+    for transaction in &batch {
+        store.apply(&name, transaction)?;
+    }
+    Ok(batch.len())
+}
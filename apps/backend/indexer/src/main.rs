@@ -0,0 +1,51 @@
+use std::{path::PathBuf, thread, time::Duration};
+
+use anyhow::Result;
+use clap::Parser;
+use indexer::{
+    index_batch,
+    source::{FixtureSource, RpcSource, Source},
+    store::Store,
+};
+
+/// Materializes kalshi markets, trades and positions into SQLite.
+#[derive(Parser)]
+struct Args {
+    /// SQLite database to write to; created if missing.
+    #[arg(long, default_value = "kalshi.db")]
+    db: PathBuf,
+
+    /// Replay a JSON-lines transaction file instead of polling RPC.
+    #[arg(long, conflicts_with = "rpc")]
+    fixture: Option<PathBuf>,
+
+    /// JSON-RPC endpoint to poll for new transactions.
+    #[arg(long, default_value = "http://127.0.0.1:8899")]
+    rpc: String,
+
+    /// Seconds between RPC polls.
+    #[arg(long, default_value_t = 2)]
+    interval: u64,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut store = Store::open(&args.db)?;
+
+    if let Some(path) = args.fixture {
+        let mut source = FixtureSource::new(path);
+        let indexed = index_batch(&mut store, &mut source)?;
+        println!("indexed {indexed} transactions from {}", source.name());
+        return Ok(());
+    }
+
+    let mut source = RpcSource::new(args.rpc);
+    loop {
+        match index_batch(&mut store, &mut source) {
+            Ok(0) => {}
+            Ok(indexed) => println!("indexed {indexed} transactions"),
+            Err(error) => eprintln!("indexing failed, retrying: {error:#}"),
+        }
+        thread::sleep(Duration::from_secs(args.interval));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+
+use anchor_lang::__private::base64::{engine::general_purpose::STANDARD, Engine};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+use crate::{
+    decode::{RawInstruction, Transaction},
+    store::Checkpoint,
+};
+
+/// Where transactions come from. Sources return transactions strictly after
+/// the checkpoint, oldest first; an empty batch means there is nothing new yet.
+pub trait Source {
+    /// Name the checkpoint is stored under.
+    fn name(&self) -> String;
+
+    fn next_batch(&mut self, after: Option<&Checkpoint>) -> Result<Vec<Transaction>>;
+}
+
+/// Replays a JSON-lines file with one `Transaction` per line, as written by
+/// an exporter or a local test validator's ledger dump.
+pub struct FixtureSource {
+    path: PathBuf,
+    done: bool,
+}
+
+impl FixtureSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            done: false,
+        }
+    }
+}
+
+impl Source for FixtureSource {
+    fn name(&self) -> String {
+        format!("fixture:{}", self.path.display())
+    }
+
+    fn next_batch(&mut self, after: Option<&Checkpoint>) -> Result<Vec<Transaction>> {
+        if self.done {
+            return Ok(Vec::new());
+        }
+        self.done = true;
+
+        let file =
+            File::open(&self.path).with_context(|| format!("opening {}", self.path.display()))?;
+        let mut transactions = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let transaction: Transaction = serde_json::from_str(&line)
+                .with_context(|| format!("{}:{}", self.path.display(), number + 1))?;
+            transactions.push(transaction);
+        }
+
+        let Some(checkpoint) = after else {
+            return Ok(transactions);
+        };
+        let resume = match transactions
+            .iter()
+            .position(|tx| tx.signature == checkpoint.signature)
+        {
+            Some(index) => index + 1,
+            None => transactions
+                .iter()
+                .position(|tx| tx.slot > checkpoint.slot)
+                .unwrap_or(transactions.len()),
+        };
+        Ok(transactions.split_off(resume))
+    }
+}
+
+/// Polls a JSON-RPC node for the program's signatures and fetches each new
+/// transaction.
+pub struct RpcSource {
+    url: String,
+    program_id: String,
+    page_size: usize,
+}
+
+impl RpcSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            program_id: backend::ID.to_string(),
+            page_size: 1_000,
+        }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let response: Value = ureq::post(&self.url)
+            .send_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))
+            .with_context(|| format!("{method} request to {}", self.url))?
+            .into_json()?;
+        if let Some(error) = response.get("error") {
+            bail!("{method} failed: {error}");
+        }
+        Ok(response["result"].clone())
+    }
+
+    /// Signatures newer than the checkpoint, oldest first.
+    fn signatures(&self, until: Option<&str>) -> Result<Vec<(u64, String)>> {
+        let mut signatures = Vec::new();
+        let mut before: Option<String> = None;
+        loop {
+            let mut config = json!({ "limit": self.page_size, "commitment": "confirmed" });
+            if let Some(until) = until {
+                config["until"] = json!(until);
+            }
+            if let Some(before) = &before {
+                config["before"] = json!(before);
+            }
+            let page = self.call("getSignaturesForAddress", json!([self.program_id, config]))?;
+            let page = page.as_array().cloned().unwrap_or_default();
+            for entry in &page {
+                let signature = entry["signature"].as_str().unwrap_or_default().to_string();
+                signatures.push((entry["slot"].as_u64().unwrap_or_default(), signature));
+            }
+            if page.len() < self.page_size {
+                break;
+            }
+            before = signatures.last().map(|(_, signature)| signature.clone());
+        }
+        signatures.reverse();
+        Ok(signatures)
+    }
+
+    fn transaction(&self, slot: u64, signature: String) -> Result<Transaction> {
+        let result = self.call(
+            "getTransaction",
+            json!([signature, {
+                "encoding": "json",
+                "commitment": "confirmed",
+                "maxSupportedTransactionVersion": 0,
+            }]),
+        )?;
+        let meta = &result["meta"];
+        let message = &result["transaction"]["message"];
+
+        let mut keys: Vec<String> = strings(&message["accountKeys"]);
+        keys.extend(strings(&meta["loadedAddresses"]["writable"]));
+        keys.extend(strings(&meta["loadedAddresses"]["readonly"]));
+        let key = |index: &Value| -> Result<String> {
+            let index = index.as_u64().context("account index")? as usize;
+            keys.get(index)
+                .cloned()
+                .context("account index out of range")
+        };
+
+        let mut instructions = Vec::new();
+        for ix in message["instructions"].as_array().into_iter().flatten() {
+            let data = bs58::decode(ix["data"].as_str().unwrap_or_default()).into_vec()?;
+            instructions.push(RawInstruction {
+                program_id: key(&ix["programIdIndex"])?,
+                accounts: ix["accounts"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(&key)
+                    .collect::<Result<_>>()?,
+                data: STANDARD.encode(data),
+            });
+        }
+
+        Ok(Transaction {
+            slot: result["slot"].as_u64().unwrap_or(slot),
+            signature,
+            block_time: result["blockTime"].as_i64(),
+            failed: !meta["err"].is_null(),
+            instructions,
+            logs: strings(&meta["logMessages"]),
+        })
+    }
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+impl Source for RpcSource {
+    fn name(&self) -> String {
+        format!("rpc:{}", self.program_id)
+    }
+
+    fn next_batch(&mut self, after: Option<&Checkpoint>) -> Result<Vec<Transaction>> {
+        let until = after.map(|checkpoint| checkpoint.signature.as_str());
+        self.signatures(until)?
+            .into_iter()
+            .map(|(slot, signature)| self.transaction(slot, signature))
+            .collect()
+    }
+}
@@ -0,0 +1,522 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use backend::Outcome;
+use rusqlite::{params, Connection, OptionalExtension, Transaction as DbTransaction};
+
+use crate::decode::{self, Event, MarketMetadata, Transaction};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS markets (
+    market_id            INTEGER PRIMARY KEY,
+    address              TEXT NOT NULL,
+    creator              TEXT NOT NULL,
+    question             TEXT,
+    description          TEXT,
+    category             TEXT,
+    oracle_source        TEXT,
+    metadata_uri         TEXT,
+    metadata_hash        TEXT,
+    created_at           INTEGER NOT NULL,
+    end_timestamp        INTEGER NOT NULL,
+    resolution_timestamp INTEGER NOT NULL,
+    initial_liquidity    INTEGER NOT NULL,
+    yes_liquidity        INTEGER NOT NULL,
+    no_liquidity         INTEGER NOT NULL,
+    total_volume         INTEGER NOT NULL DEFAULT 0,
+    status               TEXT NOT NULL,
+    winning_outcome      TEXT,
+    settled_at           INTEGER,
+    residual_withdrawn   INTEGER NOT NULL DEFAULT 0,
+    slot                 INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS trades (
+    signature   TEXT NOT NULL,
+    event_index INTEGER NOT NULL,
+    slot        INTEGER NOT NULL,
+    timestamp   INTEGER NOT NULL,
+    market_id   INTEGER NOT NULL REFERENCES markets (market_id),
+    user        TEXT NOT NULL,
+    side        TEXT NOT NULL,
+    outcome     TEXT NOT NULL,
+    shares      INTEGER NOT NULL,
+    amount      INTEGER NOT NULL,
+    fee         INTEGER NOT NULL,
+    yes_price   REAL NOT NULL,
+    PRIMARY KEY (signature, event_index)
+);
+CREATE INDEX IF NOT EXISTS trades_by_market ON trades (market_id, slot);
+CREATE INDEX IF NOT EXISTS trades_by_user ON trades (user, slot);
+
+CREATE TABLE IF NOT EXISTS positions (
+    market_id      INTEGER NOT NULL REFERENCES markets (market_id),
+    user           TEXT NOT NULL,
+    yes_shares     INTEGER NOT NULL DEFAULT 0,
+    no_shares      INTEGER NOT NULL DEFAULT 0,
+    total_invested INTEGER NOT NULL DEFAULT 0,
+    total_received INTEGER NOT NULL DEFAULT 0,
+    total_claimed  INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (market_id, user)
+);
+
+CREATE TABLE IF NOT EXISTS price_history (
+    market_id     INTEGER NOT NULL REFERENCES markets (market_id),
+    slot          INTEGER NOT NULL,
+    timestamp     INTEGER NOT NULL,
+    signature     TEXT NOT NULL,
+    event_index   INTEGER NOT NULL,
+    yes_price     REAL NOT NULL,
+    yes_liquidity INTEGER NOT NULL,
+    no_liquidity  INTEGER NOT NULL,
+    volume        INTEGER NOT NULL,
+    PRIMARY KEY (signature, event_index)
+);
+CREATE INDEX IF NOT EXISTS price_history_by_market ON price_history (market_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS checkpoints (
+    source    TEXT PRIMARY KEY,
+    slot      INTEGER NOT NULL,
+    signature TEXT NOT NULL
+);
+";
+
+/// The last transaction a source has fully applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub slot: u64,
+    pub signature: String,
+}
+
+/// Price of YES implied by the pool, matching the program's pricing.
+pub fn yes_price(yes_liquidity: u64, no_liquidity: u64) -> f64 {
+    let total = yes_liquidity as f64 + no_liquidity as f64;
+    if total == 0.0 {
+        return 0.5;
+    }
+    no_liquidity as f64 / total
+}
+
+fn outcome_name(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Yes => "yes",
+        Outcome::No => "no",
+    }
+}
+
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path.as_ref())
+            .with_context(|| format!("opening {}", path.as_ref().display()))?;
+        Self::init(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    pub fn checkpoint(&self, source: &str) -> Result<Option<Checkpoint>> {
+        let checkpoint = self
+            .conn
+            .query_row(
+                "SELECT slot, signature FROM checkpoints WHERE source = ?1",
+                [source],
+                |row| {
+                    Ok(Checkpoint {
+                        slot: row.get(0)?,
+                        signature: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(checkpoint)
+    }
+
+    /// Applies every event in `transaction` and advances the checkpoint of
+    /// `source` in a single SQLite transaction, so a crash never leaves a
+    /// transaction half indexed or indexed twice.
+    pub fn apply(&mut self, source: &str, transaction: &Transaction) -> Result<()> {
+        let db = self.conn.transaction()?;
+        if !transaction.failed {
+            let metadata = decode::market_metadata(transaction);
+            for (index, event) in decode::events(transaction).iter().enumerate() {
+                apply_event(&db, transaction, index, event, &metadata).with_context(|| {
+                    format!("applying event {index} of {}", transaction.signature)
+                })?;
+            }
+        }
+        db.execute(
+            "INSERT INTO checkpoints (source, slot, signature) VALUES (?1, ?2, ?3)
+             ON CONFLICT (source) DO UPDATE SET slot = excluded.slot, signature = excluded.signature",
+            params![source, transaction.slot, transaction.signature],
+        )?;
+        db.commit()?;
+        Ok(())
+    }
+}
+
+fn apply_event(
+    db: &DbTransaction,
+    transaction: &Transaction,
+    index: usize,
+    event: &Event,
+    metadata: &[MarketMetadata],
+) -> Result<()> {
+    match event {
+        Event::MarketCreated(created) => {
+            let address = created.market.to_string();
+            let text = metadata.iter().find(|m| m.market == address);
+            db.execute(
+                "INSERT INTO markets (market_id, address, creator, question, description,
+                    category, oracle_source, metadata_uri, metadata_hash, created_at,
+                    end_timestamp, resolution_timestamp, initial_liquidity, yes_liquidity,
+                    no_liquidity, status, slot)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                    'active', ?16)
+                 ON CONFLICT (market_id) DO UPDATE SET
+                    creator = excluded.creator, question = excluded.question,
+                    description = excluded.description, category = excluded.category,
+                    oracle_source = excluded.oracle_source,
+                    metadata_uri = excluded.metadata_uri,
+                    metadata_hash = excluded.metadata_hash, created_at = excluded.created_at,
+                    end_timestamp = excluded.end_timestamp,
+                    resolution_timestamp = excluded.resolution_timestamp,
+                    initial_liquidity = excluded.initial_liquidity,
+                    yes_liquidity = excluded.yes_liquidity, no_liquidity = excluded.no_liquidity,
+                    total_volume = 0, status = 'active', winning_outcome = NULL,
+                    settled_at = NULL, residual_withdrawn = 0, slot = excluded.slot",
+                params![
+                    created.market_id,
+                    address,
+                    created.creator.to_string(),
+                    text.map(|m| m.question.as_str()),
+                    text.map(|m| m.description.as_str()),
+                    text.map(|m| decode::category_name(m.category)),
+                    text.map(|m| m.oracle_source.as_str()),
+                    text.and_then(|m| (!m.metadata_uri.is_empty()).then_some(&m.metadata_uri)),
+                    text.and_then(|m| decode::hash_hex(&m.metadata_hash)),
+                    created.timestamp,
+                    created.end_timestamp,
+                    created.resolution_timestamp,
+                    created.initial_liquidity,
+                    created.yes_liquidity,
+                    created.no_liquidity,
+                    transaction.slot,
+                ],
+            )?;
+            record_price(
+                db,
+                transaction,
+                index,
+                created.market_id,
+                created.timestamp,
+                created.yes_liquidity,
+                created.no_liquidity,
+                0,
+            )?;
+        }
+        Event::SharesBought(bought) => {
+            let price = yes_price(bought.yes_liquidity, bought.no_liquidity);
+            db.execute(
+                "INSERT INTO trades (signature, event_index, slot, timestamp, market_id, user,
+                    side, outcome, shares, amount, fee, yes_price)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'buy', ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    transaction.signature,
+                    index,
+                    transaction.slot,
+                    bought.timestamp,
+                    bought.market_id,
+                    bought.user.to_string(),
+                    outcome_name(bought.outcome),
+                    bought.shares_out,
+                    bought.cost,
+                    bought.fee,
+                    price,
+                ],
+            )?;
+            let (yes, no) = match bought.outcome {
+                Outcome::Yes => (bought.shares_out, 0),
+                Outcome::No => (0, bought.shares_out),
+            };
+            db.execute(
+                "INSERT INTO positions (market_id, user, yes_shares, no_shares, total_invested)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (market_id, user) DO UPDATE SET
+                    yes_shares = yes_shares + excluded.yes_shares,
+                    no_shares = no_shares + excluded.no_shares,
+                    total_invested = total_invested + excluded.total_invested",
+                params![
+                    bought.market_id,
+                    bought.user.to_string(),
+                    yes,
+                    no,
+                    bought.cost
+                ],
+            )?;
+            update_pool(
+                db,
+                bought.market_id,
+                bought.yes_liquidity,
+                bought.no_liquidity,
+                bought.cost,
+            )?;
+            record_price(
+                db,
+                transaction,
+                index,
+                bought.market_id,
+                bought.timestamp,
+                bought.yes_liquidity,
+                bought.no_liquidity,
+                bought.cost,
+            )?;
+        }
+        Event::SharesSold(sold) => {
+            let price = yes_price(sold.yes_liquidity, sold.no_liquidity);
+            db.execute(
+                "INSERT INTO trades (signature, event_index, slot, timestamp, market_id, user,
+                    side, outcome, shares, amount, fee, yes_price)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'sell', ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    transaction.signature,
+                    index,
+                    transaction.slot,
+                    sold.timestamp,
+                    sold.market_id,
+                    sold.user.to_string(),
+                    outcome_name(sold.outcome),
+                    sold.shares_in,
+                    sold.payout,
+                    sold.fee,
+                    price,
+                ],
+            )?;
+            let (yes, no) = match sold.outcome {
+                Outcome::Yes => (sold.shares_in, 0),
+                Outcome::No => (0, sold.shares_in),
+            };
+            db.execute(
+                "UPDATE positions SET
+                    yes_shares = yes_shares - ?3,
+                    no_shares = no_shares - ?4,
+                    total_received = total_received + ?5
+                 WHERE market_id = ?1 AND user = ?2",
+                params![
+                    sold.market_id,
+                    sold.user.to_string(),
+                    yes,
+                    no,
+                    sold.payout - sold.fee
+                ],
+            )?;
+            update_pool(
+                db,
+                sold.market_id,
+                sold.yes_liquidity,
+                sold.no_liquidity,
+                sold.payout,
+            )?;
+            record_price(
+                db,
+                transaction,
+                index,
+                sold.market_id,
+                sold.timestamp,
+                sold.yes_liquidity,
+                sold.no_liquidity,
+                sold.payout,
+            )?;
+        }
+        Event::PositionTransferred(transferred) => {
+            db.execute(
+                "UPDATE positions SET
+                    yes_shares = yes_shares - ?3,
+                    no_shares = no_shares - ?4,
+                    total_invested = total_invested - ?5
+                 WHERE market_id = ?1 AND user = ?2",
+                params![
+                    transferred.market_id,
+                    transferred.from.to_string(),
+                    transferred.yes_shares,
+                    transferred.no_shares,
+                    transferred.total_invested
+                ],
+            )?;
+            db.execute(
+                "INSERT INTO positions (market_id, user, yes_shares, no_shares, total_invested)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (market_id, user) DO UPDATE SET
+                    yes_shares = yes_shares + excluded.yes_shares,
+                    no_shares = no_shares + excluded.no_shares,
+                    total_invested = total_invested + excluded.total_invested",
+                params![
+                    transferred.market_id,
+                    transferred.to.to_string(),
+                    transferred.yes_shares,
+                    transferred.no_shares,
+                    transferred.total_invested
+                ],
+            )?;
+        }
+        Event::MarketResolved(resolved) => {
+            db.execute(
+                "UPDATE markets SET status = 'resolved', winning_outcome = ?2, settled_at = ?3
+                 WHERE market_id = ?1",
+                params![
+                    resolved.market_id,
+                    outcome_name(resolved.winning_outcome),
+                    resolved.timestamp
+                ],
+            )?;
+        }
+        Event::MarketInvalidated(invalidated) => {
+            db.execute(
+                "UPDATE markets SET status = 'invalid', settled_at = ?2 WHERE market_id = ?1",
+                params![invalidated.market_id, invalidated.timestamp],
+            )?;
+        }
+        Event::WinningsClaimed(claimed) => {
+            let column = match claimed.outcome {
+                Outcome::Yes => "yes_shares",
+                Outcome::No => "no_shares",
+            };
+            db.execute(
+                &format!(
+                    "UPDATE positions SET {column} = 0, total_claimed = total_claimed + ?3
+                     WHERE market_id = ?1 AND user = ?2"
+                ),
+                params![claimed.market_id, claimed.user.to_string(), claimed.payout],
+            )?;
+        }
+        Event::RefundClaimed(refunded) => {
+            db.execute(
+                "UPDATE positions SET yes_shares = 0, no_shares = 0,
+                    total_claimed = total_claimed + ?3
+                 WHERE market_id = ?1 AND user = ?2",
+                params![
+                    refunded.market_id,
+                    refunded.user.to_string(),
+                    refunded.refund
+                ],
+            )?;
+        }
+        Event::ResidualWithdrawn(withdrawn) => {
+            db.execute(
+                "UPDATE markets SET residual_withdrawn = residual_withdrawn + ?2
+                 WHERE market_id = ?1",
+                params![withdrawn.market_id, withdrawn.amount],
+            )?;
+        }
+        Event::MarketMetadataUpdated(updated) => {
+            let address = updated.market.to_string();
+            let text = metadata
+                .iter()
+                .rev()
+                .find(|m| m.market == address)
+                .context("metadata update without its instruction")?;
+            db.execute(
+                "UPDATE markets SET question = ?2, description = ?3, category = ?4,
+                    oracle_source = ?5, metadata_uri = ?6, metadata_hash = ?7
+                 WHERE market_id = ?1",
+                params![
+                    updated.market_id,
+                    text.question,
+                    text.description,
+                    decode::category_name(text.category),
+                    text.oracle_source,
+                    (!text.metadata_uri.is_empty()).then_some(&text.metadata_uri),
+                    decode::hash_hex(&text.metadata_hash),
+                ],
+            )?;
+        }
+        Event::SeriesMarketSpawned(spawned) => {
+            db.execute(
+                "UPDATE markets SET question = ?2, description = ?3, category = ?4,
+                    oracle_source = ?5, metadata_uri = NULL, metadata_hash = NULL
+                 WHERE market_id = ?1",
+                params![
+                    spawned.market_id,
+                    spawned.question,
+                    spawned.description,
+                    decode::category_name(spawned.category),
+                    spawned.oracle_source,
+                ],
+            )?;
+        }
+        Event::MarketClosed(closed) => {
+            // Nothing is redeemable once a market is closed, so its positions
+            // go with it.
+            db.execute(
+                "UPDATE markets SET status = 'closed' WHERE market_id = ?1",
+                params![closed.market_id],
+            )?;
+            db.execute(
+                "DELETE FROM positions WHERE market_id = ?1",
+                params![closed.market_id],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn update_pool(
+    db: &DbTransaction,
+    market_id: u64,
+    yes_liquidity: u64,
+    no_liquidity: u64,
+    volume: u64,
+) -> Result<()> {
+    db.execute(
+        "UPDATE markets SET yes_liquidity = ?2, no_liquidity = ?3,
+            total_volume = total_volume + ?4
+         WHERE market_id = ?1",
+        params![market_id, yes_liquidity, no_liquidity, volume],
+    )?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn record_price(
+    db: &DbTransaction,
+    transaction: &Transaction,
+    index: usize,
+    market_id: u64,
+    timestamp: i64,
+    yes_liquidity: u64,
+    no_liquidity: u64,
+    volume: u64,
+) -> Result<()> {
+    db.execute(
+        "INSERT INTO price_history (market_id, slot, timestamp, signature, event_index,
+            yes_price, yes_liquidity, no_liquidity, volume)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            market_id,
+            transaction.slot,
+            timestamp,
+            transaction.signature,
+            index,
+            yes_price(yes_liquidity, no_liquidity),
+            yes_liquidity,
+            no_liquidity,
+            volume,
+        ],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,269 @@
+#[path = "../../programs/backend/tests/common/mod.rs"]
+mod common;
+
+use std::{fs, path::PathBuf};
+
+use anchor_lang::{
+    __private::base64::{engine::general_purpose::STANDARD, Engine},
+    solana_program::instruction::Instruction,
+};
+use backend::Outcome;
+use common::*;
+use indexer::{
+    decode::{RawInstruction, Transaction},
+    index_batch,
+    source::{FixtureSource, Source},
+    store::Store,
+};
+
+/// Executes instructions against the in-process runtime and records each
+/// one as the transaction an RPC node would return for it.
+struct Recorder {
+    kalshi: Kalshi,
+    transactions: Vec<Transaction>,
+}
+
+impl Recorder {
+    fn new() -> Self {
+        Self {
+            kalshi: Kalshi::new(),
+            transactions: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, ix: Instruction) {
+        self.kalshi.svm.take_logs();
+        let failed = self.kalshi.svm.process_instruction(ix.clone()).is_err();
+        let slot = self.transactions.len() as u64 + 1;
+        self.transactions.push(Transaction {
+            slot,
+            signature: format!("signature-{slot}"),
+            block_time: Some(self.kalshi.now()),
+            failed,
+            instructions: vec![RawInstruction {
+                program_id: ix.program_id.to_string(),
+                accounts: ix.accounts.iter().map(|a| a.pubkey.to_string()).collect(),
+                data: STANDARD.encode(&ix.data),
+            }],
+            logs: self.kalshi.svm.take_logs(),
+        });
+    }
+}
+
+const METADATA_URI: &str = "https://example.com/markets/2.json";
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("kalshi-{name}-{}", std::process::id()))
+}
+
+fn write_fixture(path: &PathBuf, transactions: &[Transaction]) {
+    let lines: Vec<String> = transactions
+        .iter()
+        .map(|tx| serde_json::to_string(tx).unwrap())
+        .collect();
+    fs::write(path, lines.join("\n")).unwrap();
+}
+
+/// Two markets with trades on both sides, a partial sell, a failed trade,
+/// a position transfer, a resolution with a claim and an invalidation with a
+/// refund. The second
+/// market moves its text off-chain before trading.
+fn scenario() -> (Recorder, Vec<User>, u64) {
+    let mut r = Recorder::new();
+    let creator = r.kalshi.user(1_000 * USDC);
+    let alice = r.kalshi.user(1_000 * USDC);
+    let bob = r.kalshi.user(1_000 * USDC);
+
+    let mut args = MarketArgs::new(0, r.kalshi.now());
+    args.question = "Will it rain tomorrow?".to_string();
+    r.record(r.kalshi.create_market_ix(&creator, args));
+    r.record(
+        r.kalshi
+            .create_market_ix(&creator, MarketArgs::new(1, r.kalshi.now())),
+    );
+    let args = MarketArgs::new(1, r.kalshi.now()).off_chain(METADATA_URI, [0xab; 32]);
+    r.record(r.kalshi.update_metadata_ix(&creator, args));
+
+    r.record(r.kalshi.buy_ix(&alice, 0, Outcome::Yes, 40 * USDC));
+    r.record(r.kalshi.buy_ix(&bob, 0, Outcome::No, 25 * USDC));
+    r.record(r.kalshi.buy_ix(&bob, 1, Outcome::Yes, 10 * USDC));
+    let third = r.kalshi.shares(1, &bob).0 / 3;
+    r.record(r.kalshi.transfer_position_ix(&bob, 1, &alice, third, 0));
+    let half = r.kalshi.shares(0, &alice).0 / 2;
+    r.record(r.kalshi.sell_ix(&alice, 0, Outcome::Yes, half, 0));
+    r.record(r.kalshi.sell_ix(&bob, 0, Outcome::No, u64::MAX, 0));
+
+    r.kalshi.svm.warp(DAY);
+    let authority = r.kalshi.authority;
+    r.record(r.kalshi.resolve_ix(&creator.key, 0, Outcome::Yes));
+    let winnings = r.kalshi.shares(0, &alice).0;
+    r.record(r.kalshi.claim_winnings_ix(&alice, 0));
+    r.record(r.kalshi.invalidate_ix(&authority, 1));
+    r.record(r.kalshi.claim_refund_ix(&bob, 1));
+
+    (r, vec![creator, alice, bob], winnings)
+}
+
+fn count(store: &Store, sql: &str) -> i64 {
+    store
+        .connection()
+        .query_row(sql, [], |row| row.get(0))
+        .unwrap()
+}
+
+fn assert_matches_chain(store: &Store, kalshi: &Kalshi, users: &[User]) {
+    let conn = store.connection();
+    for market_id in [0u64, 1] {
+        let market = kalshi.market_state(market_id);
+        let (yes, no, volume, status): (u64, u64, u64, String) = conn
+            .query_row(
+                "SELECT yes_liquidity, no_liquidity, total_volume, status
+                 FROM markets WHERE market_id = ?1",
+                [market_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(yes, market.yes_liquidity);
+        assert_eq!(no, market.no_liquidity);
+        assert_eq!(volume, market.total_volume);
+        let expected = match market.status() {
+            backend::MarketStatus::Active => "active",
+            backend::MarketStatus::Resolved => "resolved",
+            backend::MarketStatus::Invalid => "invalid",
+        };
+        assert_eq!(status, expected);
+
+        for user in users {
+            let held: Option<(u64, u64)> = conn
+                .query_row(
+                    "SELECT yes_shares, no_shares FROM positions
+                     WHERE market_id = ?1 AND user = ?2",
+                    rusqlite::params![market_id, user.key.to_string()],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .ok();
+            assert_eq!(
+                held.unwrap_or_default(),
+                kalshi.shares(market_id, user),
+                "position of {} in market {market_id}",
+                user.key
+            );
+        }
+    }
+}
+
+#[test]
+fn replay_materializes_markets_trades_and_positions() {
+    let (recorder, users, winnings) = scenario();
+    let path = temp_path("replay.jsonl");
+    write_fixture(&path, &recorder.transactions);
+    let mut store = Store::open_in_memory().unwrap();
+
+    let mut source = FixtureSource::new(&path);
+    assert_eq!(index_batch(&mut store, &mut source).unwrap(), 13);
+    assert_matches_chain(&store, &recorder.kalshi, &users);
+
+    let question: String = store
+        .connection()
+        .query_row(
+            "SELECT question FROM markets WHERE market_id = 0",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(question, "Will it rain tomorrow?");
+    let (question, uri, hash): (String, String, String) = store
+        .connection()
+        .query_row(
+            "SELECT question, metadata_uri, metadata_hash FROM markets WHERE market_id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!((question.as_str(), uri.as_str()), ("", METADATA_URI));
+    assert_eq!(hash, "ab".repeat(32));
+    assert_eq!(count(&store, "SELECT COUNT(*) FROM trades"), 4);
+    assert_eq!(
+        count(&store, "SELECT COUNT(*) FROM trades WHERE side = 'sell'"),
+        1
+    );
+    // One opening price per market plus one per trade.
+    assert_eq!(count(&store, "SELECT COUNT(*) FROM price_history"), 6);
+    assert_eq!(
+        count(
+            &store,
+            "SELECT SUM(total_claimed) FROM positions WHERE market_id = 0"
+        ),
+        winnings as i64
+    );
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn replay_indexes_the_text_of_spawned_series_markets() {
+    let mut r = Recorder::new();
+    let creator = r.kalshi.user(1_000 * USDC);
+    let keeper = r.kalshi.user(0);
+    r.record(
+        r.kalshi
+            .create_series_ix(&creator, SeriesArgs::daily(r.kalshi.now())),
+    );
+    r.kalshi.approve_series(&creator, 0, 100 * USDC).unwrap();
+    r.record(r.kalshi.spawn_next_market_ix(&keeper.key, 0));
+    let path = temp_path("series.jsonl");
+    write_fixture(&path, &r.transactions);
+    let mut store = Store::open_in_memory().unwrap();
+
+    index_batch(&mut store, &mut FixtureSource::new(&path)).unwrap();
+    let market = r.kalshi.market(0);
+    let (question, description, category, oracle_source): (String, String, String, String) = store
+        .connection()
+        .query_row(
+            "SELECT question, description, category, oracle_source
+                 FROM markets WHERE market_id = 0",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .unwrap();
+    assert_eq!(question, market.question);
+    assert_eq!(description, market.description);
+    assert_eq!(category, "crypto");
+    assert_eq!(oracle_source, market.oracle_source);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn resuming_from_a_checkpoint_indexes_each_transaction_once() {
+    let (recorder, users, _) = scenario();
+    let path = temp_path("resume.jsonl");
+    let db = temp_path("resume.db");
+    let _ = fs::remove_file(&db);
+
+    // Index a prefix of the history, as if the process stopped midway.
+    write_fixture(&path, &recorder.transactions[..5]);
+    {
+        let mut store = Store::open(&db).unwrap();
+        let mut source = FixtureSource::new(&path);
+        assert_eq!(index_batch(&mut store, &mut source).unwrap(), 5);
+        let checkpoint = store.checkpoint(&source.name()).unwrap().unwrap();
+        assert_eq!(checkpoint.signature, "signature-5");
+    }
+
+    // The file grows and a fresh process picks up after the checkpoint.
+    write_fixture(&path, &recorder.transactions);
+    let mut store = Store::open(&db).unwrap();
+    assert_eq!(
+        index_batch(&mut store, &mut FixtureSource::new(&path)).unwrap(),
+        8
+    );
+    assert_eq!(
+        index_batch(&mut store, &mut FixtureSource::new(&path)).unwrap(),
+        0
+    );
+    assert_matches_chain(&store, &recorder.kalshi, &users);
+    assert_eq!(count(&store, "SELECT COUNT(*) FROM trades"), 4);
+
+    for file in [path, db] {
+        let _ = fs::remove_file(file);
+    }
+}
@@ -0,0 +1,16 @@
+#![no_main]
+
+//! Drives random sequences of market instructions through the in-process
+//! runtime used by the integration tests and checks that USDC is conserved
+//! across wallets, vaults and the treasury.
+//!
+//!     cargo +nightly fuzz run instruction_sequences
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+use common::sequence::{run, Op};
+
+fuzz_target!(|ops: Vec<Op>| run(&ops));
@@ -1,8 +1,67 @@
-use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer as TokenTransfer};
+// Only for the `IdlResizeAccount` handler that `#[program]` generates at the
+// crate root: it still calls the deprecated `AccountInfo::realloc`, and an
+// allow on the program module does not reach it. Our own code uses `resize`.
+#![allow(deprecated)]
+
+use anchor_lang::{prelude::*, solana_program::hash::hashv, system_program, Discriminator};
+use anchor_spl::{
+    token_2022::spl_token_2022::{
+        self,
+        extension::{
+            transfer_fee::TransferFeeConfig, BaseStateWithExtensions, ExtensionType,
+            StateWithExtensions,
+        },
+    },
+    token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked},
+};
 
 declare_id!("32RHEHXbReKvWE2bNxcH9486qLSNnH4nYMtWHe5axizE");
 
+/// Number of snapshots kept in each market's `PriceHistory` ring buffer.
+pub const PRICE_HISTORY_LEN: usize = 64;
+/// Prices are quoted in millionths of a unit of collateral per share.
+pub const PRICE_SCALE: u64 = 1_000_000;
+/// How long after settlement holders have to claim before a market can be closed.
+pub const MARKET_CLOSE_GRACE_PERIOD: i64 = 30 * 86_400;
+/// Combined `max_len` of the text fields `Market::INIT_SPACE` reserves.
+const MARKET_TEXT_CAPACITY: usize = 200 + 1000 + 100 + 200;
+/// Zeroed space at the end of every versioned account. Fields added later go
+/// between `version` and `_reserved`, taking their bytes from it, so older
+/// accounts still deserialize with the new fields zeroed.
+pub const ACCOUNT_RESERVED_LEN: usize = 64;
+/// Size of a `UserPosition` from before versioning, which `migrate_position`
+/// refuses rather than misreading: it ends at `bump` and has no `opened_at`.
+const UNVERSIONED_POSITION_LEN: usize = 8 + 32 + 32 + 8 + 8 + 8 + 1 + 1;
+/// Most markets a single parlay can combine.
+pub const MAX_PARLAY_LEGS: usize = 8;
+/// Remaining accounts `open_parlay` takes per leg.
+pub const PARLAY_LEG_ACCOUNTS: usize = 4;
+/// Trailing window a parlay leg's time-weighted price is taken over, so a
+/// trade just before opening cannot move the odds it is priced at.
+pub const PARLAY_TWAP_WINDOW: i64 = 3_600;
+/// Least price a parlay leg may have, capping each leg's odds at 20 to 1.
+pub const PARLAY_MIN_LEG_PRICE: u64 = PRICE_SCALE / 20;
+/// Least combined YES and NO liquidity, in collateral base units, a market
+/// needs before parlays may bet on it. Thin pools are cheap to move.
+pub const PARLAY_MIN_LEG_LIQUIDITY: u64 = 1_000_000_000;
+/// Most markets a `TradingDelegate` can be restricted to.
+pub const MAX_DELEGATE_MARKETS: usize = 16;
+/// Most trades a single `batch_trade` may carry.
+pub const MAX_BATCH_LEGS: usize = 16;
+/// Remaining accounts `batch_trade` takes per leg.
+pub const BATCH_LEG_ACCOUNTS: usize = 7;
+/// Remaining accounts `crank_orders` takes per order.
+pub const ORDER_ACCOUNTS: usize = 6;
+/// Token-2022 mint extensions collateral may carry. Transfer fees are paid on
+/// top of deposits and interest only changes the UI amount; anything else
+/// could freeze, claw back or gate transfers out of a vault.
+const SUPPORTED_MINT_EXTENSIONS: &[ExtensionType] = &[
+    ExtensionType::TransferFeeConfig,
+    ExtensionType::InterestBearingConfig,
+    ExtensionType::MetadataPointer,
+    ExtensionType::TokenMetadata,
+];
+
 #[program]
 pub mod kalshi {
 
@@ -19,14 +78,308 @@ pub mod kalshi {
         protocol_state.total_volume = 0;
         protocol_state.treasury = ctx.accounts.treasury.key();
         protocol_state.bump = ctx.bumps.protocol_state;
+        protocol_state.version = ProtocolState::VERSION;
 
         msg!("Protocol initialized with {}% fee", free_bps as f64 / 10.0);
         Ok(())
     }
 
+    pub fn set_creation_bond(ctx: Context<UpdateProtocol>, creation_bond: u64) -> Result<()> {
+        let protocol_state = &mut ctx.accounts.protocol_state;
+        require!(
+            protocol_state.authority == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+        protocol_state.creation_bond = creation_bond;
+
+        msg!("Creation bond set to {}", creation_bond);
+        Ok(())
+    }
+
+    /// Registers `mint` as collateral, or updates its entry. Markets may
+    /// only be created in enabled mints; disabling one leaves its existing
+    /// markets trading and settling as before.
+    pub fn set_collateral(
+        ctx: Context<SetCollateral>,
+        min_liquidity: u64,
+        enabled: bool,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.protocol_state.authority == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+        let collateral = &mut ctx.accounts.collateral;
+        collateral.mint = ctx.accounts.mint.key();
+        collateral.decimals = ctx.accounts.mint.decimals;
+        collateral.treasury = ctx.accounts.treasury.key();
+        collateral.min_liquidity = min_liquidity;
+        collateral.enabled = enabled;
+        collateral.bump = ctx.bumps.collateral;
+        collateral.version = Collateral::VERSION;
+
+        msg!(
+            "Collateral {} {}, minimum liquidity {}",
+            collateral.mint,
+            if enabled { "enabled" } else { "disabled" },
+            min_liquidity
+        );
+        Ok(())
+    }
+
+    /// Sets the caps new markets start with. Zero leaves a cap off.
+    pub fn set_default_limits(
+        ctx: Context<UpdateProtocol>,
+        max_open_interest: u64,
+        max_position_shares: u64,
+    ) -> Result<()> {
+        let protocol_state = &mut ctx.accounts.protocol_state;
+        require!(
+            protocol_state.authority == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+        protocol_state.default_max_open_interest = max_open_interest;
+        protocol_state.default_max_position_shares = max_position_shares;
+
+        msg!(
+            "Default limits set to {} open interest, {} shares per position",
+            max_open_interest,
+            max_position_shares
+        );
+        Ok(())
+    }
+
+    /// Overrides the caps of one market. Lowering a cap below what is already
+    /// held only blocks further buys; nobody is forced out.
+    pub fn set_market_limits(
+        ctx: Context<SetMarketLimits>,
+        max_open_interest: u64,
+        max_position_shares: u64,
+    ) -> Result<()> {
+        let market = &mut ctx.accounts.market_state.load_mut()?;
+        market.max_open_interest = max_open_interest;
+        market.max_position_shares = max_position_shares;
+
+        msg!(
+            "Market {} limits set to {} open interest, {} shares per position",
+            market.market_id,
+            max_open_interest,
+            max_position_shares
+        );
+        Ok(())
+    }
+
+    /// Sets the circuit breaker new markets start with; see `MarketState`.
+    pub fn set_default_circuit_breaker(
+        ctx: Context<UpdateProtocol>,
+        window: i64,
+        max_move_bps: u16,
+        cooldown: i64,
+    ) -> Result<()> {
+        let protocol_state = &mut ctx.accounts.protocol_state;
+        require!(
+            protocol_state.authority == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+        validate_circuit_breaker(window, max_move_bps, cooldown)?;
+        protocol_state.default_breaker_window = window;
+        protocol_state.default_breaker_max_move_bps = max_move_bps;
+        protocol_state.default_breaker_cooldown = cooldown;
+
+        msg!(
+            "Default circuit breaker set to {} bps per {}s window, {}s cooldown",
+            max_move_bps,
+            window,
+            cooldown
+        );
+        Ok(())
+    }
+
+    /// Overrides the circuit breaker of one market and lifts any halt.
+    pub fn set_circuit_breaker(
+        ctx: Context<SetMarketLimits>,
+        window: i64,
+        max_move_bps: u16,
+        cooldown: i64,
+    ) -> Result<()> {
+        validate_circuit_breaker(window, max_move_bps, cooldown)?;
+        let market = &mut ctx.accounts.market_state.load_mut()?;
+        market.breaker_window = window;
+        market.breaker_max_move_bps = max_move_bps;
+        market.breaker_cooldown = cooldown;
+        market.reference_window_start = 0;
+        market.halted_until = 0;
+
+        msg!(
+            "Market {} circuit breaker set to {} bps per {}s window, {}s cooldown",
+            market.market_id,
+            max_move_bps,
+            window,
+            cooldown
+        );
+        Ok(())
+    }
+
+    /// Restricts who may buy and sell. `access_key` is the allowlist's merkle
+    /// root or the attestation issuer's address, and is ignored for `Open`.
+    /// Claims are never gated, so tightening a policy cannot trap funds.
+    pub fn set_access_policy(
+        ctx: Context<SetAccessPolicy>,
+        policy: AccessPolicy,
+        access_key: [u8; 32],
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.authority.key() == ctx.accounts.market.authority
+                || ctx.accounts.authority.key() == ctx.accounts.protocol_state.authority,
+            ErrorCode::Unauthorized
+        );
+        let market = &mut ctx.accounts.market_state.load_mut()?;
+        market.access_policy = policy as u8;
+        market.access_key = match policy {
+            AccessPolicy::Open => [0; 32],
+            _ => access_key,
+        };
+
+        msg!("Market {} access set to {:?}", market.market_id, policy);
+        Ok(())
+    }
+
+    /// Vouches for `user` on behalf of the signing issuer until `expires_at`,
+    /// or indefinitely if zero. Re-issuing updates the expiry.
+    pub fn issue_attestation(ctx: Context<IssueAttestation>, expires_at: i64) -> Result<()> {
+        let attestation = &mut ctx.accounts.attestation;
+        attestation.issuer = ctx.accounts.issuer.key();
+        attestation.user = ctx.accounts.user.key();
+        attestation.expires_at = expires_at;
+        attestation.bump = ctx.bumps.attestation;
+        attestation.version = Attestation::VERSION;
+
+        msg!(
+            "{} attested {} until {}",
+            attestation.issuer,
+            attestation.user,
+            expires_at
+        );
+        Ok(())
+    }
+
+    pub fn revoke_attestation(ctx: Context<RevokeAttestation>) -> Result<()> {
+        msg!(
+            "{} revoked attestation of {}",
+            ctx.accounts.issuer.key(),
+            ctx.accounts.attestation.user
+        );
+        Ok(())
+    }
+
+    /// Proves the signer is a leaf of the allowlist with merkle root `root`,
+    /// recording it as an attestation issued by the root itself so it serves
+    /// every market gated on that allowlist until the root changes.
+    pub fn prove_allowlist(
+        ctx: Context<ProveAllowlist>,
+        root: [u8; 32],
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let user = ctx.accounts.user.key();
+        require!(
+            verify_merkle_proof(&proof, &root, hashv(&[user.as_ref()]).to_bytes()),
+            ErrorCode::InvalidAllowlistProof
+        );
+
+        let attestation = &mut ctx.accounts.attestation;
+        attestation.issuer = Pubkey::new_from_array(root);
+        attestation.user = user;
+        attestation.expires_at = 0;
+        attestation.bump = ctx.bumps.attestation;
+        attestation.version = Attestation::VERSION;
+
+        msg!("{} proved allowlist membership", user);
+        Ok(())
+    }
+
+    /// Lets `delegate` buy and sell on the signer's positions until
+    /// `expires_at`, spending at most `max_spend` from `owner_token_account`
+    /// in the listed markets (every market if empty). Re-approving replaces
+    /// the limits and resets what has been spent.
+    ///
+    /// A token account has a single delegate, so this fails while the
+    /// account still has an allowance for anyone else, such as another
+    /// session key or the series PDA that funds `spawn_next_market`. Revoke
+    /// that first, or trade from a separate token account.
+    pub fn approve_delegate(
+        ctx: Context<ApproveDelegate>,
+        max_spend: u64,
+        expires_at: i64,
+        markets: Vec<u64>,
+    ) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            expires_at > current_time && markets.len() <= MAX_DELEGATE_MARKETS,
+            ErrorCode::InvalidDelegate
+        );
+
+        let trading_delegate = &mut ctx.accounts.trading_delegate;
+        let owner_token_account = &ctx.accounts.owner_token_account;
+        require!(
+            owner_token_account.delegated_amount == 0
+                || owner_token_account.delegate == Some(trading_delegate.key()).into(),
+            ErrorCode::DelegateConflict
+        );
+        trading_delegate.owner = ctx.accounts.owner.key();
+        trading_delegate.delegate = ctx.accounts.delegate.key();
+        trading_delegate.max_spend = max_spend;
+        trading_delegate.spent = 0;
+        trading_delegate.expires_at = expires_at;
+        trading_delegate.markets = markets;
+        trading_delegate.bump = ctx.bumps.trading_delegate;
+        trading_delegate.version = TradingDelegate::VERSION;
+
+        // The delegate never holds funds: buys pull from the owner's wallet
+        // through this allowance, signed for by the delegate's PDA.
+        token_interface::approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token_interface::Approve {
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    delegate: trading_delegate.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            max_spend,
+        )?;
+
+        msg!(
+            "{} delegated trading to {} until {} (max spend {})",
+            trading_delegate.owner,
+            trading_delegate.delegate,
+            expires_at,
+            max_spend
+        );
+        Ok(())
+    }
+
+    pub fn revoke_delegate(ctx: Context<RevokeDelegate>) -> Result<()> {
+        let trading_delegate = &ctx.accounts.trading_delegate;
+        if ctx.accounts.owner_token_account.delegate == Some(trading_delegate.key()).into() {
+            token_interface::revoke(CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token_interface::Revoke {
+                    source: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ))?;
+        }
+
+        msg!(
+            "{} revoked trading by {}",
+            trading_delegate.owner,
+            trading_delegate.delegate
+        );
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn create_market(
         ctx: Context<CreateMarket>,
-        market_id: u64,
         question: String,
         description: String,
         category: MarketCategory,
@@ -34,297 +387,1279 @@ pub mod kalshi {
         resolution_timestamp: i64,
         oracle_source: String,
         initial_liquidity: u64,
+        metadata_uri: String,
+        metadata_hash: [u8; 32],
+        parent_outcome: Option<Outcome>,
     ) -> Result<()> {
-        require!(question.len() <= 200, ErrorCode::QuestionTooLong);
-        require!(description.len() <= 1000, ErrorCode::DescriptionTooLong);
-        require!(oracle_source.len() <= 100, ErrorCode::OracleSourceTooLong);
-        require!(
-            initial_liquidity >= 1_000_000,
-            ErrorCode::InsufficientInitialLiquidity
-        );
-
         let current_time = Clock::get()?.unix_timestamp;
+        let terms = MarketTerms {
+            question,
+            description,
+            category,
+            oracle_source,
+            metadata_uri,
+            metadata_hash,
+            end_timestamp,
+            resolution_timestamp,
+            initial_liquidity,
+        };
+        terms.validate(current_time, &ctx.accounts.collateral)?;
+        check_mint_extensions(&ctx.accounts.usdc_mint)?;
+
+        // A conditional market can only resolve once its parent has, so the
+        // parent must settle within the child's resolution window.
+        let parent_market_id = match (&ctx.accounts.parent_market_state, parent_outcome) {
+            (None, None) => None,
+            (Some(parent), Some(_)) => {
+                let parent = parent.load()?;
+                require!(
+                    parent.status() == MarketStatus::Active
+                        && parent.resolution_timestamp <= resolution_timestamp,
+                    ErrorCode::InvalidParentMarket
+                );
+                Some(parent.market_id)
+            }
+            _ => return err!(ErrorCode::InvalidParentMarket),
+        };
 
-        require!(end_timestamp > current_time, ErrorCode::InvalidEndTime);
-        require!(
-            resolution_timestamp > end_timestamp,
-            ErrorCode::InvalidResolutionTime
-        );
-        require!(
-            resolution_timestamp <= end_timestamp + 604800,
-            ErrorCode::ResolutionTooLate
-        );
+        let market_id = ctx.accounts.protocol_state.total_markets;
+        let creator = ctx.accounts.creator.key();
+        let market_key = ctx.accounts.market.key();
+        let profile = &mut ctx.accounts.creator_profile;
+        profile.record_market_created(creator, ctx.bumps.creator_profile)?;
+        let namespace_index = match &mut ctx.accounts.creator_market {
+            Some(entry) => {
+                let index = profile.market_count;
+                profile.market_count = index.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+
+                entry.market_id = market_id;
+                entry.market = market_key;
+                entry.bump = ctx.bumps.creator_market.unwrap();
+                Some(index)
+            }
+            None => None,
+        };
 
         let market = &mut ctx.accounts.market;
-        market.market_id = market_id;
-        market.authority = ctx.accounts.creator.key();
-        market.question = question;
-        market.description = description;
-        market.category = category;
-        market.created_at = current_time;
-        market.end_timestamp = end_timestamp;
-        market.resolution_timestamp = resolution_timestamp;
-        market.oracle_source = oracle_source;
-        market.status = MarketStatus::Active;
-        market.yes_liquidity = initial_liquidity / 2;
-        market.no_liquidity = initial_liquidity / 2;
-        market.total_yes_shares = initial_liquidity / 2;
-        market.total_no_shares = initial_liquidity / 2;
-        market.total_volume = 0;
-        market.winning_outcome = None;
+        market.authority = creator;
         market.bump = ctx.bumps.market;
-
-        token::transfer(
+        let state = &mut ctx.accounts.market_state.load_init()?;
+        let price_history = &mut ctx.accounts.price_history.load_init()?;
+        terms.write(
+            market,
+            state,
+            price_history,
+            &ctx.accounts.protocol_state,
+            current_time,
+        )?;
+        if let (Some(parent_id), Some(outcome)) = (parent_market_id, parent_outcome) {
+            state.parent_market_id = parent_id;
+            state.parent_outcome = outcome as u8 + 1;
+        }
+        state.market_bump = ctx.bumps.market;
+        state.bump = ctx.bumps.market_state;
+        price_history.bump = ctx.bumps.price_history;
+
+        let deposit = initial_liquidity
+            .checked_add(state.creation_bond)
+            .ok_or(ErrorCode::MathOverflow)?;
+        token_interface::transfer_checked(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
-                TokenTransfer {
+                TransferChecked {
                     from: ctx.accounts.creator_token_account.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
                     to: ctx.accounts.market_vault.to_account_info(),
                     authority: ctx.accounts.creator.to_account_info(),
                 },
             ),
-            initial_liquidity,
+            deposit
+                .checked_add(transfer_fee_on(&ctx.accounts.usdc_mint, deposit)?)
+                .ok_or(ErrorCode::MathOverflow)?,
+            ctx.accounts.usdc_mint.decimals,
         )?;
 
         let protocol_state = &mut ctx.accounts.protocol_state;
-        protocol_state.total_markets += 1;
+        protocol_state.total_markets = market_id.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(MarketCreated {
+            market_id,
+            market: market.key(),
+            creator: market.authority,
+            namespace_index,
+            parent_market_id,
+            parent_outcome,
+            series_id: None,
+            series_sequence: None,
+            end_timestamp,
+            resolution_timestamp,
+            initial_liquidity,
+            creation_bond: state.creation_bond,
+            yes_liquidity: state.yes_liquidity,
+            no_liquidity: state.no_liquidity,
+            timestamp: current_time,
+        });
 
         msg!("Market {} created: {}", market_id, market.question);
         Ok(())
     }
 
-    pub fn buy_shares(ctx: Context<BuyShares>, outcome: Outcome, max_cost: u64) -> Result<()> {
-        let market = &ctx.accounts.market;
+    /// Replaces a market's text and metadata pointer. Only allowed before the
+    /// first trade, so nobody has taken a position on different rules.
+    pub fn update_metadata(
+        ctx: Context<UpdateMetadata>,
+        question: String,
+        description: String,
+        category: MarketCategory,
+        oracle_source: String,
+        metadata_uri: String,
+        metadata_hash: [u8; 32],
+    ) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        require!(
+            market.authority == ctx.accounts.creator.key(),
+            ErrorCode::Unauthorized
+        );
+        let state = ctx.accounts.market_state.load()?;
         require!(
-            market.status == MarketStatus::Active,
+            state.status() == MarketStatus::Active,
             ErrorCode::MarketNotActive
         );
+        require!(state.total_volume == 0, ErrorCode::TradingStarted);
+        validate_metadata(
+            &question,
+            &description,
+            &oracle_source,
+            &metadata_uri,
+            &metadata_hash,
+        )?;
+
+        market.question = question;
+        market.description = description;
+        market.category = category;
+        market.oracle_source = oracle_source;
+        market.metadata_uri = metadata_uri;
+        market.metadata_hash = metadata_hash;
+
+        emit!(MarketMetadataUpdated {
+            market_id: market.market_id,
+            market: market.key(),
+            metadata_uri: market.metadata_uri.clone(),
+            metadata_hash,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Market {} metadata updated", market.market_id);
+        Ok(())
+    }
+
+    /// Sets up a recurring market. Markets are spawned from the template
+    /// by `spawn_next_market`, the first ending at `first_end_timestamp`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_series(
+        ctx: Context<CreateSeries>,
+        category: MarketCategory,
+        question: String,
+        description: String,
+        oracle_source: String,
+        duration: i64,
+        resolution_window: i64,
+        initial_liquidity: u64,
+        first_end_timestamp: i64,
+    ) -> Result<()> {
+        require!(duration > 0, ErrorCode::InvalidSeriesDuration);
+        // The templates must fit the series, and their rendering a market.
+        validate_metadata(&question, &description, &oracle_source, "", &[0; 32])?;
         let current_time = Clock::get()?.unix_timestamp;
-        require!(current_time < market.end_timestamp, ErrorCode::MarketEnded);
+        let series_id = ctx.accounts.protocol_state.total_series;
+
+        let series = &mut ctx.accounts.series;
+        series.series_id = series_id;
+        series.authority = ctx.accounts.creator.key();
+        series.category = category;
+        series.question = question;
+        series.description = description;
+        series.oracle_source = oracle_source;
+        series.duration = duration;
+        series.resolution_window = resolution_window;
+        series.initial_liquidity = initial_liquidity;
+        series.last_end_timestamp = first_end_timestamp
+            .checked_sub(duration)
+            .ok_or(ErrorCode::MathOverflow)?;
+        series.mint = ctx.accounts.collateral.mint;
+        series.bump = ctx.bumps.series;
+        series.version = MarketSeries::VERSION;
+        series
+            .terms(1, first_end_timestamp)?
+            .validate(current_time, &ctx.accounts.collateral)?;
 
-        let (shares_out, actual_cost, fee) = calculate_buy_shares(
-            if outcome == Outcome::Yes {
-                market.yes_liquidity
-            } else {
-                market.no_liquidity
-            },
-            if outcome == Outcome::Yes {
-                market.no_liquidity
-            } else {
-                market.yes_liquidity
-            },
-            max_cost,
-            ctx.accounts.protocol_state.free_bps,
-        )?;
-        require!(actual_cost <= max_cost, ErrorCode::SlippageExceeded);
-        require!(shares_out > 0, ErrorCode::InsufficientOutput);
+        let protocol_state = &mut ctx.accounts.protocol_state;
+        protocol_state.total_series = series_id.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(SeriesCreated {
+            series_id,
+            series: series.key(),
+            creator: series.authority,
+            duration,
+            first_end_timestamp,
+            initial_liquidity,
+            timestamp: current_time,
+        });
 
-        token::transfer(
-            CpiContext::new(
+        msg!("Series {} created: {}", series_id, series.question);
+        Ok(())
+    }
+
+    /// Creates the series' next market once the latest one has ended.
+    /// Anyone may call it; the caller pays the new accounts' rent and the
+    /// creator's token account funds the liquidity and bond.
+    pub fn spawn_next_market(ctx: Context<SpawnNextMarket>) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        let series = &mut ctx.accounts.series;
+        let end_timestamp = series.next_end_timestamp(current_time)?;
+        let sequence = series
+            .sequence
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let terms = series.terms(sequence, end_timestamp)?;
+        terms.validate(current_time, &ctx.accounts.collateral)?;
+        check_mint_extensions(&ctx.accounts.usdc_mint)?;
+        let resolution_timestamp = terms.resolution_timestamp;
+
+        let market_id = ctx.accounts.protocol_state.total_markets;
+        let creator = series.authority;
+        ctx.accounts
+            .creator_profile
+            .record_market_created(creator, ctx.bumps.creator_profile)?;
+
+        let market = &mut ctx.accounts.market;
+        market.authority = creator;
+        market.bump = ctx.bumps.market;
+        market.series_id = series.series_id;
+        market.series_sequence = sequence;
+        let state = &mut ctx.accounts.market_state.load_init()?;
+        let price_history = &mut ctx.accounts.price_history.load_init()?;
+        terms.write(
+            market,
+            state,
+            price_history,
+            &ctx.accounts.protocol_state,
+            current_time,
+        )?;
+        state.market_bump = ctx.bumps.market;
+        state.bump = ctx.bumps.market_state;
+        price_history.bump = ctx.bumps.price_history;
+
+        series.sequence = sequence;
+        series.last_market_id = market_id;
+        series.last_end_timestamp = end_timestamp;
+
+        let series_id_bytes = series.series_id.to_le_bytes();
+        let seeds = &[b"series", series_id_bytes.as_ref(), &[series.bump]];
+        let deposit = series
+            .initial_liquidity
+            .checked_add(state.creation_bond)
+            .ok_or(ErrorCode::MathOverflow)?;
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                TokenTransfer {
-                    from: ctx.accounts.user_token_account.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.creator_token_account.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
                     to: ctx.accounts.market_vault.to_account_info(),
-                    authority: ctx.accounts.user.to_account_info(),
+                    authority: series.to_account_info(),
                 },
+                &[&seeds[..]],
             ),
-            actual_cost - fee,
+            deposit
+                .checked_add(transfer_fee_on(&ctx.accounts.usdc_mint, deposit)?)
+                .ok_or(ErrorCode::MathOverflow)?,
+            ctx.accounts.usdc_mint.decimals,
         )?;
 
-        if fee > 0 {
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    TokenTransfer {
-                        from: ctx.accounts.user_token_account.to_account_info(),
-                        to: ctx.accounts.protocol_treasury.to_account_info(),
-                        authority: ctx.accounts.user.to_account_info(),
-                    },
-                ),
-                fee,
-            )?;
+        let protocol_state = &mut ctx.accounts.protocol_state;
+        protocol_state.total_markets = market_id.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(MarketCreated {
+            market_id,
+            market: market.key(),
+            creator,
+            namespace_index: None,
+            parent_market_id: None,
+            parent_outcome: None,
+            series_id: Some(series.series_id),
+            series_sequence: Some(sequence),
+            end_timestamp,
+            resolution_timestamp,
+            initial_liquidity: state.initial_liquidity,
+            creation_bond: state.creation_bond,
+            yes_liquidity: state.yes_liquidity,
+            no_liquidity: state.no_liquidity,
+            timestamp: current_time,
+        });
+        // Unlike `create_market`, no instruction carries the rendered text.
+        emit!(SeriesMarketSpawned {
+            market_id,
+            market: market.key(),
+            series_id: series.series_id,
+            series_sequence: sequence,
+            question: market.question.clone(),
+            description: market.description.clone(),
+            category: market.category,
+            oracle_source: market.oracle_source.clone(),
+            timestamp: current_time,
+        });
+
+        msg!(
+            "Market {} spawned as #{} of series {}: {}",
+            market_id,
+            sequence,
+            series.series_id,
+            market.question
+        );
+        Ok(())
+    }
+
+    /// Buys shares of `outcome` for up to `max_cost`, fee included.
+    ///
+    /// Returns whether the trade filled. A trade that trips a circuit
+    /// breaker with a cooldown halts the market and returns `false` without
+    /// filling, so the halt is kept; callers must check the return data or
+    /// the `CircuitBreakerTripped` event rather than the transaction status.
+    pub fn buy_shares(ctx: Context<BuyShares>, outcome: Outcome, max_cost: u64) -> Result<bool> {
+        let market = &mut ctx.accounts.market_state.load_mut()?;
+        let current_time = Clock::get()?.unix_timestamp;
+        market.check_trading(current_time)?;
+        let parent = ctx
+            .accounts
+            .parent_market_state
+            .as_ref()
+            .map(|parent| parent.load())
+            .transpose()?;
+        market.check_condition(parent.as_deref())?;
+        market.check_access(
+            &ctx.accounts.owner.key(),
+            ctx.accounts.attestation.as_deref(),
+            current_time,
+        )?;
+        let delegated = ctx.accounts.owner.key() != ctx.accounts.user.key();
+        if delegated {
+            ctx.accounts
+                .trading_delegate
+                .as_ref()
+                .ok_or(ErrorCode::DelegateNotAuthorized)?
+                .authorize(market.market_id, current_time)?;
         }
 
-        let market = &mut ctx.accounts.market;
-        match outcome {
-            Outcome::Yes => {
-                market.yes_liquidity = market.yes_liquidity.checked_add(actual_cost - fee).unwrap();
-                market.total_yes_shares = market.total_yes_shares.checked_add(shares_out).unwrap();
-            }
-            Outcome::No => {
-                market.no_liquidity = market.no_liquidity.checked_add(actual_cost - fee).unwrap();
-                market.total_no_shares = market.total_no_shares.checked_add(shares_out).unwrap();
+        let fill = market.quote_buy(outcome, max_cost, ctx.accounts.protocol_state.free_bps)?;
+        market.check_limits(
+            outcome,
+            ctx.accounts.user_position.shares(outcome),
+            fill.shares,
+        )?;
+
+        // A delegate pays out of the owner's wallet through the allowance
+        // `approve_delegate` granted its PDA, counted against its limit
+        // before the transfer draws on that allowance.
+        let (authority, delegate_bump, spent) = match &mut ctx.accounts.trading_delegate {
+            Some(delegate) if delegated => {
+                let spent = delegate.spent;
+                let deposit = buy_deposit(&ctx.accounts.usdc_mint, &fill)?;
+                delegate.spend(
+                    deposit
+                        .checked_add(fill.fee)
+                        .ok_or(ErrorCode::MathOverflow)?,
+                )?;
+                (delegate.to_account_info(), delegate.bump, spent)
             }
-        }
-        market.total_volume = market.total_volume.checked_add(actual_cost).unwrap();
+            _ => (ctx.accounts.user.to_account_info(), 0, 0),
+        };
+        let owner = ctx.accounts.owner.key();
+        let user = ctx.accounts.user.key();
+        let seeds = &[
+            b"trading_delegate",
+            owner.as_ref(),
+            user.as_ref(),
+            &[delegate_bump],
+        ];
+        let signer: &[&[&[u8]]] = if delegated { &[&seeds[..]] } else { &[] };
 
         let position = &mut ctx.accounts.user_position;
-        if !position.initialized {
-            position.user = ctx.accounts.user.key();
-            position.market = market.key();
-            position.yes_shares = 0;
-            position.no_shares = 0;
-            position.total_invested = 0;
-            position.initialized = true;
-            position.bump = ctx.bumps.user_position;
-        }
-        match outcome {
-            Outcome::Yes => {
-                position.yes_shares = position.yes_shares.checked_add(shares_out).unwrap();
-            }
-            Outcome::No => {
-                position.no_shares = position.no_shares.checked_add(shares_out).unwrap();
+        position.init_if_needed(owner, market.market, current_time, ctx.bumps.user_position);
+        let settled = settle_trade(
+            &Settlement {
+                token_program: &ctx.accounts.token_program,
+                mint: &ctx.accounts.usdc_mint,
+                vault: &ctx.accounts.market_vault.to_account_info(),
+                trader: &ctx.accounts.user_token_account.to_account_info(),
+                authority: &authority,
+                signer,
+                treasury: Some(&ctx.accounts.protocol_treasury.to_account_info()),
+                keeper: None,
+            },
+            &fill,
+            market,
+            &mut *ctx.accounts.price_history.load_mut()?,
+            position,
+            &mut ctx.accounts.protocol_state,
+            current_time,
+        )?;
+        if !settled {
+            // The halt is kept, but nothing was drawn on the allowance.
+            if let Some(delegate) = ctx.accounts.trading_delegate.as_mut() {
+                delegate.spent = spent;
             }
+            return Ok(false);
         }
-        position.total_invested = position.total_invested.checked_add(actual_cost).unwrap();
-        ctx.accounts.protocol_state.total_volume = ctx
-            .accounts
-            .protocol_state
-            .total_volume
-            .checked_add(actual_cost)
-            .unwrap();
 
         msg!(
             "Bought {} {:?} shares for {} USDC (fee: {})",
-            shares_out,
+            fill.shares,
             outcome,
-            actual_cost,
-            fee
+            fill.collateral,
+            fill.fee
         );
-        Ok(())
+        Ok(true)
     }
 
+    /// Sells `shares_in` shares of `outcome` for at least `min_payout`
+    /// after the fee. Returns whether the trade filled, as `buy_shares` does.
     pub fn sell_shares(
         ctx: Context<SellShares>,
         outcome: Outcome,
         shares_in: u64,
         min_payout: u64,
-    ) -> Result<()> {
-        let market = &ctx.accounts.market;
-        require!(
-            market.status == MarketStatus::Active,
-            ErrorCode::MarketNotActive
-        );
+    ) -> Result<bool> {
+        let market = &mut ctx.accounts.market_state.load_mut()?;
         let current_time = Clock::get()?.unix_timestamp;
-        require!(current_time < market.end_timestamp, ErrorCode::MarketEnded);
+        market.check_trading(current_time)?;
+        let parent = ctx
+            .accounts
+            .parent_market_state
+            .as_ref()
+            .map(|parent| parent.load())
+            .transpose()?;
+        market.check_condition(parent.as_deref())?;
+        market.check_access(
+            &ctx.accounts.owner.key(),
+            ctx.accounts.attestation.as_deref(),
+            current_time,
+        )?;
+        if ctx.accounts.owner.key() != ctx.accounts.user.key() {
+            ctx.accounts
+                .trading_delegate
+                .as_ref()
+                .ok_or(ErrorCode::DelegateNotAuthorized)?
+                .authorize(market.market_id, current_time)?;
+        }
 
         let position = &mut ctx.accounts.user_position;
         require!(position.initialized, ErrorCode::NoPosition);
 
-        let user_shares = match outcome {
-            Outcome::Yes => position.yes_shares,
-            Outcome::No => position.no_shares,
-        };
-        require!(user_shares >= shares_in, ErrorCode::InsufficientShares);
+        require!(
+            position.available(outcome) >= shares_in,
+            ErrorCode::InsufficientShares
+        );
         require!(shares_in > 0, ErrorCode::InvalidAmount);
 
-        let (payout, fee) = calculate_sell_shares(
-            if outcome == Outcome::Yes {
-                market.yes_liquidity
-            } else {
-                market.no_liquidity
-            },
-            if outcome == Outcome::Yes {
-                market.no_liquidity
-            } else {
-                market.yes_liquidity
+        let fill = market.quote_sell(outcome, shares_in, ctx.accounts.protocol_state.free_bps)?;
+        require!(
+            fill.collateral - fill.fee >= min_payout,
+            ErrorCode::SlippageExceeded
+        );
+
+        let market_id_bytes = market.market_id.to_le_bytes();
+        let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+        let settled = settle_trade(
+            &Settlement {
+                token_program: &ctx.accounts.token_program,
+                mint: &ctx.accounts.usdc_mint,
+                vault: &ctx.accounts.market_vault.to_account_info(),
+                trader: &ctx.accounts.user_token_account.to_account_info(),
+                authority: &ctx.accounts.market.to_account_info(),
+                signer: &[&seeds[..]],
+                treasury: Some(&ctx.accounts.protocol_treasury.to_account_info()),
+                keeper: None,
             },
-            shares_in,
-            ctx.accounts.protocol_state.free_bps,
+            &fill,
+            market,
+            &mut *ctx.accounts.price_history.load_mut()?,
+            position,
+            &mut ctx.accounts.protocol_state,
+            current_time,
         )?;
+        if !settled {
+            return Ok(false);
+        }
 
-        let payout_after_fee = payout.checked_sub(fee).unwrap();
-        require!(payout_after_fee >= min_payout, ErrorCode::SlippageExceeded);
+        msg!(
+            "Sold {} {:?} shares for {} (fee: {})",
+            shares_in,
+            outcome,
+            fill.collateral,
+            fill.fee
+        );
 
-        let market_key = market.key();
-        let seeds = &[b"market", market_key.as_ref(), &[market.bump]];
-        let signer = &[&seeds[..]];
+        Ok(true)
+    }
 
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                TokenTransfer {
-                    from: ctx.accounts.market_vault.to_account_info(),
-                    to: ctx.accounts.user_token_account.to_account_info(),
-                    authority: ctx.accounts.market.to_account_info(),
+    /// Trades several markets in one transaction. Each leg takes
+    /// `BATCH_LEG_ACCOUNTS` remaining accounts, in order: the market, its
+    /// `MarketState`, vault and `PriceHistory`, the user's position in it,
+    /// the user's attestation if the market is gated or the program id if
+    /// not, and the parent's `MarketState` if the market is conditional or
+    /// the program id if not. Each leg carries the vault and position bumps,
+    /// and a missing position is opened for a buy. Legs fill in order against
+    /// the pool as earlier legs left it; one that misses its limit fails the
+    /// whole batch, and a leg that would trip the circuit breaker fails it too
+    /// rather than halting the market. Fees of every leg are paid to the
+    /// treasury in a single transfer.
+    pub fn batch_trade<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchTrade<'info>>,
+        legs: Vec<TradeLeg>,
+    ) -> Result<()> {
+        require!(
+            (1..=MAX_BATCH_LEGS).contains(&legs.len())
+                && ctx.remaining_accounts.len() == legs.len() * BATCH_LEG_ACCOUNTS,
+            ErrorCode::InvalidBatch
+        );
+        let current_time = Clock::get()?.unix_timestamp;
+        let user = ctx.accounts.user.key();
+        let free_bps = ctx.accounts.protocol_state.free_bps;
+        let mint = &ctx.accounts.usdc_mint;
+        let mut fees: u64 = 0;
+
+        for (accounts, leg) in ctx.remaining_accounts.chunks(BATCH_LEG_ACCOUNTS).zip(&legs) {
+            let [market_info, market_state, vault, price_history, position, attestation, parent] =
+                accounts
+            else {
+                return err!(ErrorCode::InvalidBatch);
+            };
+            let market_key = market_info.key();
+            let market_state = AccountLoader::<MarketState>::try_from(market_state)?;
+            let market = &mut market_state.load_mut()?;
+            require_keys_eq!(market.market, market_key, ErrorCode::InvalidBatch);
+            let vault_key = Pubkey::create_program_address(
+                &[b"market_vault", market_key.as_ref(), &[leg.vault_bump]],
+                &crate::ID,
+            )
+            .map_err(|_| ErrorCode::InvalidBatch)?;
+            require_keys_eq!(vault.key(), vault_key, ErrorCode::InvalidBatch);
+            require_keys_eq!(
+                InterfaceAccount::<TokenAccount>::try_from(vault)?.mint,
+                mint.key(),
+                ErrorCode::InvalidBatch
+            );
+            let price_history = AccountLoader::<PriceHistory>::try_from(price_history)?;
+            let price_history = &mut price_history.load_mut()?;
+            require_keys_eq!(price_history.market, market_key, ErrorCode::InvalidBatch);
+
+            market.check_trading(current_time)?;
+            let parent = (parent.key() != crate::ID)
+                .then(|| AccountLoader::<MarketState>::try_from(parent))
+                .transpose()?;
+            let parent = parent.as_ref().map(|parent| parent.load()).transpose()?;
+            market.check_condition(parent.as_deref())?;
+            let attestation = (attestation.key() != crate::ID)
+                .then(|| Account::<Attestation>::try_from(attestation))
+                .transpose()?;
+            market.check_access(&user, attestation.as_deref(), current_time)?;
+
+            let position_seeds: &[&[u8]] = &[
+                b"position",
+                market_key.as_ref(),
+                user.as_ref(),
+                &[leg.position_bump],
+            ];
+            let position_key = Pubkey::create_program_address(position_seeds, &crate::ID)
+                .map_err(|_| ErrorCode::InvalidBatch)?;
+            require_keys_eq!(position.key(), position_key, ErrorCode::InvalidBatch);
+            let mut position = if position.owner != &crate::ID {
+                require!(leg.side == TradeSide::Buy, ErrorCode::NoPosition);
+                create_pda_account(
+                    &ctx.accounts.user.to_account_info(),
+                    position,
+                    &ctx.accounts.system_program.to_account_info(),
+                    8 + UserPosition::INIT_SPACE,
+                    position_seeds,
+                )?;
+                let mut opened = Account::<UserPosition>::try_from_unchecked(position)?;
+                opened.init_if_needed(user, market_key, current_time, leg.position_bump);
+                opened
+            } else {
+                Account::<UserPosition>::try_from(position)?
+            };
+            require!(position.initialized, ErrorCode::NoPosition);
+
+            let fill = match leg.side {
+                TradeSide::Buy => {
+                    let fill = market.quote_buy(leg.outcome, leg.amount, free_bps)?;
+                    require!(fill.shares >= leg.limit, ErrorCode::SlippageExceeded);
+                    market.check_limits(leg.outcome, position.shares(leg.outcome), fill.shares)?;
+                    fill
+                }
+                TradeSide::Sell => {
+                    require!(leg.amount > 0, ErrorCode::InvalidAmount);
+                    require!(
+                        position.available(leg.outcome) >= leg.amount,
+                        ErrorCode::InsufficientShares
+                    );
+                    let fill = market.quote_sell(leg.outcome, leg.amount, free_bps)?;
+                    require!(
+                        fill.collateral - fill.fee >= leg.limit,
+                        ErrorCode::SlippageExceeded
+                    );
+                    fill
+                }
+            };
+            // Sells are paid in full and buys deposit net of their fee; the
+            // fees are settled together once every leg has filled.
+            let market_id_bytes = market.market_id.to_le_bytes();
+            let market_seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+            let (authority, signer): (_, &[&[&[u8]]]) = match leg.side {
+                TradeSide::Buy => (ctx.accounts.user.to_account_info(), &[]),
+                TradeSide::Sell => (market_info.clone(), &[&market_seeds[..]]),
+            };
+            let settled = settle_trade(
+                &Settlement {
+                    token_program: &ctx.accounts.token_program,
+                    mint,
+                    vault,
+                    trader: &ctx.accounts.user_token_account.to_account_info(),
+                    authority: &authority,
+                    signer,
+                    treasury: None,
+                    keeper: None,
                 },
-                signer,
-            ),
-            payout_after_fee,
-        )?;
+                &fill,
+                market,
+                price_history,
+                &mut position,
+                &mut ctx.accounts.protocol_state,
+                current_time,
+            )?;
+            require!(settled, ErrorCode::PriceMoveTooLarge);
+            fees = fees.checked_add(fill.fee).ok_or(ErrorCode::MathOverflow)?;
+            position.exit(&crate::ID)?;
+        }
 
-        if fee > 0 {
-            token::transfer(
-                CpiContext::new_with_signer(
+        if fees > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new(
                     ctx.accounts.token_program.to_account_info(),
-                    TokenTransfer {
-                        from: ctx.accounts.market_vault.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.user_token_account.to_account_info(),
+                        mint: mint.to_account_info(),
                         to: ctx.accounts.protocol_treasury.to_account_info(),
-                        authority: ctx.accounts.market.to_account_info(),
+                        authority: ctx.accounts.user.to_account_info(),
                     },
-                    signer,
                 ),
-                fee,
+                fees,
+                mint.decimals,
             )?;
         }
 
-        let market = &mut ctx.accounts.market;
-        match outcome {
-            Outcome::Yes => {
-                market.yes_liquidity = market.yes_liquidity.checked_sub(payout).unwrap();
-                market.total_yes_shares = market.total_yes_shares.checked_sub(shares_in).unwrap();
-            }
-            Outcome::No => {
-                market.no_liquidity = market.no_liquidity.checked_sub(payout).unwrap();
-                market.total_no_shares = market.total_no_shares.checked_sub(shares_in).unwrap();
-            }
-        }
-        market.total_volume = market.total_volume.checked_add(payout).unwrap();
+        msg!("Filled {} legs for {} in fees", legs.len(), fees);
+        Ok(())
+    }
+
+    /// Rests an order that `crank_orders` fills once the price of `outcome`
+    /// reaches `trigger_price`: at or below it for a buy, at or above it for
+    /// a sell. A buy escrows `amount` and `tip` in the order's vault, which is
+    /// passed for buys only; a sell locks `amount` shares of the signer's
+    /// position and pays the tip out of its proceeds.
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_limit_order(
+        ctx: Context<PlaceLimitOrder>,
+        nonce: u64,
+        side: TradeSide,
+        outcome: Outcome,
+        amount: u64,
+        trigger_price: u64,
+        limit: u64,
+        tip: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.load()?;
+        let current_time = Clock::get()?.unix_timestamp;
+        market.check_trading(current_time)?;
+        let owner = ctx.accounts.owner.key();
+        market.check_access(&owner, ctx.accounts.attestation.as_deref(), current_time)?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require!(
+            trigger_price > 0 && trigger_price < PRICE_SCALE,
+            ErrorCode::InvalidTriggerPrice
+        );
 
         let position = &mut ctx.accounts.user_position;
-        match outcome {
-            Outcome::Yes => {
-                position.yes_shares = position.yes_shares.checked_sub(shares_in).unwrap();
-            }
-            Outcome::No => {
-                position.no_shares = position.no_shares.checked_sub(shares_in).unwrap();
+        position.init_if_needed(owner, market.market, current_time, ctx.bumps.user_position);
+
+        match (side, &ctx.accounts.order_vault) {
+            (TradeSide::Buy, Some(order_vault)) => {
+                // The vault pays the pool, treasury and cranker separately,
+                // so it also holds the transfer fee on the way to the pool.
+                let mint = &ctx.accounts.usdc_mint;
+                let pool_fee = transfer_fee_on(mint, amount)?;
+                let held = amount
+                    .checked_add(tip)
+                    .and_then(|v| v.checked_add(pool_fee))
+                    .ok_or(ErrorCode::MathOverflow)?;
+                transfer_collateral(
+                    &ctx.accounts.token_program,
+                    mint,
+                    &ctx.accounts.owner_token_account.to_account_info(),
+                    &order_vault.to_account_info(),
+                    &ctx.accounts.owner.to_account_info(),
+                    &[],
+                    held.checked_add(transfer_fee_on(mint, held)?)
+                        .ok_or(ErrorCode::MathOverflow)?,
+                )?;
             }
+            (TradeSide::Sell, None) => position.lock(outcome, amount)?,
+            _ => return err!(ErrorCode::InvalidOrder),
         }
 
+        let order = &mut ctx.accounts.limit_order;
+        order.owner = owner;
+        order.market = market.market;
+        order.nonce = nonce;
+        order.side = side;
+        order.outcome = outcome;
+        order.amount = amount;
+        order.trigger_price = trigger_price;
+        order.limit = limit;
+        order.tip = tip;
+        order.created_at = current_time;
+        order.vault_bump = ctx.bumps.order_vault.unwrap_or_default();
+        order.bump = ctx.bumps.limit_order;
+        order.version = LimitOrder::VERSION;
+
+        emit!(LimitOrderPlaced {
+            order: order.key(),
+            market_id: market.market_id,
+            owner,
+            side,
+            outcome,
+            amount,
+            trigger_price,
+            limit,
+            tip,
+            timestamp: current_time,
+        });
+
         msg!(
-            "Sold {} {:?} shares for {} (fee: {})",
-            shares_in,
+            "Placed {:?} {:?} order for {} at {}",
+            side,
             outcome,
-            payout,
-            fee
+            amount,
+            trigger_price
         );
+        Ok(())
+    }
+
+    /// Withdraws an order, refunding a buy's escrow or unlocking a sell's
+    /// shares. Orders stay cancellable after their market settles.
+    pub fn cancel_limit_order(ctx: Context<CancelLimitOrder>) -> Result<()> {
+        let order = &ctx.accounts.limit_order;
+        match order.side {
+            TradeSide::Buy => {
+                let order_vault = ctx
+                    .accounts
+                    .order_vault
+                    .as_ref()
+                    .ok_or(ErrorCode::InvalidOrder)?;
+                let nonce_bytes = order.nonce.to_le_bytes();
+                let seeds = &[
+                    b"limit_order",
+                    order.market.as_ref(),
+                    order.owner.as_ref(),
+                    nonce_bytes.as_ref(),
+                    &[order.bump],
+                ];
+                close_order_vault(
+                    &ctx.accounts.token_program,
+                    &ctx.accounts.usdc_mint,
+                    &order_vault.to_account_info(),
+                    &ctx.accounts.owner_token_account.to_account_info(),
+                    &ctx.accounts.owner.to_account_info(),
+                    &order.to_account_info(),
+                    &[&seeds[..]],
+                )?;
+            }
+            TradeSide::Sell => ctx
+                .accounts
+                .user_position
+                .as_mut()
+                .ok_or(ErrorCode::InvalidOrder)?
+                .unlock(order.outcome, order.amount),
+        }
 
+        emit!(LimitOrderCancelled {
+            order: order.key(),
+            owner: order.owner,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Cancelled order {}", order.key());
         Ok(())
     }
 
-    pub fn resolve_market(ctx: Context<ResolveMarket>, winning_outcome: Outcome) -> Result<()> {
-        let market = &mut ctx.accounts.market;
+    /// Fills every passed order of one market whose trigger the current
+    /// price has reached, in order, and pays each order's tip to the
+    /// cranker. Each order takes `ORDER_ACCOUNTS` remaining accounts: the
+    /// order, its vault (the program id for a sell), the owner's position,
+    /// the owner, their token account, and their attestation or the program
+    /// id. Orders not yet triggered, whose fill would miss their limit, or
+    /// whose owner has since closed their position or token account or lost
+    /// access to the market, keep resting; a fill that trips the circuit
+    /// breaker halts the market and ends the crank.
+    pub fn crank_orders<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CrankOrders<'info>>,
+    ) -> Result<()> {
         require!(
-            ctx.accounts.oracle.key() == market.authority
-                || ctx.accounts.protocol_state.authority == ctx.accounts.oracle.key(),
-            ErrorCode::UnauthorizedOracle
+            !ctx.remaining_accounts.is_empty()
+                && ctx.remaining_accounts.len().is_multiple_of(ORDER_ACCOUNTS),
+            ErrorCode::InvalidOrder
+        );
+        let market = &mut ctx.accounts.market_state.load_mut()?;
+        let current_time = Clock::get()?.unix_timestamp;
+        market.check_trading(current_time)?;
+        let parent = ctx
+            .accounts
+            .parent_market_state
+            .as_ref()
+            .map(|parent| parent.load())
+            .transpose()?;
+        market.check_condition(parent.as_deref())?;
+        let market_key = ctx.accounts.market.key();
+        let free_bps = ctx.accounts.protocol_state.free_bps;
+        let mint = &ctx.accounts.usdc_mint;
+        let token_program = &ctx.accounts.token_program;
+        let market_vault = ctx.accounts.market_vault.to_account_info();
+        let market_id_bytes = market.market_id.to_le_bytes();
+        let market_seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+        let mut filled: usize = 0;
+
+        for accounts in ctx.remaining_accounts.chunks(ORDER_ACCOUNTS) {
+            let [order_info, order_vault, position, owner, owner_token_account, attestation] =
+                accounts
+            else {
+                return err!(ErrorCode::InvalidOrder);
+            };
+            let order = Account::<LimitOrder>::try_from(order_info)?;
+            require_keys_eq!(order.market, market_key, ErrorCode::InvalidOrder);
+            require_keys_eq!(owner.key(), order.owner, ErrorCode::InvalidOrder);
+
+            let price = market.price_of(order.outcome)?;
+            let triggered = match order.side {
+                TradeSide::Buy => price <= order.trigger_price,
+                TradeSide::Sell => price >= order.trigger_price,
+            };
+            if !triggered {
+                continue;
+            }
+
+            // What the owner can change after placing the order, such as
+            // closing their position or token account or letting their
+            // attestation lapse, only skips that order: one owner must not
+            // be able to stall the crank for everyone else.
+            let Ok(mut position) = Account::<UserPosition>::try_from(position) else {
+                continue;
+            };
+            require!(
+                position.user == order.owner && position.market == market_key,
+                ErrorCode::InvalidOrder
+            );
+            let Ok(owner_token) = InterfaceAccount::<TokenAccount>::try_from(owner_token_account)
+            else {
+                continue;
+            };
+            require!(
+                owner_token.owner == order.owner && owner_token.mint == mint.key(),
+                ErrorCode::InvalidOrder
+            );
+            let attestation = (attestation.key() != crate::ID)
+                .then(|| Account::<Attestation>::try_from(attestation).ok())
+                .flatten();
+            if market
+                .check_access(&order.owner, attestation.as_deref(), current_time)
+                .is_err()
+            {
+                continue;
+            }
+
+            let fill = match order.side {
+                TradeSide::Buy => {
+                    let fill = market.quote_buy(order.outcome, order.amount, free_bps)?;
+                    if fill.shares < order.limit
+                        || market
+                            .check_limits(
+                                order.outcome,
+                                position.shares(order.outcome),
+                                fill.shares,
+                            )
+                            .is_err()
+                    {
+                        continue;
+                    }
+                    fill
+                }
+                TradeSide::Sell => {
+                    let fill = market.quote_sell(order.outcome, order.amount, free_bps)?;
+                    match (fill.collateral - fill.fee).checked_sub(order.tip) {
+                        Some(proceeds) if proceeds >= order.limit => fill,
+                        _ => continue,
+                    }
+                }
+            };
+            let nonce_bytes = order.nonce.to_le_bytes();
+            let order_seeds = &[
+                b"limit_order",
+                market_key.as_ref(),
+                order.owner.as_ref(),
+                nonce_bytes.as_ref(),
+                &[order.bump],
+            ];
+            // A buy is paid out of the order's vault, a sell to the owner.
+            let (trader, authority, signer): (_, _, &[&[&[u8]]]) = match order.side {
+                TradeSide::Buy => {
+                    let vault_key = Pubkey::create_program_address(
+                        &[b"order_vault", order_info.key.as_ref(), &[order.vault_bump]],
+                        &crate::ID,
+                    )
+                    .map_err(|_| ErrorCode::InvalidOrder)?;
+                    require_keys_eq!(order_vault.key(), vault_key, ErrorCode::InvalidOrder);
+                    (order_vault, order_info, &[&order_seeds[..]])
+                }
+                TradeSide::Sell => (
+                    owner_token_account,
+                    &*ctx.accounts.market,
+                    &[&market_seeds[..]],
+                ),
+            };
+            let settled = settle_trade(
+                &Settlement {
+                    token_program,
+                    mint,
+                    vault: &market_vault,
+                    trader,
+                    authority,
+                    signer,
+                    treasury: Some(&ctx.accounts.protocol_treasury.to_account_info()),
+                    keeper: Some((
+                        &ctx.accounts.cranker_token_account.to_account_info(),
+                        order.tip,
+                    )),
+                },
+                &fill,
+                market,
+                &mut *ctx.accounts.price_history.load_mut()?,
+                &mut position,
+                &mut ctx.accounts.protocol_state,
+                current_time,
+            )?;
+            if !settled {
+                break;
+            }
+            match order.side {
+                TradeSide::Buy => close_order_vault(
+                    token_program,
+                    mint,
+                    order_vault,
+                    owner_token_account,
+                    owner,
+                    order_info,
+                    signer,
+                )?,
+                TradeSide::Sell => position.unlock(order.outcome, order.amount),
+            }
+            emit!(LimitOrderFilled {
+                order: order.key(),
+                owner: order.owner,
+                cranker: ctx.accounts.cranker.key(),
+                tip: order.tip,
+                timestamp: current_time,
+            });
+            position.exit(&crate::ID)?;
+            order.close(owner.clone())?;
+            filled += 1;
+        }
+
+        msg!(
+            "Filled {} of {} orders in market {}",
+            filled,
+            ctx.remaining_accounts.len() / ORDER_ACCOUNTS,
+            market.market_id
         );
+        Ok(())
+    }
+
+    /// Locks `shares` of `outcome` in the signer's position to be sold by a
+    /// keeper once the price of `outcome` falls to `stop_price` or rises to
+    /// `take_profit_price`; zero leaves a side unset. The order expires when
+    /// the market stops trading.
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_exit_order(
+        ctx: Context<PlaceExitOrder>,
+        nonce: u64,
+        outcome: Outcome,
+        shares: u64,
+        stop_price: u64,
+        take_profit_price: u64,
+        min_payout: u64,
+        tip: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.load()?;
         let current_time = Clock::get()?.unix_timestamp;
+        market.check_trading(current_time)?;
+        require!(shares > 0, ErrorCode::InvalidAmount);
         require!(
-            current_time >= market.end_timestamp,
-            ErrorCode::MarketNotEnded
+            stop_price < PRICE_SCALE
+                && take_profit_price < PRICE_SCALE
+                && (stop_price > 0 || take_profit_price > 0)
+                && (stop_price == 0 || take_profit_price == 0 || stop_price < take_profit_price),
+            ErrorCode::InvalidTriggerPrice
+        );
+        let position = &mut ctx.accounts.user_position;
+        position.lock(outcome, shares)?;
+
+        let order = &mut ctx.accounts.exit_order;
+        order.owner = position.user;
+        order.market = market.market;
+        order.position = position.key();
+        order.nonce = nonce;
+        order.outcome = outcome;
+        order.shares = shares;
+        order.stop_price = stop_price;
+        order.take_profit_price = take_profit_price;
+        order.min_payout = min_payout;
+        order.tip = tip;
+        order.expires_at = market.end_timestamp;
+        order.bump = ctx.bumps.exit_order;
+        order.version = ExitOrder::VERSION;
+
+        emit!(ExitOrderPlaced {
+            order: order.key(),
+            market_id: market.market_id,
+            owner: order.owner,
+            outcome,
+            shares,
+            stop_price,
+            take_profit_price,
+            expires_at: order.expires_at,
+            timestamp: current_time,
+        });
+
+        msg!(
+            "Placed exit of {} {:?} shares at stop {} / take profit {}",
+            shares,
+            outcome,
+            stop_price,
+            take_profit_price
         );
+        Ok(())
+    }
+
+    /// Sells an exit order's shares as `sell_shares` would once the price of
+    /// its outcome has crossed a threshold, paying the tip to the keeper.
+    /// Anyone may execute; the proceeds go to the order's owner. Returns
+    /// whether the order filled: one that would trip a circuit breaker with
+    /// a cooldown halts the market instead and stays open.
+    pub fn execute_exit_order(ctx: Context<ExecuteExitOrder>) -> Result<bool> {
+        let market = &mut ctx.accounts.market_state.load_mut()?;
+        let current_time = Clock::get()?.unix_timestamp;
+        market.check_trading(current_time)?;
+        let parent = ctx
+            .accounts
+            .parent_market_state
+            .as_ref()
+            .map(|parent| parent.load())
+            .transpose()?;
+        market.check_condition(parent.as_deref())?;
+        let order = &ctx.accounts.exit_order;
+        market.check_access(
+            &order.owner,
+            ctx.accounts.attestation.as_deref(),
+            current_time,
+        )?;
+
+        let price = market.price_of(order.outcome)?;
         require!(
-            current_time <= market.resolution_timestamp,
-            ErrorCode::ResolutionDeadlinePassed
+            (order.stop_price > 0 && price <= order.stop_price)
+                || (order.take_profit_price > 0 && price >= order.take_profit_price),
+            ErrorCode::ExitNotTriggered
+        );
+        let fill = market.quote_sell(
+            order.outcome,
+            order.shares,
+            ctx.accounts.protocol_state.free_bps,
+        )?;
+        let proceeds = (fill.collateral - fill.fee)
+            .checked_sub(order.tip)
+            .ok_or(ErrorCode::SlippageExceeded)?;
+        require!(proceeds >= order.min_payout, ErrorCode::SlippageExceeded);
+
+        let market_id_bytes = market.market_id.to_le_bytes();
+        let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+        let position = &mut ctx.accounts.user_position;
+        let settled = settle_trade(
+            &Settlement {
+                token_program: &ctx.accounts.token_program,
+                mint: &ctx.accounts.usdc_mint,
+                vault: &ctx.accounts.market_vault.to_account_info(),
+                trader: &ctx.accounts.owner_token_account.to_account_info(),
+                authority: &ctx.accounts.market.to_account_info(),
+                signer: &[&seeds[..]],
+                treasury: Some(&ctx.accounts.protocol_treasury.to_account_info()),
+                keeper: Some((
+                    &ctx.accounts.keeper_token_account.to_account_info(),
+                    order.tip,
+                )),
+            },
+            &fill,
+            market,
+            &mut *ctx.accounts.price_history.load_mut()?,
+            position,
+            &mut ctx.accounts.protocol_state,
+            current_time,
+        )?;
+        if !settled {
+            return Ok(false);
+        }
+        position.unlock(order.outcome, order.shares);
+
+        emit!(ExitOrderExecuted {
+            order: order.key(),
+            owner: order.owner,
+            keeper: ctx.accounts.keeper.key(),
+            price,
+            tip: order.tip,
+            timestamp: current_time,
+        });
+        order.close(ctx.accounts.owner.to_account_info())?;
+
+        msg!(
+            "Exited {} {:?} shares at {} for {}",
+            order.shares,
+            order.outcome,
+            price,
+            proceeds
         );
+        Ok(true)
+    }
+
+    /// Unlocks an exit order's shares and closes it. The owner may cancel at
+    /// any time, anyone else once the order has expired.
+    pub fn cancel_exit_order(ctx: Context<CancelExitOrder>) -> Result<()> {
+        let order = &ctx.accounts.exit_order;
+        let current_time = Clock::get()?.unix_timestamp;
         require!(
-            market.status == MarketStatus::Active,
-            ErrorCode::AlreadyResolved
+            ctx.accounts.signer.key() == order.owner || current_time >= order.expires_at,
+            ErrorCode::Unauthorized
         );
+        ctx.accounts
+            .user_position
+            .unlock(order.outcome, order.shares);
+
+        emit!(ExitOrderCancelled {
+            order: order.key(),
+            owner: order.owner,
+            timestamp: current_time,
+        });
+
+        msg!("Cancelled exit order {}", order.key());
+        Ok(())
+    }
+
+    pub fn resolve_market(ctx: Context<ResolveMarket>, winning_outcome: Outcome) -> Result<()> {
+        let market = &mut ctx.accounts.market_state.load_mut()?;
+        let current_time = Clock::get()?.unix_timestamp;
+        let parent = match &ctx.accounts.parent_market_state {
+            Some(parent) => Some(*parent.load()?),
+            None => None,
+        };
+        // A conditional market whose parent settled the other way never
+        // happened: anyone may settle it as invalid so traders are refunded.
+        let condition_failed = market.condition_failed(parent.as_ref())?;
+        if condition_failed {
+            require!(
+                market.status() == MarketStatus::Active,
+                ErrorCode::AlreadyResolved
+            );
+            market.invalidate(current_time)?;
+        } else {
+            require!(
+                ctx.accounts.oracle.key() == ctx.accounts.market.authority
+                    || ctx.accounts.protocol_state.authority == ctx.accounts.oracle.key(),
+                ErrorCode::UnauthorizedOracle
+            );
+            require!(
+                current_time >= market.end_timestamp,
+                ErrorCode::MarketNotEnded
+            );
+            require!(
+                current_time <= market.resolution_timestamp,
+                ErrorCode::ResolutionDeadlinePassed
+            );
+            require!(
+                market.status() == MarketStatus::Active,
+                ErrorCode::AlreadyResolved
+            );
+
+            let (yes_held, no_held) = market.held_shares()?;
+            market.set_status(MarketStatus::Resolved);
+            market.set_winning_outcome(winning_outcome);
+            market.liability = match winning_outcome {
+                Outcome::Yes => yes_held,
+                Outcome::No => no_held,
+            };
+            market.settled_at = current_time;
+        }
+
+        // Either way the creator is not at fault, so they get their bond back.
+        let bond_returned = market.creation_bond;
+        market.creation_bond = 0;
+        if bond_returned > 0 {
+            let market_id_bytes = market.market_id.to_le_bytes();
+            let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        mint: ctx.accounts.usdc_mint.to_account_info(),
+                        to: ctx.accounts.creator_token_account.to_account_info(),
+                        authority: ctx.accounts.market.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                bond_returned,
+                ctx.accounts.usdc_mint.decimals,
+            )?;
+        }
 
-        market.status = MarketStatus::Resolved;
-        market.winning_outcome = Some(winning_outcome);
+        if condition_failed {
+            emit!(MarketInvalidated {
+                market_id: market.market_id,
+                creator: ctx.accounts.market.authority,
+                bond_forfeited: 0,
+                timestamp: current_time,
+            });
+            msg!(
+                "Market {} invalidated: parent market {} did not resolve to {:?}",
+                market.market_id,
+                market.parent_market_id,
+                market.parent_outcome().unwrap()
+            );
+            return Ok(());
+        }
+
+        emit!(MarketResolved {
+            market_id: market.market_id,
+            winning_outcome,
+            oracle: ctx.accounts.oracle.key(),
+            bond_returned,
+            timestamp: current_time,
+        });
 
         msg!(
             "Market {} resolved: {:?} wins",
@@ -335,12 +1670,12 @@ pub mod kalshi {
     }
 
     pub fn claim_winnings(ctx: Context<ClaimWinnings>) -> Result<()> {
-        let market = &ctx.accounts.market;
+        let market = &mut ctx.accounts.market_state.load_mut()?;
         require!(
-            market.status == MarketStatus::Resolved,
+            market.status() == MarketStatus::Resolved,
             ErrorCode::MarketNotResolved
         );
-        let winning_outcome = market.winning_outcome.unwrap();
+        let winning_outcome = market.winning_outcome().unwrap();
 
         let position = &mut ctx.accounts.user_position;
         require!(position.initialized, ErrorCode::NoPosition);
@@ -353,21 +1688,23 @@ pub mod kalshi {
 
         let payout = winning_shares;
 
-        let market_key = market.key();
-        let seeds = &[b"market", market_key.as_ref(), &[market.bump]];
+        let market_id_bytes = market.market_id.to_le_bytes();
+        let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
         let signer = &[&seeds[..]];
 
-        token::transfer(
+        token_interface::transfer_checked(
             CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                TokenTransfer {
+                TransferChecked {
                     from: ctx.accounts.market_vault.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
                     to: ctx.accounts.user_token_account.to_account_info(),
                     authority: ctx.accounts.market.to_account_info(),
                 },
                 signer,
             ),
             payout,
+            ctx.accounts.usdc_mint.decimals,
         )?;
 
         match winning_outcome {
@@ -379,6 +1716,18 @@ pub mod kalshi {
             }
         }
 
+        market.liability = market
+            .liability
+            .checked_sub(payout)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(WinningsClaimed {
+            market_id: market.market_id,
+            user: position.user,
+            outcome: winning_outcome,
+            payout,
+        });
+
         msg!(
             "Claimed {} winnings for {:?} shares",
             payout,
@@ -388,26 +1737,63 @@ pub mod kalshi {
     }
 
     pub fn invalidate_market(ctx: Context<InvalidateMarket>) -> Result<()> {
-        let market = &mut ctx.accounts.market;
+        let market = &mut ctx.accounts.market_state.load_mut()?;
         require!(
             ctx.accounts.protocol_state.authority == ctx.accounts.authority.key(),
             ErrorCode::Unauthorized
         );
         require!(
-            market.status == MarketStatus::Active,
+            market.status() == MarketStatus::Active,
             ErrorCode::InvalidMarketState
         );
 
-        market.status = MarketStatus::Invalid;
+        let current_time = Clock::get()?.unix_timestamp;
+        market.invalidate(current_time)?;
+
+        let creator = ctx.accounts.market.authority;
+        let profile = &mut ctx.accounts.creator_profile;
+        profile.init_if_needed(creator, ctx.bumps.creator_profile);
+        profile.markets_invalidated = profile
+            .markets_invalidated
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        let bond_forfeited = market.creation_bond;
+        market.creation_bond = 0;
+        if bond_forfeited > 0 {
+            let market_id_bytes = market.market_id.to_le_bytes();
+            let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        mint: ctx.accounts.usdc_mint.to_account_info(),
+                        to: ctx.accounts.protocol_treasury.to_account_info(),
+                        authority: ctx.accounts.market.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                bond_forfeited,
+                ctx.accounts.usdc_mint.decimals,
+            )?;
+        }
+
+        emit!(MarketInvalidated {
+            market_id: market.market_id,
+            creator,
+            bond_forfeited,
+            timestamp: current_time,
+        });
 
         msg!("Market {} marked as invalid", market.market_id);
         Ok(())
     }
 
     pub fn claim_refund(ctx: Context<ClaimRefund>) -> Result<()> {
-        let market = &ctx.accounts.market;
+        let market = &mut ctx.accounts.market_state.load_mut()?;
         require!(
-            market.status == MarketStatus::Invalid,
+            market.status() == MarketStatus::Invalid,
             ErrorCode::MarketNotInvalid
         );
 
@@ -417,337 +1803,3615 @@ pub mod kalshi {
         let total_shares = position.yes_shares.checked_add(position.no_shares).unwrap();
         require!(total_shares > 0, ErrorCode::NoPosition);
 
-        let refund_amount = total_shares;
+        // An invalid market settles both outcomes at half a unit per share,
+        // which the complete sets backing every share always cover.
+        let refund_amount = total_shares / 2;
 
-        let market_key = market.key();
-        let seeds = &[b"market", market_key.as_ref(), &[market.bump]];
+        let market_id_bytes = market.market_id.to_le_bytes();
+        let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
         let signer = &[&seeds[..]];
 
-        token::transfer(
+        token_interface::transfer_checked(
             CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                TokenTransfer {
+                TransferChecked {
                     from: ctx.accounts.market_vault.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
                     to: ctx.accounts.user_token_account.to_account_info(),
                     authority: ctx.accounts.market.to_account_info(),
                 },
                 signer,
             ),
             refund_amount,
+            ctx.accounts.usdc_mint.decimals,
         )?;
 
         position.yes_shares = 0;
         position.no_shares = 0;
 
+        market.liability = market
+            .liability
+            .checked_sub(refund_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(RefundClaimed {
+            market_id: market.market_id,
+            user: position.user,
+            refund: refund_amount,
+        });
+
         msg!("Claimed refund of {} for invalidated market", refund_amount);
         Ok(())
     }
-}
-
-#[derive(Accounts)]
-pub struct InitializeProtocol<'info> {
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + ProtocolState::INIT_SPACE,
-        seeds = [b"protocol-state"],
-        bump
-    )]
-    pub protocol_state: Account<'info, ProtocolState>,
-
-    #[account(mut)]
-    pub authority: Signer<'info>,
-
-    pub treasury: Account<'info, TokenAccount>,
-    pub system_program: Program<'info, System>,
-}
 
-#[derive(Accounts)]
-#[instruction(market_id: u64)]
-pub struct CreateMarket<'info> {
-    #[account(
-        init,
-        payer = creator,
-        space = 8 + Market::INIT_SPACE,
-        seeds = [b"market", market_id.to_le_bytes().as_ref()],
-        bump
-    )]
-    pub market: Account<'info, Market>,
+    pub fn withdraw_residual(ctx: Context<WithdrawResidual>) -> Result<()> {
+        require!(
+            ctx.accounts.creator.key() == ctx.accounts.market.authority,
+            ErrorCode::Unauthorized
+        );
+        let market = ctx.accounts.market_state.load()?;
+        require!(
+            market.status() != MarketStatus::Active,
+            ErrorCode::InvalidMarketState
+        );
 
-    #[account(
-        init,
-        payer = creator,
-        token::mint = usdc_mint,
-        token::authority = market,
-        seeds = [b"market_vault", market.key().as_ref()],
-        bump,
-    )]
-    pub market_vault: Account<'info, TokenAccount>,
+        // Whatever the vault holds beyond what settled holders can still
+        // claim is the creator's seed collateral and the losing reserves.
+        let residual = ctx
+            .accounts
+            .market_vault
+            .amount
+            .saturating_sub(market.liability);
+        require!(residual > 0, ErrorCode::NoResidual);
 
-    #[account(mut)]
-    pub protocol_state: Account<'info, ProtocolState>,
+        let market_id_bytes = market.market_id.to_le_bytes();
+        let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+        let signer = &[&seeds[..]];
 
-    #[account(mut)]
-    pub creator: Signer<'info>,
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
+                    to: ctx.accounts.creator_token_account.to_account_info(),
+                    authority: ctx.accounts.market.to_account_info(),
+                },
+                signer,
+            ),
+            residual,
+            ctx.accounts.usdc_mint.decimals,
+        )?;
 
-    #[account(
-        mut,
-        constraint = creator_token_account.owner == creator.key(),
-        constraint = creator_token_account.mint == usdc_mint.key(),
-    )]
-    pub creator_token_account: Account<'info, TokenAccount>,
+        emit!(ResidualWithdrawn {
+            market_id: market.market_id,
+            creator: ctx.accounts.creator.key(),
+            amount: residual,
+            liability: market.liability,
+        });
 
-    pub usdc_mint: Account<'info, Mint>,
+        msg!(
+            "Withdrew {} residual from market {}",
+            residual,
+            market.market_id
+        );
+        Ok(())
+    }
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        let position = &ctx.accounts.user_position;
+        let state_info = &ctx.accounts.market_state;
+
+        // Once the market itself is closed nothing is redeemable any more.
+        if state_info.owner == &crate::ID && !state_info.data_is_empty() {
+            let market = MarketState::read(&state_info.try_borrow_data()?)?;
+            let redeemable = match (market.status(), market.winning_outcome()) {
+                (MarketStatus::Resolved, Some(Outcome::Yes)) => position.yes_shares,
+                (MarketStatus::Resolved, Some(Outcome::No)) => position.no_shares,
+                _ => position.yes_shares.checked_add(position.no_shares).unwrap(),
+            };
+            require!(redeemable == 0, ErrorCode::PositionNotEmpty);
+        }
+        require!(
+            position.locked_yes_shares == 0 && position.locked_no_shares == 0,
+            ErrorCode::PositionHasOpenOrders
+        );
 
-#[derive(Accounts)]
-pub struct BuyShares<'info> {
-    #[account(
-        mut,
-        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
-        bump = market.bump,
-    )]
-    pub market: Account<'info, Market>,
+        msg!(
+            "Closed position of {} in {}",
+            position.user,
+            position.market
+        );
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        seeds = [b"market_vault", market.key().as_ref()],
-        bump,
-    )]
-    pub market_vault: Account<'info, TokenAccount>,
+    /// Moves `yes_shares` and `no_shares`, with the matching share of
+    /// `total_invested`, to `recipient`'s position in the same market.
+    pub fn transfer_position(
+        ctx: Context<TransferPosition>,
+        yes_shares: u64,
+        no_shares: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.load()?;
+        let current_time = Clock::get()?.unix_timestamp;
+        // Gated markets stay gated: only an attested wallet may hold shares.
+        market.check_access(
+            &ctx.accounts.recipient.key(),
+            ctx.accounts.attestation.as_deref(),
+            current_time,
+        )?;
 
-    #[account(
-        init_if_needed,
-        payer = user,
-        space = 8 + UserPosition::INIT_SPACE,
-        seeds = [b"position",market.key().as_ref(), user.key().as_ref()],
-        bump,
-    )]
-    pub user_position: Account<'info, UserPosition>,
+        let position = &mut ctx.accounts.user_position;
+        let total_invested = position.remove_shares(yes_shares, no_shares)?;
+
+        let recipient = &mut ctx.accounts.recipient_position;
+        recipient.init_if_needed(
+            ctx.accounts.recipient.key(),
+            market.market,
+            current_time,
+            ctx.bumps.recipient_position,
+        );
+        recipient.yes_shares = recipient
+            .yes_shares
+            .checked_add(yes_shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+        recipient.no_shares = recipient
+            .no_shares
+            .checked_add(no_shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+        recipient.total_invested = recipient
+            .total_invested
+            .checked_add(total_invested)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(
+            market.max_position_shares == 0
+                || recipient.yes_shares.max(recipient.no_shares) <= market.max_position_shares,
+            ErrorCode::PositionLimitExceeded
+        );
 
-    #[account(mut)]
-    pub protocol_state: Account<'info, ProtocolState>,
+        emit!(PositionTransferred {
+            market_id: market.market_id,
+            from: position.user,
+            to: recipient.user,
+            yes_shares,
+            no_shares,
+            total_invested,
+            timestamp: current_time,
+        });
 
-    #[account(
-        mut,
-        constraint = protocol_treasury.key() == protocol_state.treasury,
-    )]
-    pub protocol_treasury: Account<'info, TokenAccount>,
+        msg!(
+            "Transferred {} YES and {} NO shares of market {} from {} to {}",
+            yes_shares,
+            no_shares,
+            market.market_id,
+            position.user,
+            recipient.user
+        );
+        Ok(())
+    }
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
+        require!(
+            ctx.accounts.authority.key() == ctx.accounts.market.authority
+                || ctx.accounts.authority.key() == ctx.accounts.protocol_state.authority,
+            ErrorCode::Unauthorized
+        );
+        let market = ctx.accounts.market_state.load()?;
+        require!(
+            market.status() != MarketStatus::Active,
+            ErrorCode::InvalidMarketState
+        );
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            current_time >= market.settled_at + MARKET_CLOSE_GRACE_PERIOD,
+            ErrorCode::GracePeriodActive
+        );
+        require!(
+            market.open_parlay_legs == 0,
+            ErrorCode::MarketHasOpenParlays
+        );
+
+        let market_id_bytes = market.market_id.to_le_bytes();
+        let seeds = &[b"market", market_id_bytes.as_ref(), &[market.market_bump]];
+        let signer = &[&seeds[..]];
+
+        let swept = ctx.accounts.market_vault.amount;
+        if swept > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        mint: ctx.accounts.usdc_mint.to_account_info(),
+                        to: ctx.accounts.protocol_treasury.to_account_info(),
+                        authority: ctx.accounts.market.to_account_info(),
+                    },
+                    signer,
+                ),
+                swept,
+                ctx.accounts.usdc_mint.decimals,
+            )?;
+        }
+
+        // Token-2022 refuses to close an account holding withheld transfer
+        // fees; harvesting them to the mint is permissionless.
+        let mint = ctx.accounts.usdc_mint.to_account_info();
+        if token_interface::get_mint_extension_data::<TransferFeeConfig>(&mint).is_ok() {
+            token_interface::harvest_withheld_tokens_to_mint(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    token_interface::HarvestWithheldTokensToMint {
+                        token_program_id: ctx.accounts.token_program.to_account_info(),
+                        mint,
+                    },
+                ),
+                vec![ctx.accounts.market_vault.to_account_info()],
+            )?;
+        }
+
+        token_interface::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token_interface::CloseAccount {
+                account: ctx.accounts.market_vault.to_account_info(),
+                destination: ctx.accounts.creator.to_account_info(),
+                authority: ctx.accounts.market.to_account_info(),
+            },
+            signer,
+        ))?;
+
+        emit!(MarketClosed {
+            market_id: market.market_id,
+            swept,
+            timestamp: current_time,
+        });
+
+        msg!(
+            "Market {} closed, {} swept to treasury",
+            market.market_id,
+            swept
+        );
+        Ok(())
+    }
+
+    /// Deposits collateral backing parlay payouts, creating the pool on
+    /// first use.
+    pub fn fund_parlay_pool(ctx: Context<FundParlayPool>, amount: u64) -> Result<()> {
+        require!(
+            ctx.accounts.protocol_state.authority == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        let pool = &mut ctx.accounts.parlay_pool;
+        pool.init_if_needed(ctx.bumps.parlay_pool);
+
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.authority_token_account.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
+                    to: ctx.accounts.parlay_vault.to_account_info(),
+                    authority: ctx.accounts.authority.to_account_info(),
+                },
+            ),
+            amount
+                .checked_add(transfer_fee_on(&ctx.accounts.usdc_mint, amount)?)
+                .ok_or(ErrorCode::MathOverflow)?,
+            ctx.accounts.usdc_mint.decimals,
+        )?;
+
+        msg!("Parlay pool funded with {}", amount);
+        Ok(())
+    }
+
+    /// Sweeps pool collateral not reserved for open parlays to the treasury.
+    pub fn withdraw_parlay_pool(ctx: Context<WithdrawParlayPool>, amount: u64) -> Result<()> {
+        require!(
+            ctx.accounts.protocol_state.authority == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+        let pool = &ctx.accounts.parlay_pool;
+        let free = ctx
+            .accounts
+            .parlay_vault
+            .amount
+            .saturating_sub(pool.liability);
+        require!(amount <= free, ErrorCode::ParlayPoolInsufficient);
+
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.parlay_vault.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
+                    to: ctx.accounts.protocol_treasury.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                &[&[b"parlay_pool", &[pool.bump]]],
+            ),
+            amount,
+            ctx.accounts.usdc_mint.decimals,
+        )?;
+
+        msg!("Withdrew {} from the parlay pool", amount);
+        Ok(())
+    }
+
+    /// Approves or revokes `oracle` as a resolver parlays may bet on. Revoking
+    /// only stops new parlays; open ones still settle on its resolutions.
+    pub fn set_parlay_oracle(ctx: Context<SetParlayOracle>, approved: bool) -> Result<()> {
+        require!(
+            ctx.accounts.protocol_state.authority == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+        let parlay_oracle = &mut ctx.accounts.parlay_oracle;
+        parlay_oracle.oracle = ctx.accounts.oracle.key();
+        parlay_oracle.approved = approved;
+        parlay_oracle.bump = ctx.bumps.parlay_oracle;
+        parlay_oracle.version = ParlayOracle::VERSION;
+
+        msg!(
+            "Parlay oracle {} {}",
+            parlay_oracle.oracle,
+            if approved { "approved" } else { "revoked" }
+        );
+        Ok(())
+    }
+
+    /// Bets `stake` on every leg winning, and the pool reserves the
+    /// compounded payout. Each leg is priced at the higher of its market's
+    /// spot price and its average over the last `PARLAY_TWAP_WINDOW`, or as
+    /// much of it as the market's price history covers, so neither a trade
+    /// just before opening nor a move the average has not caught up with
+    /// lengthens the odds. Legs must be resolved by the
+    /// protocol authority or an approved oracle, and be deep enough and
+    /// likely enough to bet on.
+    ///
+    /// Each leg passes `PARLAY_LEG_ACCOUNTS` remaining accounts, in the
+    /// order of `outcomes`: its writable `MarketState`, which counts the
+    /// parlay until it settles, its `Market`, its `PriceHistory`, and the
+    /// `ParlayOracle` approving its creator, or the program ID if the
+    /// creator is the protocol authority.
+    pub fn open_parlay<'info>(
+        ctx: Context<'_, '_, 'info, 'info, OpenParlay<'info>>,
+        nonce: u64,
+        outcomes: Vec<Outcome>,
+        stake: u64,
+        min_payout: u64,
+    ) -> Result<()> {
+        require!(
+            (2..=MAX_PARLAY_LEGS).contains(&outcomes.len())
+                && ctx.remaining_accounts.len() == outcomes.len() * PARLAY_LEG_ACCOUNTS,
+            ErrorCode::InvalidParlay
+        );
+        let current_time = Clock::get()?.unix_timestamp;
+
+        let mut legs: Vec<ParlayLeg> = Vec::with_capacity(outcomes.len());
+        for (accounts, &outcome) in ctx
+            .remaining_accounts
+            .chunks(PARLAY_LEG_ACCOUNTS)
+            .zip(&outcomes)
+        {
+            let [state_info, market_info, history_info, oracle_info] = accounts else {
+                return err!(ErrorCode::InvalidParlay);
+            };
+            let market_account = Box::new(Account::<Market>::try_from(market_info)?);
+            let price_history = AccountLoader::<PriceHistory>::try_from(history_info)?;
+            let price_history = price_history.load()?;
+            let market = AccountLoader::<MarketState>::try_from(state_info)?;
+            let market = &mut market.load_mut()?;
+            require_keys_eq!(
+                market.market,
+                market_account.key(),
+                ErrorCode::InvalidParlay
+            );
+            require_keys_eq!(
+                price_history.market,
+                market_account.key(),
+                ErrorCode::InvalidParlay
+            );
+            let trusted = market_account.authority == ctx.accounts.protocol_state.authority
+                || (oracle_info.owner == &crate::ID && {
+                    let approval = Account::<ParlayOracle>::try_from(oracle_info)?;
+                    approval.approved && approval.oracle == market_account.authority
+                });
+            require!(trusted, ErrorCode::UntrustedParlayOracle);
+            require!(
+                market.status() == MarketStatus::Active,
+                ErrorCode::MarketNotActive
+            );
+            require!(current_time < market.end_timestamp, ErrorCode::MarketEnded);
+            require!(
+                current_time >= market.halted_until,
+                ErrorCode::TradingHalted
+            );
+            // Parlays do not trade against the market, so they cannot carry
+            // the attestation a gated market asks of its traders.
+            require!(
+                market.access_policy() == AccessPolicy::Open,
+                ErrorCode::AccessDenied
+            );
+            require!(
+                legs.iter().all(|leg| leg.market_id != market.market_id),
+                ErrorCode::InvalidParlay
+            );
+            require!(
+                market
+                    .yes_liquidity
+                    .checked_add(market.no_liquidity)
+                    .ok_or(ErrorCode::MathOverflow)?
+                    >= PARLAY_MIN_LEG_LIQUIDITY,
+                ErrorCode::ParlayLegTooThin
+            );
+            let spot = yes_price(market.yes_liquidity, market.no_liquidity)?;
+            let twap = price_history.recent_twap(current_time, PARLAY_TWAP_WINDOW)?;
+            let price = match outcome {
+                Outcome::Yes => spot.max(twap),
+                Outcome::No => PRICE_SCALE - spot.min(twap),
+            };
+            require!(price >= PARLAY_MIN_LEG_PRICE, ErrorCode::ParlayOddsTooLong);
+            market.open_parlay_legs = market
+                .open_parlay_legs
+                .checked_add(1)
+                .ok_or(ErrorCode::MathOverflow)?;
+            legs.push(ParlayLeg {
+                market_id: market.market_id,
+                outcome,
+                price,
+            });
+        }
+
+        let fee = calculate_fee(stake, ctx.accounts.protocol_state.free_bps)?;
+        let escrowed = stake - fee;
+        require!(escrowed > 0, ErrorCode::InvalidAmount);
+        let payout = calculate_parlay_payout(escrowed, legs.iter().map(|leg| leg.price))?;
+        require!(payout >= min_payout, ErrorCode::SlippageExceeded);
+
+        let pool = &mut ctx.accounts.parlay_pool;
+        let liability = pool
+            .liability
+            .checked_add(payout)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let backing = ctx
+            .accounts
+            .parlay_vault
+            .amount
+            .checked_add(escrowed)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(backing >= liability, ErrorCode::ParlayPoolInsufficient);
+
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.user_token_account.to_account_info(),
+                    mint: ctx.accounts.usdc_mint.to_account_info(),
+                    to: ctx.accounts.parlay_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            escrowed
+                .checked_add(transfer_fee_on(&ctx.accounts.usdc_mint, escrowed)?)
+                .ok_or(ErrorCode::MathOverflow)?,
+            ctx.accounts.usdc_mint.decimals,
+        )?;
+
+        if fee > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.user_token_account.to_account_info(),
+                        mint: ctx.accounts.usdc_mint.to_account_info(),
+                        to: ctx.accounts.protocol_treasury.to_account_info(),
+                        authority: ctx.accounts.user.to_account_info(),
+                    },
+                ),
+                fee,
+                ctx.accounts.usdc_mint.decimals,
+            )?;
+        }
+
+        pool.liability = liability;
+        pool.open_parlays = pool
+            .open_parlays
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        let parlay = &mut ctx.accounts.parlay;
+        parlay.user = ctx.accounts.user.key();
+        parlay.nonce = nonce;
+        parlay.stake = escrowed;
+        parlay.payout = payout;
+        parlay.opened_at = current_time;
+        parlay.legs = legs;
+        parlay.bump = ctx.bumps.parlay;
+        parlay.version = Parlay::VERSION;
+
+        emit!(ParlayOpened {
+            parlay: parlay.key(),
+            user: parlay.user,
+            legs: parlay.legs.clone(),
+            stake: escrowed,
+            fee,
+            payout,
+            timestamp: current_time,
+        });
+
+        msg!(
+            "Opened {}-leg parlay staking {} to win {}",
+            parlay.legs.len(),
+            escrowed,
+            payout
+        );
+        Ok(())
+    }
+
+    /// Pays out a parlay once it is decided, and closes it. Voided legs of
+    /// invalid markets drop out of the odds, so a parlay whose every leg was
+    /// voided returns its stake. Anyone may settle, passing the legs'
+    /// `MarketState`s as writable remaining accounts in the parlay's order.
+    pub fn settle_parlay<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SettleParlay<'info>>,
+    ) -> Result<()> {
+        let parlay = &ctx.accounts.parlay;
+        require!(
+            ctx.remaining_accounts.len() == parlay.legs.len(),
+            ErrorCode::ParlayLegMismatch
+        );
+
+        let mut lost = false;
+        let mut pending = false;
+        let mut legs_voided: u8 = 0;
+        let mut won_prices = Vec::with_capacity(parlay.legs.len());
+        for (info, leg) in ctx.remaining_accounts.iter().zip(&parlay.legs) {
+            let market = AccountLoader::<MarketState>::try_from(info)?;
+            let market = &mut market.load_mut()?;
+            require!(
+                market.market_id == leg.market_id,
+                ErrorCode::ParlayLegMismatch
+            );
+            market.open_parlay_legs = market
+                .open_parlay_legs
+                .checked_sub(1)
+                .ok_or(ErrorCode::MathOverflow)?;
+            match market.status() {
+                MarketStatus::Active => pending = true,
+                MarketStatus::Resolved if market.winning_outcome() == Some(leg.outcome) => {
+                    won_prices.push(leg.price)
+                }
+                MarketStatus::Resolved => lost = true,
+                MarketStatus::Invalid => legs_voided += 1,
+            }
+        }
+        // A single losing leg decides the parlay while others are still open.
+        require!(lost || !pending, ErrorCode::ParlayNotSettled);
+        let payout = if lost {
+            0
+        } else {
+            calculate_parlay_payout(parlay.stake, won_prices)?
+        };
+
+        let pool = &mut ctx.accounts.parlay_pool;
+        pool.liability = pool
+            .liability
+            .checked_sub(parlay.payout)
+            .ok_or(ErrorCode::MathOverflow)?;
+        pool.open_parlays = pool
+            .open_parlays
+            .checked_sub(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        if payout > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.parlay_vault.to_account_info(),
+                        mint: ctx.accounts.usdc_mint.to_account_info(),
+                        to: ctx.accounts.user_token_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    &[&[b"parlay_pool", &[pool.bump]]],
+                ),
+                payout,
+                ctx.accounts.usdc_mint.decimals,
+            )?;
+        }
+
+        emit!(ParlaySettled {
+            parlay: parlay.key(),
+            user: parlay.user,
+            payout,
+            legs_voided,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Parlay of {} settled, paying {}", parlay.user, payout);
+        Ok(())
+    }
+
+    /// Upgrades a market and its state to the current layout, growing the
+    /// state if a later version outgrows its reserved space. Markets from
+    /// before versioning kept their reserves inline and have no
+    /// `MarketState`; they cannot be converted and fail with
+    /// `UnversionedAccount` rather than being misread as the current layout.
+    pub fn migrate_market(ctx: Context<MigrateMarket>) -> Result<()> {
+        let market_info = ctx.accounts.market.to_account_info();
+        let state_info = ctx.accounts.market_state.to_account_info();
+        require!(
+            state_info.owner == &crate::ID && !state_info.data_is_empty(),
+            ErrorCode::UnversionedAccount
+        );
+        let mut market = Market::try_deserialize(&mut &market_info.try_borrow_data()?[..])?;
+        let state_len = 8 + std::mem::size_of::<MarketState>();
+        grow_account(
+            &state_info,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            state_len,
+        )?;
+        let mut state = MarketState::read(&state_info.try_borrow_data()?)?;
+        require!(
+            market.version < Market::VERSION || state.version < MarketState::VERSION,
+            ErrorCode::AlreadyMigrated
+        );
+
+        let from_version = market.version;
+        market.version = Market::VERSION;
+        state.version = MarketState::VERSION;
+        market.try_serialize(&mut &mut market_info.try_borrow_mut_data()?[..])?;
+        state_info.try_borrow_mut_data()?[8..state_len].copy_from_slice(bytemuck::bytes_of(&state));
+
+        msg!(
+            "Market {} migrated from v{} to v{}",
+            market.market_id,
+            from_version,
+            Market::VERSION
+        );
+        Ok(())
+    }
+
+    /// Upgrades a position to the current layout. Positions from before
+    /// versioning lack `opened_at` ahead of `bump`, so they are not a prefix
+    /// of any versioned layout; they fail with `UnversionedAccount`.
+    pub fn migrate_position(ctx: Context<MigratePosition>) -> Result<()> {
+        let info = ctx.accounts.user_position.to_account_info();
+        require!(
+            info.data_len() != UNVERSIONED_POSITION_LEN,
+            ErrorCode::UnversionedAccount
+        );
+        let mut position: UserPosition = load_for_migration(
+            &info,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            8 + UserPosition::INIT_SPACE,
+        )?;
+        require!(
+            position.version < UserPosition::VERSION,
+            ErrorCode::AlreadyMigrated
+        );
+
+        let from_version = position.version;
+        position.version = UserPosition::VERSION;
+        position.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        msg!(
+            "Position of {} migrated from v{} to v{}",
+            position.user,
+            from_version,
+            UserPosition::VERSION
+        );
+        Ok(())
+    }
+
+    /// Upgrades the protocol state to the current layout. The state from
+    /// before versioning is a prefix of it, so it is grown and its new fields
+    /// start zeroed, leaving bonds, caps and circuit breakers off.
+    pub fn migrate_protocol(ctx: Context<MigrateProtocol>) -> Result<()> {
+        let info = ctx.accounts.protocol_state.to_account_info();
+        let mut protocol_state: ProtocolState = load_for_migration(
+            &info,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            8 + ProtocolState::INIT_SPACE,
+        )?;
+        require!(
+            protocol_state.version < ProtocolState::VERSION,
+            ErrorCode::AlreadyMigrated
+        );
+
+        let from_version = protocol_state.version;
+        protocol_state.version = ProtocolState::VERSION;
+        protocol_state.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        msg!(
+            "Protocol state migrated from v{} to v{}",
+            from_version,
+            ProtocolState::VERSION
+        );
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeProtocol<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + ProtocolState::INIT_SPACE,
+        seeds = [b"protocol-state"],
+        bump
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub treasury: InterfaceAccount<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateProtocol<'info> {
+    #[account(mut, seeds = [b"protocol-state"], bump = protocol_state.bump)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetCollateral<'info> {
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + Collateral::INIT_SPACE,
+        seeds = [b"collateral", mint.key().as_ref()],
+        bump
+    )]
+    pub collateral: Account<'info, Collateral>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(constraint = treasury.mint == mint.key())]
+    pub treasury: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"protocol-state"], bump = protocol_state.bump)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(
+    question: String,
+    description: String,
+    category: MarketCategory,
+    end_timestamp: i64,
+    resolution_timestamp: i64,
+    oracle_source: String,
+    initial_liquidity: u64,
+    metadata_uri: String,
+)]
+pub struct CreateMarket<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = Market::space(&question, &description, &oracle_source, &metadata_uri),
+        seeds = [b"market", protocol_state.total_markets.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + std::mem::size_of::<MarketState>(),
+        seeds = [b"market_state", market.key().as_ref()],
+        bump
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = creator,
+        token::mint = usdc_mint,
+        token::authority = market,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + std::mem::size_of::<PriceHistory>(),
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    #[account(mut)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        init_if_needed,
+        payer = creator,
+        space = 8 + CreatorProfile::INIT_SPACE,
+        seeds = [b"creator", creator.key().as_ref()],
+        bump
+    )]
+    pub creator_profile: Box<Account<'info, CreatorProfile>>,
+
+    /// Optional: also file the market under the creator's own sequential index.
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + CreatorMarket::INIT_SPACE,
+        seeds = [
+            b"creator_market",
+            creator.key().as_ref(),
+            creator_profile.market_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub creator_market: Option<Box<Account<'info, CreatorMarket>>>,
+
+    /// Optional: the market this one is conditional on.
+    pub parent_market_state: Option<AccountLoader<'info, MarketState>>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = creator_token_account.owner == creator.key(),
+        constraint = creator_token_account.mint == usdc_mint.key(),
+    )]
+    pub creator_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Collateral mint; any enabled `Collateral`, not only USDC.
+    pub usdc_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        seeds = [b"collateral", usdc_mint.key().as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(
+    question: String,
+    description: String,
+    category: MarketCategory,
+    oracle_source: String,
+    metadata_uri: String,
+)]
+pub struct UpdateMetadata<'info> {
+    #[account(
+        mut,
+        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
+        bump = market.bump,
+        realloc = Market::space(&question, &description, &oracle_source, &metadata_uri),
+        realloc::payer = creator,
+        realloc::zero = false,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateSeries<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + MarketSeries::INIT_SPACE,
+        seeds = [b"series", protocol_state.total_series.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub series: Box<Account<'info, MarketSeries>>,
+
+    #[account(mut)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", collateral.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SpawnNextMarket<'info> {
+    #[account(
+        mut,
+        seeds = [b"series", series.series_id.to_le_bytes().as_ref()],
+        bump = series.bump,
+    )]
+    pub series: Box<Account<'info, MarketSeries>>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = series.next_market_space(),
+        seeds = [b"market", protocol_state.total_markets.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + std::mem::size_of::<MarketState>(),
+        seeds = [b"market_state", market.key().as_ref()],
+        bump
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = payer,
+        token::mint = usdc_mint,
+        token::authority = market,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + std::mem::size_of::<PriceHistory>(),
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    #[account(mut)]
+    pub protocol_state: Box<Account<'info, ProtocolState>>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + CreatorProfile::INIT_SPACE,
+        seeds = [b"creator", series.authority.as_ref()],
+        bump
+    )]
+    pub creator_profile: Box<Account<'info, CreatorProfile>>,
+
+    #[account(
+        mut,
+        constraint = creator_token_account.owner == series.authority,
+        constraint = creator_token_account.mint == usdc_mint.key(),
+    )]
+    pub creator_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = series.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        seeds = [b"collateral", series.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct BuyShares<'info> {
+    /// CHECK: only signs for the vault; `market_state` is derived from its address.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump = price_history.load()?.bump,
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    /// CHECK: holder of the position and of `user_token_account`: `user`
+    /// itself, or an owner who authorized `user` through `trading_delegate`.
+    pub owner: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserPosition::INIT_SPACE,
+        seeds = [b"position",market.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", market_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == owner.key(),
+        constraint = user_token_account.mint == market_vault.mint,
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Required when the market is gated; see `MarketState::check_access`.
+    pub attestation: Option<Account<'info, Attestation>>,
+
+    /// Required for conditional markets: the parent's `MarketState`, so a
+    /// market whose condition failed stops trading.
+    pub parent_market_state: Option<AccountLoader<'info, MarketState>>,
+
+    /// Required when `user` is not `owner`; see `TradingDelegate`.
+    #[account(
+        mut,
+        seeds = [b"trading_delegate", owner.key().as_ref(), user.key().as_ref()],
+        bump = trading_delegate.bump,
+    )]
+    pub trading_delegate: Option<Box<Account<'info, TradingDelegate>>>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u64)]
+pub struct PlaceLimitOrder<'info> {
+    /// CHECK: only used to derive the market's other accounts.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + LimitOrder::INIT_SPACE,
+        seeds = [
+            b"limit_order",
+            market.key().as_ref(),
+            owner.key().as_ref(),
+            nonce.to_le_bytes().as_ref(),
+        ],
+        bump,
+    )]
+    pub limit_order: Box<Account<'info, LimitOrder>>,
+
+    /// Escrow of a buy order; omitted for a sell.
+    #[account(
+        init,
+        payer = owner,
+        token::mint = usdc_mint,
+        token::authority = limit_order,
+        seeds = [b"order_vault", limit_order.key().as_ref()],
+        bump,
+    )]
+    pub order_vault: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + UserPosition::INIT_SPACE,
+        seeds = [b"position", market.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = owner_token_account.owner == owner.key(),
+        constraint = owner_token_account.mint == market_vault.mint,
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Required when the market is gated; see `MarketState::check_access`.
+    pub attestation: Option<Account<'info, Attestation>>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelLimitOrder<'info> {
+    #[account(
+        mut,
+        close = owner,
+        has_one = owner,
+        seeds = [
+            b"limit_order",
+            limit_order.market.as_ref(),
+            owner.key().as_ref(),
+            limit_order.nonce.to_le_bytes().as_ref(),
+        ],
+        bump = limit_order.bump,
+    )]
+    pub limit_order: Box<Account<'info, LimitOrder>>,
+
+    /// Required for a buy order.
+    #[account(
+        mut,
+        seeds = [b"order_vault", limit_order.key().as_ref()],
+        bump = limit_order.vault_bump,
+    )]
+    pub order_vault: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    /// Required for a sell order.
+    #[account(
+        mut,
+        seeds = [b"position", limit_order.market.as_ref(), owner.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Option<Box<Account<'info, UserPosition>>>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = owner_token_account.owner == owner.key(),
+        constraint = owner_token_account.mint == usdc_mint.key(),
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Mutable so withheld transfer fees can be harvested off the vault.
+    #[account(mut)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u64)]
+pub struct PlaceExitOrder<'info> {
+    /// CHECK: only used to derive the market's other accounts.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + ExitOrder::INIT_SPACE,
+        seeds = [b"exit_order", user_position.key().as_ref(), nonce.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub exit_order: Box<Account<'info, ExitOrder>>,
+
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), owner.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteExitOrder<'info> {
+    /// CHECK: only signs for the vault; `market_state` is derived from its address.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump = price_history.load()?.bump,
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    /// Closed once executed; a fill dropped by a circuit breaker halt leaves
+    /// the order open.
+    #[account(
+        mut,
+        has_one = owner,
+        constraint = exit_order.market == market.key() @ ErrorCode::InvalidOrder,
+        seeds = [b"exit_order", user_position.key().as_ref(), exit_order.nonce.to_le_bytes().as_ref()],
+        bump = exit_order.bump,
+    )]
+    pub exit_order: Box<Account<'info, ExitOrder>>,
+
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), owner.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    /// CHECK: receives the order's rent; checked against `exit_order.owner`.
+    #[account(mut)]
+    pub owner: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = owner_token_account.owner == owner.key(),
+        constraint = owner_token_account.mint == market_vault.mint,
+    )]
+    pub owner_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Required for conditional markets: the parent's `MarketState`, so a
+    /// market whose condition failed stops trading.
+    pub parent_market_state: Option<AccountLoader<'info, MarketState>>,
+
+    #[account(mut)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", market_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = keeper_token_account.mint == market_vault.mint,
+    )]
+    pub keeper_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Required when the market is gated; see `MarketState::check_access`.
+    pub attestation: Option<Account<'info, Attestation>>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelExitOrder<'info> {
+    #[account(
+        mut,
+        close = owner,
+        has_one = owner,
+        seeds = [b"exit_order", user_position.key().as_ref(), exit_order.nonce.to_le_bytes().as_ref()],
+        bump = exit_order.bump,
+    )]
+    pub exit_order: Box<Account<'info, ExitOrder>>,
+
+    #[account(
+        mut,
+        address = exit_order.position,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    /// CHECK: receives the order's rent; checked against `exit_order.owner`.
+    #[account(mut)]
+    pub owner: UncheckedAccount<'info>,
+
+    /// The owner, or anyone once the order has expired.
+    pub signer: Signer<'info>,
+}
+
+/// Accounts of the market being cranked; each order's own accounts follow
+/// as remaining accounts.
+#[derive(Accounts)]
+pub struct CrankOrders<'info> {
+    /// CHECK: only signs for the vault; `market_state` is derived from its address.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump = price_history.load()?.bump,
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    #[account(mut)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", market_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Required for conditional markets: the parent's `MarketState`, so a
+    /// market whose condition failed stops trading.
+    pub parent_market_state: Option<AccountLoader<'info, MarketState>>,
+
+    pub cranker: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = cranker_token_account.mint == market_vault.mint,
+    )]
+    pub cranker_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Mutable so withheld transfer fees can be harvested off order vaults.
+    #[account(mut, address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+/// Accounts shared by every leg of a `batch_trade`; each leg's own
+/// accounts follow as remaining accounts.
+#[derive(Accounts)]
+pub struct BatchTrade<'info> {
+    #[account(mut)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", usdc_mint.key().as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key(),
+        constraint = user_token_account.mint == usdc_mint.key(),
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SellShares<'info> {
+    /// CHECK: only signs for the vault; `market_state` is derived from its address.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump = price_history.load()?.bump,
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    /// CHECK: holder of the position and of `user_token_account`: `user`
+    /// itself, or an owner who authorized `user` through `trading_delegate`.
+    pub owner: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"position",market.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", market_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == owner.key(),
+        constraint = user_token_account.mint == market_vault.mint,
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Required when the market is gated; see `MarketState::check_access`.
+    pub attestation: Option<Account<'info, Attestation>>,
+
+    /// Required for conditional markets: the parent's `MarketState`, so a
+    /// market whose condition failed stops trading.
+    pub parent_market_state: Option<AccountLoader<'info, MarketState>>,
+
+    /// Required when `user` is not `owner`; see `TradingDelegate`.
+    #[account(
+        mut,
+        seeds = [b"trading_delegate", owner.key().as_ref(), user.key().as_ref()],
+        bump = trading_delegate.bump,
+    )]
+    pub trading_delegate: Option<Box<Account<'info, TradingDelegate>>>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveMarket<'info> {
+    #[account(
+        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        mut,
+        constraint = creator_token_account.owner == market.authority,
+        constraint = creator_token_account.mint == market_vault.mint,
+    )]
+    pub creator_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Required for conditional markets: the parent's `MarketState`.
+    pub parent_market_state: Option<AccountLoader<'info, MarketState>>,
+
+    pub oracle: Signer<'info>,
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimWinnings<'info> {
+    /// CHECK: only signs for the vault; `market_state` is derived from its address.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"position",market.key().as_ref(), user.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key(),
+        constraint = user_token_account.mint == market_vault.mint,
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetMarketLimits<'info> {
+    /// CHECK: `market_state` is derived from its address.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        constraint = protocol_state.authority == authority.key(),
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAccessPolicy<'info> {
+    #[account(
+        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct IssueAttestation<'info> {
+    #[account(
+        init_if_needed,
+        payer = issuer,
+        space = 8 + Attestation::INIT_SPACE,
+        seeds = [b"attestation", issuer.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub attestation: Account<'info, Attestation>,
+
+    #[account(mut)]
+    pub issuer: Signer<'info>,
+
+    /// CHECK: the wallet being vouched for; never read or written.
+    pub user: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeAttestation<'info> {
+    #[account(
+        mut,
+        close = issuer,
+        has_one = issuer,
+        seeds = [b"attestation", issuer.key().as_ref(), attestation.user.as_ref()],
+        bump = attestation.bump
+    )]
+    pub attestation: Account<'info, Attestation>,
+
+    #[account(mut)]
+    pub issuer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(root: [u8; 32])]
+pub struct ProveAllowlist<'info> {
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + Attestation::INIT_SPACE,
+        seeds = [b"attestation", root.as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub attestation: Account<'info, Attestation>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveDelegate<'info> {
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + TradingDelegate::INIT_SPACE,
+        seeds = [b"trading_delegate", owner.key().as_ref(), delegate.key().as_ref()],
+        bump
+    )]
+    pub trading_delegate: Account<'info, TradingDelegate>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    /// CHECK: the session key being authorized; never read or written.
+    pub delegate: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = owner_token_account.owner == owner.key(),
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeDelegate<'info> {
+    #[account(
+        mut,
+        close = owner,
+        has_one = owner,
+        seeds = [b"trading_delegate", owner.key().as_ref(), trading_delegate.delegate.as_ref()],
+        bump = trading_delegate.bump
+    )]
+    pub trading_delegate: Account<'info, TradingDelegate>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = owner_token_account.owner == owner.key(),
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InvalidateMarket<'info> {
+    #[account(
+        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        constraint = protocol_state.authority == authority.key(),
+    )]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", market_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: InterfaceAccount<'info, TokenAccount>,
+
+    /// Created here for creators whose markets predate creator profiles.
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + CreatorProfile::INIT_SPACE,
+        seeds = [b"creator", market.authority.as_ref()],
+        bump
+    )]
+    pub creator_profile: Box<Account<'info, CreatorProfile>>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRefund<'info> {
+    /// CHECK: only signs for the vault; `market_state` is derived from its address.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"position",market.key().as_ref(), user.key().as_ref()],
+        bump = user_position.bump,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key(),
+        constraint = user_token_account.mint == market_vault.mint,
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawResidual<'info> {
+    #[account(
+        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
+        bump = market.bump,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub creator: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = creator_token_account.owner == creator.key(),
+        constraint = creator_token_account.mint == market_vault.mint,
+    )]
+    pub creator_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    /// CHECK: may already be closed; its status is checked in the handler.
+    #[account(
+        seeds = [b"market_state", user_position.market.as_ref()],
+        bump,
+    )]
+    pub market_state: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"position", user_position.market.as_ref(), user.key().as_ref()],
+        bump = user_position.bump,
+        has_one = user,
+        close = user,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TransferPosition<'info> {
+    /// CHECK: only used to derive the market's other accounts.
+    pub market: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump = user_position.bump,
+        has_one = user,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserPosition::INIT_SPACE,
+        seeds = [b"position", market.key().as_ref(), recipient.key().as_ref()],
+        bump,
+    )]
+    pub recipient_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    /// CHECK: the wallet receiving the shares; never read or written.
+    #[account(constraint = recipient.key() != user.key() @ ErrorCode::InvalidRecipient)]
+    pub recipient: UncheckedAccount<'info>,
+
+    /// The recipient's attestation, required when the market is gated.
+    pub attestation: Option<Account<'info, Attestation>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMarket<'info> {
+    #[account(
+        mut,
+        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
+        bump = market.bump,
+        close = creator,
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump = market_state.load()?.bump,
+        close = creator,
+    )]
+    pub market_state: AccountLoader<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"market_vault", market.key().as_ref()],
+        bump,
+    )]
+    pub market_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump = price_history.load()?.bump,
+        close = creator,
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", market_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: the market creator, who paid the rent being returned.
+    #[account(mut, address = market.authority)]
+    pub creator: UncheckedAccount<'info>,
+
+    pub authority: Signer<'info>,
+
+    /// Writable so withheld transfer fees can be harvested before closing.
+    #[account(mut, address = market_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct FundParlayPool<'info> {
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + ParlayPool::INIT_SPACE,
+        seeds = [b"parlay_pool"],
+        bump
+    )]
+    pub parlay_pool: Account<'info, ParlayPool>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        token::mint = usdc_mint,
+        token::authority = parlay_pool,
+        seeds = [b"parlay_vault"],
+        bump,
+    )]
+    pub parlay_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"protocol-state"], bump = protocol_state.bump)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    /// Parlays are paid in a registered collateral, like markets.
+    #[account(
+        seeds = [b"collateral", usdc_mint.key().as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = authority_token_account.owner == authority.key(),
+        constraint = authority_token_account.mint == usdc_mint.key(),
+    )]
+    pub authority_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub usdc_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawParlayPool<'info> {
+    #[account(seeds = [b"parlay_pool"], bump = parlay_pool.bump)]
+    pub parlay_pool: Account<'info, ParlayPool>,
+
+    #[account(
+        mut,
+        seeds = [b"parlay_vault"],
+        bump,
+    )]
+    pub parlay_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"protocol-state"], bump = protocol_state.bump)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", parlay_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: InterfaceAccount<'info, TokenAccount>,
+
+    pub authority: Signer<'info>,
+
+    #[account(address = parlay_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetParlayOracle<'info> {
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + ParlayOracle::INIT_SPACE,
+        seeds = [b"parlay_oracle", oracle.key().as_ref()],
+        bump
+    )]
+    pub parlay_oracle: Account<'info, ParlayOracle>,
+
+    /// CHECK: Only its address is recorded.
+    pub oracle: UncheckedAccount<'info>,
+
+    #[account(seeds = [b"protocol-state"], bump = protocol_state.bump)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u64)]
+pub struct OpenParlay<'info> {
+    #[account(
+        init,
+        payer = user,
+        space = 8 + Parlay::INIT_SPACE,
+        seeds = [b"parlay", user.key().as_ref(), nonce.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub parlay: Box<Account<'info, Parlay>>,
+
+    #[account(mut, seeds = [b"parlay_pool"], bump = parlay_pool.bump)]
+    pub parlay_pool: Account<'info, ParlayPool>,
+
+    #[account(
+        mut,
+        seeds = [b"parlay_vault"],
+        bump,
+    )]
+    pub parlay_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"protocol-state"], bump = protocol_state.bump)]
+    pub protocol_state: Account<'info, ProtocolState>,
+
+    #[account(
+        seeds = [b"collateral", parlay_vault.mint.as_ref()],
+        bump = collateral.bump,
+    )]
+    pub collateral: Box<Account<'info, Collateral>>,
+
+    #[account(
+        mut,
+        constraint = protocol_treasury.key() == collateral.treasury,
+    )]
+    pub protocol_treasury: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key(),
+        constraint = user_token_account.mint == parlay_vault.mint,
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = parlay_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SettleParlay<'info> {
+    #[account(
+        mut,
+        seeds = [b"parlay", parlay.user.as_ref(), parlay.nonce.to_le_bytes().as_ref()],
+        bump = parlay.bump,
+        has_one = user,
+        close = user,
+    )]
+    pub parlay: Box<Account<'info, Parlay>>,
+
+    #[account(mut, seeds = [b"parlay_pool"], bump = parlay_pool.bump)]
+    pub parlay_pool: Account<'info, ParlayPool>,
+
+    #[account(
+        mut,
+        seeds = [b"parlay_vault"],
+        bump,
+    )]
+    pub parlay_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: the parlay's owner, who paid the rent being returned.
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key(),
+        constraint = user_token_account.mint == parlay_vault.mint,
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = parlay_vault.mint)]
+    pub usdc_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMarket<'info> {
+    /// CHECK: may predate the current layout; its discriminator is checked
+    /// in the handler.
+    #[account(mut, owner = crate::ID)]
+    pub market: UncheckedAccount<'info>,
+
+    /// CHECK: derived from `market`; missing for markets from before
+    /// versioning, which the handler rejects.
+    #[account(
+        mut,
+        seeds = [b"market_state", market.key().as_ref()],
+        bump,
+    )]
+    pub market_state: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePosition<'info> {
+    /// CHECK: may predate the current layout; its discriminator is checked
+    /// in the handler.
+    #[account(mut, owner = crate::ID)]
+    pub user_position: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateProtocol<'info> {
+    /// CHECK: may predate the current layout; its discriminator is checked
+    /// in the handler.
+    #[account(mut, owner = crate::ID, seeds = [b"protocol-state"], bump)]
+    pub protocol_state: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ProtocolState {
+    pub authority: Pubkey,
+    /// Treasury given at initialization. Fees go to the treasury of each
+    /// market's `Collateral` instead.
+    pub treasury: Pubkey,
+    pub free_bps: u16,
+    pub total_markets: u64,
+    pub total_volume: u64,
+    pub bump: u8,
+    pub version: u8,
+    /// Collateral escrowed per market by its creator, returned when the
+    /// market resolves and forfeited to the treasury if it is invalidated.
+    pub creation_bond: u64,
+    /// Caps copied into each new market; see `MarketState`.
+    pub default_max_open_interest: u64,
+    pub default_max_position_shares: u64,
+    /// Circuit breaker copied into each new market; see `MarketState`.
+    pub default_breaker_window: i64,
+    pub default_breaker_max_move_bps: u16,
+    pub default_breaker_cooldown: i64,
+    pub total_series: u64,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN - 50],
+}
+
+impl ProtocolState {
+    pub const VERSION: u8 = 1;
+}
+
+/// A mint markets may be denominated in.
+#[account]
+#[derive(InitSpace)]
+pub struct Collateral {
+    pub mint: Pubkey,
+    pub decimals: u8,
+    /// Token account of `mint` that fees, forfeited bonds and swept vaults
+    /// of its markets go to.
+    pub treasury: Pubkey,
+    /// Least `initial_liquidity` a market in this mint is seeded with.
+    pub min_liquidity: u64,
+    /// Whether new markets may be created in this mint.
+    pub enabled: bool,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
+}
+
+impl Collateral {
+    pub const VERSION: u8 = 1;
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Market {
+    pub market_id: u64,
+    pub authority: Pubkey,
+    #[max_len(200)]
+    pub question: String,
+    #[max_len(1000)]
+    pub description: String,
+    pub category: MarketCategory,
+    #[max_len(100)]
+    pub oracle_source: String,
+    /// Where the full market text lives when it is kept off-chain, with the
+    /// SHA-256 of that document. Empty and zeroed for on-chain text.
+    #[max_len(200)]
+    pub metadata_uri: String,
+    pub metadata_hash: [u8; 32],
+    pub bump: u8,
+    pub version: u8,
+    /// Series the market was spawned from; meaningless unless
+    /// `series_sequence` is set.
+    pub series_id: u64,
+    /// Position in the series, starting at one. Zero for markets created
+    /// outside a series.
+    pub series_sequence: u64,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN - 16],
+}
+
+impl Market {
+    pub const VERSION: u8 = 1;
+
+    /// Account size for a market holding exactly this text. Markets are sized
+    /// to their text rather than the maximum lengths, so one that keeps its
+    /// text behind `metadata_uri` only pays rent for the fixed fields.
+    pub fn space(
+        question: &str,
+        description: &str,
+        oracle_source: &str,
+        metadata_uri: &str,
+    ) -> usize {
+        8 + Market::INIT_SPACE - MARKET_TEXT_CAPACITY
+            + question.len()
+            + description.len()
+            + oracle_source.len()
+            + metadata_uri.len()
+    }
+}
+
+/// Template for a recurring market. Each market spawned from it ends one
+/// `duration` after the previous one and is funded from the creator's
+/// token account, which must have delegated enough to the series. That
+/// allowance occupies the account's only delegate slot, so the same account
+/// cannot also back a trading delegate.
+#[account]
+#[derive(InitSpace)]
+pub struct MarketSeries {
+    pub series_id: u64,
+    pub authority: Pubkey,
+    pub category: MarketCategory,
+    /// Question of each market, with `{seq}` replaced by its sequence
+    /// number and `{date}` by its end date (`YYYY-MM-DD`, UTC).
+    #[max_len(200)]
+    pub question: String,
+    /// Templated like `question`.
+    #[max_len(1000)]
+    pub description: String,
+    #[max_len(100)]
+    pub oracle_source: String,
+    pub duration: i64,
+    /// Seconds after its end each market may be resolved in.
+    pub resolution_window: i64,
+    pub initial_liquidity: u64,
+    /// Sequence number of the latest market; zero before the first.
+    pub sequence: u64,
+    pub last_market_id: u64,
+    /// End of the latest market, or the start of the first before it exists.
+    pub last_end_timestamp: i64,
+    pub bump: u8,
+    pub version: u8,
+    /// Collateral mint the series' markets are denominated in.
+    pub mint: Pubkey,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN - 32],
+}
+
+impl MarketSeries {
+    pub const VERSION: u8 = 1;
+
+    pub fn render(template: &str, sequence: u64, end_timestamp: i64) -> String {
+        template
+            .replace("{seq}", &sequence.to_string())
+            .replace("{date}", &format_date(end_timestamp))
+    }
+
+    /// Account size of the next market. `{date}` always renders to ten
+    /// characters, so the size does not depend on when it is spawned.
+    pub fn next_market_space(&self) -> usize {
+        let sequence = self.sequence + 1;
+        Market::space(
+            &Self::render(&self.question, sequence, self.last_end_timestamp),
+            &Self::render(&self.description, sequence, self.last_end_timestamp),
+            &self.oracle_source,
+            "",
+        )
+    }
+
+    /// End of the next market: the first whole `duration` after the latest
+    /// end that is still ahead of `now`, so a late spawn keeps the schedule.
+    pub fn next_end_timestamp(&self, now: i64) -> Result<i64> {
+        require!(
+            now >= self.last_end_timestamp,
+            ErrorCode::SeriesMarketNotEnded
+        );
+        let periods = (now - self.last_end_timestamp) / self.duration + 1;
+        periods
+            .checked_mul(self.duration)
+            .and_then(|elapsed| self.last_end_timestamp.checked_add(elapsed))
+            .ok_or(error!(ErrorCode::MathOverflow))
+    }
+
+    /// Terms of the market numbered `sequence` ending at `end_timestamp`.
+    fn terms(&self, sequence: u64, end_timestamp: i64) -> Result<MarketTerms> {
+        Ok(MarketTerms {
+            question: Self::render(&self.question, sequence, end_timestamp),
+            description: Self::render(&self.description, sequence, end_timestamp),
+            category: self.category,
+            oracle_source: self.oracle_source.clone(),
+            metadata_uri: String::new(),
+            metadata_hash: [0; 32],
+            end_timestamp,
+            resolution_timestamp: end_timestamp
+                .checked_add(self.resolution_window)
+                .ok_or(ErrorCode::MathOverflow)?,
+            initial_liquidity: self.initial_liquidity,
+        })
+    }
+}
+
+/// Schedule, pool and settlement of a market: everything trading reads or
+/// writes. Kept apart from the descriptive `Market` and laid out for
+/// zero-copy access, so trades update it in place instead of deserializing
+/// and reserializing the market's text.
+#[account(zero_copy)]
+pub struct MarketState {
+    pub market: Pubkey,
+    pub market_id: u64,
+    pub created_at: i64,
+    pub end_timestamp: i64,
+    pub resolution_timestamp: i64,
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub total_yes_shares: u64,
+    pub total_no_shares: u64,
+    pub total_volume: u64,
+    pub total_fees: u64,
+    pub initial_liquidity: u64,
+    /// Collateral still owed to holders once settled: unclaimed winning
+    /// shares, or unclaimed refunds of an invalid market.
+    pub liability: u64,
+    pub settled_at: i64,
+    /// A `MarketStatus` discriminant.
+    pub status: u8,
+    /// Zero until resolved, then one more than the winning `Outcome`.
+    pub winning_outcome: u8,
+    /// Bump of the `Market` PDA, which signs for the vault.
+    pub market_bump: u8,
+    pub bump: u8,
+    /// Zero for states created before versioning, which lack `_reserved`.
+    pub version: u8,
+    /// An `AccessPolicy` discriminant.
+    pub access_policy: u8,
+    /// Furthest a trade may move the YES price from the window's reference,
+    /// in basis points of `PRICE_SCALE`. Zero disables the circuit breaker.
+    pub breaker_max_move_bps: u16,
+    /// Creator's bond still held in the vault; zero once settled.
+    pub creation_bond: u64,
+    /// Most shares of one outcome traders may hold in total; zero for no cap.
+    pub max_open_interest: u64,
+    /// Most shares of one outcome a single position may hold; zero for no cap.
+    pub max_position_shares: u64,
+    /// Merkle root of the allowlist, or the address of the attestation issuer.
+    pub access_key: [u8; 32],
+    /// Length in seconds of the window a reference price holds for.
+    pub breaker_window: i64,
+    /// How long trading halts once the breaker trips. Zero instead rejects
+    /// the offending trade and leaves trading open.
+    pub breaker_cooldown: i64,
+    /// YES price before the first trade of the current window.
+    pub reference_price: u64,
+    pub reference_window_start: i64,
+    pub halted_until: i64,
+    /// Market this one is conditional on; meaningless unless `parent_outcome`
+    /// is set.
+    pub parent_market_id: u64,
+    /// Zero for an unconditional market, else one more than the `Outcome`
+    /// the parent must resolve to for this market to resolve normally.
+    pub parent_outcome: u8,
+    pub _padding: [u8; 3],
+    /// Legs of open parlays on this market. It cannot be closed while any
+    /// remain, since settling them needs its outcome.
+    pub open_parlay_legs: u32,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN - 16],
+}
+
+impl MarketState {
+    pub const VERSION: u8 = 1;
+
+    /// Copies the state out of raw account data, for callers that cannot
+    /// hold an `AccountLoader` because the account may already be closed.
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let body = data
+            .strip_prefix(MarketState::DISCRIMINATOR)
+            .and_then(|body| body.get(..std::mem::size_of::<MarketState>()))
+            .ok_or(error!(
+                anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch
+            ))?;
+        Ok(bytemuck::pod_read_unaligned(body))
+    }
+
+    pub fn status(&self) -> MarketStatus {
+        match self.status {
+            0 => MarketStatus::Active,
+            1 => MarketStatus::Resolved,
+            _ => MarketStatus::Invalid,
+        }
+    }
+
+    pub fn set_status(&mut self, status: MarketStatus) {
+        self.status = status as u8;
+    }
+
+    pub fn winning_outcome(&self) -> Option<Outcome> {
+        match self.winning_outcome {
+            1 => Some(Outcome::Yes),
+            2 => Some(Outcome::No),
+            _ => None,
+        }
+    }
+
+    pub fn set_winning_outcome(&mut self, outcome: Outcome) {
+        self.winning_outcome = outcome as u8 + 1;
+    }
+
+    pub fn parent_outcome(&self) -> Option<Outcome> {
+        match self.parent_outcome {
+            1 => Some(Outcome::Yes),
+            2 => Some(Outcome::No),
+            _ => None,
+        }
+    }
+
+    /// Whether a conditional market's parent settled without the outcome it
+    /// is conditional on. Fails while the parent is still open.
+    pub fn condition_failed(&self, parent: Option<&MarketState>) -> Result<bool> {
+        let Some(outcome) = self.parent_outcome() else {
+            return Ok(false);
+        };
+        let parent = parent.ok_or(ErrorCode::ParentMarketMismatch)?;
+        require!(
+            parent.market_id == self.parent_market_id,
+            ErrorCode::ParentMarketMismatch
+        );
+        match parent.status() {
+            MarketStatus::Active => err!(ErrorCode::ParentMarketUnresolved),
+            MarketStatus::Resolved => Ok(parent.winning_outcome() != Some(outcome)),
+            MarketStatus::Invalid => Ok(true),
+        }
+    }
+
+    /// Fails once a conditional market's parent has settled without the
+    /// outcome it is conditional on. The market is then void and must not
+    /// trade while it waits for `resolve_market` to invalidate it.
+    pub fn check_condition(&self, parent: Option<&MarketState>) -> Result<()> {
+        if self.parent_outcome().is_none() {
+            return Ok(());
+        }
+        let parent = parent.ok_or(ErrorCode::ParentMarketMismatch)?;
+        if parent.status() == MarketStatus::Active {
+            require!(
+                parent.market_id == self.parent_market_id,
+                ErrorCode::ParentMarketMismatch
+            );
+            return Ok(());
+        }
+        require!(
+            !self.condition_failed(Some(parent))?,
+            ErrorCode::ConditionFailed
+        );
+        Ok(())
+    }
+
+    /// Settles the market as invalid so every held share refunds half a unit.
+    pub fn invalidate(&mut self, now: i64) -> Result<()> {
+        let (yes_held, no_held) = self.held_shares()?;
+        self.set_status(MarketStatus::Invalid);
+        // Refunds round down per position, so their sum never exceeds this.
+        self.liability = yes_held
+            .checked_add(no_held)
+            .ok_or(ErrorCode::MathOverflow)?
+            / 2;
+        self.settled_at = now;
+        Ok(())
+    }
+
+    pub fn access_policy(&self) -> AccessPolicy {
+        match self.access_policy {
+            1 => AccessPolicy::Allowlist,
+            2 => AccessPolicy::Attestation,
+            _ => AccessPolicy::Open,
+        }
+    }
+
+    /// Fails unless `user` may trade: gated markets need an unexpired
+    /// attestation of the user by `access_key`, which for an allowlist is the
+    /// root the user proved membership of.
+    pub fn check_access(
+        &self,
+        user: &Pubkey,
+        attestation: Option<&Attestation>,
+        now: i64,
+    ) -> Result<()> {
+        if self.access_policy() == AccessPolicy::Open {
+            return Ok(());
+        }
+        let attestation = attestation.ok_or(ErrorCode::AccessDenied)?;
+        require!(
+            attestation.issuer.to_bytes() == self.access_key && attestation.user == *user,
+            ErrorCode::AccessDenied
+        );
+        require!(
+            attestation.expires_at == 0 || now < attestation.expires_at,
+            ErrorCode::AttestationExpired
+        );
+        Ok(())
+    }
+
+    /// Fails unless the market takes trades at `now`.
+    pub fn check_trading(&self, now: i64) -> Result<()> {
+        require!(
+            self.status() == MarketStatus::Active,
+            ErrorCode::MarketNotActive
+        );
+        require!(now < self.end_timestamp, ErrorCode::MarketEnded);
+        require!(now >= self.halted_until, ErrorCode::TradingHalted);
+        Ok(())
+    }
+
+    /// `(outcome, opposite)` reserves of the pool.
+    fn reserves(&self, outcome: Outcome) -> (u64, u64) {
+        match outcome {
+            Outcome::Yes => (self.yes_liquidity, self.no_liquidity),
+            Outcome::No => (self.no_liquidity, self.yes_liquidity),
+        }
+    }
+
+    /// Prices spending `max_cost`, fee included, on `outcome`. The pool is
+    /// left untouched until the fill is passed to `apply`.
+    pub fn quote_buy(&self, outcome: Outcome, max_cost: u64, free_bps: u16) -> Result<Fill> {
+        let (outcome_liquidity, opposite_liquidity) = self.reserves(outcome);
+        let (shares, cost, fee) =
+            calculate_buy_shares(outcome_liquidity, opposite_liquidity, max_cost, free_bps)?;
+        require!(shares > 0, ErrorCode::InsufficientOutput);
+        let minted = cost - fee;
+        let outcome_after = outcome_liquidity
+            .checked_add(minted)
+            .and_then(|v| v.checked_sub(shares))
+            .ok_or(ErrorCode::MathOverflow)?;
+        let opposite_after = opposite_liquidity
+            .checked_add(minted)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(Fill::new(
+            TradeSide::Buy,
+            outcome,
+            shares,
+            cost,
+            fee,
+            (outcome_after, opposite_after),
+        ))
+    }
+
+    /// Prices selling `shares_in` of `outcome` back to the pool.
+    pub fn quote_sell(&self, outcome: Outcome, shares_in: u64, free_bps: u16) -> Result<Fill> {
+        let (outcome_liquidity, opposite_liquidity) = self.reserves(outcome);
+        let (payout, fee) =
+            calculate_sell_shares(outcome_liquidity, opposite_liquidity, shares_in, free_bps)?;
+        let outcome_after = outcome_liquidity
+            .checked_add(shares_in)
+            .and_then(|v| v.checked_sub(payout))
+            .ok_or(ErrorCode::MathOverflow)?;
+        let opposite_after = opposite_liquidity
+            .checked_sub(payout)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(Fill::new(
+            TradeSide::Sell,
+            outcome,
+            shares_in,
+            payout,
+            fee,
+            (outcome_after, opposite_after),
+        ))
+    }
+
+    /// Spot price of `outcome`.
+    pub fn price_of(&self, outcome: Outcome) -> Result<u64> {
+        let yes = yes_price(self.yes_liquidity, self.no_liquidity)?;
+        Ok(match outcome {
+            Outcome::Yes => yes,
+            Outcome::No => PRICE_SCALE - yes,
+        })
+    }
+
+    /// Moves the pool to where `fill` leaves it and books its volume and fee.
+    pub fn apply(&mut self, fill: &Fill) -> Result<()> {
+        let total_shares = match fill.outcome {
+            Outcome::Yes => &mut self.total_yes_shares,
+            Outcome::No => &mut self.total_no_shares,
+        };
+        *total_shares = match fill.side {
+            TradeSide::Buy => total_shares.checked_add(fill.shares),
+            TradeSide::Sell => total_shares.checked_sub(fill.shares),
+        }
+        .ok_or(ErrorCode::MathOverflow)?;
+        self.yes_liquidity = fill.yes_liquidity;
+        self.no_liquidity = fill.no_liquidity;
+        self.total_volume = self
+            .total_volume
+            .checked_add(fill.collateral)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.total_fees = self
+            .total_fees
+            .checked_add(fill.fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Checks a trade that moves the YES price to `new_price` against the
+    /// band around the window's reference price, opening a new window at the
+    /// current price if the last one has lapsed. Out-of-band trades fail, or
+    /// with a cooldown halt the market and return `true` so the caller drops
+    /// the trade while keeping the halt, and reports it did not fill.
+    pub fn trip_circuit_breaker(&mut self, now: i64, new_price: u64) -> Result<bool> {
+        if self.breaker_window == 0 || self.breaker_max_move_bps == 0 {
+            return Ok(false);
+        }
+        if now
+            >= self
+                .reference_window_start
+                .saturating_add(self.breaker_window)
+        {
+            self.reference_window_start = now;
+            self.reference_price = yes_price(self.yes_liquidity, self.no_liquidity)?;
+        }
+        let band = PRICE_SCALE * self.breaker_max_move_bps as u64 / 10_000;
+        if new_price.abs_diff(self.reference_price) <= band {
+            return Ok(false);
+        }
+        require!(self.breaker_cooldown > 0, ErrorCode::PriceMoveTooLarge);
+
+        self.halted_until = now.saturating_add(self.breaker_cooldown);
+        emit!(CircuitBreakerTripped {
+            market_id: self.market_id,
+            reference_price: self.reference_price,
+            attempted_price: new_price,
+            halted_until: self.halted_until,
+        });
+        msg!(
+            "Market {} halted until {}: price move to {} from {}",
+            self.market_id,
+            self.halted_until,
+            new_price,
+            self.reference_price
+        );
+        Ok(true)
+    }
+
+    /// Fails if buying `shares_out` of `outcome` on top of `position_shares`
+    /// would breach the market's open interest or per-position cap.
+    pub fn check_limits(
+        &self,
+        outcome: Outcome,
+        position_shares: u64,
+        shares_out: u64,
+    ) -> Result<()> {
+        let (yes_held, no_held) = self.held_shares()?;
+        let held = match outcome {
+            Outcome::Yes => yes_held,
+            Outcome::No => no_held,
+        };
+        require!(
+            self.max_open_interest == 0
+                || held.saturating_add(shares_out) <= self.max_open_interest,
+            ErrorCode::OpenInterestLimitExceeded
+        );
+        require!(
+            self.max_position_shares == 0
+                || position_shares.saturating_add(shares_out) <= self.max_position_shares,
+            ErrorCode::PositionLimitExceeded
+        );
+        Ok(())
+    }
+
+    /// `(yes, no)` shares held by traders, excluding the creator's seed.
+    pub fn held_shares(&self) -> Result<(u64, u64)> {
+        let seed = self.initial_liquidity / 2;
+        let yes = self.total_yes_shares.checked_sub(seed);
+        let no = self.total_no_shares.checked_sub(seed);
+        Ok((
+            yes.ok_or(ErrorCode::MathOverflow)?,
+            no.ok_or(ErrorCode::MathOverflow)?,
+        ))
+    }
+}
+
+/// A trade priced against a market's pool, as quoted by
+/// `MarketState::quote_buy` and `MarketState::quote_sell`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fill {
+    pub side: TradeSide,
+    pub outcome: Outcome,
+    /// Shares bought or sold.
+    pub shares: u64,
+    /// Collateral paid for a buy, or paid out for a sell, fee included.
+    pub collateral: u64,
+    pub fee: u64,
+    /// Reserves once the trade is applied.
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+}
+
+impl Fill {
+    fn new(
+        side: TradeSide,
+        outcome: Outcome,
+        shares: u64,
+        collateral: u64,
+        fee: u64,
+        (outcome_liquidity, opposite_liquidity): (u64, u64),
+    ) -> Self {
+        let (yes_liquidity, no_liquidity) = match outcome {
+            Outcome::Yes => (outcome_liquidity, opposite_liquidity),
+            Outcome::No => (opposite_liquidity, outcome_liquidity),
+        };
+        Self {
+            side,
+            outcome,
+            shares,
+            collateral,
+            fee,
+            yes_liquidity,
+            no_liquidity,
+        }
+    }
+
+    /// YES price the trade leaves the pool at.
+    pub fn yes_price(&self) -> Result<u64> {
+        yes_price(self.yes_liquidity, self.no_liquidity)
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct UserPosition {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub yes_shares: u64,
+    pub no_shares: u64,
+    pub total_invested: u64,
+    pub initialized: bool,
+    /// When the position was first opened. Informational only: market ids
+    /// are never reused, so a position always belongs to the market it was
+    /// opened in.
+    pub opened_at: i64,
+    pub bump: u8,
+    pub version: u8,
+    /// Shares set aside for open orders, which cannot be sold or moved.
+    pub locked_yes_shares: u64,
+    pub locked_no_shares: u64,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN - 16],
+}
+
+impl UserPosition {
+    pub const VERSION: u8 = 1;
+
+    /// Fills in a position `init_if_needed` just created.
+    fn init_if_needed(&mut self, user: Pubkey, market: Pubkey, now: i64, bump: u8) {
+        if !self.initialized {
+            self.user = user;
+            self.market = market;
+            self.yes_shares = 0;
+            self.no_shares = 0;
+            self.total_invested = 0;
+            self.initialized = true;
+            self.opened_at = now;
+            self.bump = bump;
+            self.version = Self::VERSION;
+        }
+    }
+
+    pub fn shares(&self, outcome: Outcome) -> u64 {
+        match outcome {
+            Outcome::Yes => self.yes_shares,
+            Outcome::No => self.no_shares,
+        }
+    }
+
+    /// Shares of `outcome` not locked by an open order.
+    pub fn available(&self, outcome: Outcome) -> u64 {
+        let locked = match outcome {
+            Outcome::Yes => self.locked_yes_shares,
+            Outcome::No => self.locked_no_shares,
+        };
+        self.shares(outcome).saturating_sub(locked)
+    }
+
+    /// Sets `shares` of `outcome` aside for an order.
+    pub fn lock(&mut self, outcome: Outcome, shares: u64) -> Result<()> {
+        require!(
+            self.available(outcome) >= shares,
+            ErrorCode::InsufficientShares
+        );
+        let locked = match outcome {
+            Outcome::Yes => &mut self.locked_yes_shares,
+            Outcome::No => &mut self.locked_no_shares,
+        };
+        *locked += shares;
+        Ok(())
+    }
+
+    /// Releases shares an order locked. Claims ignore locks, so the shares
+    /// may be gone by the time an order of a settled market is cancelled.
+    pub fn unlock(&mut self, outcome: Outcome, shares: u64) {
+        let locked = match outcome {
+            Outcome::Yes => &mut self.locked_yes_shares,
+            Outcome::No => &mut self.locked_no_shares,
+        };
+        *locked = locked.saturating_sub(shares);
+    }
+
+    /// Takes `yes_shares` and `no_shares` out of the position along with
+    /// their share of `total_invested`, rounded down, which is returned.
+    pub fn remove_shares(&mut self, yes_shares: u64, no_shares: u64) -> Result<u64> {
+        require!(
+            yes_shares <= self.available(Outcome::Yes) && no_shares <= self.available(Outcome::No),
+            ErrorCode::InsufficientShares
+        );
+        let moved = yes_shares
+            .checked_add(no_shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(moved > 0, ErrorCode::InvalidAmount);
+        let held = self.yes_shares as u128 + self.no_shares as u128;
+        let invested = (self.total_invested as u128 * moved as u128 / held) as u64;
+
+        self.yes_shares -= yes_shares;
+        self.no_shares -= no_shares;
+        self.total_invested -= invested;
+        Ok(invested)
+    }
+}
+
+/// A creator's track record and private market counter. Markets created
+/// with a `creator_market` entry are filed under
+/// `[b"creator_market", creator, index]`, so a creator's markets can be
+/// listed by deriving `0..market_count` instead of scanning.
+#[account]
+#[derive(InitSpace)]
+pub struct CreatorProfile {
+    pub creator: Pubkey,
+    pub market_count: u64,
+    pub markets_created: u64,
+    pub markets_invalidated: u64,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
+}
+
+impl CreatorProfile {
+    pub const VERSION: u8 = 1;
+
+    /// Fills in a profile `init_if_needed` just created.
+    fn init_if_needed(&mut self, creator: Pubkey, bump: u8) {
+        if self.version == 0 {
+            self.creator = creator;
+            self.bump = bump;
+            self.version = Self::VERSION;
+        }
+    }
+
+    /// Counts a new market against a profile `init_if_needed` may have just
+    /// created.
+    fn record_market_created(&mut self, creator: Pubkey, bump: u8) -> Result<()> {
+        self.init_if_needed(creator, bump);
+        self.markets_created = self
+            .markets_created
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
 
-    #[account(
-        mut,
-        constraint = user_token_account.owner == user.key(),
-        constraint = user_token_account.mint == market_vault.mint,
-    )]
-    pub user_token_account: Account<'info, TokenAccount>,
+/// Entry `index` of a creator's namespace, pointing at the global market.
+#[account]
+#[derive(InitSpace)]
+pub struct CreatorMarket {
+    pub market_id: u64,
+    pub market: Pubkey,
+    pub bump: u8,
+}
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+/// `issuer` vouches for `user`. Issued by an attestation authority, or by
+/// `prove_allowlist` with the allowlist's merkle root as the issuer.
+#[account]
+#[derive(InitSpace)]
+pub struct Attestation {
+    pub issuer: Pubkey,
+    pub user: Pubkey,
+    /// Zero for no expiry.
+    pub expires_at: i64,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
 }
 
-#[derive(Accounts)]
-pub struct SellShares<'info> {
-    #[account(
-        mut,
-        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
-        bump = market.bump,
-    )]
-    pub market: Account<'info, Market>,
+impl Attestation {
+    pub const VERSION: u8 = 1;
+}
 
-    #[account(
-        mut,
-        seeds = [b"market_vault", market.key().as_ref()],
-        bump,
-    )]
-    pub market_vault: Account<'info, TokenAccount>,
+/// Authorizes `delegate`, typically a hot session key, to call `buy_shares`
+/// and `sell_shares` on `owner`'s positions. Funds never pass through the
+/// delegate: buys are paid from the owner's wallet and sale proceeds and
+/// winnings always go back to it.
+#[account]
+#[derive(InitSpace)]
+pub struct TradingDelegate {
+    pub owner: Pubkey,
+    pub delegate: Pubkey,
+    /// Most the delegate may pull from the owner's wallet, fees included.
+    pub max_spend: u64,
+    pub spent: u64,
+    pub expires_at: i64,
+    /// Markets the delegate may trade; empty allows every market.
+    #[max_len(MAX_DELEGATE_MARKETS)]
+    pub markets: Vec<u64>,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
+}
 
-    #[account(
-        mut,
-        seeds = [b"position",market.key().as_ref(), user.key().as_ref()],
-        bump,
-    )]
-    pub user_position: Account<'info, UserPosition>,
+impl TradingDelegate {
+    pub const VERSION: u8 = 1;
 
-    #[account(mut)]
-    pub protocol_state: Account<'info, ProtocolState>,
+    /// Checks the delegate may still trade in `market_id` at `now`.
+    pub fn authorize(&self, market_id: u64, now: i64) -> Result<()> {
+        require!(now < self.expires_at, ErrorCode::DelegateExpired);
+        require!(
+            self.markets.is_empty() || self.markets.contains(&market_id),
+            ErrorCode::DelegateMarketNotAllowed
+        );
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        constraint = protocol_treasury.key() == protocol_state.treasury,
-    )]
-    pub protocol_treasury: Account<'info, TokenAccount>,
+    /// Counts `amount` pulled from the owner's wallet against `max_spend`.
+    pub fn spend(&mut self, amount: u64) -> Result<()> {
+        let spent = self
+            .spent
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(spent <= self.max_spend, ErrorCode::DelegateSpendExceeded);
+        self.spent = spent;
+        Ok(())
+    }
+}
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+/// An order resting against one market's pool until `crank_orders` fills it
+/// or its owner cancels it. PDA `[b"limit_order", market, owner, nonce]`.
+#[account]
+#[derive(InitSpace)]
+pub struct LimitOrder {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub nonce: u64,
+    pub side: TradeSide,
+    pub outcome: Outcome,
+    /// Collateral a buy spends, fee included, or shares a sell returns.
+    pub amount: u64,
+    /// Price of `outcome` the order fills at or better.
+    pub trigger_price: u64,
+    /// Least shares a buy takes, or least collateral a sell keeps after the
+    /// fee and tip.
+    pub limit: u64,
+    /// Collateral paid to whoever cranks the fill.
+    pub tip: u64,
+    pub created_at: i64,
+    /// Bump of the `[b"order_vault", order]` escrow of a buy.
+    pub vault_bump: u8,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
+}
 
-    #[account(
-        mut,
-        constraint = user_token_account.owner == user.key(),
-        constraint = user_token_account.mint == market_vault.mint,
-    )]
-    pub user_token_account: Account<'info, TokenAccount>,
+impl LimitOrder {
+    pub const VERSION: u8 = 1;
+}
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+/// A stop-loss and/or take-profit on shares of a position, which stay
+/// locked until a keeper sells them through `execute_exit_order` or the
+/// order is cancelled. PDA `[b"exit_order", position, nonce]`.
+#[account]
+#[derive(InitSpace)]
+pub struct ExitOrder {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub position: Pubkey,
+    pub nonce: u64,
+    pub outcome: Outcome,
+    pub shares: u64,
+    /// Sell once the price of `outcome` is at or below this; zero for none.
+    pub stop_price: u64,
+    /// Sell once the price of `outcome` is at or above this; zero for none.
+    pub take_profit_price: u64,
+    /// Least collateral the owner keeps after the fee and tip.
+    pub min_payout: u64,
+    /// Collateral paid to the keeper that executes the order.
+    pub tip: u64,
+    /// The market's `end_timestamp`, after which anyone may cancel.
+    pub expires_at: i64,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
 }
 
-#[derive(Accounts)]
-pub struct ResolveMarket<'info> {
-    #[account(
-        mut,
-        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
-        bump = market.bump
-    )]
-    pub market: Account<'info, Market>,
+impl ExitOrder {
+    pub const VERSION: u8 = 1;
+}
 
-    pub protocol_state: Account<'info, ProtocolState>,
+/// House side of every parlay. Lost stakes stay in its vault and winning
+/// parlays are paid out of it.
+#[account]
+#[derive(InitSpace)]
+pub struct ParlayPool {
+    /// Sum of what open parlays would pay if every leg won.
+    pub liability: u64,
+    pub open_parlays: u64,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
+}
 
-    pub oracle: Signer<'info>,
+impl ParlayPool {
+    pub const VERSION: u8 = 1;
+
+    /// Fills in a pool `init_if_needed` just created.
+    fn init_if_needed(&mut self, bump: u8) {
+        if self.version == 0 {
+            self.bump = bump;
+            self.version = Self::VERSION;
+        }
+    }
 }
 
-#[derive(Accounts)]
-pub struct ClaimWinnings<'info> {
-    #[account(
-        mut,
-        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
-        bump = market.bump,
-    )]
-    pub market: Account<'info, Market>,
+/// A resolver the protocol authority trusts with the markets parlays bet on.
+#[account]
+#[derive(InitSpace)]
+pub struct ParlayOracle {
+    pub oracle: Pubkey,
+    pub approved: bool,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
+}
 
-    // Duplicate `market` field removed
-    #[account(
-        mut,
-        seeds = [b"market_vault", market.key().as_ref()],
-        bump,
-    )]
-    pub market_vault: Account<'info, TokenAccount>,
+impl ParlayOracle {
+    pub const VERSION: u8 = 1;
+}
 
-    #[account(
-        mut,
-        seeds = [b"position",market.key().as_ref(), user.key().as_ref()],
-        bump = user_position.bump,
-    )]
-    pub user_position: Account<'info, UserPosition>,
+/// A bet that every leg wins, escrowed in the parlay pool.
+#[account]
+#[derive(InitSpace)]
+pub struct Parlay {
+    pub user: Pubkey,
+    pub nonce: u64,
+    /// Collateral escrowed after the fee.
+    pub stake: u64,
+    /// What the parlay pays if every leg wins; reserved in the pool.
+    pub payout: u64,
+    pub opened_at: i64,
+    #[max_len(MAX_PARLAY_LEGS)]
+    pub legs: Vec<ParlayLeg>,
+    pub bump: u8,
+    pub version: u8,
+    pub _reserved: [u8; ACCOUNT_RESERVED_LEN],
+}
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+impl Parlay {
+    pub const VERSION: u8 = 1;
+}
 
-    #[account(
-        mut,
-        constraint = user_token_account.owner == user.key(),
-        constraint = user_token_account.mint == market_vault.mint,
-    )]
-    pub user_token_account: Account<'info, TokenAccount>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub struct ParlayLeg {
+    pub market_id: u64,
+    pub outcome: Outcome,
+    /// Price of `outcome` when the parlay was opened.
+    pub price: u64,
+}
 
-    pub token_program: Program<'info, Token>,
+/// Fixed-size ring buffer of a market's price after each trade, plus a
+/// time-weighted accumulator of the YES price. The TWAP between two points in
+/// time is the difference of the accumulator at each divided by the elapsed
+/// seconds, so any window still covered by the buffer can be priced. Laid out
+/// for zero-copy access, so a trade appends its snapshot in place instead of
+/// deserializing and reserializing the whole buffer.
+#[account(zero_copy)]
+pub struct PriceHistory {
+    pub market: Pubkey,
+    pub last_update: i64,
+    pub last_yes_price: u64,
+    /// Sum of `yes_price * seconds` since the market was created.
+    pub cumulative_yes_price: PodU128,
+    pub snapshots: [PriceSnapshot; PRICE_HISTORY_LEN],
+    /// Slot the next snapshot is written to.
+    pub head: u16,
+    pub len: u16,
+    pub bump: u8,
+    pub _padding: [u8; 3],
 }
 
-#[derive(Accounts)]
-pub struct InvalidateMarket<'info> {
-    #[account(
-        mut,
-        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
-        bump = market.bump
-    )]
-    pub market: Account<'info, Market>,
+#[zero_copy]
+#[derive(Default, PartialEq, Eq, Debug)]
+pub struct PriceSnapshot {
+    pub timestamp: i64,
+    pub yes_price: u64,
+    pub volume: u64,
+    /// `PriceHistory::cumulative_yes_price` as of `timestamp`.
+    pub cumulative_yes_price: PodU128,
+}
 
-    #[account(
-        constraint = protocol_state.authority == authority.key(),
-    )]
-    pub protocol_state: Account<'info, ProtocolState>,
+/// A `u128` stored as its low and high halves, so zero-copy accounts holding
+/// one need only the 8-byte alignment account data is guaranteed.
+#[zero_copy]
+#[derive(Default, PartialEq, Eq, Debug)]
+pub struct PodU128 {
+    pub lo: u64,
+    pub hi: u64,
+}
 
-    pub authority: Signer<'info>,
+impl From<u128> for PodU128 {
+    fn from(value: u128) -> Self {
+        Self {
+            lo: value as u64,
+            hi: (value >> 64) as u64,
+        }
+    }
 }
 
-#[derive(Accounts)]
-pub struct ClaimRefund<'info> {
-    #[account(
-        mut,
-        seeds = [b"market", market.market_id.to_le_bytes().as_ref()],
-        bump = market.bump,
-    )]
-    pub market: Account<'info, Market>,
+impl From<PodU128> for u128 {
+    fn from(value: PodU128) -> Self {
+        (value.hi as u128) << 64 | value.lo as u128
+    }
+}
 
-    #[account(
-        mut,
-        seeds = [b"market_vault", market.key().as_ref()],
-        bump,
-    )]
-    pub market_vault: Account<'info, TokenAccount>,
+impl PriceHistory {
+    /// Accrues the previous price up to `now` and appends a snapshot,
+    /// overwriting the oldest one once the buffer is full.
+    pub fn record(&mut self, now: i64, yes_price: u64, volume: u64) -> Result<()> {
+        self.cumulative_yes_price = self.cumulative_price_at(now)?.into();
+        self.last_update = now;
+        self.last_yes_price = yes_price;
+
+        self.snapshots[self.head as usize] = PriceSnapshot {
+            timestamp: now,
+            yes_price,
+            volume,
+            cumulative_yes_price: self.cumulative_yes_price,
+        };
+        self.head = ((self.head as usize + 1) % PRICE_HISTORY_LEN) as u16;
+        self.len = (self.len + 1).min(PRICE_HISTORY_LEN as u16);
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        seeds = [b"position",market.key().as_ref(), user.key().as_ref()],
-        bump = user_position.bump,
-    )]
-    pub user_position: Account<'info, UserPosition>,
+    /// Snapshots from newest to oldest.
+    pub fn snapshots(&self) -> impl Iterator<Item = &PriceSnapshot> {
+        (1..=self.len as usize).map(move |age| {
+            &self.snapshots[(self.head as usize + PRICE_HISTORY_LEN - age) % PRICE_HISTORY_LEN]
+        })
+    }
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+    /// The accumulator as of `timestamp`, which must not predate the oldest
+    /// snapshot still in the buffer.
+    pub fn cumulative_price_at(&self, timestamp: i64) -> Result<u128> {
+        let (since, price, cumulative): (i64, u64, u128) = if timestamp >= self.last_update {
+            (
+                self.last_update,
+                self.last_yes_price,
+                self.cumulative_yes_price.into(),
+            )
+        } else {
+            let snapshot = self
+                .snapshots()
+                .find(|snapshot| snapshot.timestamp <= timestamp)
+                .ok_or(ErrorCode::PriceHistoryUnavailable)?;
+            (
+                snapshot.timestamp,
+                snapshot.yes_price,
+                snapshot.cumulative_yes_price.into(),
+            )
+        };
+        let elapsed = timestamp.saturating_sub(since) as u128;
+        cumulative
+            .checked_add((price as u128) * elapsed)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
 
-    #[account(
-        mut,
-        constraint = user_token_account.owner == user.key(),
-        constraint = user_token_account.mint == market_vault.mint,
-    )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    /// Time-weighted average YES price over `[from, to]`, in `PRICE_SCALE` units.
+    pub fn twap(&self, from: i64, to: i64) -> Result<u64> {
+        require!(to > from, ErrorCode::InvalidAmount);
+        let delta = self.cumulative_price_at(to)? - self.cumulative_price_at(from)?;
+        Ok((delta / (to - from) as u128) as u64)
+    }
 
-    pub token_program: Program<'info, Token>,
+    /// Time-weighted average YES price over the `window` seconds before
+    /// `now`, or over as much of it as the buffer still covers: since the
+    /// market opened if it is younger than `window`, or since the oldest
+    /// snapshot once more than `PRICE_HISTORY_LEN` trades have landed within
+    /// it. With no time covered at all it is the last price.
+    pub fn recent_twap(&self, now: i64, window: i64) -> Result<u64> {
+        let oldest = self
+            .snapshots()
+            .last()
+            .map_or(self.last_update, |snapshot| snapshot.timestamp);
+        let from = now.saturating_sub(window).max(oldest);
+        if from >= now {
+            return Ok(self.last_yes_price);
+        }
+        self.twap(from, now)
+    }
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct ProtocolState {
-    pub authority: Pubkey,
-    pub treasury: Pubkey,
-    pub free_bps: u16,
-    pub total_markets: u64,
-    pub total_volume: u64,
-    pub bump: u8,
+#[event]
+pub struct MarketCreated {
+    pub market_id: u64,
+    pub market: Pubkey,
+    pub creator: Pubkey,
+    /// Index in the creator's namespace, if the market was filed under one.
+    pub namespace_index: Option<u64>,
+    /// Market this one is conditional on, and the outcome it requires.
+    pub parent_market_id: Option<u64>,
+    pub parent_outcome: Option<Outcome>,
+    /// Series the market was spawned from, and its position in it.
+    pub series_id: Option<u64>,
+    pub series_sequence: Option<u64>,
+    pub end_timestamp: i64,
+    pub resolution_timestamp: i64,
+    pub initial_liquidity: u64,
+    pub creation_bond: u64,
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub timestamp: i64,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Market {
+/// Text of a market `spawn_next_market` rendered from its series' templates.
+#[event]
+pub struct SeriesMarketSpawned {
     pub market_id: u64,
-    pub authority: Pubkey,
-    #[max_len(200)]
+    pub market: Pubkey,
+    pub series_id: u64,
+    pub series_sequence: u64,
     pub question: String,
-    #[max_len(1000)]
     pub description: String,
     pub category: MarketCategory,
-    pub created_at: i64,
-    pub end_timestamp: i64,
-    pub resolution_timestamp: i64,
-    #[max_len(100)]
     pub oracle_source: String,
-    pub status: MarketStatus,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SeriesCreated {
+    pub series_id: u64,
+    pub series: Pubkey,
+    pub creator: Pubkey,
+    pub duration: i64,
+    pub first_end_timestamp: i64,
+    pub initial_liquidity: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SharesBought {
+    pub market_id: u64,
+    pub user: Pubkey,
+    pub outcome: Outcome,
+    pub shares_out: u64,
+    pub cost: u64,
+    pub fee: u64,
     pub yes_liquidity: u64,
     pub no_liquidity: u64,
-    pub total_yes_shares: u64,
-    pub total_no_shares: u64,
-    pub total_volume: u64,
-    pub winning_outcome: Option<Outcome>,
-    pub bump: u8,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SharesSold {
+    pub market_id: u64,
+    pub user: Pubkey,
+    pub outcome: Outcome,
+    pub shares_in: u64,
+    pub payout: u64,
+    pub fee: u64,
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PositionTransferred {
+    pub market_id: u64,
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub yes_shares: u64,
+    pub no_shares: u64,
+    pub total_invested: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct LimitOrderPlaced {
+    pub order: Pubkey,
+    pub market_id: u64,
+    pub owner: Pubkey,
+    pub side: TradeSide,
+    pub outcome: Outcome,
+    pub amount: u64,
+    pub trigger_price: u64,
+    pub limit: u64,
+    pub tip: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct LimitOrderFilled {
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub cranker: Pubkey,
+    pub tip: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct LimitOrderCancelled {
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ExitOrderPlaced {
+    pub order: Pubkey,
+    pub market_id: u64,
+    pub owner: Pubkey,
+    pub outcome: Outcome,
+    pub shares: u64,
+    pub stop_price: u64,
+    pub take_profit_price: u64,
+    pub expires_at: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ExitOrderExecuted {
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub keeper: Pubkey,
+    /// Price of the order's outcome that triggered it.
+    pub price: u64,
+    pub tip: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ExitOrderCancelled {
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MarketResolved {
+    pub market_id: u64,
+    pub winning_outcome: Outcome,
+    pub oracle: Pubkey,
+    pub bond_returned: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MarketInvalidated {
+    pub market_id: u64,
+    pub creator: Pubkey,
+    pub bond_forfeited: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct WinningsClaimed {
+    pub market_id: u64,
+    pub user: Pubkey,
+    pub outcome: Outcome,
+    pub payout: u64,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct UserPosition {
+#[event]
+pub struct RefundClaimed {
+    pub market_id: u64,
+    pub user: Pubkey,
+    pub refund: u64,
+}
+
+#[event]
+pub struct ResidualWithdrawn {
+    pub market_id: u64,
+    pub creator: Pubkey,
+    pub amount: u64,
+    pub liability: u64,
+}
+
+#[event]
+pub struct MarketClosed {
+    pub market_id: u64,
+    pub swept: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when a trade halts its market. That trade is not filled.
+#[event]
+pub struct CircuitBreakerTripped {
+    pub market_id: u64,
+    pub reference_price: u64,
+    pub attempted_price: u64,
+    pub halted_until: i64,
+}
+
+#[event]
+pub struct ParlayOpened {
+    pub parlay: Pubkey,
+    pub user: Pubkey,
+    pub legs: Vec<ParlayLeg>,
+    pub stake: u64,
+    pub fee: u64,
+    pub payout: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ParlaySettled {
+    pub parlay: Pubkey,
     pub user: Pubkey,
+    /// Zero if a leg lost.
+    pub payout: u64,
+    pub legs_voided: u8,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct MarketMetadataUpdated {
+    pub market_id: u64,
     pub market: Pubkey,
-    pub yes_shares: u64,
-    pub no_shares: u64,
-    pub total_invested: u64,
-    pub initialized: bool,
-    pub bump: u8,
+    pub metadata_uri: String,
+    pub metadata_hash: [u8; 32],
+    pub timestamp: i64,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
@@ -756,6 +5420,27 @@ pub enum Outcome {
     No,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum TradeSide {
+    Buy,
+    Sell,
+}
+
+/// One trade of a `batch_trade`. A buy spends `amount` of collateral, fee
+/// included, for at least `limit` shares; a sell returns `amount` shares for
+/// at least `limit` of collateral after the fee.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TradeLeg {
+    pub outcome: Outcome,
+    pub side: TradeSide,
+    pub amount: u64,
+    pub limit: u64,
+    /// Bumps of the market's vault and of the user's position in it, so the
+    /// program only has to check the addresses rather than search for them.
+    pub vault_bump: u8,
+    pub position_bump: u8,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
 pub enum MarketStatus {
     Active,
@@ -763,6 +5448,13 @@ pub enum MarketStatus {
     Invalid,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccessPolicy {
+    Open,
+    Allowlist,
+    Attestation,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
 pub enum MarketCategory {
     Sports,
@@ -776,74 +5468,628 @@ pub enum MarketCategory {
 
 // helper functions
 
+/// Creates the program-owned account at the PDA signed for by `seeds`,
+/// paying rent from `payer`. Like Anchor's `init`, it tops up, allocates and
+/// assigns an address that already holds lamports, so nobody can block the
+/// account by sending to it first.
+fn create_pda_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    space: usize,
+    seeds: &[&[u8]],
+) -> Result<()> {
+    let rent = Rent::get()?.minimum_balance(space);
+    let balance = account.lamports();
+    if balance == 0 {
+        return system_program::create_account(
+            CpiContext::new_with_signer(
+                system_program.clone(),
+                system_program::CreateAccount {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+                &[seeds],
+            ),
+            rent,
+            space as u64,
+            &crate::ID,
+        );
+    }
+    if rent > balance {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                system_program::Transfer {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+            ),
+            rent - balance,
+        )?;
+    }
+    system_program::allocate(
+        CpiContext::new_with_signer(
+            system_program.clone(),
+            system_program::Allocate {
+                account_to_allocate: account.clone(),
+            },
+            &[seeds],
+        ),
+        space as u64,
+    )?;
+    system_program::assign(
+        CpiContext::new_with_signer(
+            system_program.clone(),
+            system_program::Assign {
+                account_to_assign: account.clone(),
+            },
+            &[seeds],
+        ),
+        &crate::ID,
+    )
+}
+
+/// Grows `account` to `len` bytes, zero-filling the new space and topping up
+/// its lamports from `payer` to stay rent exempt. Never shrinks.
+fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    len: usize,
+) -> Result<()> {
+    if account.data_len() >= len {
+        return Ok(());
+    }
+    let shortfall = Rent::get()?
+        .minimum_balance(len)
+        .saturating_sub(account.lamports());
+    if shortfall > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                system_program::Transfer {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+            ),
+            shortfall,
+        )?;
+    }
+    account.resize(len)?;
+    Ok(())
+}
+
+/// Reads an account that may predate the current layout. Older layouts are
+/// prefixes of the current one, so growing the account to `len` zero-fills
+/// whatever was added since and leaves `version` at the account's own.
+fn load_for_migration<'info, T: AccountDeserialize + Discriminator>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    len: usize,
+) -> Result<T> {
+    require!(
+        account.try_borrow_data()?.starts_with(T::DISCRIMINATOR),
+        anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch
+    );
+    grow_account(account, payer, system_program, len)?;
+    T::try_deserialize(&mut &account.try_borrow_data()?[..])
+}
+
+fn validate_circuit_breaker(window: i64, max_move_bps: u16, cooldown: i64) -> Result<()> {
+    require!(
+        window >= 0 && cooldown >= 0 && max_move_bps <= 10_000,
+        ErrorCode::InvalidCircuitBreaker
+    );
+    Ok(())
+}
+
+/// Whether `leaf` is in the merkle tree with `root`. Pairs are hashed in
+/// sorted order, so proofs carry no left/right flags.
+fn verify_merkle_proof(proof: &[[u8; 32]], root: &[u8; 32], leaf: [u8; 32]) -> bool {
+    let node = proof.iter().fold(leaf, |node, sibling| {
+        if node <= *sibling {
+            hashv(&[&node, sibling]).to_bytes()
+        } else {
+            hashv(&[sibling, &node]).to_bytes()
+        }
+    });
+    node == *root
+}
+
+/// Text, schedule and seed of a new market, given to `create_market` or
+/// rendered from a series template.
+struct MarketTerms {
+    question: String,
+    description: String,
+    category: MarketCategory,
+    oracle_source: String,
+    metadata_uri: String,
+    metadata_hash: [u8; 32],
+    end_timestamp: i64,
+    resolution_timestamp: i64,
+    initial_liquidity: u64,
+}
+
+impl MarketTerms {
+    fn validate(&self, now: i64, collateral: &Collateral) -> Result<()> {
+        require!(collateral.enabled, ErrorCode::CollateralNotAllowed);
+        validate_metadata(
+            &self.question,
+            &self.description,
+            &self.oracle_source,
+            &self.metadata_uri,
+            &self.metadata_hash,
+        )?;
+        require!(
+            self.initial_liquidity >= collateral.min_liquidity,
+            ErrorCode::InsufficientInitialLiquidity
+        );
+        require!(self.end_timestamp > now, ErrorCode::InvalidEndTime);
+        require!(
+            self.resolution_timestamp > self.end_timestamp,
+            ErrorCode::InvalidResolutionTime
+        );
+        require!(
+            self.resolution_timestamp <= self.end_timestamp + 604800,
+            ErrorCode::ResolutionTooLate
+        );
+        Ok(())
+    }
+
+    /// Writes the terms into a new market's accounts under the next market
+    /// id. The bond, caps and circuit breaker are the protocol's current
+    /// defaults; bumps and the creator are left to the caller.
+    fn write(
+        self,
+        market: &mut Account<Market>,
+        state: &mut MarketState,
+        price_history: &mut PriceHistory,
+        protocol_state: &ProtocolState,
+        now: i64,
+    ) -> Result<()> {
+        let market_id = protocol_state.total_markets;
+        market.market_id = market_id;
+        market.question = self.question;
+        market.description = self.description;
+        market.category = self.category;
+        market.oracle_source = self.oracle_source;
+        market.metadata_uri = self.metadata_uri;
+        market.metadata_hash = self.metadata_hash;
+        market.version = Market::VERSION;
+
+        let seed = self.initial_liquidity / 2;
+        state.market = market.key();
+        state.market_id = market_id;
+        state.created_at = now;
+        state.end_timestamp = self.end_timestamp;
+        state.resolution_timestamp = self.resolution_timestamp;
+        state.set_status(MarketStatus::Active);
+        state.yes_liquidity = seed;
+        state.no_liquidity = seed;
+        state.total_yes_shares = seed;
+        state.total_no_shares = seed;
+        state.initial_liquidity = self.initial_liquidity;
+        state.creation_bond = protocol_state.creation_bond;
+        state.max_open_interest = protocol_state.default_max_open_interest;
+        state.max_position_shares = protocol_state.default_max_position_shares;
+        state.breaker_window = protocol_state.default_breaker_window;
+        state.breaker_max_move_bps = protocol_state.default_breaker_max_move_bps;
+        state.breaker_cooldown = protocol_state.default_breaker_cooldown;
+        state.version = MarketState::VERSION;
+
+        price_history.market = market.key();
+        price_history.last_update = now;
+        price_history.last_yes_price = PRICE_SCALE / 2;
+        price_history.record(now, PRICE_SCALE / 2, 0)
+    }
+}
+
+/// `YYYY-MM-DD` of a unix timestamp in UTC, by Howard Hinnant's
+/// days-to-civil algorithm.
+fn format_date(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// A market needs a question on-chain or a metadata document to point at,
+/// and the hash is set exactly when the URI is.
+fn validate_metadata(
+    question: &str,
+    description: &str,
+    oracle_source: &str,
+    metadata_uri: &str,
+    metadata_hash: &[u8; 32],
+) -> Result<()> {
+    require!(question.len() <= 200, ErrorCode::QuestionTooLong);
+    require!(description.len() <= 1000, ErrorCode::DescriptionTooLong);
+    require!(oracle_source.len() <= 100, ErrorCode::OracleSourceTooLong);
+    require!(metadata_uri.len() <= 200, ErrorCode::MetadataUriTooLong);
+    require!(
+        !question.is_empty() || !metadata_uri.is_empty(),
+        ErrorCode::MissingMarketText
+    );
+    require!(
+        metadata_uri.is_empty() == (*metadata_hash == [0; 32]),
+        ErrorCode::InvalidMetadataHash
+    );
+    Ok(())
+}
+
+fn yes_price(yes_liquidity: u64, no_liquidity: u64) -> Result<u64> {
+    let total = (yes_liquidity as u128) + (no_liquidity as u128);
+    require!(total > 0, ErrorCode::MathOverflow);
+    Ok(((no_liquidity as u128) * (PRICE_SCALE as u128) / total) as u64)
+}
+
+/// What `stake` returns if every leg wins: the stake compounded at the fair
+/// odds of each leg's price.
+fn calculate_parlay_payout(stake: u64, prices: impl IntoIterator<Item = u64>) -> Result<u64> {
+    let mut payout = stake as u128;
+    for price in prices {
+        payout = payout
+            .checked_mul(PRICE_SCALE as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / price as u128;
+    }
+    Ok(payout.try_into().map_err(|_| ErrorCode::MathOverflow)?)
+}
+
+/// Rejects Token-2022 mints with extensions outside `SUPPORTED_MINT_EXTENSIONS`.
+fn check_mint_extensions(mint: &InterfaceAccount<Mint>) -> Result<()> {
+    let info = mint.to_account_info();
+    let data = info.try_borrow_data()?;
+    let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    for extension in state.get_extension_types()? {
+        require!(
+            SUPPORTED_MINT_EXTENSIONS.contains(&extension),
+            ErrorCode::UnsupportedMintExtension
+        );
+    }
+    Ok(())
+}
+
+/// Moves `amount` of `mint` between token accounts, signing with `signer`
+/// seeds when `authority` is a PDA. Zero amounts are skipped.
+fn transfer_collateral<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    token_interface::transfer_checked(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            TransferChecked {
+                from: from.clone(),
+                mint: mint.to_account_info(),
+                to: to.clone(),
+                authority: authority.clone(),
+            },
+            signer,
+        ),
+        amount,
+        mint.decimals,
+    )
+}
+
+/// Accounts a fill's collateral moves between. A buy is paid into `vault`
+/// from `trader`, a sell out of `vault` to `trader`, and whichever pays also
+/// pays the fee and any tip; `authority` signs for it, with `signer` seeds
+/// when it is a PDA.
+struct Settlement<'a, 'info> {
+    token_program: &'a Interface<'info, TokenInterface>,
+    mint: &'a InterfaceAccount<'info, Mint>,
+    vault: &'a AccountInfo<'info>,
+    trader: &'a AccountInfo<'info>,
+    authority: &'a AccountInfo<'info>,
+    signer: &'a [&'a [&'a [u8]]],
+    /// Receives the fee, or `None` when the caller collects fees itself and
+    /// the trade moves them with its collateral.
+    treasury: Option<&'a AccountInfo<'info>>,
+    /// Keeper's token account and the tip paid to it out of the trade.
+    keeper: Option<(&'a AccountInfo<'info>, u64)>,
+}
+
+/// Settles a quoted fill unless it trips the circuit breaker: moves its
+/// collateral, fee and tip, applies it to the pool, records it in the price
+/// history and the position, books buy volume and emits the trade. Returns
+/// `false`, having settled nothing, if the breaker halted the market. Every
+/// trading instruction settles through here.
+fn settle_trade(
+    settlement: &Settlement,
+    fill: &Fill,
+    market: &mut MarketState,
+    price_history: &mut PriceHistory,
+    position: &mut UserPosition,
+    protocol_state: &mut ProtocolState,
+    now: i64,
+) -> Result<bool> {
+    if market.trip_circuit_breaker(now, fill.yes_price()?)? {
+        return Ok(false);
+    }
+
+    let Settlement {
+        token_program,
+        mint,
+        vault,
+        trader,
+        authority,
+        signer,
+        treasury,
+        keeper,
+    } = *settlement;
+    let fee = treasury.map_or(0, |_| fill.fee);
+    let tip = keeper.map_or(0, |(_, tip)| tip);
+    let payer = match fill.side {
+        TradeSide::Buy => {
+            let deposit = buy_deposit(mint, fill)?;
+            transfer_collateral(
+                token_program,
+                mint,
+                trader,
+                vault,
+                authority,
+                signer,
+                deposit,
+            )?;
+            trader
+        }
+        TradeSide::Sell => {
+            let proceeds = fill
+                .collateral
+                .checked_sub(fee)
+                .and_then(|proceeds| proceeds.checked_sub(tip))
+                .ok_or(ErrorCode::SlippageExceeded)?;
+            transfer_collateral(
+                token_program,
+                mint,
+                vault,
+                trader,
+                authority,
+                signer,
+                proceeds,
+            )?;
+            vault
+        }
+    };
+    if let Some(treasury) = treasury {
+        transfer_collateral(token_program, mint, payer, treasury, authority, signer, fee)?;
+    }
+    if let Some((keeper, tip)) = keeper {
+        transfer_collateral(token_program, mint, payer, keeper, authority, signer, tip)?;
+    }
+
+    market.apply(fill)?;
+    price_history.record(
+        now,
+        yes_price(market.yes_liquidity, market.no_liquidity)?,
+        fill.collateral,
+    )?;
+    let shares = match fill.outcome {
+        Outcome::Yes => &mut position.yes_shares,
+        Outcome::No => &mut position.no_shares,
+    };
+    match fill.side {
+        TradeSide::Buy => {
+            *shares = shares
+                .checked_add(fill.shares)
+                .ok_or(ErrorCode::MathOverflow)?;
+            position.total_invested = position
+                .total_invested
+                .checked_add(fill.collateral)
+                .ok_or(ErrorCode::MathOverflow)?;
+            protocol_state.total_volume = protocol_state
+                .total_volume
+                .checked_add(fill.collateral)
+                .ok_or(ErrorCode::MathOverflow)?;
+            emit!(SharesBought {
+                market_id: market.market_id,
+                user: position.user,
+                outcome: fill.outcome,
+                shares_out: fill.shares,
+                cost: fill.collateral,
+                fee: fill.fee,
+                yes_liquidity: market.yes_liquidity,
+                no_liquidity: market.no_liquidity,
+                timestamp: now,
+            });
+        }
+        TradeSide::Sell => {
+            *shares = shares
+                .checked_sub(fill.shares)
+                .ok_or(ErrorCode::InsufficientShares)?;
+            emit!(SharesSold {
+                market_id: market.market_id,
+                user: position.user,
+                outcome: fill.outcome,
+                shares_in: fill.shares,
+                payout: fill.collateral,
+                fee: fill.fee,
+                yes_liquidity: market.yes_liquidity,
+                no_liquidity: market.no_liquidity,
+                timestamp: now,
+            });
+        }
+    }
+    Ok(true)
+}
+
+/// What a buy deposits into the vault: its collateral net of the fee, plus
+/// whatever transfer fee the mint withholds on the way in.
+fn buy_deposit(mint: &InterfaceAccount<Mint>, fill: &Fill) -> Result<u64> {
+    let minted = fill.collateral - fill.fee;
+    minted
+        .checked_add(transfer_fee_on(mint, minted)?)
+        .ok_or(error!(ErrorCode::MathOverflow))
+}
+
+/// Returns what is left in a buy order's vault to `owner_token_account` and
+/// closes the vault, refunding its rent to `owner`.
+fn close_order_vault<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    vault: &AccountInfo<'info>,
+    owner_token_account: &AccountInfo<'info>,
+    owner: &AccountInfo<'info>,
+    order: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
+) -> Result<()> {
+    let left = TokenAccount::try_deserialize(&mut &vault.try_borrow_data()?[..])?.amount;
+    transfer_collateral(
+        token_program,
+        mint,
+        vault,
+        owner_token_account,
+        order,
+        signer,
+        left,
+    )?;
+    // Token-2022 refuses to close an account holding withheld transfer fees.
+    let mint_info = mint.to_account_info();
+    if token_interface::get_mint_extension_data::<TransferFeeConfig>(&mint_info).is_ok() {
+        token_interface::harvest_withheld_tokens_to_mint(
+            CpiContext::new(
+                token_program.to_account_info(),
+                token_interface::HarvestWithheldTokensToMint {
+                    token_program_id: token_program.to_account_info(),
+                    mint: mint_info,
+                },
+            ),
+            vec![vault.clone()],
+        )?;
+    }
+    token_interface::close_account(CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        token_interface::CloseAccount {
+            account: vault.clone(),
+            destination: owner.clone(),
+            authority: order.clone(),
+        },
+        signer,
+    ))
+}
+
+/// The transfer fee `mint` withholds from a transfer that must deliver
+/// `amount`. Deposits into a vault send `amount` plus this fee, so the vault
+/// is credited exactly what the program accounts for.
+fn transfer_fee_on(mint: &InterfaceAccount<Mint>, amount: u64) -> Result<u64> {
+    let Ok(config) =
+        token_interface::get_mint_extension_data::<TransferFeeConfig>(&mint.to_account_info())
+    else {
+        return Ok(0);
+    };
+    let fee = config
+        .calculate_inverse_epoch_fee(Clock::get()?.epoch, amount)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(fee)
+}
+
+fn calculate_fee(amount: u64, free_bps: u16) -> Result<u64> {
+    let fee: u64 = ((amount as u128) * (free_bps as u128) / 10_000)
+        .try_into()
+        .map_err(|_| ErrorCode::MathOverflow)?;
+    Ok(fee)
+}
+
+/// Shares of the outcome that `max_payment`, fee included, buys, with the
+/// payment and the fee. The pool is a fixed-product market maker over
+/// complete sets: every unit of collateral that enters the vault mints one YES
+/// and one NO share, so each outstanding share is always backed by one unit in
+/// `market_vault`. Rounding always favours the pool, so
+/// `yes_liquidity * no_liquidity` never decreases.
 fn calculate_buy_shares(
     outcome_liquidity: u64,
     opposite_liquidity: u64,
     max_payment: u64,
     free_bps: u16,
 ) -> Result<(u64, u64, u64)> {
-    let fee: u64 = ((max_payment as u128)
-        .checked_mul(free_bps as u128)
-        .unwrap()
-        .checked_div(10_000)
-        .unwrap())
-    .try_into()
-    .unwrap();
-
-    let payment_after_fee = max_payment.checked_sub(fee).unwrap();
-
-    let k: u128 = (outcome_liquidity as u128)
-        .checked_mul(opposite_liquidity as u128)
-        .unwrap();
-
-    let new_outcome_liquidity: u128 = (outcome_liquidity as u128)
-        .checked_add(payment_after_fee as u128)
-        .unwrap();
+    let fee = calculate_fee(max_payment, free_bps)?;
+    let payment_after_fee = max_payment
+        .checked_sub(fee)
+        .ok_or(ErrorCode::MathOverflow)?;
 
-    let new_opposite_liquidity: u128 = k.checked_div(new_outcome_liquidity).unwrap();
+    let k: u128 = (outcome_liquidity as u128) * (opposite_liquidity as u128);
 
-    let shares_out_u128: u128 = (opposite_liquidity as u128)
-        .checked_sub(new_opposite_liquidity)
-        .unwrap();
+    // Minting `payment_after_fee` sets grows both reserves, then the outcome
+    // side is drawn down until the product is restored.
+    let new_opposite_liquidity: u128 = (opposite_liquidity as u128) + (payment_after_fee as u128);
+    require!(new_opposite_liquidity > 0, ErrorCode::MathOverflow);
+    let new_outcome_liquidity: u128 = k.div_ceil(new_opposite_liquidity);
 
-    let shares_out: u64 = shares_out_u128.try_into().unwrap();
+    let shares_out: u64 = ((outcome_liquidity as u128) + (payment_after_fee as u128))
+        .checked_sub(new_outcome_liquidity)
+        .and_then(|v| u64::try_from(v).ok())
+        .ok_or(ErrorCode::MathOverflow)?;
 
     Ok((shares_out, max_payment, fee))
 }
 
+/// Collateral paid for returning `shares_in` of the outcome, burning that many
+/// complete sets, with the fee taken from it.
 fn calculate_sell_shares(
     outcome_liquidity: u64,
     opposite_liquidity: u64,
     shares_in: u64,
     free_bps: u16,
 ) -> Result<(u64, u64)> {
-    let k: u128 = (outcome_liquidity as u128)
-        .checked_mul(opposite_liquidity as u128)
-        .unwrap();
-
-    let new_opposite_liquidity: u128 = (opposite_liquidity as u128)
-        .checked_add(shares_in as u128)
-        .unwrap();
-
-    let new_outcome_liquidity: u128 = k.checked_div(new_opposite_liquidity).unwrap();
-
-    let payout_u128: u128 = (outcome_liquidity as u128)
-        .checked_sub(new_outcome_liquidity)
-        .unwrap();
+    // Returning `shares_in` to the pool and burning `payout` sets must keep
+    // (outcome + shares_in - payout) * (opposite - payout) >= k, i.e. payout
+    // is the smaller root of payout^2 - b * payout + shares_in * opposite.
+    let b: u128 = (outcome_liquidity as u128) + (shares_in as u128) + (opposite_liquidity as u128);
+    let discriminant: u128 = b
+        .checked_mul(b)
+        .and_then(|v| v.checked_sub(4 * (shares_in as u128) * (opposite_liquidity as u128)))
+        .ok_or(ErrorCode::MathOverflow)?;
 
-    let payout: u64 = payout_u128.try_into().unwrap();
+    let payout: u64 = ((b - isqrt_ceil(discriminant)) / 2)
+        .try_into()
+        .map_err(|_| ErrorCode::MathOverflow)?;
 
-    let fee: u64 = ((payout as u128)
-        .checked_mul(free_bps as u128)
-        .unwrap()
-        .checked_div(10_000)
-        .unwrap())
-    .try_into()
-    .unwrap();
+    let fee = calculate_fee(payout, free_bps)?;
 
     Ok((payout, fee))
 }
 
+fn isqrt_ceil(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = 1u128 << (n.ilog2() / 2 + 1);
+    loop {
+        let y = (x + n / x) / 2;
+        if y >= x {
+            break;
+        }
+        x = y;
+    }
+    if x * x < n {
+        x + 1
+    } else {
+        x
+    }
+}
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("Fee cannot exceed 10%")]
@@ -917,4 +6163,446 @@ pub enum ErrorCode {
 
     #[msg("Market is not marked as invalid")]
     MarketNotInvalid,
+
+    #[msg("Math overflow")]
+    MathOverflow,
+
+    #[msg("Price history does not cover the requested time")]
+    PriceHistoryUnavailable,
+
+    #[msg("Position still holds redeemable shares")]
+    PositionNotEmpty,
+
+    #[msg("Settled market is still within its claim grace period")]
+    GracePeriodActive,
+
+    #[msg("No residual collateral to withdraw")]
+    NoResidual,
+
+    #[msg("Metadata URI too long")]
+    MetadataUriTooLong,
+
+    #[msg("Market needs a question or a metadata URI")]
+    MissingMarketText,
+
+    #[msg("Metadata hash must be set exactly when a metadata URI is")]
+    InvalidMetadataHash,
+
+    #[msg("Market metadata cannot change once trading has started")]
+    TradingStarted,
+
+    #[msg("Account is already at the current version")]
+    AlreadyMigrated,
+
+    #[msg("Purchase would exceed the market's open interest limit")]
+    OpenInterestLimitExceeded,
+
+    #[msg("Purchase would exceed the per-position share limit")]
+    PositionLimitExceeded,
+
+    #[msg("Market is restricted to attested or allowlisted users")]
+    AccessDenied,
+
+    #[msg("Attestation has expired")]
+    AttestationExpired,
+
+    #[msg("Merkle proof does not match the allowlist root")]
+    InvalidAllowlistProof,
+
+    #[msg("Trade would move the price beyond the circuit breaker band")]
+    PriceMoveTooLarge,
+
+    #[msg("Trading is halted by the circuit breaker")]
+    TradingHalted,
+
+    #[msg("Circuit breaker window and cooldown must be non-negative and the band at most 100%")]
+    InvalidCircuitBreaker,
+
+    #[msg("Parent market must be open and settle no later than the conditional market")]
+    InvalidParentMarket,
+
+    #[msg("Parent market account is missing or is not this market's parent")]
+    ParentMarketMismatch,
+
+    #[msg("Parent market has not settled yet")]
+    ParentMarketUnresolved,
+
+    #[msg("Parlay needs 2 to 8 legs on distinct open markets")]
+    InvalidParlay,
+
+    #[msg("Parlay pool cannot cover the payout")]
+    ParlayPoolInsufficient,
+
+    #[msg("Market accounts do not match the parlay's legs")]
+    ParlayLegMismatch,
+
+    #[msg("Parlay legs have not settled yet")]
+    ParlayNotSettled,
+
+    #[msg("Series duration must be positive")]
+    InvalidSeriesDuration,
+
+    #[msg("The series' latest market has not ended yet")]
+    SeriesMarketNotEnded,
+
+    #[msg("Mint is not enabled as collateral")]
+    CollateralNotAllowed,
+
+    #[msg("Collateral mint has an unsupported Token-2022 extension")]
+    UnsupportedMintExtension,
+
+    #[msg("Delegate expiry must be in the future with at most 16 markets")]
+    InvalidDelegate,
+
+    #[msg("Signer is not authorized to trade for this owner")]
+    DelegateNotAuthorized,
+
+    #[msg("Delegation has expired")]
+    DelegateExpired,
+
+    #[msg("Delegate is not allowed to trade this market")]
+    DelegateMarketNotAllowed,
+
+    #[msg("Trade exceeds the delegate's spending limit")]
+    DelegateSpendExceeded,
+
+    #[msg("A position cannot be transferred to its own holder")]
+    InvalidRecipient,
+
+    #[msg("Batch must have 1-16 legs, each with its market's accounts")]
+    InvalidBatch,
+
+    #[msg("Position has shares locked by open orders")]
+    PositionHasOpenOrders,
+
+    #[msg("Trigger price must be strictly between 0 and 1")]
+    InvalidTriggerPrice,
+
+    #[msg("Order accounts do not match the order")]
+    InvalidOrder,
+
+    #[msg("Price has not reached the order's stop or take-profit")]
+    ExitNotTriggered,
+
+    #[msg("Token account already has an allowance for another delegate")]
+    DelegateConflict,
+
+    #[msg("Parent market settled against this market's condition")]
+    ConditionFailed,
+
+    #[msg("Market has open parlays")]
+    MarketHasOpenParlays,
+
+    #[msg("Parlay legs must be resolved by the protocol or an approved oracle")]
+    UntrustedParlayOracle,
+
+    #[msg("Market is too thin to bet parlays on")]
+    ParlayLegTooThin,
+
+    #[msg("Parlay leg odds are too long")]
+    ParlayOddsTooLong,
+
+    #[msg("Account predates versioning and cannot be migrated")]
+    UnversionedAccount,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MAX_RESERVE: u64 = 1_000_000_000_000_000_000;
+
+    fn reserve() -> impl Strategy<Value = u64> {
+        1..=MAX_RESERVE
+    }
+
+    fn fee_bps() -> impl Strategy<Value = u16> {
+        0..=10_000u16
+    }
+
+    #[test]
+    fn balanced_pool_round_trip_matches_fixed_product() {
+        let (shares_out, cost, fee) = calculate_buy_shares(100, 100, 50, 0).unwrap();
+        // Minting 50 sets gives (150, 150); 67 * 150 >= 100 * 100.
+        assert_eq!((shares_out, cost, fee), (83, 50, 0));
+
+        let (payout, fee) = calculate_sell_shares(67, 150, 83, 0).unwrap();
+        assert_eq!((payout, fee), (49, 0));
+    }
+
+    #[test]
+    fn math_rejects_empty_pools_instead_of_panicking() {
+        assert!(calculate_buy_shares(0, 0, 0, 0).is_err());
+        assert!(calculate_buy_shares(1, 1, 10_000, 10_001).is_err());
+        assert!(calculate_sell_shares(u64::MAX, u64::MAX, u64::MAX, 0).is_err());
+        assert!(calculate_parlay_payout(u64::MAX, [1, 1]).is_err());
+    }
+
+    #[test]
+    fn dates_render_in_utc() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951_868_799), "2000-02-29");
+        assert_eq!(format_date(1_700_000_000), "2023-11-14");
+        assert_eq!(format_date(-1), "1969-12-31");
+    }
+
+    #[test]
+    fn parlay_payout_compounds_each_leg_and_rounds_down() {
+        assert_eq!(calculate_parlay_payout(100, []).unwrap(), 100);
+        assert_eq!(
+            calculate_parlay_payout(100, [PRICE_SCALE / 2, PRICE_SCALE / 4]).unwrap(),
+            800
+        );
+        assert_eq!(calculate_parlay_payout(10, [PRICE_SCALE / 3]).unwrap(), 30);
+    }
+
+    #[test]
+    fn removed_shares_take_their_share_of_the_investment() {
+        let mut position = UserPosition {
+            user: Pubkey::default(),
+            market: Pubkey::default(),
+            yes_shares: 30,
+            no_shares: 10,
+            total_invested: 25,
+            initialized: true,
+            opened_at: 0,
+            bump: 0,
+            version: UserPosition::VERSION,
+            locked_yes_shares: 0,
+            locked_no_shares: 0,
+            _reserved: [0; ACCOUNT_RESERVED_LEN - 16],
+        };
+        assert!(position.remove_shares(31, 0).is_err());
+        assert!(position.remove_shares(0, 0).is_err());
+        assert_eq!(position.remove_shares(10, 5).unwrap(), 9);
+        assert_eq!(
+            (
+                position.yes_shares,
+                position.no_shares,
+                position.total_invested
+            ),
+            (20, 5, 16)
+        );
+        assert_eq!(position.remove_shares(20, 5).unwrap(), 16);
+        assert_eq!(position.total_invested, 0);
+    }
+
+    fn price_history(created_at: i64) -> PriceHistory {
+        let mut history = PriceHistory {
+            market: Pubkey::default(),
+            last_update: created_at,
+            last_yes_price: PRICE_SCALE / 2,
+            cumulative_yes_price: PodU128::default(),
+            snapshots: [PriceSnapshot::default(); PRICE_HISTORY_LEN],
+            head: 0,
+            len: 0,
+            bump: 0,
+            _padding: [0; 3],
+        };
+        history.record(created_at, PRICE_SCALE / 2, 0).unwrap();
+        history
+    }
+
+    #[test]
+    fn twap_weights_each_price_by_how_long_it_held() {
+        let mut history = price_history(1_000);
+        history.record(1_100, 800_000, 10).unwrap();
+        history.record(1_400, 200_000, 10).unwrap();
+
+        // 0.5 for 100s, then 0.8 for 300s.
+        assert_eq!(history.twap(1_000, 1_400).unwrap(), 725_000);
+        // Windows may start between snapshots and end after the last one.
+        assert_eq!(history.twap(1_300, 1_500).unwrap(), 500_000);
+        assert_eq!(history.twap(1_400, 1_500).unwrap(), 200_000);
+        assert!(history.twap(1_500, 1_400).is_err());
+        assert!(history.twap(999, 1_400).is_err());
+    }
+
+    #[test]
+    fn recent_twap_falls_back_to_the_history_it_has() {
+        // A market younger than the window averages since it opened.
+        let mut history = price_history(1_000);
+        assert_eq!(history.recent_twap(1_000, 3_600).unwrap(), PRICE_SCALE / 2);
+        history.record(1_100, 800_000, 10).unwrap();
+        assert_eq!(history.recent_twap(1_400, 3_600).unwrap(), 725_000);
+
+        // A buffer filled within the window averages since its oldest
+        // snapshot, where the full window is no longer available.
+        for t in 1..=PRICE_HISTORY_LEN as i64 {
+            history.record(2_000 + t, 200_000, 1).unwrap();
+        }
+        let now = 2_000 + PRICE_HISTORY_LEN as i64;
+        assert!(history.twap(now - 3_600, now).is_err());
+        assert_eq!(history.recent_twap(now, 3_600).unwrap(), 200_000);
+    }
+
+    #[test]
+    fn price_history_overwrites_the_oldest_snapshot_when_full() {
+        let mut history = price_history(0);
+        for t in 1..=PRICE_HISTORY_LEN as i64 {
+            history.record(t * 10, t as u64, 1).unwrap();
+        }
+
+        assert_eq!(history.len as usize, PRICE_HISTORY_LEN);
+        let newest: Vec<i64> = history.snapshots().map(|s| s.timestamp).collect();
+        assert_eq!(newest.first(), Some(&(PRICE_HISTORY_LEN as i64 * 10)));
+        assert_eq!(newest.last(), Some(&10));
+        assert!(newest.windows(2).all(|pair| pair[0] > pair[1]));
+        // The opening snapshot was evicted, so windows before t=10 are gone.
+        assert!(history.cumulative_price_at(5).is_err());
+        assert_eq!(
+            history.cumulative_price_at(10).unwrap(),
+            (PRICE_SCALE / 2) as u128 * 10
+        );
+    }
+
+    #[test]
+    fn price_history_needs_only_eight_byte_alignment() {
+        assert_eq!(std::mem::align_of::<PriceHistory>(), 8);
+
+        let value = u128::MAX - 12_345;
+        assert_eq!(u128::from(PodU128::from(value)), value);
+    }
+
+    proptest! {
+        #[test]
+        fn isqrt_ceil_is_the_smallest_root_not_below(n in any::<u128>()) {
+            let root = isqrt_ceil(n);
+            prop_assert!(root.checked_mul(root).is_none_or(|sq| sq >= n));
+            if root > 0 {
+                prop_assert!((root - 1) * (root - 1) < n);
+            }
+        }
+
+        #[test]
+        fn buy_never_panics(
+            outcome in any::<u64>(),
+            opposite in any::<u64>(),
+            payment in any::<u64>(),
+            bps in any::<u16>(),
+        ) {
+            let _ = calculate_buy_shares(outcome, opposite, payment, bps);
+        }
+
+        #[test]
+        fn sell_never_panics(
+            outcome in any::<u64>(),
+            opposite in any::<u64>(),
+            shares in any::<u64>(),
+            bps in any::<u16>(),
+        ) {
+            let _ = calculate_sell_shares(outcome, opposite, shares, bps);
+        }
+
+        #[test]
+        fn buy_output_never_exceeds_reserves(
+            outcome in reserve(),
+            opposite in reserve(),
+            payment in 0..=MAX_RESERVE,
+            bps in fee_bps(),
+        ) {
+            let (shares_out, cost, fee) =
+                calculate_buy_shares(outcome, opposite, payment, bps).unwrap();
+            prop_assert_eq!(cost, payment);
+            prop_assert!(fee <= payment);
+            prop_assert!(shares_out < outcome + (payment - fee));
+        }
+
+        #[test]
+        fn sell_output_never_exceeds_reserves(
+            outcome in reserve(),
+            opposite in reserve(),
+            shares in 0..=MAX_RESERVE,
+            bps in fee_bps(),
+        ) {
+            let (payout, fee) = calculate_sell_shares(outcome, opposite, shares, bps).unwrap();
+            prop_assert!(payout < opposite);
+            prop_assert!(payout <= shares);
+            prop_assert!(fee <= payout);
+        }
+
+        #[test]
+        fn buy_never_decreases_k(
+            outcome in reserve(),
+            opposite in reserve(),
+            payment in 0..=MAX_RESERVE,
+            bps in fee_bps(),
+        ) {
+            let (shares_out, _, fee) =
+                calculate_buy_shares(outcome, opposite, payment, bps).unwrap();
+            let minted = (payment - fee) as u128;
+            let k = outcome as u128 * opposite as u128;
+            let new_outcome = outcome as u128 + minted - shares_out as u128;
+            let new_opposite = opposite as u128 + minted;
+            prop_assert!(new_outcome * new_opposite >= k);
+        }
+
+        #[test]
+        fn sell_never_decreases_k(
+            outcome in reserve(),
+            opposite in reserve(),
+            shares in 0..=MAX_RESERVE,
+            bps in fee_bps(),
+        ) {
+            let (payout, _) = calculate_sell_shares(outcome, opposite, shares, bps).unwrap();
+            let k = outcome as u128 * opposite as u128;
+            let new_outcome = outcome as u128 + shares as u128 - payout as u128;
+            let new_opposite = (opposite - payout) as u128;
+            prop_assert!(new_outcome * new_opposite >= k);
+        }
+
+        #[test]
+        fn round_trip_never_profits(
+            outcome in reserve(),
+            opposite in reserve(),
+            payment in 0..=MAX_RESERVE,
+            bps in fee_bps(),
+        ) {
+            let (shares_out, _, fee) =
+                calculate_buy_shares(outcome, opposite, payment, bps).unwrap();
+            let minted = payment - fee;
+            let (payout, sell_fee) = calculate_sell_shares(
+                outcome + minted - shares_out,
+                opposite + minted,
+                shares_out,
+                bps,
+            )
+            .unwrap();
+            prop_assert!(payout - sell_fee <= payment);
+        }
+
+        #[test]
+        fn buy_fees_are_monotone_in_bps(
+            outcome in reserve(),
+            opposite in reserve(),
+            payment in 0..=MAX_RESERVE,
+            low in fee_bps(),
+            high in fee_bps(),
+        ) {
+            let (low, high) = (low.min(high), low.max(high));
+            let (cheap_shares, _, cheap_fee) =
+                calculate_buy_shares(outcome, opposite, payment, low).unwrap();
+            let (dear_shares, _, dear_fee) =
+                calculate_buy_shares(outcome, opposite, payment, high).unwrap();
+            prop_assert!(cheap_fee <= dear_fee);
+            prop_assert!(cheap_shares >= dear_shares);
+        }
+
+        #[test]
+        fn sell_fees_are_monotone_in_bps(
+            outcome in reserve(),
+            opposite in reserve(),
+            shares in 0..=MAX_RESERVE,
+            low in fee_bps(),
+            high in fee_bps(),
+        ) {
+            let (low, high) = (low.min(high), low.max(high));
+            let (payout, cheap_fee) =
+                calculate_sell_shares(outcome, opposite, shares, low).unwrap();
+            let (_, dear_fee) = calculate_sell_shares(outcome, opposite, shares, high).unwrap();
+            prop_assert!(cheap_fee <= dear_fee);
+            prop_assert!(payout - cheap_fee >= payout - dear_fee);
+        }
+    }
 }
@@ -0,0 +1,2022 @@
+#![allow(dead_code)]
+
+pub mod sequence;
+pub mod svm;
+
+use anchor_lang::{
+    prelude::*,
+    solana_program::{hash::hashv, instruction::Instruction, sysvar},
+    system_program, InstructionData, ToAccountMetas,
+};
+use backend::{MarketCategory, Outcome};
+use spl_token_2022::extension::StateWithExtensions;
+use std::collections::HashMap;
+
+pub use svm::{Svm, TxError, TxResult};
+
+pub const USDC: u64 = 1_000_000;
+pub const DAY: i64 = 86_400;
+pub const GENESIS: i64 = 1_700_000_000;
+pub const FEE_BPS: u16 = 100;
+
+pub fn protocol_state_pda() -> Pubkey {
+    Pubkey::find_program_address(&[b"protocol-state"], &backend::ID).0
+}
+
+pub fn market_pda(market_id: u64) -> Pubkey {
+    Pubkey::find_program_address(&[b"market", market_id.to_le_bytes().as_ref()], &backend::ID).0
+}
+
+pub fn creator_profile_pda(creator: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"creator", creator.as_ref()], &backend::ID).0
+}
+
+pub fn creator_market_pda(creator: &Pubkey, index: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            b"creator_market",
+            creator.as_ref(),
+            index.to_le_bytes().as_ref(),
+        ],
+        &backend::ID,
+    )
+    .0
+}
+
+pub fn attestation_pda(issuer: &[u8; 32], user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"attestation", issuer, user.as_ref()], &backend::ID).0
+}
+
+pub fn collateral_pda(mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"collateral", mint.as_ref()], &backend::ID).0
+}
+
+pub fn trading_delegate_pda(owner: &Pubkey, delegate: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"trading_delegate", owner.as_ref(), delegate.as_ref()],
+        &backend::ID,
+    )
+    .0
+}
+
+/// The trading delegate a trade signed by `signer` for `owner` goes through.
+fn delegate_of(signer: &Pubkey, owner: &User) -> Option<Pubkey> {
+    (*signer != owner.key).then(|| trading_delegate_pda(&owner.key, signer))
+}
+
+pub fn series_pda(series_id: u64) -> Pubkey {
+    Pubkey::find_program_address(&[b"series", &series_id.to_le_bytes()], &backend::ID).0
+}
+
+pub fn parlay_pool_pda() -> Pubkey {
+    Pubkey::find_program_address(&[b"parlay_pool"], &backend::ID).0
+}
+
+pub fn parlay_vault_pda() -> Pubkey {
+    Pubkey::find_program_address(&[b"parlay_vault"], &backend::ID).0
+}
+
+pub fn parlay_oracle_pda(oracle: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"parlay_oracle", oracle.as_ref()], &backend::ID).0
+}
+
+pub fn parlay_pda(user: &Pubkey, nonce: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"parlay", user.as_ref(), &nonce.to_le_bytes()],
+        &backend::ID,
+    )
+    .0
+}
+
+pub fn limit_order_pda(market: &Pubkey, owner: &Pubkey, nonce: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            b"limit_order",
+            market.as_ref(),
+            owner.as_ref(),
+            &nonce.to_le_bytes(),
+        ],
+        &backend::ID,
+    )
+    .0
+}
+
+pub fn order_vault_pda(order: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"order_vault", order.as_ref()], &backend::ID).0
+}
+
+pub fn exit_order_pda(position: &Pubkey, nonce: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"exit_order", position.as_ref(), &nonce.to_le_bytes()],
+        &backend::ID,
+    )
+    .0
+}
+
+/// Leaf of `user` in an allowlist merkle tree.
+pub fn allowlist_leaf(user: &Pubkey) -> [u8; 32] {
+    hashv(&[user.as_ref()]).to_bytes()
+}
+
+/// Root of the tree over `leaves` and the proof of each leaf, hashing pairs
+/// in sorted order like the program does.
+pub fn merkle_tree(leaves: &[[u8; 32]]) -> ([u8; 32], Vec<Vec<[u8; 32]>>) {
+    let mut proofs = vec![Vec::new(); leaves.len()];
+    let mut level: Vec<([u8; 32], Vec<usize>)> = leaves
+        .iter()
+        .enumerate()
+        .map(|(i, leaf)| (*leaf, vec![i]))
+        .collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [(left, lefts), (right, rights)] => {
+                    lefts.iter().for_each(|&i| proofs[i].push(*right));
+                    rights.iter().for_each(|&i| proofs[i].push(*left));
+                    let (a, b) = if left <= right {
+                        (left, right)
+                    } else {
+                        (right, left)
+                    };
+                    let members = lefts.iter().chain(rights).copied().collect();
+                    (hashv(&[a, b]).to_bytes(), members)
+                }
+                [odd] => odd.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    (level[0].0, proofs)
+}
+
+pub fn vault_pda(market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"market_vault", market.as_ref()], &backend::ID).0
+}
+
+/// The bump of the program address derived from `seeds`.
+pub fn bump_of(seeds: &[&[u8]]) -> u8 {
+    Pubkey::find_program_address(seeds, &backend::ID).1
+}
+
+pub fn market_state_pda(market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"market_state", market.as_ref()], &backend::ID).0
+}
+
+pub fn price_history_pda(market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"price_history", market.as_ref()], &backend::ID).0
+}
+
+pub fn position_pda(market: &Pubkey, user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"position", market.as_ref(), user.as_ref()], &backend::ID).0
+}
+
+/// The error a failed transaction reports for a program `ErrorCode`.
+pub fn program_error(code: backend::ErrorCode) -> TxError {
+    TxError::Program(ProgramError::Custom(code.into()))
+}
+
+pub fn anchor_error(code: anchor_lang::error::ErrorCode) -> TxError {
+    TxError::Program(ProgramError::Custom(code.into()))
+}
+
+/// How a market is settled once trading ends.
+#[derive(Clone, Copy, Debug)]
+pub enum Settlement {
+    Resolve(Outcome),
+    Invalidate,
+}
+
+/// A wallet holding SOL for rent and a USDC token account.
+#[derive(Clone, Copy, Debug)]
+pub struct User {
+    pub key: Pubkey,
+    pub token: Pubkey,
+}
+
+/// Arguments of `place_limit_order`.
+#[derive(Clone, Copy, Debug)]
+pub struct OrderArgs {
+    pub nonce: u64,
+    pub side: backend::TradeSide,
+    pub outcome: Outcome,
+    pub amount: u64,
+    pub trigger_price: u64,
+    pub limit: u64,
+    pub tip: u64,
+}
+
+impl OrderArgs {
+    /// Spend `amount` on `outcome` once it is priced at `trigger_price` or less.
+    pub fn buy(outcome: Outcome, amount: u64, trigger_price: u64) -> Self {
+        Self {
+            nonce: 0,
+            side: backend::TradeSide::Buy,
+            outcome,
+            amount,
+            trigger_price,
+            limit: 0,
+            tip: 0,
+        }
+    }
+
+    /// Sell `amount` shares of `outcome` once it is priced at `trigger_price`
+    /// or more.
+    pub fn sell(outcome: Outcome, amount: u64, trigger_price: u64) -> Self {
+        Self {
+            side: backend::TradeSide::Sell,
+            ..Self::buy(outcome, amount, trigger_price)
+        }
+    }
+}
+
+/// Arguments of `place_exit_order`.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitArgs {
+    pub nonce: u64,
+    pub outcome: Outcome,
+    pub shares: u64,
+    pub stop_price: u64,
+    pub take_profit_price: u64,
+    pub min_payout: u64,
+    pub tip: u64,
+}
+
+impl ExitArgs {
+    /// Sell `shares` of `outcome` once it is priced at `stop_price` or less.
+    pub fn stop_loss(outcome: Outcome, shares: u64, stop_price: u64) -> Self {
+        Self {
+            nonce: 0,
+            outcome,
+            shares,
+            stop_price,
+            take_profit_price: 0,
+            min_payout: 0,
+            tip: 0,
+        }
+    }
+
+    /// Sell `shares` of `outcome` once it is priced at `take_profit_price`
+    /// or more.
+    pub fn take_profit(outcome: Outcome, shares: u64, take_profit_price: u64) -> Self {
+        Self {
+            stop_price: 0,
+            take_profit_price,
+            ..Self::stop_loss(outcome, shares, 0)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MarketArgs {
+    /// The id the program is expected to assign, used to derive addresses.
+    pub market_id: u64,
+    pub question: String,
+    pub description: String,
+    pub category: MarketCategory,
+    pub end_timestamp: i64,
+    pub resolution_timestamp: i64,
+    pub oracle_source: String,
+    pub initial_liquidity: u64,
+    pub metadata_uri: String,
+    pub metadata_hash: [u8; 32],
+    /// Also file the market under the creator's namespace.
+    pub namespaced: bool,
+    /// Make the market conditional on another market resolving this way.
+    pub parent: Option<(u64, Outcome)>,
+    /// Collateral mint, if not USDC.
+    pub mint: Option<Pubkey>,
+}
+
+impl MarketArgs {
+    /// A market ending in one day and resolvable for one day after that.
+    pub fn new(market_id: u64, now: i64) -> Self {
+        Self {
+            market_id,
+            question: "Will BTC close above $100k today?".to_string(),
+            description: "Resolves YES if the daily close is above $100,000.".to_string(),
+            category: MarketCategory::Crypto,
+            end_timestamp: now + DAY,
+            resolution_timestamp: now + 2 * DAY,
+            oracle_source: "binance:BTCUSDT".to_string(),
+            initial_liquidity: 100 * USDC,
+            metadata_uri: String::new(),
+            metadata_hash: [0; 32],
+            namespaced: false,
+            parent: None,
+            mint: None,
+        }
+    }
+
+    pub fn namespaced(mut self) -> Self {
+        self.namespaced = true;
+        self
+    }
+
+    /// The same market denominated in `mint`; the creator's account must be
+    /// of that mint.
+    pub fn in_mint(mut self, mint: Pubkey) -> Self {
+        self.mint = Some(mint);
+        self
+    }
+
+    pub fn conditional_on(mut self, parent_id: u64, outcome: Outcome) -> Self {
+        self.parent = Some((parent_id, outcome));
+        self
+    }
+
+    /// The same market with its text replaced by a pointer to a hosted document.
+    pub fn off_chain(mut self, uri: &str, hash: [u8; 32]) -> Self {
+        self.question.clear();
+        self.description.clear();
+        self.oracle_source.clear();
+        self.metadata_uri = uri.to_string();
+        self.metadata_hash = hash;
+        self
+    }
+}
+
+/// Template of a daily market series, its first market ending in one day.
+pub struct SeriesArgs {
+    pub category: MarketCategory,
+    pub question: String,
+    pub description: String,
+    pub oracle_source: String,
+    pub duration: i64,
+    pub resolution_window: i64,
+    pub initial_liquidity: u64,
+    pub first_end_timestamp: i64,
+}
+
+impl SeriesArgs {
+    pub fn daily(now: i64) -> Self {
+        Self {
+            category: MarketCategory::Crypto,
+            question: "Will BTC close above $100k on {date}?".to_string(),
+            description: "Day {seq}: resolves YES if the daily close is above $100,000."
+                .to_string(),
+            oracle_source: "binance:BTCUSDT".to_string(),
+            duration: DAY,
+            resolution_window: DAY,
+            initial_liquidity: 100 * USDC,
+            first_end_timestamp: now + DAY,
+        }
+    }
+}
+
+/// A deployed `kalshi` program with a USDC mint and protocol treasury.
+pub struct Kalshi {
+    pub svm: Svm,
+    pub authority: Pubkey,
+    pub mint: Pubkey,
+    pub treasury: Pubkey,
+    /// Token account of every wallet made by `user` or `wallet`, by owner
+    /// and mint.
+    wallets: HashMap<(Pubkey, Pubkey), Pubkey>,
+}
+
+impl Kalshi {
+    /// A fresh deployment whose protocol has not been initialized yet.
+    pub fn uninitialized() -> Self {
+        let mut svm = Svm::new();
+        svm.set_unix_timestamp(GENESIS);
+        let authority = Pubkey::new_unique();
+        svm.airdrop(&authority, 10_000_000_000);
+        let mint = svm.create_mint(&authority, 6);
+        let treasury = svm.create_token_account(&mint, &authority, 0);
+        Self {
+            svm,
+            authority,
+            mint,
+            treasury,
+            wallets: HashMap::new(),
+        }
+    }
+
+    /// A deployment with the protocol initialized at `FEE_BPS` and USDC
+    /// registered as collateral.
+    pub fn new() -> Self {
+        let mut kalshi = Self::uninitialized();
+        kalshi.initialize_protocol(FEE_BPS).unwrap();
+        let (authority, mint, treasury) = (kalshi.authority, kalshi.mint, kalshi.treasury);
+        kalshi
+            .set_collateral(&authority, &mint, &treasury, USDC, true)
+            .unwrap();
+        kalshi
+    }
+
+    pub fn now(&self) -> i64 {
+        self.svm.clock().unix_timestamp
+    }
+
+    pub fn user(&mut self, usdc: u64) -> User {
+        let key = Pubkey::new_unique();
+        self.svm.airdrop(&key, 10_000_000_000);
+        let token = self.svm.create_token_account(&self.mint, &key, usdc);
+        self.wallets.insert((key, self.mint), token);
+        User { key, token }
+    }
+
+    /// `user` paying from a new account of `mint` holding `amount`.
+    pub fn wallet(&mut self, user: &User, mint: &Pubkey, amount: u64) -> User {
+        let token = self.svm.create_token_account(mint, &user.key, amount);
+        self.wallets.insert((user.key, *mint), token);
+        User {
+            key: user.key,
+            token,
+        }
+    }
+
+    pub fn balance(&self, token: &Pubkey) -> u64 {
+        self.svm.token_balance(token)
+    }
+
+    /// Creator of the market, if it exists and is readable.
+    fn creator_of(&self, market_id: u64) -> Option<Pubkey> {
+        let account = self.svm.get_account(&market_pda(market_id))?;
+        let market = backend::Market::try_deserialize(&mut &account.data[..]).ok()?;
+        Some(market.authority)
+    }
+
+    /// The user's attestation for a gated market, if they hold one.
+    fn attestation_for(&self, market_id: u64, user: &User) -> Option<Pubkey> {
+        let state = self
+            .svm
+            .get_account(&market_state_pda(&market_pda(market_id)))?;
+        let state = backend::MarketState::read(&state.data).ok()?;
+        if state.access_policy() == backend::AccessPolicy::Open {
+            return None;
+        }
+        let key = attestation_pda(&state.access_key, &user.key);
+        self.svm.get_account(&key).map(|_| key)
+    }
+
+    /// State of the market a conditional market depends on.
+    fn parent_state_of(&self, market_id: u64) -> Option<Pubkey> {
+        let state = self
+            .svm
+            .get_account(&market_state_pda(&market_pda(market_id)))?;
+        let state = backend::MarketState::read(&state.data).ok()?;
+        state.parent_outcome()?;
+        Some(market_state_pda(&market_pda(state.parent_market_id)))
+    }
+
+    /// Collateral mint of the market, or USDC if it has no vault.
+    fn mint_of(&self, market_id: u64) -> Pubkey {
+        self.svm
+            .get_account(&vault_pda(&market_pda(market_id)))
+            .and_then(|vault| {
+                StateWithExtensions::<spl_token_2022::state::Account>::unpack(&vault.data)
+                    .map(|vault| vault.base.mint)
+                    .ok()
+            })
+            .unwrap_or(self.mint)
+    }
+
+    /// The token program that owns `mint`.
+    pub fn token_program_of(&self, mint: &Pubkey) -> Pubkey {
+        self.svm
+            .get_account(mint)
+            .map_or(anchor_spl::token::ID, |account| account.owner)
+    }
+
+    /// Treasury registered for `mint`, or the USDC treasury if there is none.
+    fn treasury_for(&self, mint: &Pubkey) -> Pubkey {
+        self.svm
+            .get_account(&collateral_pda(mint))
+            .and_then(|account| backend::Collateral::try_deserialize(&mut &account.data[..]).ok())
+            .map_or(self.treasury, |collateral| collateral.treasury)
+    }
+
+    /// The market creator's account of its mint, or the treasury if there
+    /// is none.
+    fn creator_token(&self, market_id: u64) -> Pubkey {
+        let mint = self.mint_of(market_id);
+        self.creator_of(market_id)
+            .and_then(|creator| self.wallets.get(&(creator, mint)).copied())
+            .unwrap_or_else(|| self.treasury_for(&mint))
+    }
+
+    pub fn vault_balance(&self, market_id: u64) -> u64 {
+        self.svm.token_balance(&vault_pda(&market_pda(market_id)))
+    }
+
+    pub fn protocol(&self) -> backend::ProtocolState {
+        self.svm.account(&protocol_state_pda())
+    }
+
+    pub fn market(&self, market_id: u64) -> backend::Market {
+        self.svm.account(&market_pda(market_id))
+    }
+
+    pub fn market_state(&self, market_id: u64) -> backend::MarketState {
+        let key = market_state_pda(&market_pda(market_id));
+        let account = self.svm.get_account(&key).expect("market state");
+        backend::MarketState::read(&account.data).unwrap()
+    }
+
+    pub fn position(&self, market_id: u64, user: &User) -> backend::UserPosition {
+        self.svm
+            .account(&position_pda(&market_pda(market_id), &user.key))
+    }
+
+    /// Drains the logs and decodes every `T` event emitted since the last call.
+    pub fn events<T: anchor_lang::Event>(&mut self) -> Vec<T> {
+        use anchor_lang::__private::base64::{engine::general_purpose::STANDARD, Engine};
+        self.svm
+            .take_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("Program data: "))
+            .filter_map(|data| STANDARD.decode(data).ok())
+            .filter(|data| data.starts_with(T::DISCRIMINATOR))
+            .map(|data| T::deserialize(&mut &data[T::DISCRIMINATOR.len()..]).unwrap())
+            .collect()
+    }
+
+    pub fn price_history(&self, market_id: u64) -> backend::PriceHistory {
+        use anchor_lang::__private::bytemuck;
+        let key = price_history_pda(&market_pda(market_id));
+        let account = self.svm.get_account(&key).expect("price history");
+        let body = account
+            .data
+            .strip_prefix(backend::PriceHistory::DISCRIMINATOR)
+            .expect("price history discriminator");
+        bytemuck::pod_read_unaligned(&body[..std::mem::size_of::<backend::PriceHistory>()])
+    }
+
+    /// The user's `(yes, no)` shares, or nothing if they never traded or the
+    /// market is closed.
+    pub fn shares(&self, market_id: u64, user: &User) -> (u64, u64) {
+        let key = position_pda(&market_pda(market_id), &user.key);
+        if self.svm.get_account(&key).is_none() {
+            return (0, 0);
+        }
+        let position = self.position(market_id, user);
+        let market = market_pda(market_id);
+        if self.svm.get_account(&market).is_none() {
+            return (0, 0);
+        }
+        (position.yes_shares, position.no_shares)
+    }
+
+    /// What the vault owes `users` if the market settles as `settlement`, or
+    /// the worst case over every settlement while it is still undecided.
+    pub fn owed(&self, market_id: u64, users: &[User], settlement: Option<Settlement>) -> u64 {
+        let held: Vec<(u64, u64)> = users
+            .iter()
+            .map(|user| self.shares(market_id, user))
+            .collect();
+        let yes: u64 = held.iter().map(|(yes, _)| yes).sum();
+        let no: u64 = held.iter().map(|(_, no)| no).sum();
+        let refunds: u64 = held.iter().map(|(yes, no)| (yes + no) / 2).sum();
+        match settlement {
+            None => yes.max(no).max(refunds),
+            Some(Settlement::Resolve(Outcome::Yes)) => yes,
+            Some(Settlement::Resolve(Outcome::No)) => no,
+            Some(Settlement::Invalidate) => refunds,
+        }
+    }
+
+    pub fn initialize_protocol_ix(&self, free_bps: u16) -> Instruction {
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::InitializeProtocol {
+                protocol_state: protocol_state_pda(),
+                authority: self.authority,
+                treasury: self.treasury,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::InitializeProtocol { free_bps }.data(),
+        }
+    }
+
+    pub fn initialize_protocol(&mut self, free_bps: u16) -> TxResult {
+        let ix = self.initialize_protocol_ix(free_bps);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn set_collateral(
+        &mut self,
+        authority: &Pubkey,
+        mint: &Pubkey,
+        treasury: &Pubkey,
+        min_liquidity: u64,
+        enabled: bool,
+    ) -> TxResult {
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::SetCollateral {
+                collateral: collateral_pda(mint),
+                mint: *mint,
+                treasury: *treasury,
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetCollateral {
+                min_liquidity,
+                enabled,
+            }
+            .data(),
+        })
+    }
+
+    pub fn set_creation_bond_ix(&self, authority: &Pubkey, creation_bond: u64) -> Instruction {
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::UpdateProtocol {
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetCreationBond { creation_bond }.data(),
+        }
+    }
+
+    pub fn set_creation_bond(&mut self, authority: &Pubkey, creation_bond: u64) -> TxResult {
+        let ix = self.set_creation_bond_ix(authority, creation_bond);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn set_default_limits(
+        &mut self,
+        authority: &Pubkey,
+        max_open_interest: u64,
+        max_position_shares: u64,
+    ) -> TxResult {
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::UpdateProtocol {
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetDefaultLimits {
+                max_open_interest,
+                max_position_shares,
+            }
+            .data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn set_default_circuit_breaker(
+        &mut self,
+        authority: &Pubkey,
+        window: i64,
+        max_move_bps: u16,
+        cooldown: i64,
+    ) -> TxResult {
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::UpdateProtocol {
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetDefaultCircuitBreaker {
+                window,
+                max_move_bps,
+                cooldown,
+            }
+            .data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn set_circuit_breaker(
+        &mut self,
+        authority: &Pubkey,
+        market_id: u64,
+        window: i64,
+        max_move_bps: u16,
+        cooldown: i64,
+    ) -> TxResult {
+        let market = market_pda(market_id);
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::SetMarketLimits {
+                market,
+                market_state: market_state_pda(&market),
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetCircuitBreaker {
+                window,
+                max_move_bps,
+                cooldown,
+            }
+            .data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn set_access_policy(
+        &mut self,
+        authority: &Pubkey,
+        market_id: u64,
+        policy: backend::AccessPolicy,
+        access_key: [u8; 32],
+    ) -> TxResult {
+        let market = market_pda(market_id);
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::SetAccessPolicy {
+                market,
+                market_state: market_state_pda(&market),
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetAccessPolicy { policy, access_key }.data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn issue_attestation(&mut self, issuer: &Pubkey, user: &User, expires_at: i64) -> TxResult {
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::IssueAttestation {
+                attestation: attestation_pda(&issuer.to_bytes(), &user.key),
+                issuer: *issuer,
+                user: user.key,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::IssueAttestation { expires_at }.data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn revoke_attestation(&mut self, issuer: &Pubkey, user: &User) -> TxResult {
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::RevokeAttestation {
+                attestation: attestation_pda(&issuer.to_bytes(), &user.key),
+                issuer: *issuer,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::RevokeAttestation {}.data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn trading_delegate(&self, owner: &User, delegate: &Pubkey) -> backend::TradingDelegate {
+        self.svm
+            .account(&trading_delegate_pda(&owner.key, delegate))
+    }
+
+    pub fn approve_delegate(
+        &mut self,
+        owner: &User,
+        delegate: &Pubkey,
+        max_spend: u64,
+        expires_at: i64,
+        markets: Vec<u64>,
+    ) -> TxResult {
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::ApproveDelegate {
+                trading_delegate: trading_delegate_pda(&owner.key, delegate),
+                owner: owner.key,
+                delegate: *delegate,
+                owner_token_account: owner.token,
+                token_program: self.token_program_of(&self.mint),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::ApproveDelegate {
+                max_spend,
+                expires_at,
+                markets,
+            }
+            .data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn revoke_delegate(&mut self, owner: &User, delegate: &Pubkey) -> TxResult {
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::RevokeDelegate {
+                trading_delegate: trading_delegate_pda(&owner.key, delegate),
+                owner: owner.key,
+                owner_token_account: owner.token,
+                token_program: self.token_program_of(&self.mint),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::RevokeDelegate {}.data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn prove_allowlist(
+        &mut self,
+        user: &User,
+        root: [u8; 32],
+        proof: Vec<[u8; 32]>,
+    ) -> TxResult {
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::ProveAllowlist {
+                attestation: attestation_pda(&root, &user.key),
+                user: user.key,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::ProveAllowlist { root, proof }.data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn set_market_limits(
+        &mut self,
+        authority: &Pubkey,
+        market_id: u64,
+        max_open_interest: u64,
+        max_position_shares: u64,
+    ) -> TxResult {
+        let market = market_pda(market_id);
+        let ix = Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::SetMarketLimits {
+                market,
+                market_state: market_state_pda(&market),
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetMarketLimits {
+                max_open_interest,
+                max_position_shares,
+            }
+            .data(),
+        };
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn creator_profile(&self, creator: &User) -> Option<backend::CreatorProfile> {
+        let key = creator_profile_pda(&creator.key);
+        self.svm.get_account(&key)?;
+        Some(self.svm.account(&key))
+    }
+
+    pub fn creator_market(&self, creator: &User, index: u64) -> backend::CreatorMarket {
+        self.svm.account(&creator_market_pda(&creator.key, index))
+    }
+
+    pub fn create_market_ix(&self, creator: &User, args: MarketArgs) -> Instruction {
+        let market = market_pda(args.market_id);
+        let mint = args.mint.unwrap_or(self.mint);
+        let creator_market = args.namespaced.then(|| {
+            let index = self.creator_profile(creator).map_or(0, |p| p.market_count);
+            creator_market_pda(&creator.key, index)
+        });
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::CreateMarket {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                price_history: price_history_pda(&market),
+                protocol_state: protocol_state_pda(),
+                creator_profile: creator_profile_pda(&creator.key),
+                creator_market,
+                parent_market_state: args
+                    .parent
+                    .map(|(parent_id, _)| market_state_pda(&market_pda(parent_id))),
+                creator: creator.key,
+                creator_token_account: creator.token,
+                usdc_mint: mint,
+                collateral: collateral_pda(&mint),
+                token_program: self.token_program_of(&mint),
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::CreateMarket {
+                question: args.question,
+                description: args.description,
+                category: args.category,
+                end_timestamp: args.end_timestamp,
+                resolution_timestamp: args.resolution_timestamp,
+                oracle_source: args.oracle_source,
+                initial_liquidity: args.initial_liquidity,
+                metadata_uri: args.metadata_uri,
+                metadata_hash: args.metadata_hash,
+                parent_outcome: args.parent.map(|(_, outcome)| outcome),
+            }
+            .data(),
+        }
+    }
+
+    pub fn create_market_with(&mut self, creator: &User, args: MarketArgs) -> TxResult {
+        let ix = self.create_market_ix(creator, args);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn create_market(&mut self, creator: &User, market_id: u64) -> TxResult {
+        let args = MarketArgs::new(market_id, self.now());
+        self.create_market_with(creator, args)
+    }
+
+    /// Replaces the text of market `args.market_id` with that of `args`.
+    pub fn update_metadata_ix(&self, creator: &User, args: MarketArgs) -> Instruction {
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::UpdateMetadata {
+                market: market_pda(args.market_id),
+                market_state: market_state_pda(&market_pda(args.market_id)),
+                creator: creator.key,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::UpdateMetadata {
+                question: args.question,
+                description: args.description,
+                category: args.category,
+                oracle_source: args.oracle_source,
+                metadata_uri: args.metadata_uri,
+                metadata_hash: args.metadata_hash,
+            }
+            .data(),
+        }
+    }
+
+    pub fn update_metadata(&mut self, creator: &User, args: MarketArgs) -> TxResult {
+        let ix = self.update_metadata_ix(creator, args);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn buy_ix(
+        &self,
+        user: &User,
+        market_id: u64,
+        outcome: Outcome,
+        max_cost: u64,
+    ) -> Instruction {
+        self.buy_as_ix(&user.key, user, market_id, outcome, max_cost)
+    }
+
+    /// A buy signed by `signer` for `owner`, through their trading delegate
+    /// unless `signer` is the owner.
+    pub fn buy_as_ix(
+        &self,
+        signer: &Pubkey,
+        owner: &User,
+        market_id: u64,
+        outcome: Outcome,
+        max_cost: u64,
+    ) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::BuyShares {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                price_history: price_history_pda(&market),
+                owner: owner.key,
+                user_position: position_pda(&market, &owner.key),
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(&self.mint_of(market_id)),
+                protocol_treasury: self.treasury_for(&self.mint_of(market_id)),
+                user: *signer,
+                user_token_account: owner.token,
+                attestation: self.attestation_for(market_id, owner),
+                parent_market_state: self.parent_state_of(market_id),
+                trading_delegate: delegate_of(signer, owner),
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::BuyShares { outcome, max_cost }.data(),
+        }
+    }
+
+    pub fn buy(
+        &mut self,
+        user: &User,
+        market_id: u64,
+        outcome: Outcome,
+        max_cost: u64,
+    ) -> TxResult {
+        let ix = self.buy_ix(user, market_id, outcome, max_cost);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn buy_as(
+        &mut self,
+        signer: &Pubkey,
+        owner: &User,
+        market_id: u64,
+        outcome: Outcome,
+        max_cost: u64,
+    ) -> TxResult {
+        let ix = self.buy_as_ix(signer, owner, market_id, outcome, max_cost);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn sell_ix(
+        &self,
+        user: &User,
+        market_id: u64,
+        outcome: Outcome,
+        shares_in: u64,
+        min_payout: u64,
+    ) -> Instruction {
+        self.sell_as_ix(&user.key, user, market_id, outcome, shares_in, min_payout)
+    }
+
+    /// A sale signed by `signer` for `owner`, through their trading delegate
+    /// unless `signer` is the owner.
+    pub fn sell_as_ix(
+        &self,
+        signer: &Pubkey,
+        owner: &User,
+        market_id: u64,
+        outcome: Outcome,
+        shares_in: u64,
+        min_payout: u64,
+    ) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::SellShares {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                price_history: price_history_pda(&market),
+                owner: owner.key,
+                user_position: position_pda(&market, &owner.key),
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(&self.mint_of(market_id)),
+                protocol_treasury: self.treasury_for(&self.mint_of(market_id)),
+                user: *signer,
+                user_token_account: owner.token,
+                attestation: self.attestation_for(market_id, owner),
+                parent_market_state: self.parent_state_of(market_id),
+                trading_delegate: delegate_of(signer, owner),
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SellShares {
+                outcome,
+                shares_in,
+                min_payout,
+            }
+            .data(),
+        }
+    }
+
+    pub fn sell(
+        &mut self,
+        user: &User,
+        market_id: u64,
+        outcome: Outcome,
+        shares_in: u64,
+        min_payout: u64,
+    ) -> TxResult {
+        let ix = self.sell_ix(user, market_id, outcome, shares_in, min_payout);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn sell_as(
+        &mut self,
+        signer: &Pubkey,
+        owner: &User,
+        market_id: u64,
+        outcome: Outcome,
+        shares_in: u64,
+        min_payout: u64,
+    ) -> TxResult {
+        let ix = self.sell_as_ix(signer, owner, market_id, outcome, shares_in, min_payout);
+        self.svm.process_instruction(ix)
+    }
+
+    /// A batch of trades by `user`, each paired with the market it trades.
+    pub fn batch_trade_ix(&self, user: &User, legs: &[(u64, backend::TradeLeg)]) -> Instruction {
+        let mut accounts = backend::accounts::BatchTrade {
+            protocol_state: protocol_state_pda(),
+            collateral: collateral_pda(&self.mint),
+            protocol_treasury: self.treasury,
+            user: user.key,
+            user_token_account: user.token,
+            usdc_mint: self.mint,
+            token_program: self.token_program_of(&self.mint),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None);
+        for &(market_id, _) in legs {
+            let market = market_pda(market_id);
+            accounts.extend([
+                AccountMeta::new_readonly(market, false),
+                AccountMeta::new(market_state_pda(&market), false),
+                AccountMeta::new(vault_pda(&market), false),
+                AccountMeta::new(price_history_pda(&market), false),
+                AccountMeta::new(position_pda(&market, &user.key), false),
+                AccountMeta::new_readonly(
+                    self.attestation_for(market_id, user).unwrap_or(backend::ID),
+                    false,
+                ),
+                AccountMeta::new_readonly(
+                    self.parent_state_of(market_id).unwrap_or(backend::ID),
+                    false,
+                ),
+            ]);
+        }
+        Instruction {
+            program_id: backend::ID,
+            accounts,
+            data: backend::instruction::BatchTrade {
+                legs: legs
+                    .iter()
+                    .map(|&(market_id, leg)| {
+                        let market = market_pda(market_id);
+                        backend::TradeLeg {
+                            vault_bump: bump_of(&[b"market_vault", market.as_ref()]),
+                            position_bump: bump_of(&[
+                                b"position",
+                                market.as_ref(),
+                                user.key.as_ref(),
+                            ]),
+                            ..leg
+                        }
+                    })
+                    .collect(),
+            }
+            .data(),
+        }
+    }
+
+    pub fn batch_trade(&mut self, user: &User, legs: &[(u64, backend::TradeLeg)]) -> TxResult {
+        let ix = self.batch_trade_ix(user, legs);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn place_limit_order_ix(
+        &self,
+        user: &User,
+        market_id: u64,
+        args: OrderArgs,
+    ) -> Instruction {
+        let market = market_pda(market_id);
+        let order = limit_order_pda(&market, &user.key, args.nonce);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::PlaceLimitOrder {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                limit_order: order,
+                order_vault: (args.side == backend::TradeSide::Buy)
+                    .then(|| order_vault_pda(&order)),
+                user_position: position_pda(&market, &user.key),
+                owner: user.key,
+                owner_token_account: user.token,
+                attestation: self.attestation_for(market_id, user),
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::PlaceLimitOrder {
+                nonce: args.nonce,
+                side: args.side,
+                outcome: args.outcome,
+                amount: args.amount,
+                trigger_price: args.trigger_price,
+                limit: args.limit,
+                tip: args.tip,
+            }
+            .data(),
+        }
+    }
+
+    pub fn place_limit_order(&mut self, user: &User, market_id: u64, args: OrderArgs) -> TxResult {
+        let ix = self.place_limit_order_ix(user, market_id, args);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn limit_order(&self, market_id: u64, user: &User, nonce: u64) -> backend::LimitOrder {
+        self.svm
+            .account(&limit_order_pda(&market_pda(market_id), &user.key, nonce))
+    }
+
+    /// Side of an open order, or a buy if there is none.
+    fn order_side(&self, market_id: u64, user: &User, nonce: u64) -> backend::TradeSide {
+        self.svm
+            .get_account(&limit_order_pda(&market_pda(market_id), &user.key, nonce))
+            .and_then(|account| backend::LimitOrder::try_deserialize(&mut &account.data[..]).ok())
+            .map_or(backend::TradeSide::Buy, |order| order.side)
+    }
+
+    pub fn cancel_limit_order_ix(&self, user: &User, market_id: u64, nonce: u64) -> Instruction {
+        let market = market_pda(market_id);
+        let order = limit_order_pda(&market, &user.key, nonce);
+        let buy = self.order_side(market_id, user, nonce) == backend::TradeSide::Buy;
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::CancelLimitOrder {
+                limit_order: order,
+                order_vault: buy.then(|| order_vault_pda(&order)),
+                user_position: (!buy).then(|| position_pda(&market, &user.key)),
+                owner: user.key,
+                owner_token_account: user.token,
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::CancelLimitOrder {}.data(),
+        }
+    }
+
+    pub fn cancel_limit_order(&mut self, user: &User, market_id: u64, nonce: u64) -> TxResult {
+        let ix = self.cancel_limit_order_ix(user, market_id, nonce);
+        self.svm.process_instruction(ix)
+    }
+
+    /// Cranks the orders of `market_id` placed by each user under each nonce.
+    pub fn crank_orders_ix(
+        &self,
+        cranker: &User,
+        market_id: u64,
+        orders: &[(User, u64)],
+    ) -> Instruction {
+        let market = market_pda(market_id);
+        let mint = self.mint_of(market_id);
+        let mut accounts = backend::accounts::CrankOrders {
+            market,
+            market_state: market_state_pda(&market),
+            market_vault: vault_pda(&market),
+            price_history: price_history_pda(&market),
+            protocol_state: protocol_state_pda(),
+            collateral: collateral_pda(&mint),
+            protocol_treasury: self.treasury_for(&mint),
+            parent_market_state: self.parent_state_of(market_id),
+            cranker: cranker.key,
+            cranker_token_account: cranker.token,
+            usdc_mint: mint,
+            token_program: self.token_program_of(&mint),
+        }
+        .to_account_metas(None);
+        for (owner, nonce) in orders {
+            let order = limit_order_pda(&market, &owner.key, *nonce);
+            let buy = self.order_side(market_id, owner, *nonce) == backend::TradeSide::Buy;
+            accounts.extend([
+                AccountMeta::new(order, false),
+                if buy {
+                    AccountMeta::new(order_vault_pda(&order), false)
+                } else {
+                    AccountMeta::new_readonly(backend::ID, false)
+                },
+                AccountMeta::new(position_pda(&market, &owner.key), false),
+                AccountMeta::new(owner.key, false),
+                AccountMeta::new(owner.token, false),
+                AccountMeta::new_readonly(
+                    self.attestation_for(market_id, owner)
+                        .unwrap_or(backend::ID),
+                    false,
+                ),
+            ]);
+        }
+        Instruction {
+            program_id: backend::ID,
+            accounts,
+            data: backend::instruction::CrankOrders {}.data(),
+        }
+    }
+
+    pub fn crank_orders(
+        &mut self,
+        cranker: &User,
+        market_id: u64,
+        orders: &[(User, u64)],
+    ) -> TxResult {
+        let ix = self.crank_orders_ix(cranker, market_id, orders);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn place_exit_order_ix(&self, user: &User, market_id: u64, args: ExitArgs) -> Instruction {
+        let market = market_pda(market_id);
+        let position = position_pda(&market, &user.key);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::PlaceExitOrder {
+                market,
+                market_state: market_state_pda(&market),
+                exit_order: exit_order_pda(&position, args.nonce),
+                user_position: position,
+                owner: user.key,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::PlaceExitOrder {
+                nonce: args.nonce,
+                outcome: args.outcome,
+                shares: args.shares,
+                stop_price: args.stop_price,
+                take_profit_price: args.take_profit_price,
+                min_payout: args.min_payout,
+                tip: args.tip,
+            }
+            .data(),
+        }
+    }
+
+    pub fn place_exit_order(&mut self, user: &User, market_id: u64, args: ExitArgs) -> TxResult {
+        let ix = self.place_exit_order_ix(user, market_id, args);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn exit_order(&self, market_id: u64, user: &User, nonce: u64) -> backend::ExitOrder {
+        let position = position_pda(&market_pda(market_id), &user.key);
+        self.svm.account(&exit_order_pda(&position, nonce))
+    }
+
+    pub fn execute_exit_order_ix(
+        &self,
+        keeper: &User,
+        owner: &User,
+        market_id: u64,
+        nonce: u64,
+    ) -> Instruction {
+        let market = market_pda(market_id);
+        let position = position_pda(&market, &owner.key);
+        let mint = self.mint_of(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::ExecuteExitOrder {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                price_history: price_history_pda(&market),
+                exit_order: exit_order_pda(&position, nonce),
+                user_position: position,
+                owner: owner.key,
+                owner_token_account: owner.token,
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(&mint),
+                protocol_treasury: self.treasury_for(&mint),
+                parent_market_state: self.parent_state_of(market_id),
+                keeper: keeper.key,
+                keeper_token_account: keeper.token,
+                attestation: self.attestation_for(market_id, owner),
+                usdc_mint: mint,
+                token_program: self.token_program_of(&mint),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::ExecuteExitOrder {}.data(),
+        }
+    }
+
+    pub fn execute_exit_order(
+        &mut self,
+        keeper: &User,
+        owner: &User,
+        market_id: u64,
+        nonce: u64,
+    ) -> TxResult {
+        let ix = self.execute_exit_order_ix(keeper, owner, market_id, nonce);
+        self.svm.process_instruction(ix)
+    }
+
+    /// Cancels `owner`'s exit order, signed by `signer`.
+    pub fn cancel_exit_order_ix(
+        &self,
+        signer: &Pubkey,
+        owner: &User,
+        market_id: u64,
+        nonce: u64,
+    ) -> Instruction {
+        let position = position_pda(&market_pda(market_id), &owner.key);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::CancelExitOrder {
+                exit_order: exit_order_pda(&position, nonce),
+                user_position: position,
+                owner: owner.key,
+                signer: *signer,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::CancelExitOrder {}.data(),
+        }
+    }
+
+    pub fn cancel_exit_order(
+        &mut self,
+        signer: &Pubkey,
+        owner: &User,
+        market_id: u64,
+        nonce: u64,
+    ) -> TxResult {
+        let ix = self.cancel_exit_order_ix(signer, owner, market_id, nonce);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn resolve_ix(
+        &self,
+        oracle: &Pubkey,
+        market_id: u64,
+        winning_outcome: Outcome,
+    ) -> Instruction {
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::ResolveMarket {
+                market: market_pda(market_id),
+                market_state: market_state_pda(&market_pda(market_id)),
+                market_vault: vault_pda(&market_pda(market_id)),
+                protocol_state: protocol_state_pda(),
+                creator_token_account: self.creator_token(market_id),
+                parent_market_state: self.parent_state_of(market_id),
+                oracle: *oracle,
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::ResolveMarket { winning_outcome }.data(),
+        }
+    }
+
+    pub fn resolve(
+        &mut self,
+        oracle: &Pubkey,
+        market_id: u64,
+        winning_outcome: Outcome,
+    ) -> TxResult {
+        let ix = self.resolve_ix(oracle, market_id, winning_outcome);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn claim_winnings_ix(&self, user: &User, market_id: u64) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::ClaimWinnings {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                user_position: position_pda(&market, &user.key),
+                user: user.key,
+                user_token_account: user.token,
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::ClaimWinnings {}.data(),
+        }
+    }
+
+    pub fn claim_winnings(&mut self, user: &User, market_id: u64) -> TxResult {
+        let ix = self.claim_winnings_ix(user, market_id);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn invalidate_ix(&self, authority: &Pubkey, market_id: u64) -> Instruction {
+        let creator = self.creator_of(market_id).unwrap_or_default();
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::InvalidateMarket {
+                market: market_pda(market_id),
+                market_state: market_state_pda(&market_pda(market_id)),
+                market_vault: vault_pda(&market_pda(market_id)),
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(&self.mint_of(market_id)),
+                protocol_treasury: self.treasury_for(&self.mint_of(market_id)),
+                creator_profile: creator_profile_pda(&creator),
+                authority: *authority,
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::InvalidateMarket {}.data(),
+        }
+    }
+
+    pub fn invalidate(&mut self, authority: &Pubkey, market_id: u64) -> TxResult {
+        let ix = self.invalidate_ix(authority, market_id);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn claim_refund_ix(&self, user: &User, market_id: u64) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::ClaimRefund {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                user_position: position_pda(&market, &user.key),
+                user: user.key,
+                user_token_account: user.token,
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::ClaimRefund {}.data(),
+        }
+    }
+
+    pub fn claim_refund(&mut self, user: &User, market_id: u64) -> TxResult {
+        let ix = self.claim_refund_ix(user, market_id);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn withdraw_residual_ix(&self, creator: &User, market_id: u64) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::WithdrawResidual {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                creator: creator.key,
+                creator_token_account: creator.token,
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::WithdrawResidual {}.data(),
+        }
+    }
+
+    pub fn withdraw_residual(&mut self, creator: &User, market_id: u64) -> TxResult {
+        let ix = self.withdraw_residual_ix(creator, market_id);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn close_position_ix(&self, user: &User, market_id: u64) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::ClosePosition {
+                market_state: market_state_pda(&market),
+                user_position: position_pda(&market, &user.key),
+                user: user.key,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::ClosePosition {}.data(),
+        }
+    }
+
+    pub fn close_position(&mut self, user: &User, market_id: u64) -> TxResult {
+        let ix = self.close_position_ix(user, market_id);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn transfer_position_ix(
+        &self,
+        user: &User,
+        market_id: u64,
+        recipient: &User,
+        yes_shares: u64,
+        no_shares: u64,
+    ) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::TransferPosition {
+                market,
+                market_state: market_state_pda(&market),
+                user_position: position_pda(&market, &user.key),
+                recipient_position: position_pda(&market, &recipient.key),
+                user: user.key,
+                recipient: recipient.key,
+                attestation: self.attestation_for(market_id, recipient),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::TransferPosition {
+                yes_shares,
+                no_shares,
+            }
+            .data(),
+        }
+    }
+
+    pub fn transfer_position(
+        &mut self,
+        user: &User,
+        market_id: u64,
+        recipient: &User,
+        yes_shares: u64,
+        no_shares: u64,
+    ) -> TxResult {
+        let ix = self.transfer_position_ix(user, market_id, recipient, yes_shares, no_shares);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn close_market_ix(
+        &self,
+        authority: &Pubkey,
+        market_id: u64,
+        creator: &Pubkey,
+    ) -> Instruction {
+        let market = market_pda(market_id);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::CloseMarket {
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                price_history: price_history_pda(&market),
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(&self.mint_of(market_id)),
+                protocol_treasury: self.treasury_for(&self.mint_of(market_id)),
+                creator: *creator,
+                authority: *authority,
+                usdc_mint: self.mint_of(market_id),
+                token_program: self.token_program_of(&self.mint_of(market_id)),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::CloseMarket {}.data(),
+        }
+    }
+
+    pub fn close_market(
+        &mut self,
+        authority: &Pubkey,
+        market_id: u64,
+        creator: &Pubkey,
+    ) -> TxResult {
+        let ix = self.close_market_ix(authority, market_id, creator);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn series(&self, series_id: u64) -> backend::MarketSeries {
+        self.svm.account(&series_pda(series_id))
+    }
+
+    pub fn create_series_ix(&self, creator: &User, args: SeriesArgs) -> Instruction {
+        let series_id = self.protocol().total_series;
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::CreateSeries {
+                series: series_pda(series_id),
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(&self.mint),
+                creator: creator.key,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::CreateSeries {
+                category: args.category,
+                question: args.question,
+                description: args.description,
+                oracle_source: args.oracle_source,
+                duration: args.duration,
+                resolution_window: args.resolution_window,
+                initial_liquidity: args.initial_liquidity,
+                first_end_timestamp: args.first_end_timestamp,
+            }
+            .data(),
+        }
+    }
+
+    pub fn create_series(&mut self, creator: &User, args: SeriesArgs) -> TxResult {
+        let ix = self.create_series_ix(creator, args);
+        self.svm.process_instruction(ix)
+    }
+
+    /// Lets series `series_id` draw up to `amount` from the creator's wallet.
+    pub fn approve_series(&mut self, creator: &User, series_id: u64, amount: u64) -> TxResult {
+        let mint = self.series(series_id).mint;
+        let ix = spl_token_2022::instruction::approve(
+            &self.token_program_of(&mint),
+            &creator.token,
+            &series_pda(series_id),
+            &creator.key,
+            &[],
+            amount,
+        )
+        .unwrap();
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn spawn_next_market_ix(&self, payer: &Pubkey, series_id: u64) -> Instruction {
+        let series = self.series(series_id);
+        let market = market_pda(self.protocol().total_markets);
+        Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::SpawnNextMarket {
+                series: series_pda(series_id),
+                market,
+                market_state: market_state_pda(&market),
+                market_vault: vault_pda(&market),
+                price_history: price_history_pda(&market),
+                protocol_state: protocol_state_pda(),
+                creator_profile: creator_profile_pda(&series.authority),
+                creator_token_account: self.wallets[&(series.authority, series.mint)],
+                usdc_mint: series.mint,
+                collateral: collateral_pda(&series.mint),
+                payer: *payer,
+                token_program: self.token_program_of(&series.mint),
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SpawnNextMarket {}.data(),
+        }
+    }
+
+    pub fn spawn_next_market(&mut self, payer: &Pubkey, series_id: u64) -> TxResult {
+        let ix = self.spawn_next_market_ix(payer, series_id);
+        self.svm.process_instruction(ix)
+    }
+
+    pub fn parlay(&self, user: &User, nonce: u64) -> backend::Parlay {
+        self.svm.account(&parlay_pda(&user.key, nonce))
+    }
+
+    pub fn parlay_pool(&self) -> backend::ParlayPool {
+        self.svm.account(&parlay_pool_pda())
+    }
+
+    /// Funds the parlay pool from a fresh account of `authority` holding
+    /// `amount`.
+    pub fn fund_parlay_pool(&mut self, authority: &Pubkey, amount: u64) -> TxResult {
+        self.fund_parlay_pool_in(authority, &self.mint.clone(), amount)
+    }
+
+    /// Like `fund_parlay_pool`, in `mint`.
+    pub fn fund_parlay_pool_in(
+        &mut self,
+        authority: &Pubkey,
+        mint: &Pubkey,
+        amount: u64,
+    ) -> TxResult {
+        let source = self.svm.create_token_account(mint, authority, amount);
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::FundParlayPool {
+                parlay_pool: parlay_pool_pda(),
+                parlay_vault: parlay_vault_pda(),
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(mint),
+                authority: *authority,
+                authority_token_account: source,
+                usdc_mint: *mint,
+                token_program: self.token_program_of(mint),
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::FundParlayPool { amount }.data(),
+        })
+    }
+
+    pub fn withdraw_parlay_pool(&mut self, authority: &Pubkey, amount: u64) -> TxResult {
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::WithdrawParlayPool {
+                parlay_pool: parlay_pool_pda(),
+                parlay_vault: parlay_vault_pda(),
+                protocol_state: protocol_state_pda(),
+                collateral: collateral_pda(&self.mint),
+                protocol_treasury: self.treasury,
+                authority: *authority,
+                usdc_mint: self.mint,
+                token_program: self.token_program_of(&self.mint),
+            }
+            .to_account_metas(None),
+            data: backend::instruction::WithdrawParlayPool { amount }.data(),
+        })
+    }
+
+    pub fn set_parlay_oracle(
+        &mut self,
+        authority: &Pubkey,
+        oracle: &Pubkey,
+        approved: bool,
+    ) -> TxResult {
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::SetParlayOracle {
+                parlay_oracle: parlay_oracle_pda(oracle),
+                oracle: *oracle,
+                protocol_state: protocol_state_pda(),
+                authority: *authority,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::SetParlayOracle { approved }.data(),
+        })
+    }
+
+    /// Opens a parlay on `legs`, passing each market's state, account, price
+    /// history and the approval of its creator as an oracle.
+    pub fn open_parlay_ix(
+        &self,
+        user: &User,
+        nonce: u64,
+        legs: &[(u64, Outcome)],
+        stake: u64,
+        min_payout: u64,
+    ) -> Instruction {
+        let mut accounts = backend::accounts::OpenParlay {
+            parlay: parlay_pda(&user.key, nonce),
+            parlay_pool: parlay_pool_pda(),
+            parlay_vault: parlay_vault_pda(),
+            protocol_state: protocol_state_pda(),
+            collateral: collateral_pda(&self.mint),
+            protocol_treasury: self.treasury,
+            user: user.key,
+            user_token_account: user.token,
+            usdc_mint: self.mint,
+            token_program: self.token_program_of(&self.mint),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None);
+        for &(market_id, _) in legs {
+            let market = market_pda(market_id);
+            let creator = self.market(market_id).authority;
+            let approval = if creator == self.authority {
+                backend::ID
+            } else {
+                parlay_oracle_pda(&creator)
+            };
+            accounts.extend([
+                AccountMeta::new(market_state_pda(&market), false),
+                AccountMeta::new_readonly(market, false),
+                AccountMeta::new_readonly(price_history_pda(&market), false),
+                AccountMeta::new_readonly(approval, false),
+            ]);
+        }
+        Instruction {
+            program_id: backend::ID,
+            accounts,
+            data: backend::instruction::OpenParlay {
+                nonce,
+                outcomes: legs.iter().map(|&(_, outcome)| outcome).collect(),
+                stake,
+                min_payout,
+            }
+            .data(),
+        }
+    }
+
+    pub fn open_parlay(
+        &mut self,
+        user: &User,
+        nonce: u64,
+        legs: &[(u64, Outcome)],
+        stake: u64,
+        min_payout: u64,
+    ) -> TxResult {
+        let ix = self.open_parlay_ix(user, nonce, legs, stake, min_payout);
+        self.svm.process_instruction(ix)
+    }
+
+    /// Settles the user's parlay, passing its legs' markets in order.
+    pub fn settle_parlay(&mut self, user: &User, nonce: u64) -> TxResult {
+        let mut accounts = backend::accounts::SettleParlay {
+            parlay: parlay_pda(&user.key, nonce),
+            parlay_pool: parlay_pool_pda(),
+            parlay_vault: parlay_vault_pda(),
+            user: user.key,
+            user_token_account: user.token,
+            usdc_mint: self.mint,
+            token_program: self.token_program_of(&self.mint),
+        }
+        .to_account_metas(None);
+        accounts.extend(
+            self.parlay(user, nonce)
+                .legs
+                .iter()
+                .map(|leg| AccountMeta::new(market_state_pda(&market_pda(leg.market_id)), false)),
+        );
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts,
+            data: backend::instruction::SettleParlay {}.data(),
+        })
+    }
+
+    pub fn migrate_market(&mut self, payer: &Pubkey, market_id: u64) -> TxResult {
+        let market = market_pda(market_id);
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::MigrateMarket {
+                market,
+                market_state: market_state_pda(&market),
+                payer: *payer,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::MigrateMarket {}.data(),
+        })
+    }
+
+    pub fn migrate_position(&mut self, payer: &Pubkey, market_id: u64, user: &User) -> TxResult {
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::MigratePosition {
+                user_position: position_pda(&market_pda(market_id), &user.key),
+                payer: *payer,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::MigratePosition {}.data(),
+        })
+    }
+
+    pub fn migrate_protocol(&mut self, payer: &Pubkey) -> TxResult {
+        self.svm.process_instruction(Instruction {
+            program_id: backend::ID,
+            accounts: backend::accounts::MigrateProtocol {
+                protocol_state: protocol_state_pda(),
+                payer: *payer,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: backend::instruction::MigrateProtocol {}.data(),
+        })
+    }
+
+    /// Rewrites an account as it was before its layout gained `removed`
+    /// trailing bytes, to stand in for accounts created by older programs.
+    pub fn downgrade(&mut self, key: &Pubkey, removed: usize) {
+        let mut account = self.svm.get_account(key).unwrap();
+        account.data.truncate(account.data.len() - removed);
+        self.svm.set_account(*key, account);
+    }
+
+    /// Writes a market in the layout the program had before versioning: its
+    /// reserves inline, sized to the maximum text, and no `MarketState`.
+    pub fn write_unversioned_market(&mut self, market_id: u64, authority: &Pubkey) {
+        let mut data = backend::Market::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&market_id.to_le_bytes());
+        data.extend_from_slice(authority.as_ref());
+        for text in ["Will it rain tomorrow?", "Settles on the forecast."] {
+            data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+            data.extend_from_slice(text.as_bytes());
+        }
+        data.push(MarketCategory::Other as u8);
+        for timestamp in [self.now(), self.now() + DAY, self.now() + 2 * DAY] {
+            data.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.push(0);
+        for amount in [500 * USDC, 500 * USDC, 500 * USDC, 500 * USDC, 0] {
+            data.extend_from_slice(&amount.to_le_bytes());
+        }
+        data.push(0);
+        data.push(
+            Pubkey::find_program_address(&[b"market", &market_id.to_le_bytes()], &backend::ID).1,
+        );
+        // Sized for the longest question, description and oracle source.
+        data.resize(
+            8 + 8 + 32 + (4 + 200) + (4 + 1000) + 1 + 3 * 8 + (4 + 100) + 1 + 5 * 8 + 2 + 1,
+            0,
+        );
+        self.write_program_account(market_pda(market_id), data);
+    }
+
+    /// Writes `user`'s position in `market_id` in the layout the program had
+    /// before versioning.
+    pub fn write_unversioned_position(&mut self, market_id: u64, user: &User, yes_shares: u64) {
+        let market = market_pda(market_id);
+        let key = position_pda(&market, &user.key);
+        let mut data = backend::UserPosition::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(user.key.as_ref());
+        data.extend_from_slice(market.as_ref());
+        for amount in [yes_shares, 0, yes_shares] {
+            data.extend_from_slice(&amount.to_le_bytes());
+        }
+        data.push(1);
+        data.push(
+            Pubkey::find_program_address(
+                &[b"position", market.as_ref(), user.key.as_ref()],
+                &backend::ID,
+            )
+            .1,
+        );
+        self.write_program_account(key, data);
+    }
+
+    fn write_program_account(&mut self, key: Pubkey, data: Vec<u8>) {
+        self.svm.set_account(
+            key,
+            svm::Account {
+                lamports: Rent::default().minimum_balance(data.len()),
+                data,
+                owner: backend::ID,
+                executable: false,
+                rent_epoch: u64::MAX,
+            },
+        );
+    }
+}
+
+impl Default for Kalshi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
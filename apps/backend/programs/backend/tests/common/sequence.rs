@@ -0,0 +1,288 @@
+//! Random instruction sequences shared by the `fuzz/` targets and the
+//! `sequences` integration test.
+//!
+//! Every operation picks its user and market out of a small fixed pool so
+//! that arbitrary input quickly reaches interesting states: several traders
+//! in the same market, sells that drain a reserve, claims after partial
+//! sells, and settlement racing further trades.
+
+use arbitrary::Arbitrary;
+use backend::{MarketStatus, Outcome};
+
+use super::*;
+
+pub const USERS: usize = 4;
+pub const MARKETS: u64 = 3;
+pub const WALLET: u64 = 1_000_000 * USDC;
+pub const BOND: u64 = 5 * USDC;
+
+#[derive(Arbitrary, Clone, Copy, Debug)]
+pub enum Op {
+    CreateMarket {
+        creator: u8,
+        market: u8,
+        liquidity: u32,
+        duration: u16,
+    },
+    Buy {
+        user: u8,
+        market: u8,
+        yes: bool,
+        max_cost: u32,
+    },
+    /// Sells `portion / 255` of the user's shares of one outcome.
+    Sell {
+        user: u8,
+        market: u8,
+        yes: bool,
+        portion: u8,
+    },
+    Resolve {
+        market: u8,
+        yes: bool,
+        by_authority: bool,
+    },
+    Invalidate {
+        market: u8,
+    },
+    ClaimWinnings {
+        user: u8,
+        market: u8,
+    },
+    ClaimRefund {
+        user: u8,
+        market: u8,
+    },
+    WithdrawResidual {
+        market: u8,
+    },
+    ClosePosition {
+        user: u8,
+        market: u8,
+    },
+    CloseMarket {
+        market: u8,
+        by_authority: bool,
+    },
+    Warp {
+        hours: u8,
+    },
+}
+
+fn outcome(yes: bool) -> Outcome {
+    if yes {
+        Outcome::Yes
+    } else {
+        Outcome::No
+    }
+}
+
+/// A deployment with `USERS` funded wallets and up to `MARKETS` markets.
+pub struct World {
+    pub kalshi: Kalshi,
+    pub users: Vec<User>,
+    /// Id of the market last created in each slot, and its creator while open.
+    ids: Vec<Option<u64>>,
+    creators: Vec<Option<User>>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        let mut kalshi = Kalshi::new();
+        let authority = kalshi.authority;
+        kalshi.set_creation_bond(&authority, BOND).unwrap();
+        let users = (0..USERS).map(|_| kalshi.user(WALLET)).collect();
+        Self {
+            kalshi,
+            users,
+            ids: vec![None; MARKETS as usize],
+            creators: vec![None; MARKETS as usize],
+        }
+    }
+
+    fn user(&self, index: u8) -> User {
+        self.users[index as usize % USERS]
+    }
+
+    fn slot(market: u8) -> usize {
+        (market as u64 % MARKETS) as usize
+    }
+
+    /// The market in `market`'s slot, or the next id if the slot was never used.
+    fn market_id(&self, market: u8) -> u64 {
+        self.ids[Self::slot(market)].unwrap_or_else(|| self.kalshi.protocol().total_markets)
+    }
+
+    pub fn apply(&mut self, op: Op) -> TxResult {
+        match op {
+            Op::CreateMarket {
+                creator,
+                market,
+                liquidity,
+                duration,
+            } => {
+                let creator = self.user(creator);
+                // An open slot is re-created under its own id, which the
+                // program rejects.
+                let slot = Self::slot(market);
+                let market_id = match self.creators[slot] {
+                    Some(_) => self.market_id(market),
+                    None => self.kalshi.protocol().total_markets,
+                };
+                let mut args = MarketArgs::new(market_id, self.kalshi.now());
+                args.end_timestamp = self.kalshi.now() + 60 + duration as i64 * 60;
+                args.resolution_timestamp = args.end_timestamp + DAY;
+                args.initial_liquidity = liquidity as u64;
+                let result = self.kalshi.create_market_with(&creator, args);
+                if result.is_ok() {
+                    self.ids[slot] = Some(market_id);
+                    self.creators[slot] = Some(creator);
+                }
+                result
+            }
+            Op::Buy {
+                user,
+                market,
+                yes,
+                max_cost,
+            } => {
+                let user = self.user(user);
+                self.kalshi
+                    .buy(&user, self.market_id(market), outcome(yes), max_cost as u64)
+            }
+            Op::Sell {
+                user,
+                market,
+                yes,
+                portion,
+            } => {
+                let user = self.user(user);
+                let market_id = self.market_id(market);
+                let (yes_shares, no_shares) = self.kalshi.shares(market_id, &user);
+                let held = if yes { yes_shares } else { no_shares };
+                let shares_in = (held as u128 * portion as u128 / u8::MAX as u128) as u64;
+                self.kalshi
+                    .sell(&user, market_id, outcome(yes), shares_in, 0)
+            }
+            Op::Resolve {
+                market,
+                yes,
+                by_authority,
+            } => {
+                let market_id = self.market_id(market);
+                let oracle = match self.creators[Self::slot(market)] {
+                    Some(creator) if !by_authority => creator.key,
+                    _ => self.kalshi.authority,
+                };
+                self.kalshi.resolve(&oracle, market_id, outcome(yes))
+            }
+            Op::Invalidate { market } => {
+                let authority = self.kalshi.authority;
+                self.kalshi.invalidate(&authority, self.market_id(market))
+            }
+            Op::ClaimWinnings { user, market } => {
+                let user = self.user(user);
+                self.kalshi.claim_winnings(&user, self.market_id(market))
+            }
+            Op::ClaimRefund { user, market } => {
+                let user = self.user(user);
+                self.kalshi.claim_refund(&user, self.market_id(market))
+            }
+            Op::WithdrawResidual { market } => {
+                let market_id = self.market_id(market);
+                let creator = self.creators[Self::slot(market)].unwrap_or(self.users[0]);
+                self.kalshi.withdraw_residual(&creator, market_id)
+            }
+            Op::ClosePosition { user, market } => {
+                let user = self.user(user);
+                self.kalshi.close_position(&user, self.market_id(market))
+            }
+            Op::CloseMarket {
+                market,
+                by_authority,
+            } => {
+                let market_id = self.market_id(market);
+                let creator = self.creators[Self::slot(market)]
+                    .map_or(self.kalshi.authority, |creator| creator.key);
+                let authority = if by_authority {
+                    self.kalshi.authority
+                } else {
+                    creator
+                };
+                let result = self.kalshi.close_market(&authority, market_id, &creator);
+                if result.is_ok() {
+                    self.creators[Self::slot(market)] = None;
+                }
+                result
+            }
+            Op::Warp { hours } => {
+                self.kalshi.svm.warp(hours as i64 * 3_600);
+                Ok(())
+            }
+        }
+    }
+
+    /// USDC is only ever moved between wallets, vaults and the treasury,
+    /// and every vault covers what its market could still owe.
+    pub fn check(&self) {
+        let mut total: u64 = self
+            .users
+            .iter()
+            .map(|user| self.kalshi.balance(&user.token))
+            .sum();
+        total += self.kalshi.balance(&self.kalshi.treasury);
+
+        for (slot, creator) in self.creators.iter().enumerate() {
+            let (Some(market_id), Some(_)) = (self.ids[slot], creator) else {
+                continue;
+            };
+            let vault = self.kalshi.vault_balance(market_id);
+            total += vault;
+
+            let market = self.kalshi.market_state(market_id);
+            let settlement = match market.status() {
+                MarketStatus::Active => None,
+                MarketStatus::Resolved => market.winning_outcome().map(Settlement::Resolve),
+                MarketStatus::Invalid => Some(Settlement::Invalidate),
+            };
+            let owed = self.kalshi.owed(market_id, &self.users, settlement);
+            assert!(
+                vault >= owed,
+                "market {market_id} vault {vault} cannot cover {owed} owed to users"
+            );
+            if settlement.is_some() {
+                assert!(
+                    market.liability >= owed && vault >= market.liability,
+                    "market {market_id} tracks liability {} for {owed} owed and vault {vault}",
+                    market.liability
+                );
+            }
+        }
+
+        assert_eq!(
+            total,
+            USERS as u64 * WALLET,
+            "USDC was created or destroyed"
+        );
+    }
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Applies `ops` in order, checking the invariants after each one. Failed
+/// transactions are expected; a panic inside the program never is.
+pub fn run(ops: &[Op]) {
+    let mut world = World::new();
+    for op in ops {
+        let result = world.apply(*op);
+        assert!(
+            !matches!(result, Err(TxError::Panicked(_))),
+            "{op:?} panicked: {result:?}"
+        );
+        world.check();
+    }
+}
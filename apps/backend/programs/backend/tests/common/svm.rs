@@ -0,0 +1,335 @@
+//! Drives the built `kalshi` program under LiteSVM.
+//!
+//! Tests run the SBF program itself, so `backend.so` must be built first
+//! with `anchor build`. It is looked up in `SBF_OUT_DIR`, `BPF_OUT_DIR` or
+//! the nearest `target/deploy`, as `solana-program-test` does. Both SPL
+//! token programs are LiteSVM's own builds, so Token-2022 extensions such as
+//! transfer fees behave as they do on-chain.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anchor_lang::{
+    prelude::*,
+    solana_program::{
+        instruction::{Instruction, InstructionError},
+        message::Message,
+        program_pack::Pack,
+    },
+    system_program,
+};
+use litesvm::LiteSVM;
+use solana_compute_budget::compute_budget::ComputeBudget;
+use solana_transaction::Transaction;
+use solana_transaction_error::TransactionError;
+use spl_token_2022::extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions};
+
+pub use solana_account::Account;
+
+/// Pays the fees of every transaction; never a harness account.
+const FEE_PAYER: Pubkey = Pubkey::new_from_array([0xfe; 32]);
+
+/// The most compute a transaction can request.
+const MAX_COMPUTE_UNIT_LIMIT: u64 = 1_400_000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TxError {
+    Program(ProgramError),
+    /// The program aborted, e.g. on a panic; carries the transaction logs.
+    Panicked(String),
+    /// A runtime failure with no `ProgramError` equivalent.
+    Runtime(String),
+}
+
+impl From<ProgramError> for TxError {
+    fn from(err: ProgramError) -> Self {
+        TxError::Program(err)
+    }
+}
+
+pub type TxResult = std::result::Result<(), TxError>;
+
+pub struct Svm {
+    svm: LiteSVM,
+    logs: Vec<String>,
+    return_data: Vec<u8>,
+    compute_units: u64,
+}
+
+impl Svm {
+    pub fn new() -> Self {
+        let mut svm = LiteSVM::new()
+            .with_sigverify(false)
+            .with_blockhash_check(false)
+            .with_transaction_history(0)
+            .with_log_bytes_limit(None)
+            .with_compute_budget(ComputeBudget {
+                compute_unit_limit: MAX_COMPUTE_UNIT_LIMIT,
+                ..ComputeBudget::default()
+            });
+        svm.add_program(backend::ID, sbf_program()).unwrap();
+        svm.set_account(
+            FEE_PAYER,
+            Account::new(u64::MAX / 2, 0, &system_program::ID),
+        )
+        .unwrap();
+        Self {
+            svm,
+            logs: Vec::new(),
+            return_data: Vec::new(),
+            compute_units: 0,
+        }
+    }
+
+    pub fn set_account(&mut self, key: Pubkey, account: Account) {
+        self.svm.set_account(key, account).unwrap();
+    }
+
+    pub fn get_account(&self, key: &Pubkey) -> Option<Account> {
+        self.svm.get_account(key)
+    }
+
+    pub fn lamports(&self, key: &Pubkey) -> u64 {
+        self.svm.get_balance(key).unwrap_or(0)
+    }
+
+    pub fn airdrop(&mut self, key: &Pubkey, lamports: u64) {
+        let mut account = self
+            .get_account(key)
+            .unwrap_or_else(|| Account::new(0, 0, &system_program::ID));
+        account.lamports += lamports;
+        self.set_account(*key, account);
+    }
+
+    /// Deserializes an Anchor account, checking its discriminator.
+    pub fn account<T: AccountDeserialize>(&self, key: &Pubkey) -> T {
+        let account = self
+            .get_account(key)
+            .unwrap_or_else(|| panic!("account {key} does not exist"));
+        T::try_deserialize(&mut account.data.as_slice()).unwrap()
+    }
+
+    pub fn create_mint(&mut self, authority: &Pubkey, decimals: u8) -> Pubkey {
+        let key = Pubkey::new_unique();
+        let mint = spl_token::state::Mint {
+            mint_authority: Some(*authority).into(),
+            supply: 0,
+            decimals,
+            is_initialized: true,
+            freeze_authority: None.into(),
+        };
+        let mut data = vec![0; spl_token::state::Mint::LEN];
+        mint.pack_into_slice(&mut data);
+        self.set_account(key, self.rent_exempt(data, spl_token::ID));
+        key
+    }
+
+    /// Creates a Token-2022 mint with `extensions`, initialized by the
+    /// instructions `init` returns for the new mint's address. The mint
+    /// authority is `authority`.
+    pub fn create_mint_2022(
+        &mut self,
+        authority: &Pubkey,
+        decimals: u8,
+        extensions: &[ExtensionType],
+        init: impl FnOnce(&Pubkey) -> Vec<Instruction>,
+    ) -> Pubkey {
+        let key = Pubkey::new_unique();
+        let len =
+            ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(extensions)
+                .unwrap();
+        self.set_account(key, self.rent_exempt(vec![0; len], spl_token_2022::ID));
+        let mut ixs = init(&key);
+        ixs.push(
+            spl_token_2022::instruction::initialize_mint2(
+                &spl_token_2022::ID,
+                &key,
+                authority,
+                None,
+                decimals,
+            )
+            .unwrap(),
+        );
+        self.process_transaction(&ixs).unwrap();
+        key
+    }
+
+    /// Creates a token account and mints `amount` into it out of thin air.
+    pub fn create_token_account(&mut self, mint: &Pubkey, owner: &Pubkey, amount: u64) -> Pubkey {
+        let key = Pubkey::new_unique();
+        if self.get_account(mint).unwrap().owner == spl_token_2022::ID {
+            self.create_token_2022_account(key, mint, owner, amount);
+            return key;
+        }
+        let token = spl_token::state::Account {
+            mint: *mint,
+            owner: *owner,
+            amount,
+            state: spl_token::state::AccountState::Initialized,
+            ..Default::default()
+        };
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        token.pack_into_slice(&mut data);
+        self.set_account(key, self.rent_exempt(data, spl_token::ID));
+        self.adjust_supply(mint, amount as i128);
+        key
+    }
+
+    /// Token-2022 accounts carry whatever extensions their mint requires, so
+    /// they are sized and minted into through the program itself.
+    fn create_token_2022_account(
+        &mut self,
+        key: Pubkey,
+        mint: &Pubkey,
+        owner: &Pubkey,
+        amount: u64,
+    ) {
+        let data = self.get_account(mint).unwrap().data;
+        let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data).unwrap();
+        let extensions = ExtensionType::get_required_init_account_extensions(
+            &mint_state.get_extension_types().unwrap(),
+        );
+        let authority = Option::<Pubkey>::from(mint_state.base.mint_authority).unwrap();
+        let len =
+            ExtensionType::try_calculate_account_len::<spl_token_2022::state::Account>(&extensions)
+                .unwrap();
+        self.set_account(key, self.rent_exempt(vec![0; len], spl_token_2022::ID));
+        let mut ixs = vec![spl_token_2022::instruction::initialize_account3(
+            &spl_token_2022::ID,
+            &key,
+            mint,
+            owner,
+        )
+        .unwrap()];
+        if amount > 0 {
+            ixs.push(
+                spl_token_2022::instruction::mint_to(
+                    &spl_token_2022::ID,
+                    mint,
+                    &key,
+                    &authority,
+                    &[],
+                    amount,
+                )
+                .unwrap(),
+            );
+        }
+        self.process_transaction(&ixs).unwrap();
+    }
+
+    fn adjust_supply(&mut self, mint: &Pubkey, delta: i128) {
+        let mut account = self.get_account(mint).unwrap();
+        let mut state = spl_token::state::Mint::unpack(&account.data).unwrap();
+        state.supply = (state.supply as i128 + delta) as u64;
+        state.pack_into_slice(&mut account.data);
+        self.set_account(*mint, account);
+    }
+
+    pub fn token_balance(&self, key: &Pubkey) -> u64 {
+        self.get_account(key).map_or(0, |a| {
+            StateWithExtensions::<spl_token_2022::state::Account>::unpack(&a.data)
+                .unwrap()
+                .base
+                .amount
+        })
+    }
+
+    fn rent_exempt(&self, data: Vec<u8>, owner: Pubkey) -> Account {
+        Account {
+            lamports: self.svm.minimum_balance_for_rent_exemption(data.len()),
+            data,
+            owner,
+            executable: false,
+            rent_epoch: u64::MAX,
+        }
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.svm.get_sysvar()
+    }
+
+    pub fn set_unix_timestamp(&mut self, unix_timestamp: i64) {
+        let mut clock = self.clock();
+        clock.unix_timestamp = unix_timestamp;
+        self.svm.set_sysvar(&clock);
+    }
+
+    /// Moves the clock forward by `seconds`, advancing the slot alongside.
+    pub fn warp(&mut self, seconds: i64) {
+        let mut clock = self.clock();
+        clock.unix_timestamp += seconds;
+        clock.slot += seconds.unsigned_abs() * 5 / 2;
+        self.svm.set_sysvar(&clock);
+    }
+
+    /// Drains the logs of every transaction since the last call.
+    pub fn take_logs(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.logs)
+    }
+
+    /// What the last transaction passed to `set_return_data`.
+    pub fn return_data(&self) -> Vec<u8> {
+        self.return_data.clone()
+    }
+
+    /// Compute units the last transaction consumed.
+    pub fn compute_units(&self) -> u64 {
+        self.compute_units
+    }
+
+    pub fn process_instruction(&mut self, ix: Instruction) -> TxResult {
+        self.process_transaction(&[ix])
+    }
+
+    /// Executes the instructions atomically: on any failure no account
+    /// changes are committed.
+    pub fn process_transaction(&mut self, ixs: &[Instruction]) -> TxResult {
+        let tx = Transaction::new_unsigned(Message::new(ixs, Some(&FEE_PAYER)));
+        let (result, meta) = match self.svm.send_transaction(tx) {
+            Ok(meta) => (Ok(()), meta),
+            Err(failed) => (Err(failed.err), failed.meta),
+        };
+        self.compute_units = meta.compute_units_consumed;
+        self.return_data = meta.return_data.data;
+        self.logs.extend(meta.logs.iter().cloned());
+        result.map_err(|err| match err {
+            TransactionError::InstructionError(_, InstructionError::ProgramFailedToComplete) => {
+                TxError::Panicked(meta.logs.join("\n"))
+            }
+            TransactionError::InstructionError(_, err) => ProgramError::try_from(err.clone())
+                .map_or_else(|_| TxError::Runtime(err.to_string()), TxError::Program),
+            err => TxError::Runtime(err.to_string()),
+        })
+    }
+}
+
+impl Default for Svm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The built SBF program.
+fn sbf_program() -> &'static [u8] {
+    static PROGRAM: OnceLock<Vec<u8>> = OnceLock::new();
+    PROGRAM.get_or_init(|| {
+        // The indexer and fuzz crates share this harness, so search upwards
+        // from whichever crate is being tested.
+        let deploy = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .ancestors()
+            .map(|dir| dir.join("target/deploy"));
+        let path = ["SBF_OUT_DIR", "BPF_OUT_DIR"]
+            .iter()
+            .filter_map(std::env::var_os)
+            .map(PathBuf::from)
+            .chain(deploy)
+            .map(|dir| dir.join("backend.so"))
+            .find(|path| path.exists())
+            .expect(
+                "backend.so not found; run `anchor build` or `cargo build-sbf` before the tests",
+            );
+        std::fs::read(path).unwrap()
+    })
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use backend::Outcome;
+use common::*;
+use proptest::prelude::*;
+
+const USERS: usize = 3;
+const WALLET: u64 = 10_000 * USDC;
+
+#[derive(Clone, Debug)]
+enum Action {
+    Buy {
+        user: usize,
+        outcome: Outcome,
+        amount: u64,
+    },
+    /// Sells `quarters / 4` of the user's shares of `outcome`.
+    Sell {
+        user: usize,
+        outcome: Outcome,
+        quarters: u64,
+    },
+}
+
+fn outcome() -> impl Strategy<Value = Outcome> {
+    prop_oneof![Just(Outcome::Yes), Just(Outcome::No)]
+}
+
+fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        (0..USERS, outcome(), 0..=2_000 * USDC).prop_map(|(user, outcome, amount)| {
+            Action::Buy {
+                user,
+                outcome,
+                amount,
+            }
+        }),
+        (0..USERS, outcome(), 1..=4u64).prop_map(|(user, outcome, quarters)| Action::Sell {
+            user,
+            outcome,
+            quarters,
+        }),
+    ]
+}
+
+fn settlement() -> impl Strategy<Value = Settlement> {
+    prop_oneof![
+        outcome().prop_map(Settlement::Resolve),
+        Just(Settlement::Invalidate),
+    ]
+}
+
+/// Every claim the vault could owe once the market settles must be covered
+/// by the collateral it holds. Before settlement that is the worst case over
+/// both outcomes and an invalidation.
+fn assert_solvent(kalshi: &Kalshi, users: &[User], settlement: Option<Settlement>) {
+    let owed = kalshi.owed(0, users, settlement);
+    let vault = kalshi.vault_balance(0);
+    assert!(
+        vault >= owed,
+        "vault {vault} cannot cover {owed} owed to users"
+    );
+}
+
+fn run(initial_liquidity: u64, actions: Vec<Action>, settlement: Settlement) {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(initial_liquidity);
+    let mut args = MarketArgs::new(0, kalshi.now());
+    args.initial_liquidity = initial_liquidity;
+    kalshi.create_market_with(&creator, args).unwrap();
+    let users: Vec<User> = (0..USERS).map(|_| kalshi.user(WALLET)).collect();
+
+    for action in actions {
+        let result = match action {
+            Action::Buy {
+                user,
+                outcome,
+                amount,
+            } => kalshi.buy(&users[user], 0, outcome, amount),
+            Action::Sell {
+                user,
+                outcome,
+                quarters,
+            } => {
+                let (yes, no) = kalshi.shares(0, &users[user]);
+                let held = if outcome == Outcome::Yes { yes } else { no };
+                kalshi.sell(&users[user], 0, outcome, held * quarters / 4, 0)
+            }
+        };
+        assert!(
+            !matches!(result, Err(TxError::Panicked(_))),
+            "{action:?} panicked: {result:?}"
+        );
+        assert_solvent(&kalshi, &users, None);
+    }
+
+    kalshi.svm.warp(DAY);
+    let authority = kalshi.authority;
+    match settlement {
+        Settlement::Resolve(winner) => kalshi.resolve(&authority, 0, winner).unwrap(),
+        Settlement::Invalidate => kalshi.invalidate(&authority, 0).unwrap(),
+    }
+
+    for user in &users {
+        let (yes, no) = kalshi.shares(0, user);
+        match settlement {
+            Settlement::Resolve(Outcome::Yes) if yes > 0 => {
+                kalshi.claim_winnings(user, 0).unwrap();
+            }
+            Settlement::Resolve(Outcome::No) if no > 0 => {
+                kalshi.claim_winnings(user, 0).unwrap();
+            }
+            Settlement::Invalidate if yes + no > 0 => kalshi.claim_refund(user, 0).unwrap(),
+            _ => {}
+        }
+        assert_solvent(&kalshi, &users, Some(settlement));
+    }
+
+    let total: u64 = users
+        .iter()
+        .map(|user| kalshi.balance(&user.token))
+        .sum::<u64>()
+        + kalshi.balance(&creator.token)
+        + kalshi.vault_balance(0)
+        + kalshi.balance(&kalshi.treasury);
+    assert_eq!(total, USERS as u64 * WALLET + initial_liquidity);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn vault_stays_solvent_through_trades_and_claims(
+        initial_liquidity in USDC..=1_000 * USDC,
+        actions in prop::collection::vec(action(), 1..24),
+        settlement in settlement(),
+    ) {
+        run(initial_liquidity, actions, settlement);
+    }
+}
+
+#[test]
+fn vault_stays_solvent_after_lopsided_trading() {
+    // Inflating one reserve and then buying the other side drained the
+    // vault under the previous single-sided pricing.
+    let actions = vec![
+        Action::Buy {
+            user: 0,
+            outcome: Outcome::No,
+            amount: 2_000 * USDC,
+        },
+        Action::Buy {
+            user: 1,
+            outcome: Outcome::Yes,
+            amount: 10 * USDC,
+        },
+        Action::Buy {
+            user: 1,
+            outcome: Outcome::Yes,
+            amount: 100 * USDC,
+        },
+        Action::Buy {
+            user: 2,
+            outcome: Outcome::Yes,
+            amount: 1_000 * USDC,
+        },
+    ];
+    run(USDC, actions, Settlement::Resolve(Outcome::Yes));
+}
@@ -0,0 +1,2984 @@
+mod common;
+
+use anchor_lang::{
+    prelude::{Pubkey, Rent},
+    solana_program::program_pack::Pack,
+};
+use backend::{AccessPolicy, ErrorCode, MarketStatus, Outcome};
+use common::*;
+
+const HALF: u64 = 50 * USDC;
+
+fn fee(amount: u64) -> u64 {
+    amount * FEE_BPS as u64 / 10_000
+}
+
+#[test]
+fn initialize_protocol_sets_authority_treasury_and_fee() {
+    let kalshi = Kalshi::new();
+    let protocol = kalshi.protocol();
+    assert_eq!(protocol.authority, kalshi.authority);
+    assert_eq!(protocol.treasury, kalshi.treasury);
+    assert_eq!(protocol.free_bps, FEE_BPS);
+    assert_eq!(protocol.total_markets, 0);
+    assert_eq!(protocol.total_volume, 0);
+}
+
+#[test]
+fn initialize_protocol_rejects_fee_above_ten_percent() {
+    let mut kalshi = Kalshi::uninitialized();
+    assert_eq!(
+        kalshi.initialize_protocol(1001),
+        Err(program_error(ErrorCode::FeeTooHigh))
+    );
+    kalshi.initialize_protocol(1000).unwrap();
+}
+
+#[test]
+fn initialize_protocol_only_once() {
+    let mut kalshi = Kalshi::new();
+    assert!(kalshi.initialize_protocol(FEE_BPS).is_err());
+}
+
+#[test]
+fn create_market_escrows_liquidity_and_seeds_pool() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    let market = kalshi.market(0);
+    assert_eq!(market.market_id, 0);
+    assert_eq!(market.authority, creator.key);
+    let market = kalshi.market_state(0);
+    assert_eq!(market.market, market_pda(0));
+    assert_eq!(market.status(), MarketStatus::Active);
+    assert_eq!(market.created_at, GENESIS);
+    assert_eq!(market.end_timestamp, GENESIS + DAY);
+    assert_eq!(market.yes_liquidity, HALF);
+    assert_eq!(market.no_liquidity, HALF);
+    assert_eq!(market.total_yes_shares, HALF);
+    assert_eq!(market.total_no_shares, HALF);
+    assert_eq!(market.winning_outcome(), None);
+
+    assert_eq!(kalshi.vault_balance(0), 100 * USDC);
+    assert_eq!(kalshi.balance(&creator.token), 900 * USDC);
+    assert_eq!(kalshi.protocol().total_markets, 1);
+}
+
+#[test]
+fn create_market_assigns_sequential_ids() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let other = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    assert_eq!(kalshi.events::<backend::MarketCreated>()[0].market_id, 0);
+
+    // Only the next id's address is accepted.
+    assert_eq!(
+        kalshi.create_market(&other, 0),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintSeeds))
+    );
+    assert_eq!(
+        kalshi.create_market(&other, 2),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintSeeds))
+    );
+    kalshi.create_market(&other, 1).unwrap();
+    let created = kalshi.events::<backend::MarketCreated>();
+    assert_eq!(created[0].market_id, 1);
+    assert_eq!(created[0].market, market_pda(1));
+    assert_eq!(created[0].namespace_index, None);
+    assert_eq!(kalshi.market(1).authority, other.key);
+    assert_eq!(kalshi.protocol().total_markets, 2);
+}
+
+#[test]
+fn create_market_files_markets_under_the_creator_namespace() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let other = kalshi.user(1_000 * USDC);
+    let now = kalshi.now();
+    kalshi
+        .create_market_with(&creator, MarketArgs::new(0, now).namespaced())
+        .unwrap();
+    kalshi.create_market(&other, 1).unwrap();
+    kalshi
+        .create_market_with(&creator, MarketArgs::new(2, now).namespaced())
+        .unwrap();
+    let indices: Vec<_> = kalshi
+        .events::<backend::MarketCreated>()
+        .iter()
+        .map(|created| created.namespace_index)
+        .collect();
+    assert_eq!(indices, [Some(0), None, Some(1)]);
+
+    let profile = kalshi.creator_profile(&creator).unwrap();
+    assert_eq!(profile.creator, creator.key);
+    assert_eq!(profile.market_count, 2);
+    assert_eq!(kalshi.creator_profile(&other).unwrap().market_count, 0);
+    let ids: Vec<u64> = (0..profile.market_count)
+        .map(|index| kalshi.creator_market(&creator, index).market_id)
+        .collect();
+    assert_eq!(ids, [0, 2]);
+    assert_eq!(kalshi.creator_market(&creator, 1).market, market_pda(2));
+}
+
+#[test]
+fn create_market_validates_text_lengths() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let now = kalshi.now();
+
+    let mut args = MarketArgs::new(0, now);
+    args.question = "q".repeat(201);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::QuestionTooLong))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.description = "d".repeat(1001);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::DescriptionTooLong))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.oracle_source = "o".repeat(101);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::OracleSourceTooLong))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.question = "q".repeat(200);
+    args.description = "d".repeat(1000);
+    args.oracle_source = "o".repeat(100);
+    kalshi.create_market_with(&creator, args).unwrap();
+}
+
+#[test]
+fn off_chain_metadata_shrinks_the_market_account() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let now = kalshi.now();
+    let uri = "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi
+        .create_market_with(&creator, MarketArgs::new(1, now).off_chain(uri, [7; 32]))
+        .unwrap();
+
+    let on_chain = kalshi.svm.get_account(&market_pda(0)).unwrap();
+    let off_chain = kalshi.svm.get_account(&market_pda(1)).unwrap();
+    let args = MarketArgs::new(0, now);
+    assert_eq!(
+        on_chain.data.len(),
+        backend::Market::space(&args.question, &args.description, &args.oracle_source, "")
+    );
+    assert_eq!(
+        off_chain.data.len(),
+        backend::Market::space("", "", "", uri)
+    );
+    assert!(off_chain.lamports < on_chain.lamports);
+
+    let market = kalshi.market(1);
+    assert_eq!(market.question, "");
+    assert_eq!(market.metadata_uri, uri);
+    assert_eq!(market.metadata_hash, [7; 32]);
+}
+
+#[test]
+fn create_market_validates_metadata_pointer() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let now = kalshi.now();
+
+    let args = MarketArgs::new(0, now).off_chain("", [0; 32]);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::MissingMarketText))
+    );
+
+    let args = MarketArgs::new(0, now).off_chain("https://example.com/1.json", [0; 32]);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::InvalidMetadataHash))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.metadata_hash = [1; 32];
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::InvalidMetadataHash))
+    );
+
+    let args = MarketArgs::new(0, now).off_chain(&"u".repeat(201), [1; 32]);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::MetadataUriTooLong))
+    );
+}
+
+#[test]
+fn update_metadata_resizes_the_market_until_trading_starts() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let now = kalshi.now();
+    let uri = "https://example.com/markets/1.json";
+    kalshi
+        .create_market_with(&creator, MarketArgs::new(0, now).off_chain(uri, [7; 32]))
+        .unwrap();
+
+    let mut args = MarketArgs::new(0, now);
+    args.description = "d".repeat(1000);
+    assert_eq!(
+        kalshi.update_metadata(&alice, MarketArgs::new(0, now)),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+    kalshi.update_metadata(&creator, args).unwrap();
+
+    let market = kalshi.market(0);
+    assert_eq!(market.description.len(), 1000);
+    assert_eq!(market.metadata_uri, "");
+    assert_eq!(market.metadata_hash, [0; 32]);
+    let account = kalshi.svm.get_account(&market_pda(0)).unwrap();
+    assert_eq!(
+        account.data.len(),
+        backend::Market::space(
+            &market.question,
+            &market.description,
+            &market.oracle_source,
+            ""
+        )
+    );
+    assert_eq!(
+        kalshi.events::<backend::MarketMetadataUpdated>()[0].metadata_uri,
+        ""
+    );
+
+    // Shrinking back to a pointer refunds the rent difference to the creator.
+    let lamports = kalshi.svm.lamports(&creator.key);
+    kalshi
+        .update_metadata(&creator, MarketArgs::new(0, now).off_chain(uri, [8; 32]))
+        .unwrap();
+    assert!(kalshi.svm.lamports(&creator.key) > lamports);
+    assert_eq!(kalshi.market(0).metadata_hash, [8; 32]);
+
+    kalshi.buy(&alice, 0, Outcome::Yes, USDC).unwrap();
+    assert_eq!(
+        kalshi.update_metadata(&creator, MarketArgs::new(0, now)),
+        Err(program_error(ErrorCode::TradingStarted))
+    );
+}
+
+#[test]
+fn create_market_validates_liquidity_and_schedule() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let now = kalshi.now();
+
+    let mut args = MarketArgs::new(0, now);
+    args.initial_liquidity = USDC - 1;
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::InsufficientInitialLiquidity))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.end_timestamp = now;
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::InvalidEndTime))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.resolution_timestamp = args.end_timestamp;
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::InvalidResolutionTime))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.resolution_timestamp = args.end_timestamp + 7 * DAY + 1;
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::ResolutionTooLate))
+    );
+
+    let mut args = MarketArgs::new(0, now);
+    args.resolution_timestamp = args.end_timestamp + 7 * DAY;
+    kalshi.create_market_with(&creator, args).unwrap();
+}
+
+#[test]
+fn buy_shares_routes_fee_to_treasury() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    let cost = 10 * USDC;
+    kalshi.buy(&alice, 0, Outcome::Yes, cost).unwrap();
+
+    let market = kalshi.market_state(0);
+    let position = kalshi.position(0, &alice);
+    assert!(position.initialized);
+    assert_eq!(position.user, alice.key);
+    assert_eq!(position.market, market_pda(0));
+    assert!(position.yes_shares > 0);
+    assert_eq!(position.no_shares, 0);
+    assert_eq!(position.total_invested, cost);
+
+    let minted = cost - fee(cost);
+    assert_eq!(market.yes_liquidity, HALF + minted - position.yes_shares);
+    assert_eq!(market.no_liquidity, HALF + minted);
+    assert!(market.yes_liquidity * market.no_liquidity >= HALF * HALF);
+    assert_eq!(market.total_yes_shares, HALF + position.yes_shares);
+    assert_eq!(market.total_volume, cost);
+
+    assert_eq!(kalshi.balance(&alice.token), 990 * USDC);
+    assert_eq!(kalshi.balance(&kalshi.treasury), fee(cost));
+    assert_eq!(kalshi.vault_balance(0), 100 * USDC + cost - fee(cost));
+    assert_eq!(kalshi.protocol().total_volume, cost);
+}
+
+#[test]
+fn trades_emit_events_with_post_trade_reserves() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 10 * USDC).unwrap();
+
+    let bought = kalshi.events::<backend::SharesBought>();
+    assert_eq!(bought.len(), 1);
+    let market = kalshi.market_state(0);
+    let position = kalshi.position(0, &alice);
+    assert_eq!(bought[0].market_id, 0);
+    assert_eq!(bought[0].user, alice.key);
+    assert_eq!(bought[0].outcome, Outcome::No);
+    assert_eq!(bought[0].shares_out, position.no_shares);
+    assert_eq!(bought[0].cost, 10 * USDC);
+    assert_eq!(bought[0].fee, fee(10 * USDC));
+    assert_eq!(bought[0].yes_liquidity, market.yes_liquidity);
+    assert_eq!(bought[0].no_liquidity, market.no_liquidity);
+    assert_eq!(bought[0].timestamp, kalshi.now());
+
+    kalshi
+        .sell(&alice, 0, Outcome::No, position.no_shares, 0)
+        .unwrap();
+    let sold = kalshi.events::<backend::SharesSold>();
+    assert_eq!(sold.len(), 1);
+    assert_eq!(sold[0].shares_in, position.no_shares);
+    assert_eq!(
+        sold[0].payout - sold[0].fee,
+        kalshi.balance(&alice.token) - 990 * USDC
+    );
+    assert_eq!(sold[0].no_liquidity, kalshi.market_state(0).no_liquidity);
+}
+
+#[test]
+fn trades_update_market_state_in_place_and_leave_the_market_untouched() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    let market = market_pda(0);
+    let text = kalshi.svm.get_account(&market).unwrap().data.clone();
+
+    let buy = kalshi.buy_ix(&alice, 0, Outcome::Yes, 10 * USDC);
+    let meta = buy.accounts.iter().find(|meta| meta.pubkey == market);
+    assert!(!meta.unwrap().is_writable);
+    kalshi.svm.process_instruction(buy).unwrap();
+    let bought = kalshi.events::<backend::SharesBought>();
+    kalshi
+        .sell(&alice, 0, Outcome::Yes, bought[0].shares_out, 0)
+        .unwrap();
+    let sold = kalshi.events::<backend::SharesSold>();
+
+    assert_eq!(kalshi.svm.get_account(&market).unwrap().data, text);
+    let fees = bought[0].fee + sold[0].fee;
+    assert_eq!(kalshi.market_state(0).total_fees, fees);
+}
+
+/// Compute units each instruction may use: the 200,000 a transaction gives
+/// every instruction by default, so trading never needs a compute budget
+/// instruction. Batches and cranks are measured with two legs and two orders.
+const CREATE_MARKET_BUDGET: u64 = 200_000;
+const BUY_BUDGET: u64 = 200_000;
+const SELL_BUDGET: u64 = 200_000;
+const BATCH_TRADE_BUDGET: u64 = 200_000;
+const CRANK_ORDERS_BUDGET: u64 = 200_000;
+
+#[test]
+fn trades_fit_the_default_compute_budget() {
+    use backend::TradeSide::Buy;
+
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    let used = |kalshi: &Kalshi, instruction: &str, budget: u64| {
+        let units = kalshi.svm.compute_units();
+        assert!(
+            units <= budget,
+            "{instruction} used {units} compute units, over its budget of {budget}"
+        );
+    };
+
+    kalshi.create_market(&creator, 0).unwrap();
+    used(&kalshi, "create_market", CREATE_MARKET_BUDGET);
+    kalshi.create_market(&creator, 1).unwrap();
+
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    used(&kalshi, "buy_shares", BUY_BUDGET);
+    let shares = kalshi.position(0, &alice).yes_shares;
+    kalshi.sell(&alice, 0, Outcome::Yes, shares, 0).unwrap();
+    used(&kalshi, "sell_shares", SELL_BUDGET);
+
+    kalshi
+        .batch_trade(
+            &alice,
+            &[
+                (0, leg(Outcome::Yes, Buy, 10 * USDC, 1)),
+                (1, leg(Outcome::No, Buy, 10 * USDC, 1)),
+            ],
+        )
+        .unwrap();
+    used(&kalshi, "batch_trade", BATCH_TRADE_BUDGET);
+
+    let order = OrderArgs::buy(Outcome::No, 10 * USDC, 400_000);
+    kalshi.place_limit_order(&alice, 0, order).unwrap();
+    kalshi.place_limit_order(&bob, 0, order).unwrap();
+    kalshi.buy(&creator, 0, Outcome::Yes, 100 * USDC).unwrap();
+    kalshi
+        .crank_orders(&keeper, 0, &[(alice, 0), (bob, 0)])
+        .unwrap();
+    assert!(kalshi.shares(0, &alice).1 > 0 && kalshi.shares(0, &bob).1 > 0);
+    used(&kalshi, "crank_orders", CRANK_ORDERS_BUDGET);
+}
+
+#[test]
+fn trades_append_price_snapshots_and_accrue_twap() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    let opened = kalshi.now();
+
+    let history = kalshi.price_history(0);
+    assert_eq!(history.market, market_pda(0));
+    assert_eq!(history.len, 1);
+    assert_eq!(history.last_yes_price, backend::PRICE_SCALE / 2);
+
+    kalshi.svm.warp(600);
+    kalshi.buy(&alice, 0, Outcome::Yes, 30 * USDC).unwrap();
+    let market = kalshi.market_state(0);
+    let price =
+        market.no_liquidity * backend::PRICE_SCALE / (market.yes_liquidity + market.no_liquidity);
+    assert!(price > backend::PRICE_SCALE / 2);
+
+    kalshi.svm.warp(600);
+    let shares = kalshi.position(0, &alice).yes_shares;
+    kalshi.sell(&alice, 0, Outcome::Yes, shares, 0).unwrap();
+
+    let history = kalshi.price_history(0);
+    assert_eq!(history.len, 3);
+    let snapshots: Vec<_> = history.snapshots().copied().collect();
+    assert_eq!(snapshots[1].timestamp, opened + 600);
+    assert_eq!(snapshots[1].yes_price, price);
+    assert_eq!(snapshots[1].volume, 30 * USDC);
+    assert!(snapshots[0].yes_price < price);
+    assert_eq!(
+        u128::from(history.cumulative_yes_price),
+        (backend::PRICE_SCALE / 2 * 600 + price * 600) as u128
+    );
+    assert_eq!(
+        history.twap(opened, opened + 1_200).unwrap(),
+        (backend::PRICE_SCALE / 2 + price) / 2
+    );
+}
+
+#[test]
+fn buy_shares_accumulates_position_across_outcomes() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let first = kalshi.position(0, &alice).yes_shares;
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 5 * USDC).unwrap();
+
+    let position = kalshi.position(0, &alice);
+    let market = kalshi.market_state(0);
+    assert!(position.yes_shares > first);
+    assert_eq!(market.total_yes_shares, HALF + position.yes_shares);
+    assert_eq!(market.total_no_shares, HALF + position.no_shares);
+    assert_eq!(position.total_invested, 25 * USDC);
+    assert_eq!(
+        kalshi.balance(&kalshi.treasury),
+        2 * fee(10 * USDC) + fee(5 * USDC)
+    );
+}
+
+#[test]
+fn buy_shares_rejects_zero_cost() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    assert_eq!(
+        kalshi.buy(&alice, 0, Outcome::Yes, 0),
+        Err(program_error(ErrorCode::InsufficientOutput))
+    );
+}
+
+#[test]
+fn buy_shares_after_end_fails_with_market_ended() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    kalshi.svm.warp(DAY - 1);
+    kalshi.buy(&alice, 0, Outcome::Yes, USDC).unwrap();
+    kalshi.svm.warp(1);
+    assert_eq!(
+        kalshi.buy(&alice, 0, Outcome::Yes, USDC),
+        Err(program_error(ErrorCode::MarketEnded))
+    );
+}
+
+#[test]
+fn buy_shares_enforces_position_limits() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    assert_eq!(
+        kalshi.set_default_limits(&creator.key, 0, 0),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+    kalshi
+        .set_default_limits(&authority, 1_000 * USDC, 20 * USDC)
+        .unwrap();
+    kalshi.create_market(&creator, 0).unwrap();
+    let market = kalshi.market_state(0);
+    assert_eq!(market.max_open_interest, 1_000 * USDC);
+    assert_eq!(market.max_position_shares, 20 * USDC);
+
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    assert_eq!(
+        kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC),
+        Err(program_error(ErrorCode::PositionLimitExceeded))
+    );
+    // Each outcome is capped separately.
+    kalshi.buy(&alice, 0, Outcome::No, 5 * USDC).unwrap();
+
+    // Only the protocol authority may override a market's caps.
+    assert_eq!(
+        kalshi.set_market_limits(&creator.key, 0, 0, 0),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintRaw))
+    );
+    let (yes_held, _) = kalshi.market_state(0).held_shares().unwrap();
+    kalshi
+        .set_market_limits(&authority, 0, yes_held + USDC, 0)
+        .unwrap();
+    assert_eq!(
+        kalshi.buy(&bob, 0, Outcome::Yes, 5 * USDC),
+        Err(program_error(ErrorCode::OpenInterestLimitExceeded))
+    );
+    kalshi.buy(&bob, 0, Outcome::Yes, USDC / 2).unwrap();
+
+    kalshi.set_market_limits(&authority, 0, 0, 0).unwrap();
+    kalshi.buy(&bob, 0, Outcome::Yes, 5 * USDC).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+}
+
+#[test]
+fn attestation_gated_market_requires_an_unexpired_attestation() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let kyc = kalshi.user(0);
+    let other_kyc = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    assert_eq!(
+        kalshi.set_access_policy(&alice.key, 0, AccessPolicy::Attestation, kyc.key.to_bytes()),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+    kalshi
+        .set_access_policy(
+            &creator.key,
+            0,
+            AccessPolicy::Attestation,
+            kyc.key.to_bytes(),
+        )
+        .unwrap();
+    assert_eq!(
+        kalshi.market_state(0).access_policy(),
+        AccessPolicy::Attestation
+    );
+
+    assert_eq!(
+        kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC),
+        Err(program_error(ErrorCode::AccessDenied))
+    );
+    let now = kalshi.now();
+    kalshi
+        .issue_attestation(&kyc.key, &alice, now + DAY / 2)
+        .unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+
+    // Only the configured issuer counts.
+    kalshi.issue_attestation(&other_kyc.key, &bob, 0).unwrap();
+    let mut ix = kalshi.buy_ix(&bob, 0, Outcome::Yes, 10 * USDC);
+    // The first omitted optional account.
+    let attestation = ix
+        .accounts
+        .iter()
+        .position(|meta| meta.pubkey == backend::ID)
+        .unwrap();
+    ix.accounts[attestation].pubkey = attestation_pda(&other_kyc.key.to_bytes(), &bob.key);
+    assert_eq!(
+        kalshi.svm.process_instruction(ix),
+        Err(program_error(ErrorCode::AccessDenied))
+    );
+    kalshi.issue_attestation(&kyc.key, &bob, 0).unwrap();
+    kalshi.buy(&bob, 0, Outcome::No, 10 * USDC).unwrap();
+    kalshi.revoke_attestation(&kyc.key, &bob).unwrap();
+    assert_eq!(
+        kalshi.buy(&bob, 0, Outcome::No, 10 * USDC),
+        Err(program_error(ErrorCode::AccessDenied))
+    );
+
+    kalshi.svm.warp(DAY / 2);
+    let shares = kalshi.position(0, &alice).yes_shares;
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::Yes, shares, 0),
+        Err(program_error(ErrorCode::AttestationExpired))
+    );
+
+    // Claims stay open to everyone.
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&creator.key, 0, Outcome::Yes).unwrap();
+    kalshi.claim_winnings(&alice, 0).unwrap();
+    assert_eq!(kalshi.position(0, &alice).yes_shares, 0);
+}
+
+#[test]
+fn allowlist_gated_market_accepts_merkle_proofs() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let users: Vec<User> = (0..3).map(|_| kalshi.user(1_000 * USDC)).collect();
+    let outsider = kalshi.user(1_000 * USDC);
+    let leaves: Vec<[u8; 32]> = users.iter().map(|user| allowlist_leaf(&user.key)).collect();
+    let (root, proofs) = merkle_tree(&leaves);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi
+        .set_access_policy(&authority, 0, AccessPolicy::Allowlist, root)
+        .unwrap();
+
+    assert_eq!(
+        kalshi.buy(&users[2], 0, Outcome::Yes, 10 * USDC),
+        Err(program_error(ErrorCode::AccessDenied))
+    );
+    for (user, proof) in users.iter().zip(&proofs) {
+        kalshi.prove_allowlist(user, root, proof.clone()).unwrap();
+        kalshi.buy(user, 0, Outcome::Yes, 10 * USDC).unwrap();
+    }
+    assert_eq!(
+        kalshi.prove_allowlist(&outsider, root, proofs[0].clone()),
+        Err(program_error(ErrorCode::InvalidAllowlistProof))
+    );
+
+    // Proofs are bound to the root, so replacing the allowlist revokes them.
+    let (new_root, _) = merkle_tree(&leaves[1..]);
+    kalshi
+        .set_access_policy(&creator.key, 0, AccessPolicy::Allowlist, new_root)
+        .unwrap();
+    assert_eq!(
+        kalshi.buy(&users[0], 0, Outcome::Yes, 10 * USDC),
+        Err(program_error(ErrorCode::AccessDenied))
+    );
+
+    kalshi
+        .set_access_policy(&creator.key, 0, AccessPolicy::Open, [0; 32])
+        .unwrap();
+    kalshi.buy(&outsider, 0, Outcome::Yes, 10 * USDC).unwrap();
+}
+
+#[test]
+fn circuit_breaker_rejects_trades_outside_the_band() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    assert_eq!(
+        kalshi.set_default_circuit_breaker(&authority, 3_600, 10_001, 0),
+        Err(program_error(ErrorCode::InvalidCircuitBreaker))
+    );
+    kalshi
+        .set_default_circuit_breaker(&authority, 3_600, 1_000, 0)
+        .unwrap();
+    kalshi.create_market(&creator, 0).unwrap();
+    assert_eq!(kalshi.market_state(0).breaker_max_move_bps, 1_000);
+
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let market = kalshi.market_state(0);
+    assert_eq!(market.reference_price, backend::PRICE_SCALE / 2);
+    assert_eq!(market.reference_window_start, GENESIS);
+
+    // Moves add up within a window.
+    assert_eq!(
+        kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC),
+        Err(program_error(ErrorCode::PriceMoveTooLarge))
+    );
+    kalshi.buy(&alice, 0, Outcome::No, USDC).unwrap();
+
+    // A new window is measured from the price it opens at.
+    kalshi.svm.warp(3_600);
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let market = kalshi.market_state(0);
+    assert!(market.reference_price > backend::PRICE_SCALE / 2);
+    assert_eq!(market.halted_until, 0);
+}
+
+#[test]
+fn circuit_breaker_halts_trading_for_the_cooldown() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&bob, 0, Outcome::Yes, USDC).unwrap();
+    assert_eq!(
+        kalshi.set_circuit_breaker(&creator.key, 0, 3_600, 500, 600),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintRaw))
+    );
+    kalshi
+        .set_circuit_breaker(&authority, 0, 3_600, 500, 600)
+        .unwrap();
+
+    // The tripping trade is dropped but the halt sticks.
+    let before = kalshi.market_state(0);
+    kalshi.buy(&alice, 0, Outcome::Yes, 20 * USDC).unwrap();
+    assert_eq!(kalshi.svm.return_data(), [0]);
+    let tripped = kalshi.events::<backend::CircuitBreakerTripped>();
+    assert_eq!(tripped.len(), 1);
+    assert_eq!(tripped[0].halted_until, GENESIS + 600);
+    assert!(tripped[0].attempted_price > tripped[0].reference_price);
+    let after = kalshi.market_state(0);
+    assert_eq!(after.yes_liquidity, before.yes_liquidity);
+    assert_eq!(after.halted_until, GENESIS + 600);
+    assert_eq!(kalshi.balance(&alice.token), 1_000 * USDC);
+
+    assert_eq!(
+        kalshi.buy(&bob, 0, Outcome::No, USDC),
+        Err(program_error(ErrorCode::TradingHalted))
+    );
+    let shares = kalshi.position(0, &bob).yes_shares;
+    assert_eq!(
+        kalshi.sell(&bob, 0, Outcome::Yes, shares, 0),
+        Err(program_error(ErrorCode::TradingHalted))
+    );
+
+    kalshi.svm.warp(600);
+    kalshi.sell(&bob, 0, Outcome::Yes, shares, 0).unwrap();
+    assert_eq!(kalshi.svm.return_data(), [1]);
+
+    // Resetting the breaker lifts a halt early.
+    kalshi.buy(&alice, 0, Outcome::Yes, 20 * USDC).unwrap();
+    assert!(kalshi.market_state(0).halted_until > kalshi.now());
+    kalshi.set_circuit_breaker(&authority, 0, 0, 0, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 20 * USDC).unwrap();
+    assert!(kalshi.position(0, &alice).yes_shares > 0);
+}
+
+#[test]
+fn sell_shares_pays_out_and_routes_fee_to_treasury() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 20 * USDC).unwrap();
+
+    let shares = kalshi.position(0, &alice).no_shares;
+    let before = kalshi.market_state(0);
+    let treasury_before = kalshi.balance(&kalshi.treasury);
+    let wallet_before = kalshi.balance(&alice.token);
+    let vault_before = kalshi.vault_balance(0);
+
+    kalshi.sell(&alice, 0, Outcome::No, shares / 2, 1).unwrap();
+
+    let after = kalshi.market_state(0);
+    let payout = before.yes_liquidity - after.yes_liquidity;
+    assert!(payout > 0);
+    assert_eq!(
+        after.no_liquidity,
+        before.no_liquidity + shares / 2 - payout
+    );
+    assert_eq!(after.total_no_shares, before.total_no_shares - shares / 2);
+    assert_eq!(after.total_volume, before.total_volume + payout);
+    assert_eq!(kalshi.position(0, &alice).no_shares, shares - shares / 2);
+
+    assert_eq!(
+        kalshi.balance(&kalshi.treasury),
+        treasury_before + fee(payout)
+    );
+    assert_eq!(
+        kalshi.balance(&alice.token),
+        wallet_before + payout - fee(payout)
+    );
+    assert_eq!(kalshi.vault_balance(0), vault_before - payout);
+}
+
+#[test]
+fn sell_shares_round_trip_loses_money() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    kalshi.buy(&alice, 0, Outcome::Yes, 50 * USDC).unwrap();
+    let shares = kalshi.position(0, &alice).yes_shares;
+    kalshi.sell(&alice, 0, Outcome::Yes, shares, 0).unwrap();
+
+    assert_eq!(kalshi.position(0, &alice).yes_shares, 0);
+    assert!(kalshi.balance(&alice.token) < 1_000 * USDC);
+}
+
+#[test]
+fn sell_shares_validates_amount_and_slippage() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let shares = kalshi.position(0, &alice).yes_shares;
+
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::Yes, 0, 0),
+        Err(program_error(ErrorCode::InvalidAmount))
+    );
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::Yes, shares + 1, 0),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::No, 1, 0),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::Yes, shares, 10 * USDC),
+        Err(program_error(ErrorCode::SlippageExceeded))
+    );
+}
+
+#[test]
+fn sell_shares_without_position_fails() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    assert_eq!(
+        kalshi.sell(&bob, 0, Outcome::Yes, 1, 0),
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotInitialized
+        ))
+    );
+}
+
+#[test]
+fn sell_shares_after_end_fails_with_market_ended() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+
+    kalshi.svm.warp(DAY);
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::Yes, 1, 0),
+        Err(program_error(ErrorCode::MarketEnded))
+    );
+}
+
+#[test]
+fn resolve_market_before_end_fails_with_market_not_ended() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    kalshi.svm.warp(DAY - 1);
+    assert_eq!(
+        kalshi.resolve(&creator.key, 0, Outcome::Yes),
+        Err(program_error(ErrorCode::MarketNotEnded))
+    );
+}
+
+#[test]
+fn resolve_market_after_deadline_fails() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    kalshi.svm.warp(2 * DAY + 1);
+    assert_eq!(
+        kalshi.resolve(&creator.key, 0, Outcome::Yes),
+        Err(program_error(ErrorCode::ResolutionDeadlinePassed))
+    );
+}
+
+#[test]
+fn resolve_market_requires_creator_or_protocol_authority() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let mallory = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.create_market(&creator, 1).unwrap();
+    kalshi.svm.warp(DAY);
+
+    assert_eq!(
+        kalshi.resolve(&mallory.key, 0, Outcome::Yes),
+        Err(program_error(ErrorCode::UnauthorizedOracle))
+    );
+
+    kalshi.resolve(&creator.key, 0, Outcome::Yes).unwrap();
+    let authority = kalshi.authority;
+    kalshi.resolve(&authority, 1, Outcome::No).unwrap();
+
+    let market = kalshi.market_state(0);
+    assert_eq!(market.status(), MarketStatus::Resolved);
+    assert_eq!(market.winning_outcome(), Some(Outcome::Yes));
+    assert_eq!(kalshi.market_state(1).winning_outcome(), Some(Outcome::No));
+}
+
+#[test]
+fn resolve_market_only_once() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.svm.warp(DAY);
+
+    kalshi.resolve(&creator.key, 0, Outcome::Yes).unwrap();
+    assert_eq!(
+        kalshi.resolve(&creator.key, 0, Outcome::No),
+        Err(program_error(ErrorCode::AlreadyResolved))
+    );
+}
+
+#[test]
+fn claim_winnings_pays_one_usdc_unit_per_winning_share() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 30 * USDC).unwrap();
+    kalshi.buy(&bob, 0, Outcome::No, 30 * USDC).unwrap();
+
+    assert_eq!(
+        kalshi.claim_winnings(&alice, 0),
+        Err(program_error(ErrorCode::MarketNotResolved))
+    );
+
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&creator.key, 0, Outcome::Yes).unwrap();
+
+    let shares = kalshi.position(0, &alice).yes_shares;
+    let wallet_before = kalshi.balance(&alice.token);
+    let vault_before = kalshi.vault_balance(0);
+    kalshi.claim_winnings(&alice, 0).unwrap();
+
+    assert_eq!(kalshi.balance(&alice.token), wallet_before + shares);
+    assert_eq!(kalshi.vault_balance(0), vault_before - shares);
+    assert_eq!(kalshi.position(0, &alice).yes_shares, 0);
+
+    assert_eq!(
+        kalshi.claim_winnings(&alice, 0),
+        Err(program_error(ErrorCode::NoWinningShares))
+    );
+    assert_eq!(
+        kalshi.claim_winnings(&bob, 0),
+        Err(program_error(ErrorCode::NoWinningShares))
+    );
+}
+
+#[test]
+fn claim_winnings_after_partial_sell_pays_remaining_shares() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 30 * USDC).unwrap();
+    let shares = kalshi.position(0, &alice).no_shares;
+    kalshi.sell(&alice, 0, Outcome::No, shares / 3, 0).unwrap();
+
+    kalshi.svm.warp(DAY);
+    kalshi
+        .resolve(&kalshi.authority.clone(), 0, Outcome::No)
+        .unwrap();
+
+    let wallet_before = kalshi.balance(&alice.token);
+    kalshi.claim_winnings(&alice, 0).unwrap();
+    assert_eq!(
+        kalshi.balance(&alice.token),
+        wallet_before + shares - shares / 3
+    );
+}
+
+#[test]
+fn invalidate_market_requires_protocol_authority() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    assert_eq!(
+        kalshi.invalidate(&creator.key, 0),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintRaw))
+    );
+
+    let authority = kalshi.authority;
+    kalshi.invalidate(&authority, 0).unwrap();
+    assert_eq!(kalshi.market_state(0).status(), MarketStatus::Invalid);
+}
+
+#[test]
+fn set_creation_bond_requires_protocol_authority() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    assert_eq!(
+        kalshi.set_creation_bond(&creator.key, 0),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+
+    let authority = kalshi.authority;
+    kalshi.set_creation_bond(&authority, 10 * USDC).unwrap();
+    assert_eq!(kalshi.protocol().creation_bond, 10 * USDC);
+}
+
+#[test]
+fn creation_bond_is_returned_when_the_market_resolves() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.set_creation_bond(&authority, 10 * USDC).unwrap();
+    kalshi.create_market(&creator, 0).unwrap();
+    assert_eq!(
+        kalshi.events::<backend::MarketCreated>()[0].creation_bond,
+        10 * USDC
+    );
+    assert_eq!(kalshi.market_state(0).creation_bond, 10 * USDC);
+    assert_eq!(kalshi.vault_balance(0), 110 * USDC);
+    assert_eq!(kalshi.balance(&creator.token), 890 * USDC);
+
+    // Later changes only apply to new markets.
+    kalshi.set_creation_bond(&authority, 50 * USDC).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 0, Outcome::Yes).unwrap();
+    assert_eq!(
+        kalshi.events::<backend::MarketResolved>()[0].bond_returned,
+        10 * USDC
+    );
+    assert_eq!(kalshi.market_state(0).creation_bond, 0);
+    assert_eq!(kalshi.balance(&creator.token), 900 * USDC);
+
+    let profile = kalshi.creator_profile(&creator).unwrap();
+    assert_eq!(profile.markets_created, 1);
+    assert_eq!(profile.markets_invalidated, 0);
+    kalshi.claim_winnings(&alice, 0).unwrap();
+}
+
+#[test]
+fn creation_bond_is_forfeited_when_the_market_is_invalidated() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.set_creation_bond(&authority, 10 * USDC).unwrap();
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.create_market(&creator, 1).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let treasury = kalshi.balance(&kalshi.treasury);
+
+    kalshi.invalidate(&authority, 0).unwrap();
+    let invalidated = kalshi.events::<backend::MarketInvalidated>();
+    assert_eq!(invalidated[0].creator, creator.key);
+    assert_eq!(invalidated[0].bond_forfeited, 10 * USDC);
+    assert_eq!(kalshi.balance(&kalshi.treasury), treasury + 10 * USDC);
+    assert_eq!(kalshi.market_state(0).creation_bond, 0);
+
+    let profile = kalshi.creator_profile(&creator).unwrap();
+    assert_eq!(profile.markets_created, 2);
+    assert_eq!(profile.markets_invalidated, 1);
+
+    // Holders are refunded from the pool, not the bond.
+    kalshi.claim_refund(&alice, 0).unwrap();
+    assert!(kalshi.vault_balance(0) >= kalshi.market_state(0).liability);
+}
+
+#[test]
+fn invalidate_market_rejects_settled_markets() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&creator.key, 0, Outcome::Yes).unwrap();
+
+    assert_eq!(
+        kalshi.invalidate(&authority, 0),
+        Err(program_error(ErrorCode::InvalidMarketState))
+    );
+}
+
+#[test]
+fn invalidated_market_halts_trading_and_resolution() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    kalshi.invalidate(&authority, 0).unwrap();
+
+    assert_eq!(
+        kalshi.buy(&alice, 0, Outcome::Yes, USDC),
+        Err(program_error(ErrorCode::MarketNotActive))
+    );
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::Yes, 1, 0),
+        Err(program_error(ErrorCode::MarketNotActive))
+    );
+    kalshi.svm.warp(DAY);
+    assert_eq!(
+        kalshi.resolve(&creator.key, 0, Outcome::Yes),
+        Err(program_error(ErrorCode::AlreadyResolved))
+    );
+    assert_eq!(
+        kalshi.claim_winnings(&alice, 0),
+        Err(program_error(ErrorCode::MarketNotResolved))
+    );
+}
+
+#[test]
+fn claim_refund_pays_half_a_unit_per_share_of_invalid_market() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 10 * USDC).unwrap();
+
+    assert_eq!(
+        kalshi.claim_refund(&alice, 0),
+        Err(program_error(ErrorCode::MarketNotInvalid))
+    );
+
+    kalshi.invalidate(&authority, 0).unwrap();
+    let position = kalshi.position(0, &alice);
+    let wallet_before = kalshi.balance(&alice.token);
+    kalshi.claim_refund(&alice, 0).unwrap();
+
+    assert_eq!(
+        kalshi.balance(&alice.token),
+        wallet_before + (position.yes_shares + position.no_shares) / 2
+    );
+    let position = kalshi.position(0, &alice);
+    assert_eq!((position.yes_shares, position.no_shares), (0, 0));
+    assert_eq!(
+        kalshi.claim_refund(&alice, 0),
+        Err(program_error(ErrorCode::NoPosition))
+    );
+}
+
+#[test]
+fn withdraw_residual_leaves_exactly_the_unclaimed_winnings() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 30 * USDC).unwrap();
+    kalshi.buy(&bob, 0, Outcome::No, 20 * USDC).unwrap();
+
+    assert_eq!(
+        kalshi.withdraw_residual(&creator, 0),
+        Err(program_error(ErrorCode::InvalidMarketState))
+    );
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&creator.key, 0, Outcome::Yes).unwrap();
+
+    let winnings = kalshi.position(0, &alice).yes_shares;
+    assert_eq!(kalshi.market_state(0).liability, winnings);
+    assert_eq!(
+        kalshi.withdraw_residual(&alice, 0),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+
+    let residual = kalshi.vault_balance(0) - winnings;
+    kalshi.withdraw_residual(&creator, 0).unwrap();
+    assert_eq!(kalshi.balance(&creator.token), 900 * USDC + residual);
+    assert_eq!(kalshi.vault_balance(0), winnings);
+    assert_eq!(
+        kalshi.withdraw_residual(&creator, 0),
+        Err(program_error(ErrorCode::NoResidual))
+    );
+
+    kalshi.claim_winnings(&alice, 0).unwrap();
+    assert_eq!(kalshi.market_state(0).liability, 0);
+    assert_eq!(kalshi.vault_balance(0), 0);
+}
+
+#[test]
+fn withdraw_residual_of_invalid_market_keeps_refunds_covered() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 30 * USDC).unwrap();
+    kalshi.buy(&bob, 0, Outcome::No, 3 * USDC).unwrap();
+    kalshi.buy(&bob, 0, Outcome::Yes, 7 * USDC).unwrap();
+    kalshi.invalidate(&authority, 0).unwrap();
+
+    kalshi.withdraw_residual(&creator, 0).unwrap();
+    assert_eq!(kalshi.vault_balance(0), kalshi.market_state(0).liability);
+
+    kalshi.claim_refund(&alice, 0).unwrap();
+    kalshi.claim_refund(&bob, 0).unwrap();
+    // Per-position rounding leaves at most one unit per claimant behind.
+    assert!(kalshi.market_state(0).liability <= 2);
+    assert_eq!(kalshi.vault_balance(0), kalshi.market_state(0).liability);
+}
+
+#[test]
+fn close_position_requires_redeemable_shares_to_be_claimed() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 10 * USDC).unwrap();
+
+    assert_eq!(
+        kalshi.close_position(&alice, 0),
+        Err(program_error(ErrorCode::PositionNotEmpty))
+    );
+
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&creator.key, 0, Outcome::No).unwrap();
+    assert_eq!(
+        kalshi.close_position(&alice, 0),
+        Err(program_error(ErrorCode::PositionNotEmpty))
+    );
+
+    // Losing YES shares are worthless and do not keep the position open.
+    kalshi.claim_winnings(&alice, 0).unwrap();
+    let position = position_pda(&market_pda(0), &alice.key);
+    let rent = kalshi.svm.lamports(&position);
+    let lamports_before = kalshi.svm.lamports(&alice.key);
+    kalshi.close_position(&alice, 0).unwrap();
+
+    assert!(kalshi.svm.get_account(&position).is_none());
+    assert_eq!(kalshi.svm.lamports(&alice.key), lamports_before + rent);
+}
+
+#[test]
+fn close_position_is_limited_to_its_owner() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let mallory = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let shares = kalshi.position(0, &alice).yes_shares;
+    kalshi.sell(&alice, 0, Outcome::Yes, shares, 0).unwrap();
+
+    let mut ix = kalshi.close_position_ix(&mallory, 0);
+    ix.accounts[1].pubkey = position_pda(&market_pda(0), &alice.key);
+    assert!(kalshi.svm.process_instruction(ix).is_err());
+
+    // An emptied position in a live market can be closed and reopened.
+    kalshi.close_position(&alice, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 10 * USDC).unwrap();
+    assert!(kalshi.position(0, &alice).no_shares > 0);
+}
+
+#[test]
+fn close_market_waits_out_the_grace_period_and_sweeps_the_vault() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+
+    assert_eq!(
+        kalshi.close_market(&creator.key, 0, &creator.key),
+        Err(program_error(ErrorCode::InvalidMarketState))
+    );
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&creator.key, 0, Outcome::Yes).unwrap();
+    kalshi.claim_winnings(&alice, 0).unwrap();
+    assert_eq!(
+        kalshi.close_market(&creator.key, 0, &creator.key),
+        Err(program_error(ErrorCode::GracePeriodActive))
+    );
+
+    kalshi.svm.warp(backend::MARKET_CLOSE_GRACE_PERIOD);
+    assert_eq!(
+        kalshi.close_market(&alice.key, 0, &creator.key),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+    assert!(kalshi.close_market(&authority, 0, &alice.key).is_err());
+
+    let market = market_pda(0);
+    let accounts = [
+        market,
+        market_state_pda(&market),
+        vault_pda(&market),
+        price_history_pda(&market),
+    ];
+    let rent: u64 = accounts.iter().map(|key| kalshi.svm.lamports(key)).sum();
+    let dust = kalshi.vault_balance(0);
+    let treasury_before = kalshi.balance(&kalshi.treasury);
+    let lamports_before = kalshi.svm.lamports(&creator.key);
+    kalshi.close_market(&authority, 0, &creator.key).unwrap();
+
+    assert_eq!(kalshi.events::<backend::MarketClosed>()[0].swept, dust);
+    assert_eq!(kalshi.balance(&kalshi.treasury), treasury_before + dust);
+    assert_eq!(kalshi.svm.lamports(&creator.key), lamports_before + rent);
+    for account in accounts {
+        assert!(kalshi.svm.get_account(&account).is_none());
+    }
+
+    // With the market gone, leftover positions can always be closed.
+    kalshi.close_position(&alice, 0).unwrap();
+}
+
+#[test]
+fn closed_market_ids_are_not_reused() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    kalshi.invalidate(&authority, 0).unwrap();
+    kalshi.svm.warp(backend::MARKET_CLOSE_GRACE_PERIOD);
+    kalshi.close_market(&authority, 0, &creator.key).unwrap();
+
+    // The successor gets a fresh id, so Alice's unclaimed position cannot
+    // carry into it.
+    assert_eq!(
+        kalshi.create_market(&creator, 0),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintSeeds))
+    );
+    kalshi.create_market(&creator, 1).unwrap();
+    kalshi.invalidate(&authority, 1).unwrap();
+    assert_eq!(
+        kalshi.claim_refund(&alice, 1),
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotInitialized
+        ))
+    );
+    kalshi.close_position(&alice, 0).unwrap();
+}
+
+#[test]
+fn failed_transaction_rolls_back_every_instruction() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    let buy = kalshi.buy_ix(&alice, 0, Outcome::Yes, 10 * USDC);
+    let bad_sell = kalshi.sell_ix(&alice, 0, Outcome::Yes, u64::MAX, 0);
+    assert_eq!(
+        kalshi.svm.process_transaction(&[buy, bad_sell]),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+    assert_eq!(kalshi.balance(&alice.token), 1_000 * USDC);
+    assert!(kalshi
+        .svm
+        .get_account(&position_pda(&market_pda(0), &alice.key))
+        .is_none());
+}
+
+/// Trailing bytes versioning added to Borsh accounts: `version` and `_reserved`.
+const VERSION_TAIL: usize = 1 + backend::ACCOUNT_RESERVED_LEN;
+
+fn assert_rent_exempt(kalshi: &Kalshi, key: &Pubkey) {
+    let account = kalshi.svm.get_account(key).unwrap();
+    assert!(account.lamports >= Rent::default().minimum_balance(account.data.len()));
+}
+
+#[test]
+fn migrate_position_rejects_positions_from_before_versioning() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    // Versioning put `opened_at` ahead of `bump`, so the old layout would be
+    // misread as a current position.
+    kalshi.write_unversioned_position(0, &alice, 10 * USDC);
+    assert_eq!(
+        kalshi.migrate_position(&creator.key, 0, &alice),
+        Err(program_error(ErrorCode::UnversionedAccount))
+    );
+
+    kalshi.buy(&bob, 0, Outcome::Yes, 10 * USDC).unwrap();
+    assert_eq!(
+        kalshi.position(0, &bob).version,
+        backend::UserPosition::VERSION
+    );
+    assert_eq!(
+        kalshi.migrate_position(&creator.key, 0, &bob),
+        Err(program_error(ErrorCode::AlreadyMigrated))
+    );
+}
+
+#[test]
+fn migrate_market_rejects_markets_from_before_versioning() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    assert_eq!(kalshi.market(0).version, backend::Market::VERSION);
+    assert_eq!(
+        kalshi.migrate_market(&creator.key, 0),
+        Err(program_error(ErrorCode::AlreadyMigrated))
+    );
+
+    // The old layout kept its reserves inline and has no `MarketState` to
+    // move them to, so it is refused rather than read as a current market.
+    kalshi.write_unversioned_market(1, &creator.key);
+    assert_eq!(
+        kalshi.migrate_market(&creator.key, 1),
+        Err(program_error(ErrorCode::UnversionedAccount))
+    );
+}
+
+#[test]
+fn migrate_protocol_upgrades_a_protocol_state_from_before_versioning() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let before = kalshi.protocol();
+    // What versioning appended leaves exactly the layout before it.
+    kalshi.downgrade(&protocol_state_pda(), VERSION_TAIL);
+    assert_eq!(
+        kalshi
+            .svm
+            .get_account(&protocol_state_pda())
+            .unwrap()
+            .data
+            .len(),
+        8 + 32 + 32 + 2 + 8 + 8 + 1
+    );
+    assert!(kalshi.create_market(&creator, 0).is_err());
+
+    let authority = kalshi.authority;
+    kalshi.migrate_protocol(&authority).unwrap();
+    let after = kalshi.protocol();
+    assert_eq!(after.version, backend::ProtocolState::VERSION);
+    assert_eq!(after.authority, before.authority);
+    assert_eq!(after.free_bps, before.free_bps);
+    assert_eq!(after.total_markets, before.total_markets);
+    assert_eq!(after.creation_bond, 0);
+    assert_rent_exempt(&kalshi, &protocol_state_pda());
+    assert_eq!(
+        kalshi.migrate_protocol(&authority),
+        Err(program_error(ErrorCode::AlreadyMigrated))
+    );
+
+    kalshi.create_market(&creator, 0).unwrap();
+}
+
+#[test]
+fn conditional_market_resolves_once_the_parent_meets_its_condition() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    let args = MarketArgs::new(1, kalshi.now()).conditional_on(0, Outcome::Yes);
+    kalshi.create_market_with(&creator, args).unwrap();
+    let created = kalshi.events::<backend::MarketCreated>();
+    assert_eq!(created[1].parent_market_id, Some(0));
+    assert_eq!(created[1].parent_outcome, Some(Outcome::Yes));
+    assert_eq!(kalshi.market_state(1).parent_outcome(), Some(Outcome::Yes));
+
+    kalshi.buy(&alice, 1, Outcome::No, 10 * USDC).unwrap();
+    kalshi.svm.warp(DAY);
+    assert_eq!(
+        kalshi.resolve(&authority, 1, Outcome::No),
+        Err(program_error(ErrorCode::ParentMarketUnresolved))
+    );
+
+    kalshi.resolve(&authority, 0, Outcome::Yes).unwrap();
+    kalshi.resolve(&authority, 1, Outcome::No).unwrap();
+    assert_eq!(kalshi.market_state(1).status(), MarketStatus::Resolved);
+    kalshi.claim_winnings(&alice, 1).unwrap();
+}
+
+#[test]
+fn conditional_market_is_invalidated_when_the_parent_resolves_otherwise() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let anyone = kalshi.user(0);
+    let authority = kalshi.authority;
+    kalshi.set_creation_bond(&authority, 10 * USDC).unwrap();
+    kalshi.create_market(&creator, 0).unwrap();
+    let args = MarketArgs::new(1, kalshi.now()).conditional_on(0, Outcome::Yes);
+    kalshi.create_market_with(&creator, args).unwrap();
+    kalshi.buy(&alice, 1, Outcome::Yes, 10 * USDC).unwrap();
+    let spent = 1_000 * USDC - kalshi.balance(&alice.token);
+
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 0, Outcome::No).unwrap();
+    kalshi.events::<backend::MarketInvalidated>();
+    // Settling a failed condition needs no oracle and happens before the
+    // child's own resolution window closes.
+    kalshi.resolve(&anyone.key, 1, Outcome::Yes).unwrap();
+    assert_eq!(
+        kalshi.events::<backend::MarketInvalidated>()[0].bond_forfeited,
+        0
+    );
+    assert_eq!(kalshi.market_state(1).status(), MarketStatus::Invalid);
+    // Both bonds are back; only the seed liquidity remains in the vaults.
+    assert_eq!(kalshi.balance(&creator.token), 800 * USDC);
+
+    assert_eq!(
+        kalshi.claim_winnings(&alice, 1),
+        Err(program_error(ErrorCode::MarketNotResolved))
+    );
+    kalshi.claim_refund(&alice, 1).unwrap();
+    assert!(1_000 * USDC - kalshi.balance(&alice.token) < spent);
+}
+
+#[test]
+fn conditional_market_stops_trading_once_its_condition_fails() {
+    use backend::TradeSide::Buy;
+
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    for (market_id, outcome) in [(1, Outcome::Yes), (2, Outcome::No)] {
+        let mut args = MarketArgs::new(market_id, kalshi.now()).conditional_on(0, outcome);
+        args.end_timestamp += 2 * DAY;
+        args.resolution_timestamp += 2 * DAY;
+        kalshi.create_market_with(&creator, args).unwrap();
+    }
+    kalshi.buy(&alice, 1, Outcome::Yes, 10 * USDC).unwrap();
+    kalshi
+        .place_exit_order(
+            &alice,
+            1,
+            ExitArgs::stop_loss(Outcome::Yes, 1, backend::PRICE_SCALE / 4),
+        )
+        .unwrap();
+
+    // Trading needs the parent to tell whether the condition still holds.
+    let mut ix = kalshi.buy_ix(&alice, 1, Outcome::No, USDC);
+    let parent = market_state_pda(&market_pda(0));
+    let meta = ix.accounts.iter_mut().find(|m| m.pubkey == parent).unwrap();
+    meta.pubkey = backend::ID;
+    assert_eq!(
+        kalshi.svm.process_instruction(ix),
+        Err(program_error(ErrorCode::ParentMarketMismatch))
+    );
+
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 0, Outcome::No).unwrap();
+
+    // Market 1 is void but not yet invalidated; nobody may trade it.
+    let shares = kalshi.shares(1, &alice).0;
+    assert_eq!(
+        kalshi.buy(&alice, 1, Outcome::No, USDC),
+        Err(program_error(ErrorCode::ConditionFailed))
+    );
+    assert_eq!(
+        kalshi.sell(&alice, 1, Outcome::Yes, shares, 0),
+        Err(program_error(ErrorCode::ConditionFailed))
+    );
+    assert_eq!(
+        kalshi.batch_trade(&alice, &[(1, leg(Outcome::No, Buy, USDC, 1))]),
+        Err(program_error(ErrorCode::ConditionFailed))
+    );
+    assert_eq!(
+        kalshi.execute_exit_order(&keeper, &alice, 1, 0),
+        Err(program_error(ErrorCode::ConditionFailed))
+    );
+
+    // Market 2's condition held, so it trades on.
+    kalshi.buy(&alice, 2, Outcome::No, USDC).unwrap();
+}
+
+#[test]
+fn conditional_market_requires_its_parent() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    let mut parent = MarketArgs::new(0, kalshi.now());
+    parent.resolution_timestamp += DAY;
+    kalshi.create_market_with(&creator, parent).unwrap();
+    kalshi.create_market(&creator, 1).unwrap();
+
+    // The parent could still be open when the child's deadline passes.
+    let args = MarketArgs::new(2, kalshi.now()).conditional_on(0, Outcome::Yes);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::InvalidParentMarket))
+    );
+
+    let args = MarketArgs::new(2, kalshi.now()).conditional_on(1, Outcome::No);
+    kalshi.create_market_with(&creator, args).unwrap();
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 1, Outcome::No).unwrap();
+
+    let mut ix = kalshi.resolve_ix(&authority, 2, Outcome::Yes);
+    ix.accounts[5].pubkey = market_state_pda(&market_pda(0));
+    assert_eq!(
+        kalshi.svm.process_instruction(ix),
+        Err(program_error(ErrorCode::ParentMarketMismatch))
+    );
+    kalshi.resolve(&authority, 2, Outcome::Yes).unwrap();
+}
+
+/// Creates markets `0..count` parlays may bet on: deep enough, resolved by
+/// an approved oracle, and with a full window of price history.
+fn create_parlay_markets(kalshi: &mut Kalshi, creator: &User, count: u64) {
+    let authority = kalshi.authority;
+    kalshi
+        .set_parlay_oracle(&authority, &creator.key, true)
+        .unwrap();
+    for market_id in 0..count {
+        let mut args = MarketArgs::new(market_id, kalshi.now());
+        args.initial_liquidity = backend::PARLAY_MIN_LEG_LIQUIDITY;
+        kalshi.create_market_with(creator, args).unwrap();
+    }
+    kalshi.svm.warp(backend::PARLAY_TWAP_WINDOW);
+}
+
+#[test]
+fn parlay_pays_the_compounded_odds_when_every_leg_wins() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let alice = kalshi.user(100 * USDC);
+    let authority = kalshi.authority;
+    create_parlay_markets(&mut kalshi, &creator, 2);
+    kalshi.fund_parlay_pool(&authority, 1_000 * USDC).unwrap();
+
+    let legs = [(0, Outcome::Yes), (1, Outcome::No)];
+    assert_eq!(
+        kalshi.open_parlay(&alice, 0, &legs, 10 * USDC, 40 * USDC),
+        Err(program_error(ErrorCode::SlippageExceeded))
+    );
+    kalshi.open_parlay(&alice, 0, &legs, 10 * USDC, 0).unwrap();
+    // Two even legs quadruple the stake left after the 1% fee.
+    let parlay = kalshi.parlay(&alice, 0);
+    assert_eq!(parlay.stake, 9_900_000);
+    assert_eq!(parlay.payout, 4 * 9_900_000);
+    assert_eq!(parlay.legs[1].price, backend::PRICE_SCALE / 2);
+    assert_eq!(kalshi.parlay_pool().liability, parlay.payout);
+    assert_eq!(kalshi.balance(&alice.token), 90 * USDC);
+
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 0, Outcome::Yes).unwrap();
+    assert_eq!(
+        kalshi.settle_parlay(&alice, 0),
+        Err(program_error(ErrorCode::ParlayNotSettled))
+    );
+    kalshi.resolve(&authority, 1, Outcome::No).unwrap();
+    kalshi.settle_parlay(&alice, 0).unwrap();
+    assert_eq!(
+        kalshi.events::<backend::ParlaySettled>()[0].payout,
+        4 * 9_900_000
+    );
+    assert_eq!(kalshi.balance(&alice.token), 90 * USDC + 4 * 9_900_000);
+    assert_eq!(kalshi.parlay_pool().liability, 0);
+    assert!(kalshi.svm.get_account(&parlay_pda(&alice.key, 0)).is_none());
+}
+
+#[test]
+fn parlay_loses_on_any_losing_leg_and_drops_voided_legs() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let alice = kalshi.user(100 * USDC);
+    let bob = kalshi.user(100 * USDC);
+    let authority = kalshi.authority;
+    create_parlay_markets(&mut kalshi, &creator, 3);
+    kalshi.fund_parlay_pool(&authority, 1_000 * USDC).unwrap();
+    kalshi
+        .open_parlay(
+            &alice,
+            0,
+            &[(0, Outcome::Yes), (1, Outcome::Yes)],
+            10 * USDC,
+            0,
+        )
+        .unwrap();
+    kalshi
+        .open_parlay(
+            &bob,
+            7,
+            &[(1, Outcome::Yes), (2, Outcome::Yes)],
+            10 * USDC,
+            0,
+        )
+        .unwrap();
+
+    // A losing leg decides the parlay while the other market is still open.
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 0, Outcome::No).unwrap();
+    kalshi.settle_parlay(&alice, 0).unwrap();
+    assert_eq!(kalshi.events::<backend::ParlaySettled>()[0].payout, 0);
+    assert_eq!(kalshi.balance(&alice.token), 90 * USDC);
+
+    kalshi.invalidate(&authority, 2).unwrap();
+    kalshi.resolve(&authority, 1, Outcome::Yes).unwrap();
+    kalshi.settle_parlay(&bob, 7).unwrap();
+    let settled = kalshi.events::<backend::ParlaySettled>();
+    assert_eq!(settled[0].legs_voided, 1);
+    assert_eq!(settled[0].payout, 2 * 9_900_000);
+    assert_eq!(kalshi.balance(&bob.token), 90 * USDC + 2 * 9_900_000);
+    assert_eq!(kalshi.parlay_pool().liability, 0);
+    assert_eq!(kalshi.parlay_pool().open_parlays, 0);
+}
+
+#[test]
+fn parlays_are_limited_by_the_pool() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let alice = kalshi.user(100 * USDC);
+    let authority = kalshi.authority;
+    create_parlay_markets(&mut kalshi, &creator, 2);
+    assert_eq!(
+        kalshi.fund_parlay_pool(&alice.key, 100 * USDC),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+    kalshi.fund_parlay_pool(&authority, 20 * USDC).unwrap();
+
+    assert_eq!(
+        kalshi.open_parlay(&alice, 0, &[(0, Outcome::Yes)], 10 * USDC, 0),
+        Err(program_error(ErrorCode::InvalidParlay))
+    );
+    assert_eq!(
+        kalshi.open_parlay(
+            &alice,
+            0,
+            &[(0, Outcome::Yes), (0, Outcome::No)],
+            10 * USDC,
+            0
+        ),
+        Err(program_error(ErrorCode::InvalidParlay))
+    );
+    let legs = [(0, Outcome::Yes), (1, Outcome::Yes)];
+    assert_eq!(
+        kalshi.open_parlay(&alice, 0, &legs, 10 * USDC, 0),
+        Err(program_error(ErrorCode::ParlayPoolInsufficient))
+    );
+    kalshi.open_parlay(&alice, 0, &legs, 5 * USDC, 0).unwrap();
+
+    // Only collateral not reserved for the open parlay can leave the pool.
+    let free = kalshi.balance(&parlay_vault_pda()) - kalshi.parlay_pool().liability;
+    assert_eq!(
+        kalshi.withdraw_parlay_pool(&authority, free + 1),
+        Err(program_error(ErrorCode::ParlayPoolInsufficient))
+    );
+    let treasury = kalshi.balance(&kalshi.treasury);
+    kalshi.withdraw_parlay_pool(&authority, free).unwrap();
+    assert_eq!(kalshi.balance(&kalshi.treasury), treasury + free);
+}
+
+#[test]
+fn markets_with_open_parlays_cannot_close() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let alice = kalshi.user(100 * USDC);
+    let authority = kalshi.authority;
+    create_parlay_markets(&mut kalshi, &creator, 2);
+    kalshi.fund_parlay_pool(&authority, 1_000 * USDC).unwrap();
+    let legs = [(0, Outcome::Yes), (1, Outcome::Yes)];
+    kalshi.open_parlay(&alice, 0, &legs, 10 * USDC, 0).unwrap();
+    assert_eq!(kalshi.market_state(0).open_parlay_legs, 1);
+
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 0, Outcome::Yes).unwrap();
+    kalshi.resolve(&authority, 1, Outcome::Yes).unwrap();
+    kalshi.svm.warp(backend::MARKET_CLOSE_GRACE_PERIOD);
+    assert_eq!(
+        kalshi.close_market(&authority, 0, &creator.key),
+        Err(program_error(ErrorCode::MarketHasOpenParlays))
+    );
+
+    kalshi.settle_parlay(&alice, 0).unwrap();
+    assert_eq!(kalshi.market_state(0).open_parlay_legs, 0);
+    kalshi.close_market(&authority, 0, &creator.key).unwrap();
+}
+
+#[test]
+fn parlays_only_bet_on_trusted_deep_markets() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let alice = kalshi.user(100 * USDC);
+    let authority = kalshi.authority;
+    kalshi.fund_parlay_pool(&authority, 1_000 * USDC).unwrap();
+    let mut args = MarketArgs::new(0, kalshi.now());
+    args.initial_liquidity = backend::PARLAY_MIN_LEG_LIQUIDITY;
+    kalshi.create_market_with(&creator, args).unwrap();
+    kalshi.create_market(&creator, 1).unwrap();
+    let legs = [(0, Outcome::Yes), (1, Outcome::Yes)];
+
+    // The creator of market 0 could resolve it however they like.
+    assert_eq!(
+        kalshi.open_parlay(&alice, 0, &legs, 10 * USDC, 0),
+        Err(program_error(ErrorCode::UntrustedParlayOracle))
+    );
+    kalshi
+        .set_parlay_oracle(&authority, &creator.key, true)
+        .unwrap();
+    // Market 1 only has the default liquidity.
+    assert_eq!(
+        kalshi.open_parlay(&alice, 0, &legs, 10 * USDC, 0),
+        Err(program_error(ErrorCode::ParlayLegTooThin))
+    );
+
+    kalshi
+        .set_parlay_oracle(&authority, &creator.key, false)
+        .unwrap();
+    assert_eq!(
+        kalshi.open_parlay(
+            &alice,
+            0,
+            &[(0, Outcome::Yes), (0, Outcome::No)],
+            10 * USDC,
+            0
+        ),
+        Err(program_error(ErrorCode::UntrustedParlayOracle))
+    );
+}
+
+#[test]
+fn parlay_legs_are_priced_at_their_time_weighted_price() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let whale = kalshi.user(100_000 * USDC);
+    let alice = kalshi.user(100 * USDC);
+    let authority = kalshi.authority;
+    create_parlay_markets(&mut kalshi, &creator, 3);
+    kalshi.fund_parlay_pool(&authority, 1_000 * USDC).unwrap();
+
+    // Pushing YES up just before opening does not lengthen the odds on NO.
+    kalshi.buy(&whale, 0, Outcome::Yes, 500 * USDC).unwrap();
+    kalshi
+        .open_parlay(
+            &alice,
+            0,
+            &[(0, Outcome::No), (1, Outcome::Yes)],
+            10 * USDC,
+            0,
+        )
+        .unwrap();
+    let parlay = kalshi.parlay(&alice, 0);
+    assert_eq!(parlay.legs[0].price, backend::PRICE_SCALE / 2);
+    assert_eq!(parlay.payout, 4 * 9_900_000);
+
+    // Nor does a move the average has not caught up with shorten YES.
+    let state = kalshi.market_state(0);
+    let spot =
+        state.no_liquidity * backend::PRICE_SCALE / (state.yes_liquidity + state.no_liquidity);
+    kalshi
+        .open_parlay(
+            &alice,
+            1,
+            &[(0, Outcome::Yes), (1, Outcome::Yes)],
+            10 * USDC,
+            0,
+        )
+        .unwrap();
+    assert_eq!(kalshi.parlay(&alice, 1).legs[0].price, spot);
+
+    // Once a long shot has been one for a whole window, it is too long.
+    while {
+        let state = kalshi.market_state(2);
+        state.no_liquidity * 20 < state.yes_liquidity * 19 + state.no_liquidity * 19
+    } {
+        kalshi.buy(&whale, 2, Outcome::Yes, 1_000 * USDC).unwrap();
+    }
+    kalshi.svm.warp(backend::PARLAY_TWAP_WINDOW);
+    assert_eq!(
+        kalshi.open_parlay(
+            &alice,
+            2,
+            &[(1, Outcome::Yes), (2, Outcome::No)],
+            10 * USDC,
+            0
+        ),
+        Err(program_error(ErrorCode::ParlayOddsTooLong))
+    );
+    kalshi
+        .open_parlay(
+            &alice,
+            2,
+            &[(1, Outcome::Yes), (2, Outcome::Yes)],
+            10 * USDC,
+            0,
+        )
+        .unwrap();
+}
+
+#[test]
+fn parlay_legs_are_priced_over_the_history_they_have() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(10_000 * USDC);
+    let spammer = kalshi.user(100 * USDC);
+    let alice = kalshi.user(100 * USDC);
+    let authority = kalshi.authority;
+    kalshi
+        .set_parlay_oracle(&authority, &creator.key, true)
+        .unwrap();
+    for market_id in 0..2 {
+        let mut args = MarketArgs::new(market_id, kalshi.now());
+        args.initial_liquidity = backend::PARLAY_MIN_LEG_LIQUIDITY;
+        kalshi.create_market_with(&creator, args).unwrap();
+    }
+    kalshi.fund_parlay_pool(&authority, 1_000 * USDC).unwrap();
+    let legs = [(0, Outcome::Yes), (1, Outcome::Yes)];
+
+    // Markets younger than the window are averaged over their life so far.
+    kalshi.open_parlay(&alice, 0, &legs, 10 * USDC, 0).unwrap();
+    assert_eq!(
+        kalshi.parlay(&alice, 0).legs[0].price,
+        backend::PRICE_SCALE / 2
+    );
+
+    // Dust trades overwriting the whole history within the window only
+    // shorten the span averaged over; they do not lock parlays out.
+    kalshi.svm.warp(backend::PARLAY_TWAP_WINDOW);
+    for _ in 0..=backend::PRICE_HISTORY_LEN {
+        kalshi.svm.warp(1);
+        kalshi.buy(&spammer, 0, Outcome::Yes, USDC / 100).unwrap();
+    }
+    let history = kalshi.price_history(0);
+    assert_eq!(history.len as usize, backend::PRICE_HISTORY_LEN);
+    assert!(history
+        .snapshots()
+        .all(|snapshot| { snapshot.timestamp > kalshi.now() - backend::PARLAY_TWAP_WINDOW }));
+    kalshi.open_parlay(&alice, 1, &legs, 10 * USDC, 0).unwrap();
+    let state = kalshi.market_state(0);
+    let spot =
+        state.no_liquidity * backend::PRICE_SCALE / (state.yes_liquidity + state.no_liquidity);
+    assert_eq!(kalshi.parlay(&alice, 1).legs[0].price, spot);
+}
+
+#[test]
+fn parlay_pool_only_takes_registered_collateral() {
+    let mut kalshi = Kalshi::new();
+    let authority = kalshi.authority;
+    let mint = kalshi.svm.create_mint(&authority, 6);
+    assert_eq!(
+        kalshi.fund_parlay_pool_in(&authority, &mint, 100 * USDC),
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotInitialized
+        ))
+    );
+    kalshi.fund_parlay_pool(&authority, 100 * USDC).unwrap();
+}
+
+#[test]
+fn series_spawns_each_market_when_the_previous_one_ends() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    kalshi
+        .create_series(&creator, SeriesArgs::daily(kalshi.now()))
+        .unwrap();
+    assert_eq!(kalshi.protocol().total_series, 1);
+    assert_eq!(kalshi.series(0).last_end_timestamp, GENESIS);
+
+    // Spawned markets are funded through the creator's delegation.
+    assert!(kalshi.spawn_next_market(&keeper.key, 0).is_err());
+    kalshi.approve_series(&creator, 0, 300 * USDC).unwrap();
+    kalshi.spawn_next_market(&keeper.key, 0).unwrap();
+    let created = kalshi.events::<backend::MarketCreated>();
+    assert_eq!(created[0].series_id, Some(0));
+    assert_eq!(created[0].series_sequence, Some(1));
+    let market = kalshi.market(0);
+    assert_eq!(market.question, "Will BTC close above $100k on 2023-11-15?");
+    assert_eq!(
+        market.description,
+        "Day 1: resolves YES if the daily close is above $100,000."
+    );
+    assert_eq!((market.series_id, market.series_sequence), (0, 1));
+    assert_eq!(market.authority, creator.key);
+    assert_eq!(kalshi.market_state(0).end_timestamp, GENESIS + DAY);
+    assert_eq!(kalshi.balance(&creator.token), 900 * USDC);
+
+    assert_eq!(
+        kalshi.spawn_next_market(&keeper.key, 0),
+        Err(program_error(ErrorCode::SeriesMarketNotEnded))
+    );
+
+    // A late spawn still ends on the series' schedule, skipping missed days.
+    kalshi.svm.warp(DAY + 3 * 3_600);
+    kalshi.spawn_next_market(&keeper.key, 0).unwrap();
+    assert_eq!(kalshi.market(1).series_sequence, 2);
+    // The rendered text is logged, as no instruction carries it.
+    let spawned = kalshi.events::<backend::SeriesMarketSpawned>();
+    assert_eq!(spawned[0].question, kalshi.market(1).question);
+    assert_eq!(spawned[0].description, kalshi.market(1).description);
+    assert_eq!(kalshi.market_state(1).end_timestamp, GENESIS + 2 * DAY);
+    kalshi.svm.warp(2 * DAY);
+    kalshi.spawn_next_market(&keeper.key, 0).unwrap();
+    assert_eq!(
+        kalshi.market(2).question,
+        "Will BTC close above $100k on 2023-11-18?"
+    );
+    assert_eq!(kalshi.market_state(2).end_timestamp, GENESIS + 4 * DAY);
+
+    let series = kalshi.series(0);
+    assert_eq!((series.sequence, series.last_market_id), (3, 2));
+    assert_eq!(kalshi.creator_profile(&creator).unwrap().markets_created, 3);
+
+    // The delegation is used up.
+    kalshi.svm.warp(DAY);
+    assert!(kalshi.spawn_next_market(&keeper.key, 0).is_err());
+}
+
+#[test]
+fn create_series_validates_the_template() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let now = kalshi.now();
+
+    let mut args = SeriesArgs::daily(now);
+    args.duration = 0;
+    assert_eq!(
+        kalshi.create_series(&creator, args),
+        Err(program_error(ErrorCode::InvalidSeriesDuration))
+    );
+    let mut args = SeriesArgs::daily(now);
+    args.resolution_window = 8 * DAY;
+    assert_eq!(
+        kalshi.create_series(&creator, args),
+        Err(program_error(ErrorCode::ResolutionTooLate))
+    );
+    let mut args = SeriesArgs::daily(now);
+    args.first_end_timestamp = now;
+    assert_eq!(
+        kalshi.create_series(&creator, args),
+        Err(program_error(ErrorCode::InvalidEndTime))
+    );
+    let mut args = SeriesArgs::daily(now);
+    args.question = "{date}".repeat(33);
+    assert_eq!(
+        kalshi.create_series(&creator, args),
+        Err(program_error(ErrorCode::QuestionTooLong))
+    );
+    kalshi
+        .create_series(&creator, SeriesArgs::daily(now))
+        .unwrap();
+}
+
+#[test]
+fn markets_in_another_collateral_pay_fees_to_its_treasury() {
+    const SOL: u64 = 1_000_000_000;
+    let mut kalshi = Kalshi::new();
+    let authority = kalshi.authority;
+    let wsol = kalshi.svm.create_mint(&authority, 9);
+    let sol_treasury = kalshi.svm.create_token_account(&wsol, &authority, 0);
+    kalshi
+        .set_collateral(&authority, &wsol, &sol_treasury, SOL / 10, true)
+        .unwrap();
+    let collateral: backend::Collateral = kalshi.svm.account(&collateral_pda(&wsol));
+    assert_eq!(collateral.decimals, 9);
+    assert_eq!(collateral.treasury, sol_treasury);
+
+    let creator = kalshi.user(0);
+    let creator = kalshi.wallet(&creator, &wsol, 10 * SOL);
+    let alice = kalshi.user(0);
+    let alice = kalshi.wallet(&alice, &wsol, 10 * SOL);
+    let mut args = MarketArgs::new(0, kalshi.now()).in_mint(wsol);
+    args.initial_liquidity = SOL / 20;
+    assert_eq!(
+        kalshi.create_market_with(&creator, args.clone()),
+        Err(program_error(ErrorCode::InsufficientInitialLiquidity))
+    );
+    args.initial_liquidity = SOL;
+    kalshi.create_market_with(&creator, args).unwrap();
+
+    kalshi.buy(&alice, 0, Outcome::Yes, SOL).unwrap();
+    assert_eq!(kalshi.balance(&sol_treasury), SOL / 100);
+    assert_eq!(kalshi.balance(&kalshi.treasury), 0);
+
+    kalshi.svm.warp(DAY);
+    kalshi.resolve(&authority, 0, Outcome::Yes).unwrap();
+    kalshi.claim_winnings(&alice, 0).unwrap();
+    assert!(kalshi.balance(&alice.token) > 10 * SOL);
+}
+
+#[test]
+fn create_market_requires_an_enabled_collateral() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let authority = kalshi.authority;
+    let usdt = kalshi.svm.create_mint(&authority, 6);
+    let usdt_treasury = kalshi.svm.create_token_account(&usdt, &authority, 0);
+    let usdt_creator = kalshi.wallet(&creator, &usdt, 1_000 * USDC);
+
+    let args = MarketArgs::new(0, kalshi.now()).in_mint(usdt);
+    assert_eq!(
+        kalshi.create_market_with(&usdt_creator, args),
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotInitialized
+        ))
+    );
+    assert_eq!(
+        kalshi.set_collateral(&creator.key, &usdt, &usdt_treasury, USDC, true),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+    let treasury = kalshi.treasury;
+    assert_eq!(
+        kalshi.set_collateral(&authority, &usdt, &treasury, USDC, true),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintRaw))
+    );
+    kalshi
+        .set_collateral(&authority, &usdt, &usdt_treasury, USDC, true)
+        .unwrap();
+    let args = MarketArgs::new(0, kalshi.now()).in_mint(usdt);
+    kalshi.create_market_with(&usdt_creator, args).unwrap();
+
+    // Disabling a mint stops new markets but not trading in existing ones.
+    kalshi.create_market(&creator, 1).unwrap();
+    let mint = kalshi.mint;
+    kalshi
+        .set_collateral(&authority, &mint, &treasury, USDC, false)
+        .unwrap();
+    assert_eq!(
+        kalshi.create_market(&creator, 2),
+        Err(program_error(ErrorCode::CollateralNotAllowed))
+    );
+    kalshi.buy(&alice, 1, Outcome::No, 10 * USDC).unwrap();
+}
+
+/// A Token-2022 mint charging `bps` on every transfer, registered as
+/// collateral with its own treasury.
+fn transfer_fee_collateral(kalshi: &mut Kalshi, bps: u16) -> (Pubkey, Pubkey) {
+    let authority = kalshi.authority;
+    let mint = kalshi.svm.create_mint_2022(
+        &authority,
+        6,
+        &[spl_token_2022::extension::ExtensionType::TransferFeeConfig],
+        |mint| {
+            vec![
+                spl_token_2022::extension::transfer_fee::instruction::initialize_transfer_fee_config(
+                    &spl_token_2022::ID,
+                    mint,
+                    Some(&authority),
+                    Some(&authority),
+                    bps,
+                    u64::MAX,
+                )
+                .unwrap(),
+            ]
+        },
+    );
+    let treasury = kalshi.svm.create_token_account(&mint, &authority, 0);
+    kalshi
+        .set_collateral(&authority, &mint, &treasury, USDC, true)
+        .unwrap();
+    (mint, treasury)
+}
+
+#[test]
+fn transfer_fee_collateral_credits_vaults_exactly() {
+    let mut kalshi = Kalshi::new();
+    let (mint, treasury) = transfer_fee_collateral(&mut kalshi, 50);
+    let creator = kalshi.user(0);
+    let creator = kalshi.wallet(&creator, &mint, 1_000 * USDC);
+    let alice = kalshi.user(0);
+    let alice = kalshi.wallet(&alice, &mint, 1_000 * USDC);
+
+    // The creator pays the 0.5% transfer fee on top of the liquidity.
+    let mut args = MarketArgs::new(0, kalshi.now()).in_mint(mint);
+    args.initial_liquidity = 100 * USDC;
+    kalshi.create_market_with(&creator, args).unwrap();
+    assert_eq!(kalshi.vault_balance(0), 100 * USDC);
+    assert_eq!(kalshi.balance(&creator.token), 900 * USDC - 502_513);
+
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let state = kalshi.market_state(0);
+    assert_eq!(
+        kalshi.vault_balance(0),
+        100 * USDC + state.total_volume - state.total_fees
+    );
+    assert!(1_000 * USDC - kalshi.balance(&alice.token) > state.total_volume);
+    // Payouts to the treasury bear the fee like any other transfer.
+    assert!(kalshi.balance(&treasury) < state.total_fees);
+
+    kalshi.svm.warp(DAY);
+    let authority = kalshi.authority;
+    kalshi.resolve(&authority, 0, Outcome::Yes).unwrap();
+    kalshi.claim_winnings(&alice, 0).unwrap();
+
+    // Closing harvests the fees withheld in the vault first.
+    kalshi.svm.warp(31 * DAY);
+    kalshi.close_market(&authority, 0, &creator.key).unwrap();
+    assert!(kalshi.svm.get_account(&vault_pda(&market_pda(0))).is_none());
+}
+
+#[test]
+fn create_market_rejects_unsupported_mint_extensions() {
+    let mut kalshi = Kalshi::new();
+    let authority = kalshi.authority;
+    let mint = kalshi.svm.create_mint_2022(
+        &authority,
+        6,
+        &[spl_token_2022::extension::ExtensionType::PermanentDelegate],
+        |mint| {
+            vec![spl_token_2022::instruction::initialize_permanent_delegate(
+                &spl_token_2022::ID,
+                mint,
+                &authority,
+            )
+            .unwrap()]
+        },
+    );
+    let treasury = kalshi.svm.create_token_account(&mint, &authority, 0);
+    kalshi
+        .set_collateral(&authority, &mint, &treasury, USDC, true)
+        .unwrap();
+    let creator = kalshi.user(0);
+    let creator = kalshi.wallet(&creator, &mint, 1_000 * USDC);
+
+    let args = MarketArgs::new(0, kalshi.now()).in_mint(mint);
+    assert_eq!(
+        kalshi.create_market_with(&creator, args),
+        Err(program_error(ErrorCode::UnsupportedMintExtension))
+    );
+
+    // Transfer fees are supported.
+    let (mint, _) = transfer_fee_collateral(&mut kalshi, 100);
+    let creator = kalshi.wallet(&creator, &mint, 1_000 * USDC);
+    let args = MarketArgs::new(0, kalshi.now()).in_mint(mint);
+    kalshi.create_market_with(&creator, args).unwrap();
+}
+
+#[test]
+fn delegates_trade_for_their_owner_within_limits() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let session = Pubkey::new_unique();
+    kalshi.svm.airdrop(&session, 1_000_000_000);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.create_market(&creator, 1).unwrap();
+
+    assert_eq!(
+        kalshi.buy_as(&session, &alice, 0, Outcome::Yes, 10 * USDC),
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotInitialized
+        ))
+    );
+    let now = kalshi.now();
+    assert_eq!(
+        kalshi.approve_delegate(&alice, &session, 20 * USDC, now, vec![0]),
+        Err(program_error(ErrorCode::InvalidDelegate))
+    );
+    kalshi
+        .approve_delegate(&alice, &session, 20 * USDC, now + DAY / 2, vec![0])
+        .unwrap();
+
+    // The session key trades on alice's position with alice's funds.
+    kalshi
+        .buy_as(&session, &alice, 0, Outcome::Yes, 10 * USDC)
+        .unwrap();
+    let (yes, _) = kalshi.shares(0, &alice);
+    assert!(yes > 0);
+    let spent = 1_000 * USDC - kalshi.balance(&alice.token);
+    assert_eq!(kalshi.trading_delegate(&alice, &session).spent, spent);
+    assert_eq!(
+        kalshi.buy_as(&session, &alice, 1, Outcome::Yes, 10 * USDC),
+        Err(program_error(ErrorCode::DelegateMarketNotAllowed))
+    );
+    assert_eq!(
+        kalshi.buy_as(&session, &alice, 0, Outcome::Yes, 15 * USDC),
+        Err(program_error(ErrorCode::DelegateSpendExceeded))
+    );
+
+    // Another key cannot borrow alice's delegation.
+    let mallory = Pubkey::new_unique();
+    kalshi.svm.airdrop(&mallory, 1_000_000_000);
+    let mut ix = kalshi.buy_as_ix(&mallory, &alice, 0, Outcome::Yes, USDC);
+    let delegate = trading_delegate_pda(&alice.key, &mallory);
+    let meta = ix
+        .accounts
+        .iter_mut()
+        .find(|meta| meta.pubkey == delegate)
+        .unwrap();
+    meta.pubkey = trading_delegate_pda(&alice.key, &session);
+    assert_eq!(
+        kalshi.svm.process_instruction(ix),
+        Err(anchor_error(anchor_lang::error::ErrorCode::ConstraintSeeds))
+    );
+
+    // Sale proceeds go to alice, never to the session key.
+    let balance = kalshi.balance(&alice.token);
+    kalshi
+        .sell_as(&session, &alice, 0, Outcome::Yes, yes / 2, 0)
+        .unwrap();
+    assert!(kalshi.balance(&alice.token) > balance);
+
+    kalshi.svm.warp(DAY / 2);
+    assert_eq!(
+        kalshi.sell_as(&session, &alice, 0, Outcome::Yes, 1, 0),
+        Err(program_error(ErrorCode::DelegateExpired))
+    );
+
+    kalshi.revoke_delegate(&alice, &session).unwrap();
+    assert!(kalshi
+        .svm
+        .get_account(&trading_delegate_pda(&alice.key, &session))
+        .is_none());
+    let wallet = kalshi.svm.get_account(&alice.token).unwrap();
+    let wallet = spl_token::state::Account::unpack(&wallet.data).unwrap();
+    assert!(wallet.delegate.is_none());
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+}
+
+#[test]
+fn delegates_do_not_silently_replace_another_allowance() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let expires_at = kalshi.now() + DAY;
+
+    kalshi
+        .approve_delegate(&alice, &first, 20 * USDC, expires_at, vec![])
+        .unwrap();
+    assert_eq!(
+        kalshi.approve_delegate(&alice, &second, 20 * USDC, expires_at, vec![]),
+        Err(program_error(ErrorCode::DelegateConflict))
+    );
+    // The same key can still be re-approved, and a revoked slot is free.
+    kalshi
+        .approve_delegate(&alice, &first, 30 * USDC, expires_at, vec![])
+        .unwrap();
+    kalshi.revoke_delegate(&alice, &first).unwrap();
+    kalshi
+        .approve_delegate(&alice, &second, 20 * USDC, expires_at, vec![])
+        .unwrap();
+
+    // A wallet funding a series cannot also back a session key.
+    kalshi
+        .create_series(&creator, SeriesArgs::daily(kalshi.now()))
+        .unwrap();
+    kalshi.approve_series(&creator, 0, 100 * USDC).unwrap();
+    assert_eq!(
+        kalshi.approve_delegate(&creator, &first, 20 * USDC, expires_at, vec![]),
+        Err(program_error(ErrorCode::DelegateConflict))
+    );
+}
+
+#[test]
+fn positions_transfer_shares_and_their_cost_basis() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 20 * USDC).unwrap();
+    kalshi.buy(&alice, 0, Outcome::No, 10 * USDC).unwrap();
+    let before = kalshi.position(0, &alice);
+
+    assert_eq!(
+        kalshi.transfer_position(&alice, 0, &alice, 1, 0),
+        Err(program_error(ErrorCode::InvalidRecipient))
+    );
+    assert_eq!(
+        kalshi.transfer_position(&alice, 0, &bob, before.yes_shares + 1, 0),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+
+    let yes = before.yes_shares / 2;
+    kalshi.events::<backend::PositionTransferred>();
+    kalshi.transfer_position(&alice, 0, &bob, yes, 0).unwrap();
+    let event = kalshi.events::<backend::PositionTransferred>().remove(0);
+    assert_eq!((event.from, event.to), (alice.key, bob.key));
+    assert_eq!((event.yes_shares, event.no_shares), (yes, 0));
+
+    let (after, received) = (kalshi.position(0, &alice), kalshi.position(0, &bob));
+    assert_eq!(received.user, bob.key);
+    assert_eq!((received.yes_shares, received.no_shares), (yes, 0));
+    assert_eq!(received.total_invested, event.total_invested);
+    assert_eq!(
+        after.total_invested + received.total_invested,
+        before.total_invested
+    );
+    assert_eq!(
+        received.total_invested,
+        before.total_invested * yes / (before.yes_shares + before.no_shares)
+    );
+
+    // Both holders redeem their part once the market resolves.
+    kalshi.svm.warp(DAY);
+    let authority = kalshi.authority;
+    kalshi.resolve(&authority, 0, Outcome::Yes).unwrap();
+    kalshi.claim_winnings(&bob, 0).unwrap();
+    assert_eq!(kalshi.balance(&bob.token), yes);
+    let balance = kalshi.balance(&alice.token);
+    kalshi.claim_winnings(&alice, 0).unwrap();
+    assert_eq!(
+        kalshi.balance(&alice.token),
+        balance + before.yes_shares - yes
+    );
+}
+
+#[test]
+fn gated_positions_only_transfer_to_attested_wallets() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(0);
+    let kyc = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi
+        .set_access_policy(
+            &creator.key,
+            0,
+            AccessPolicy::Attestation,
+            kyc.key.to_bytes(),
+        )
+        .unwrap();
+    kalshi.issue_attestation(&kyc.key, &alice, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+
+    assert_eq!(
+        kalshi.transfer_position(&alice, 0, &bob, 1, 0),
+        Err(program_error(ErrorCode::AccessDenied))
+    );
+    kalshi.issue_attestation(&kyc.key, &bob, 0).unwrap();
+    kalshi.transfer_position(&alice, 0, &bob, 1, 0).unwrap();
+    assert_eq!(kalshi.shares(0, &bob), (1, 0));
+}
+
+fn leg(outcome: Outcome, side: backend::TradeSide, amount: u64, limit: u64) -> backend::TradeLeg {
+    backend::TradeLeg {
+        outcome,
+        side,
+        amount,
+        limit,
+        // Filled in by `batch_trade_ix`.
+        vault_bump: 0,
+        position_bump: 0,
+    }
+}
+
+#[test]
+fn batch_trades_fill_like_single_trades_with_one_fee_transfer() {
+    use backend::TradeSide::{Buy, Sell};
+
+    // The same trades, once one at a time and once as a batch.
+    let mut single = Kalshi::new();
+    let mut batch = Kalshi::new();
+    let mut traders = Vec::new();
+    for kalshi in [&mut single, &mut batch] {
+        let creator = kalshi.user(1_000 * USDC);
+        let alice = kalshi.user(1_000 * USDC);
+        for market_id in 0..3 {
+            kalshi.create_market(&creator, market_id).unwrap();
+        }
+        kalshi.buy(&alice, 2, Outcome::Yes, 20 * USDC).unwrap();
+        traders.push(alice);
+    }
+    let (alice, bob) = (traders[0], traders[1]);
+    let held = single.shares(2, &alice).0;
+    assert_eq!(batch.shares(2, &bob).0, held);
+
+    single.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    single.buy(&alice, 1, Outcome::No, 5 * USDC).unwrap();
+    single.sell(&alice, 2, Outcome::Yes, held / 2, 0).unwrap();
+
+    let treasury = batch.balance(&batch.treasury);
+    let booked: u64 = (0..3).map(|id| batch.market_state(id).total_fees).sum();
+    batch.events::<backend::SharesBought>();
+    batch
+        .batch_trade(
+            &bob,
+            &[
+                (0, leg(Outcome::Yes, Buy, 10 * USDC, 1)),
+                (1, leg(Outcome::No, Buy, 5 * USDC, 1)),
+                (2, leg(Outcome::Yes, Sell, held / 2, 1)),
+            ],
+        )
+        .unwrap();
+
+    for market_id in 0..3 {
+        let (expected, actual) = (
+            single.market_state(market_id),
+            batch.market_state(market_id),
+        );
+        assert_eq!(
+            (expected.yes_liquidity, expected.no_liquidity),
+            (actual.yes_liquidity, actual.no_liquidity)
+        );
+        assert_eq!(expected.total_fees, actual.total_fees);
+        assert_eq!(
+            single.vault_balance(market_id),
+            batch.vault_balance(market_id)
+        );
+        assert_eq!(
+            single.shares(market_id, &alice),
+            batch.shares(market_id, &bob)
+        );
+        assert_eq!(
+            single.position(market_id, &alice).total_invested,
+            batch.position(market_id, &bob).total_invested
+        );
+    }
+    assert_eq!(single.balance(&alice.token), batch.balance(&bob.token));
+    assert_eq!(
+        single.balance(&single.treasury),
+        batch.balance(&batch.treasury)
+    );
+    assert_eq!(
+        single.protocol().total_volume,
+        batch.protocol().total_volume
+    );
+
+    let fees: u64 = (0..3)
+        .map(|id| batch.market_state(id).total_fees)
+        .sum::<u64>()
+        - booked;
+    assert_eq!(batch.balance(&batch.treasury), treasury + fees);
+    assert_eq!(batch.events::<backend::SharesBought>().len(), 2);
+}
+
+#[test]
+fn batch_trades_are_all_or_nothing() {
+    use backend::TradeSide::{Buy, Sell};
+
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let kyc = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.create_market(&creator, 1).unwrap();
+    let buy_both = |limit: u64| {
+        [
+            (0, leg(Outcome::Yes, Buy, 10 * USDC, 1)),
+            (1, leg(Outcome::No, Buy, 10 * USDC, limit)),
+        ]
+    };
+
+    assert_eq!(
+        kalshi.batch_trade(&alice, &[]),
+        Err(program_error(ErrorCode::InvalidBatch))
+    );
+    assert_eq!(
+        kalshi.batch_trade(&alice, &buy_both(20 * USDC)),
+        Err(program_error(ErrorCode::SlippageExceeded))
+    );
+    assert_eq!(
+        kalshi.batch_trade(&alice, &[(0, leg(Outcome::Yes, Sell, 1, 0))]),
+        Err(program_error(ErrorCode::NoPosition))
+    );
+    kalshi
+        .set_access_policy(
+            &creator.key,
+            1,
+            AccessPolicy::Attestation,
+            kyc.key.to_bytes(),
+        )
+        .unwrap();
+    assert_eq!(
+        kalshi.batch_trade(&alice, &buy_both(1)),
+        Err(program_error(ErrorCode::AccessDenied))
+    );
+
+    // No failed leg left anything behind.
+    assert_eq!(kalshi.balance(&alice.token), 1_000 * USDC);
+    assert_eq!(kalshi.market_state(0).total_volume, 0);
+    let position = position_pda(&market_pda(0), &alice.key);
+    assert!(kalshi.svm.get_account(&position).is_none());
+
+    kalshi.issue_attestation(&kyc.key, &alice, 0).unwrap();
+    kalshi.batch_trade(&alice, &buy_both(1)).unwrap();
+    assert!(kalshi.shares(0, &alice).0 > 0 && kalshi.shares(1, &alice).1 > 0);
+}
+
+#[test]
+fn batch_trades_open_positions_someone_already_sent_lamports_to() {
+    use backend::TradeSide::Buy;
+
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    kalshi.create_market(&creator, 0).unwrap();
+    let position = position_pda(&market_pda(0), &alice.key);
+    kalshi.svm.airdrop(&position, 1);
+
+    kalshi
+        .batch_trade(&alice, &[(0, leg(Outcome::Yes, Buy, 10 * USDC, 1))])
+        .unwrap();
+    assert!(kalshi.shares(0, &alice).0 > 0);
+    assert_rent_exempt(&kalshi, &position);
+
+    // A wrong bump is rejected rather than searched for.
+    let mut ix = kalshi.batch_trade_ix(&alice, &[(0, leg(Outcome::Yes, Buy, USDC, 1))]);
+    let position_bump = ix.data.last_mut().unwrap();
+    *position_bump = position_bump.wrapping_sub(1);
+    assert_eq!(
+        kalshi.svm.process_instruction(ix),
+        Err(program_error(ErrorCode::InvalidBatch))
+    );
+}
+
+#[test]
+fn limit_orders_fill_once_the_price_crosses_their_trigger() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    // Buy NO once it drops to 40%, tipping the keeper 0.1 USDC.
+    let tip = USDC / 10;
+    let buy = OrderArgs {
+        tip,
+        ..OrderArgs::buy(Outcome::No, 10 * USDC, 400_000)
+    };
+    kalshi.place_limit_order(&alice, 0, buy).unwrap();
+    let order = limit_order_pda(&market_pda(0), &alice.key, 0);
+    assert_eq!(kalshi.balance(&alice.token), 1_000 * USDC - 10 * USDC - tip);
+    assert_eq!(kalshi.balance(&order_vault_pda(&order)), 10 * USDC + tip);
+
+    // At 50% the order keeps resting.
+    kalshi.crank_orders(&keeper, 0, &[(alice, 0)]).unwrap();
+    assert_eq!(kalshi.limit_order(0, &alice, 0).amount, 10 * USDC);
+    assert_eq!(kalshi.balance(&keeper.token), 0);
+
+    kalshi.buy(&bob, 0, Outcome::Yes, 30 * USDC).unwrap();
+    assert!(kalshi.market_state(0).price_of(Outcome::No).unwrap() <= 400_000);
+    let treasury = kalshi.balance(&kalshi.treasury);
+    kalshi.events::<backend::SharesBought>();
+    kalshi.crank_orders(&keeper, 0, &[(alice, 0)]).unwrap();
+
+    let bought = kalshi.events::<backend::SharesBought>().remove(0);
+    let position = kalshi.position(0, &alice);
+    assert_eq!(bought.user, alice.key);
+    assert_eq!(
+        (position.yes_shares, position.no_shares),
+        (0, bought.shares_out)
+    );
+    assert_eq!(position.total_invested, 10 * USDC);
+    assert_eq!(kalshi.balance(&keeper.token), tip);
+    assert_eq!(kalshi.balance(&kalshi.treasury), treasury + fee(10 * USDC));
+    assert_eq!(kalshi.balance(&alice.token), 1_000 * USDC - 10 * USDC - tip);
+    assert!(kalshi.svm.get_account(&order).is_none());
+    assert!(kalshi.svm.get_account(&order_vault_pda(&order)).is_none());
+
+    // Take profit on the NO shares once they are back at 45%; they are
+    // locked until then.
+    let shares = position.no_shares;
+    let sell = OrderArgs {
+        nonce: 1,
+        tip,
+        ..OrderArgs::sell(Outcome::No, shares, 450_000)
+    };
+    kalshi.place_limit_order(&alice, 0, sell).unwrap();
+    assert_eq!(kalshi.position(0, &alice).locked_no_shares, shares);
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::No, 1, 0),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+    kalshi.crank_orders(&keeper, 0, &[(alice, 1)]).unwrap();
+    assert_eq!(kalshi.shares(0, &alice), (0, shares));
+
+    let yes = kalshi.shares(0, &bob).0;
+    kalshi.sell(&bob, 0, Outcome::Yes, yes, 0).unwrap();
+    let balance = kalshi.balance(&alice.token);
+    kalshi.events::<backend::SharesSold>();
+    kalshi.crank_orders(&keeper, 0, &[(alice, 1)]).unwrap();
+    let sold = kalshi.events::<backend::SharesSold>().remove(0);
+    assert_eq!(sold.shares_in, shares);
+    let position = kalshi.position(0, &alice);
+    assert_eq!((position.no_shares, position.locked_no_shares), (0, 0));
+    assert_eq!(
+        kalshi.balance(&alice.token),
+        balance + sold.payout - sold.fee - tip
+    );
+    assert_eq!(kalshi.balance(&keeper.token), 2 * tip);
+}
+
+#[test]
+fn crank_skips_orders_whose_owner_closed_their_position() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let carol = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+
+    let buy = OrderArgs::buy(Outcome::No, 10 * USDC, 400_000);
+    kalshi.place_limit_order(&alice, 0, buy).unwrap();
+    kalshi.place_limit_order(&carol, 0, buy).unwrap();
+    kalshi.close_position(&alice, 0).unwrap();
+    kalshi.buy(&bob, 0, Outcome::Yes, 30 * USDC).unwrap();
+
+    kalshi
+        .crank_orders(&keeper, 0, &[(alice, 0), (carol, 0)])
+        .unwrap();
+    assert!(kalshi.shares(0, &carol).1 > 0);
+    assert_eq!(kalshi.limit_order(0, &alice, 0).amount, 10 * USDC);
+
+    // Alice can still take her escrow back.
+    kalshi.cancel_limit_order(&alice, 0, 0).unwrap();
+    assert_eq!(kalshi.balance(&alice.token), 1_000 * USDC);
+}
+
+#[test]
+fn limit_orders_respect_their_limits_and_can_be_cancelled() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 10 * USDC).unwrap();
+    let held = kalshi.shares(0, &alice).0;
+    let balance = kalshi.balance(&alice.token);
+
+    assert_eq!(
+        kalshi.place_limit_order(
+            &alice,
+            0,
+            OrderArgs::buy(Outcome::Yes, USDC, backend::PRICE_SCALE)
+        ),
+        Err(program_error(ErrorCode::InvalidTriggerPrice))
+    );
+    let mut ix = kalshi.place_limit_order_ix(&alice, 0, OrderArgs::sell(Outcome::Yes, 1, 1));
+    ix.accounts[4] = kalshi
+        .place_limit_order_ix(&alice, 0, OrderArgs::buy(Outcome::Yes, 1, 1))
+        .accounts[4]
+        .clone();
+    assert_eq!(
+        kalshi.svm.process_instruction(ix),
+        Err(program_error(ErrorCode::InvalidOrder))
+    );
+    assert_eq!(
+        kalshi.place_limit_order(&alice, 0, OrderArgs::sell(Outcome::Yes, held + 1, 1)),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+
+    // Triggered orders whose fill would miss their limit keep resting.
+    let buy = OrderArgs {
+        limit: 100 * USDC,
+        ..OrderArgs::buy(Outcome::Yes, 10 * USDC, 900_000)
+    };
+    let sell = OrderArgs {
+        nonce: 1,
+        limit: 100 * USDC,
+        ..OrderArgs::sell(Outcome::Yes, held, 1)
+    };
+    kalshi.place_limit_order(&alice, 0, buy).unwrap();
+    kalshi.place_limit_order(&alice, 0, sell).unwrap();
+    kalshi
+        .crank_orders(&keeper, 0, &[(alice, 0), (alice, 1)])
+        .unwrap();
+    assert_eq!(kalshi.shares(0, &alice), (held, 0));
+    assert_eq!(kalshi.balance(&alice.token), balance - 10 * USDC);
+
+    kalshi.cancel_limit_order(&alice, 0, 0).unwrap();
+    kalshi.cancel_limit_order(&alice, 0, 1).unwrap();
+    assert_eq!(kalshi.balance(&alice.token), balance);
+    assert_eq!(kalshi.position(0, &alice).locked_yes_shares, 0);
+    kalshi.sell(&alice, 0, Outcome::Yes, held, 0).unwrap();
+}
+
+#[test]
+fn limit_order_escrow_covers_transfer_fees() {
+    let mut kalshi = Kalshi::new();
+    let (mint, _) = transfer_fee_collateral(&mut kalshi, 50);
+    let creator = kalshi.user(0);
+    let creator = kalshi.wallet(&creator, &mint, 1_000 * USDC);
+    let alice = kalshi.user(0);
+    let alice = kalshi.wallet(&alice, &mint, 1_000 * USDC);
+    let keeper = kalshi.user(0);
+    let keeper = kalshi.wallet(&keeper, &mint, 0);
+    kalshi
+        .create_market_with(&creator, MarketArgs::new(0, kalshi.now()).in_mint(mint))
+        .unwrap();
+
+    let buy = OrderArgs {
+        tip: USDC,
+        ..OrderArgs::buy(Outcome::Yes, 10 * USDC, 600_000)
+    };
+    kalshi.place_limit_order(&alice, 0, buy).unwrap();
+    kalshi.crank_orders(&keeper, 0, &[(alice, 0)]).unwrap();
+
+    // The pool is credited exactly, the keeper is tipped net of the fee, and
+    // the harvested order vault is gone.
+    let state = kalshi.market_state(0);
+    assert_eq!(
+        kalshi.vault_balance(0),
+        100 * USDC + state.total_volume - state.total_fees
+    );
+    assert!(kalshi.balance(&keeper.token) > 0);
+    let order = limit_order_pda(&market_pda(0), &alice.key, 0);
+    assert!(kalshi.svm.get_account(&order_vault_pda(&order)).is_none());
+}
+
+#[test]
+fn exit_orders_sell_locked_shares_once_a_threshold_is_crossed() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let bob = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 20 * USDC).unwrap();
+    let held = kalshi.shares(0, &alice).0;
+    let half = held / 2;
+
+    let tip = USDC / 10;
+    let stop = ExitArgs {
+        tip,
+        ..ExitArgs::stop_loss(Outcome::Yes, half, 450_000)
+    };
+    let take = ExitArgs {
+        nonce: 1,
+        tip,
+        ..ExitArgs::take_profit(Outcome::Yes, held - half, 700_000)
+    };
+    kalshi.place_exit_order(&alice, 0, stop).unwrap();
+    kalshi.place_exit_order(&alice, 0, take).unwrap();
+    let position = kalshi.position(0, &alice);
+    assert_eq!(position.locked_yes_shares, held);
+    assert_eq!(
+        kalshi.exit_order(0, &alice, 0).expires_at,
+        kalshi.market_state(0).end_timestamp
+    );
+    assert_eq!(
+        kalshi.sell(&alice, 0, Outcome::Yes, 1, 0),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+    assert_eq!(
+        kalshi.execute_exit_order(&keeper, &alice, 0, 0),
+        Err(program_error(ErrorCode::ExitNotTriggered))
+    );
+
+    // The price falls through the stop.
+    kalshi.buy(&bob, 0, Outcome::No, 40 * USDC).unwrap();
+    assert!(kalshi.market_state(0).price_of(Outcome::Yes).unwrap() <= 450_000);
+    assert_eq!(
+        kalshi.execute_exit_order(&keeper, &alice, 0, 1),
+        Err(program_error(ErrorCode::ExitNotTriggered))
+    );
+    let balance = kalshi.balance(&alice.token);
+    kalshi.events::<backend::SharesSold>();
+    kalshi.execute_exit_order(&keeper, &alice, 0, 0).unwrap();
+    let sold = kalshi.events::<backend::SharesSold>().remove(0);
+    assert_eq!((sold.user, sold.shares_in), (alice.key, half));
+    assert_eq!(
+        kalshi.balance(&alice.token),
+        balance + sold.payout - sold.fee - tip
+    );
+    assert_eq!(kalshi.balance(&keeper.token), tip);
+    let position = kalshi.position(0, &alice);
+    assert_eq!(
+        (position.yes_shares, position.locked_yes_shares),
+        (held - half, held - half)
+    );
+    let order = exit_order_pda(&position_pda(&market_pda(0), &alice.key), 0);
+    assert!(kalshi.svm.get_account(&order).is_none());
+
+    // And later rallies through the take-profit.
+    let no = kalshi.shares(0, &bob).1;
+    kalshi.sell(&bob, 0, Outcome::No, no, 0).unwrap();
+    kalshi.buy(&bob, 0, Outcome::Yes, 60 * USDC).unwrap();
+    kalshi.execute_exit_order(&keeper, &alice, 0, 1).unwrap();
+    let position = kalshi.position(0, &alice);
+    assert_eq!((position.yes_shares, position.locked_yes_shares), (0, 0));
+    assert_eq!(kalshi.balance(&keeper.token), 2 * tip);
+}
+
+#[test]
+fn exit_orders_expire_with_the_market_and_survive_a_halt() {
+    let mut kalshi = Kalshi::new();
+    let creator = kalshi.user(1_000 * USDC);
+    let alice = kalshi.user(1_000 * USDC);
+    let keeper = kalshi.user(0);
+    let authority = kalshi.authority;
+    kalshi.create_market(&creator, 0).unwrap();
+    kalshi.buy(&alice, 0, Outcome::Yes, 40 * USDC).unwrap();
+    let held = kalshi.shares(0, &alice).0;
+
+    assert_eq!(
+        kalshi.place_exit_order(
+            &alice,
+            0,
+            ExitArgs {
+                stop_price: 800_000,
+                ..ExitArgs::take_profit(Outcome::Yes, held, 700_000)
+            }
+        ),
+        Err(program_error(ErrorCode::InvalidTriggerPrice))
+    );
+    assert_eq!(
+        kalshi.place_exit_order(&alice, 0, ExitArgs::stop_loss(Outcome::Yes, held + 1, 1)),
+        Err(program_error(ErrorCode::InsufficientShares))
+    );
+
+    // Selling everything back would move the price out of the band, so the
+    // breaker halts the market and the order stays open.
+    kalshi
+        .place_exit_order(&alice, 0, ExitArgs::take_profit(Outcome::Yes, held, 1))
+        .unwrap();
+    kalshi
+        .set_circuit_breaker(&authority, 0, 3_600, 500, 600)
+        .unwrap();
+    kalshi.execute_exit_order(&keeper, &alice, 0, 0).unwrap();
+    assert_eq!(kalshi.svm.return_data(), [0]);
+    assert!(kalshi.market_state(0).halted_until > kalshi.now());
+    assert_eq!(kalshi.exit_order(0, &alice, 0).shares, held);
+    assert_eq!(kalshi.position(0, &alice).locked_yes_shares, held);
+
+    // Only the owner may cancel before the market ends; anyone after.
+    assert_eq!(
+        kalshi.cancel_exit_order(&keeper.key, &alice, 0, 0),
+        Err(program_error(ErrorCode::Unauthorized))
+    );
+    kalshi.svm.warp(DAY);
+    assert_eq!(
+        kalshi.execute_exit_order(&keeper, &alice, 0, 0),
+        Err(program_error(ErrorCode::MarketEnded))
+    );
+    kalshi.cancel_exit_order(&keeper.key, &alice, 0, 0).unwrap();
+    assert_eq!(kalshi.position(0, &alice).locked_yes_shares, 0);
+}
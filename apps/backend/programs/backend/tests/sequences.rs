@@ -0,0 +1,184 @@
+//! Runs the fuzz driver from `common::sequence` under `cargo test`, so the
+//! invariants are exercised without a nightly toolchain. Longer campaigns
+//! live in `fuzz/` (`cargo fuzz run instruction_sequences`).
+
+mod common;
+
+use arbitrary::{Arbitrary, Unstructured};
+use common::sequence::{run, Op};
+use proptest::prelude::*;
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn random_sequences_conserve_usdc(bytes in prop::collection::vec(any::<u8>(), 0..1_024)) {
+        let mut input = Unstructured::new(&bytes);
+        if let Ok(ops) = Vec::<Op>::arbitrary(&mut input) {
+            run(&ops);
+        }
+    }
+}
+
+#[test]
+fn selling_into_a_near_empty_pool() {
+    run(&[
+        Op::CreateMarket {
+            creator: 0,
+            market: 0,
+            liquidity: 1_000_000,
+            duration: 60,
+        },
+        Op::Buy {
+            user: 1,
+            market: 0,
+            yes: true,
+            max_cost: u32::MAX,
+        },
+        Op::Buy {
+            user: 2,
+            market: 0,
+            yes: true,
+            max_cost: u32::MAX,
+        },
+        Op::Sell {
+            user: 1,
+            market: 0,
+            yes: true,
+            portion: u8::MAX,
+        },
+        Op::Sell {
+            user: 2,
+            market: 0,
+            yes: true,
+            portion: u8::MAX,
+        },
+    ]);
+}
+
+#[test]
+fn claiming_after_partial_sells() {
+    for yes in [true, false] {
+        run(&[
+            Op::CreateMarket {
+                creator: 0,
+                market: 1,
+                liquidity: 50_000_000,
+                duration: 1,
+            },
+            Op::Buy {
+                user: 1,
+                market: 1,
+                yes: true,
+                max_cost: 30_000_000,
+            },
+            Op::Buy {
+                user: 2,
+                market: 1,
+                yes: false,
+                max_cost: 20_000_000,
+            },
+            Op::Sell {
+                user: 1,
+                market: 1,
+                yes: true,
+                portion: 100,
+            },
+            Op::Sell {
+                user: 2,
+                market: 1,
+                yes: false,
+                portion: 30,
+            },
+            Op::Warp { hours: 1 },
+            Op::Resolve {
+                market: 1,
+                yes,
+                by_authority: true,
+            },
+            Op::ClaimWinnings { user: 1, market: 1 },
+            Op::ClaimWinnings { user: 2, market: 1 },
+            Op::ClaimWinnings { user: 1, market: 1 },
+        ]);
+    }
+}
+
+#[test]
+fn refunds_after_invalidation_mid_trading() {
+    run(&[
+        Op::CreateMarket {
+            creator: 3,
+            market: 2,
+            liquidity: 10_000_000,
+            duration: 600,
+        },
+        Op::Buy {
+            user: 0,
+            market: 2,
+            yes: false,
+            max_cost: 5_000_000,
+        },
+        Op::Buy {
+            user: 1,
+            market: 2,
+            yes: true,
+            max_cost: 7_000_000,
+        },
+        Op::Invalidate { market: 2 },
+        Op::Buy {
+            user: 2,
+            market: 2,
+            yes: true,
+            max_cost: 1_000_000,
+        },
+        Op::ClaimRefund { user: 0, market: 2 },
+        Op::ClaimRefund { user: 1, market: 2 },
+        Op::ClaimRefund { user: 1, market: 2 },
+    ]);
+}
+
+#[test]
+fn closing_a_market_and_creating_another_in_its_slot() {
+    let mut ops = vec![
+        Op::CreateMarket {
+            creator: 0,
+            market: 0,
+            liquidity: 10_000_000,
+            duration: 1,
+        },
+        Op::Buy {
+            user: 1,
+            market: 0,
+            yes: false,
+            max_cost: 4_000_000,
+        },
+        Op::Warp { hours: 1 },
+        Op::Resolve {
+            market: 0,
+            yes: false,
+            by_authority: false,
+        },
+    ];
+    ops.extend([Op::Warp { hours: u8::MAX }; 3]);
+    ops.extend([
+        Op::CloseMarket {
+            market: 0,
+            by_authority: false,
+        },
+        Op::CreateMarket {
+            creator: 2,
+            market: 0,
+            liquidity: 10_000_000,
+            duration: 1,
+        },
+        Op::ClaimWinnings { user: 1, market: 0 },
+        Op::ClosePosition { user: 1, market: 0 },
+        Op::Buy {
+            user: 1,
+            market: 0,
+            yes: true,
+            max_cost: 1_000_000,
+        },
+    ]);
+    run(&ops);
+}